@@ -0,0 +1,203 @@
+use crate::admin::json_string;
+use crate::response;
+use serde::Deserialize;
+use std::time::Duration;
+
+/// How balancebeam logs each request it answers
+#[derive(clap::ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccessLogFormat {
+    /// A human-readable `client <- status line` log message, followed by the reason if balancebeam
+    /// answered the request itself
+    Plain,
+    /// One JSON object per line on stdout, apart from the other log messages (which go to stderr)
+    Json,
+}
+
+/// What happened to one client request, logged as it is answered
+pub struct AccessLogEntry<'a> {
+    client_ip: &'a str,
+    response: &'a http::Response<Vec<u8>>,
+    request: Option<&'a http::Request<Vec<u8>>>,
+    request_id: Option<&'a str>,
+    upstream: Option<&'a str>,
+    elapsed: Option<Duration>,
+    error: Option<&'static str>,
+    response_bytes: Option<usize>,
+    bytes: Option<(u64, u64)>,
+}
+
+impl<'a> AccessLogEntry<'a> {
+    pub fn new(client_ip: &'a str, response: &'a http::Response<Vec<u8>>) -> Self {
+        AccessLogEntry {
+            client_ip,
+            response,
+            request: None,
+            request_id: None,
+            upstream: None,
+            elapsed: None,
+            error: None,
+            response_bytes: None,
+            bytes: None,
+        }
+    }
+
+    /// The request being answered, unless it couldn't be parsed
+    pub fn request(mut self, request: &'a http::Request<Vec<u8>>) -> Self {
+        self.request = Some(request);
+        self
+    }
+
+    /// The ID the request was traced by, i.e. its X-Request-Id
+    pub fn request_id(mut self, id: &'a str) -> Self {
+        self.request_id = Some(id);
+        self
+    }
+
+    /// The upstream the request was (last) forwarded to
+    pub fn upstream(mut self, address: &'a str) -> Self {
+        self.upstream = Some(address);
+        self
+    }
+
+    /// How long forwarding the request and reading the response took
+    pub fn elapsed(mut self, elapsed: Duration) -> Self {
+        self.elapsed = Some(elapsed);
+        self
+    }
+
+    /// Why balancebeam answered the request itself, e.g. `"rate_limited"`
+    pub fn error(mut self, reason: &'static str) -> Self {
+        self.error = Some(reason);
+        self
+    }
+
+    /// The size of the response body, when it was streamed through rather than held in `response`
+    pub fn response_bytes(mut self, len: usize) -> Self {
+        self.response_bytes = Some(len);
+        self
+    }
+
+    /// What went over the client's connection for the request, both ways, headers and all
+    pub fn bytes(mut self, bytes_in: u64, bytes_out: u64) -> Self {
+        self.bytes = Some((bytes_in, bytes_out));
+        self
+    }
+
+    pub fn response(&self) -> &http::Response<Vec<u8>> {
+        self.response
+    }
+
+    /// What other log messages about the request start with, so they can be told apart from those
+    /// about other requests: `[request ID] `, or nothing if it has none.
+    pub fn log_prefix(&self) -> String {
+        match self.request_id {
+            Some(id) => format!("[{}] ", id),
+            None => String::new(),
+        }
+    }
+
+    pub fn log(&self, format: AccessLogFormat) {
+        let prefix = self.log_prefix();
+        match format {
+            AccessLogFormat::Plain => match self.error {
+                Some(reason) => log::info!(
+                    "{}{} <- {} ({})",
+                    prefix,
+                    self.client_ip,
+                    response::format_response_line(self.response),
+                    reason
+                ),
+                None => log::info!(
+                    "{}{} <- {}",
+                    prefix,
+                    self.client_ip,
+                    response::format_response_line(self.response)
+                ),
+            },
+            AccessLogFormat::Json => println!("{}", self.to_json()),
+        }
+    }
+
+    /// Formats the entry as a single-line JSON object, e.g. `{"client_ip":"10.0.0.7","method":
+    /// "GET","path":"/","request_id":"abc-123","upstream":"10.0.0.1:80","status":200,
+    /// "request_bytes":0,"response_bytes":512,"bytes_in":78,"bytes_out":720,"elapsed_ms":1.204,
+    /// "error":null}`. The request and response byte counts are of the bodies, while `bytes_in`
+    /// and `bytes_out` are everything read from and written to the client for the request; fields
+    /// that don't apply (e.g. the upstream of a rate-limited request) are null.
+    fn to_json(&self) -> String {
+        let or_null = |value: Option<String>| value.unwrap_or_else(|| String::from("null"));
+        format!(
+            "{{\"client_ip\":{},\"method\":{},\"path\":{},\"request_id\":{},\"upstream\":{},\
+             \"status\":{},\"request_bytes\":{},\"response_bytes\":{},\"bytes_in\":{},\"bytes_out\":{},\"elapsed_ms\":{},\
+             \"error\":{}}}",
+            json_string(self.client_ip),
+            or_null(
+                self.request
+                    .map(|request| json_string(request.method().as_str()))
+            ),
+            or_null(
+                self.request
+                    .map(|request| json_string(request.uri().path()))
+            ),
+            or_null(self.request_id.map(json_string)),
+            or_null(self.upstream.map(json_string)),
+            self.response.status().as_u16(),
+            or_null(self.request.map(|request| request.body().len().to_string())),
+            self.response_bytes
+                .unwrap_or_else(|| self.response.body().len()),
+            or_null(self.bytes.map(|(bytes_in, _)| bytes_in.to_string())),
+            or_null(self.bytes.map(|(_, bytes_out)| bytes_out.to_string())),
+            or_null(
+                self.elapsed
+                    .map(|elapsed| format!("{:.3}", elapsed.as_secs_f64() * 1000.0))
+            ),
+            or_null(self.error.map(json_string)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_forwarded_request_json() {
+        let request = http::Request::builder()
+            .method("POST")
+            .uri("/submit?draft=1")
+            .body(b"hello".to_vec())
+            .unwrap();
+        let response = http::Response::builder()
+            .status(201)
+            .body(b"created".to_vec())
+            .unwrap();
+        let entry = AccessLogEntry::new("10.0.0.7", &response)
+            .request(&request)
+            .request_id("abc-123")
+            .upstream("10.0.0.1:80")
+            .elapsed(Duration::from_micros(1204))
+            .bytes(96, 85);
+        assert_eq!(
+            entry.to_json(),
+            "{\"client_ip\":\"10.0.0.7\",\"method\":\"POST\",\"path\":\"/submit\",\
+             \"request_id\":\"abc-123\",\"upstream\":\"10.0.0.1:80\",\"status\":201,\"request_bytes\":5,\
+             \"response_bytes\":7,\"bytes_in\":96,\"bytes_out\":85,\"elapsed_ms\":1.204,\"error\":null}"
+        );
+    }
+
+    #[test]
+    fn test_unforwarded_request_json() {
+        let response = response::make_http_error(http::StatusCode::BAD_REQUEST);
+        let entry = AccessLogEntry::new("10.0.0.7", &response).error("bad_request");
+        assert_eq!(
+            entry.to_json(),
+            format!(
+                "{{\"client_ip\":\"10.0.0.7\",\"method\":null,\"path\":null,\"request_id\":null,\
+                 \"upstream\":null,\"status\":400,\"request_bytes\":null,\"response_bytes\":{},\
+                 \"bytes_in\":null,\"bytes_out\":null,\"elapsed_ms\":null,\"error\":\"bad_request\"}}",
+                response.body().len()
+            )
+        );
+    }
+}
@@ -0,0 +1,532 @@
+use crate::admin_auth::{self, AdminAuth};
+use crate::cidr;
+use crate::upstream::{self, UpstreamInfo};
+use crate::{request, response, ProxyState};
+use std::fmt::Write;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::BufReader;
+use tokio::net::{TcpListener, TcpStream};
+
+/// Serves the admin endpoints on `listener`. Admin requests never go through the rate limiter or
+/// to an upstream. With `auth`, every request but those for --self-health-path needs one of its
+/// credentials, or gets a 401.
+pub async fn serve(
+    mut listener: TcpListener,
+    state: Arc<ProxyState>,
+    auth: Option<Arc<AdminAuth>>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, _)) => {
+                let state = state.clone();
+                let auth = auth.clone();
+                tokio::spawn(async move {
+                    handle_admin_connection(socket, &state, auth.as_deref()).await;
+                });
+            }
+            Err(error) => log::warn!("Failed to accept admin connection: {}", error),
+        }
+    }
+}
+
+async fn handle_admin_connection(conn: TcpStream, state: &ProxyState, auth: Option<&AdminAuth>) {
+    let mut conn = BufReader::new(conn);
+    loop {
+        let request = match request::read_from_stream(
+            &mut conn,
+            state.header_limits,
+            state.max_request_body_bytes,
+        )
+        .await
+        {
+            Ok(request) => request,
+            Err(request::Error::IncompleteRequest(0)) | Err(request::Error::ConnectionError(_)) => {
+                return;
+            }
+            Err(error) => {
+                log::debug!("Error parsing admin request: {:?}", error);
+                let response = response::make_http_error(http::StatusCode::BAD_REQUEST);
+                let _ = response::write_to_stream(&response, conn.get_mut()).await;
+                return;
+            }
+        };
+        let path = request.uri().path();
+        let response = match (request.method(), path) {
+            // Whatever checks on balancebeam may not have credentials, and learns only two counts
+            (method, _) if path == state.self_health_path => self_health(state, method),
+            // Only the request line is ever logged, never the Authorization header
+            _ if !auth.is_none_or(|auth| auth.allows(request.headers())) => admin_auth::challenge(),
+            (&http::Method::GET, "/status") => make_text_response(
+                http::StatusCode::OK,
+                "application/json",
+                status_json(state).await,
+            ),
+            (&http::Method::GET, "/metrics") => make_text_response(
+                http::StatusCode::OK,
+                "text/plain; version=0.0.4",
+                metrics_text(state).await,
+            ),
+            (&http::Method::POST, "/upstreams") => add_upstream(state, &request).await,
+            (&http::Method::POST, _) if path.starts_with("/upstreams/") => {
+                match path["/upstreams/".len()..].rsplit_once('/') {
+                    Some((address, "drain")) => set_draining(state, address, true).await,
+                    Some((address, "undrain")) => set_draining(state, address, false).await,
+                    _ => response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED),
+                }
+            }
+            (&http::Method::DELETE, _) if path.starts_with("/upstreams/") => {
+                remove_upstream(state, &path["/upstreams/".len()..]).await
+            }
+            (&http::Method::GET, "/denylist") => denylist_response(state, http::StatusCode::OK),
+            (&http::Method::POST, "/denylist") => add_denied(state, &request),
+            (&http::Method::DELETE, _) if path.starts_with("/denylist/") => {
+                remove_denied(state, &path["/denylist/".len()..])
+            }
+            (_, "/status") | (_, "/metrics") | (_, "/upstreams") | (_, "/denylist") => {
+                response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED)
+            }
+            _ if path.starts_with("/upstreams/") || path.starts_with("/denylist/") => {
+                response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED)
+            }
+            _ => response::make_http_error(http::StatusCode::NOT_FOUND),
+        };
+        log::debug!(
+            "[admin] {} <- {}",
+            request::format_request_line(&request),
+            response::format_response_line(&response)
+        );
+        if let Err(error) = response::write_to_stream(&response, conn.get_mut()).await {
+            log::warn!("Failed to send admin response: {}", error);
+            return;
+        }
+    }
+}
+
+fn make_text_response(
+    status: http::StatusCode,
+    content_type: &str,
+    body: String,
+) -> http::Response<Vec<u8>> {
+    let body = body.into_bytes();
+    http::Response::builder()
+        .status(status)
+        .header("Content-Type", content_type)
+        .header("Content-Length", body.len().to_string())
+        .version(http::Version::HTTP_11)
+        .body(body)
+        .unwrap()
+}
+
+/// Answers --self-health-path: 200 if any upstream is alive, 503 if none is, e.g.
+/// `{"healthy":2,"upstreams":3}`. An address in several groups counts once for each. Only GET and
+/// HEAD are allowed.
+pub fn self_health(state: &ProxyState, method: &http::Method) -> http::Response<Vec<u8>> {
+    if method != http::Method::GET && method != http::Method::HEAD {
+        return response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let (mut healthy, mut total) = (0, 0);
+    for group in &state.groups {
+        let upstreams = group.upstreams.read().unwrap();
+        healthy += upstreams.iter().filter(|upstream| upstream.healthy).count();
+        total += upstreams.len();
+    }
+    let status = if healthy > 0 {
+        http::StatusCode::OK
+    } else {
+        http::StatusCode::SERVICE_UNAVAILABLE
+    };
+    let mut response = make_text_response(
+        status,
+        "application/json",
+        format!("{{\"healthy\":{},\"upstreams\":{}}}", healthy, total),
+    );
+    if method == http::Method::HEAD {
+        response.body_mut().clear();
+    }
+    response
+}
+
+/// Builds the `/status` document, e.g.
+/// `{"upstreams":[{"address":"10.0.0.1:80","alive":true,"circuit":"closed","requests":12,
+/// "failures":0}],"rate_limiter_clients":3,"request_permits":{"in_use":2,"max":64}}`.
+/// `request_permits` is null unless --max-concurrent-requests is set. The upstreams of each route
+/// follow the default ones, with a `"route"` naming it. An address an upstream hostname resolved to
+/// has a `"resolved_from"` giving the hostname. One with a request limit has `"slots"`, like
+/// `request_permits` but for that upstream alone. One that has answered an active health check has
+/// `"probe_latency_ms"` with the p50, p95 and max of its recent ones, e.g.
+/// `{"p50":1.204,"p95":3.5,"max":4.012}`. A backup upstream has `"backup":true`, and a draining
+/// one `"draining":true`. `bytes_in` and `bytes_out` count everything read from and written to
+/// clients, headers and all.
+async fn status_json(state: &ProxyState) -> String {
+    let mut upstreams: Vec<String> = Vec::new();
+    for group in &state.groups {
+        let route = match group.name() {
+            Some(name) => format!(",\"route\":{}", json_string(name)),
+            None => String::new(),
+        };
+        upstreams.extend(group.upstreams.read().unwrap().iter().map(|upstream| {
+            let slots = match upstream.max_requests {
+                0 => String::new(),
+                max => format!(
+                    ",\"slots\":{{\"in_use\":{},\"max\":{}}}",
+                    upstream.requests_in_flight(),
+                    max
+                ),
+            };
+            let resolved_from = match &upstream.name {
+                Some(name) => format!(",\"resolved_from\":{}", json_string(name)),
+                None => String::new(),
+            };
+            let backup = if upstream.backup {
+                ",\"backup\":true"
+            } else {
+                ""
+            };
+            let draining = if upstream.draining {
+                ",\"draining\":true"
+            } else {
+                ""
+            };
+            let ms = |latency: Duration| latency.as_secs_f64() * 1000.0;
+            let probe_latency = match upstream.probe_latencies.summary() {
+                Some(summary) => format!(
+                    ",\"probe_latency_ms\":{{\"p50\":{:.3},\"p95\":{:.3},\"max\":{:.3}}}",
+                    ms(summary.p50),
+                    ms(summary.p95),
+                    ms(summary.max)
+                ),
+                None => String::new(),
+            };
+            format!(
+                "{{\"address\":{},\"alive\":{},\"circuit\":\"{}\",\"requests\":{},\"failures\":{}{}{}{}{}{}{}}}",
+                json_string(&upstream.address),
+                upstream.healthy,
+                upstream.circuit,
+                upstream.stats.requests(),
+                upstream.stats.failures(),
+                backup,
+                draining,
+                slots,
+                probe_latency,
+                resolved_from,
+                route
+            )
+        }));
+    }
+    let rate_limiter_clients = state.rate_limiter.len();
+    let request_permits = match state.max_concurrent_requests {
+        0 => String::from("null"),
+        max => format!(
+            "{{\"in_use\":{},\"max\":{}}}",
+            max - state.request_permits.available_permits(),
+            max
+        ),
+    };
+    format!(
+        "{{\"upstreams\":[{}],\"rate_limiter_clients\":{},\"request_permits\":{},\"bytes_in\":{},\"bytes_out\":{}}}",
+        upstreams.join(","),
+        rate_limiter_clients,
+        request_permits,
+        state.client_bytes.bytes_in(),
+        state.client_bytes.bytes_out()
+    )
+}
+
+/// Handles `POST /upstreams`, whose body names the upstream to add the same way `--upstream` does
+/// (`host:port` or `host:port=weight`). The new upstream starts out healthy. Only the default
+/// group (`groups[0]`) is ever added to; routes' upstreams can't be managed at runtime.
+async fn add_upstream(
+    state: &ProxyState,
+    request: &http::Request<Vec<u8>>,
+) -> http::Response<Vec<u8>> {
+    let parsed = std::str::from_utf8(request.body())
+        .map_err(|_| String::from("upstream must be UTF-8"))
+        .and_then(|body| upstream::parse_upstream(body.trim()));
+    let mut upstream = match parsed {
+        Ok(upstream) => upstream,
+        Err(message) => {
+            return make_text_response(http::StatusCode::BAD_REQUEST, "text/plain", message + "\n")
+        }
+    };
+    {
+        let mut upstreams = state.groups[0].upstreams.write().unwrap();
+        if upstreams.iter().any(|info| {
+            info.address == upstream.address || info.configured_address() == upstream.address
+        }) {
+            return make_text_response(
+                http::StatusCode::CONFLICT,
+                "text/plain",
+                format!("upstream {} already exists\n", upstream.address),
+            );
+        }
+        log::info!("Adding upstream {}", upstream.address);
+        upstream::default_max_requests(Some(&mut upstream), state.max_requests_per_upstream);
+        upstreams.push(UpstreamInfo::new(upstream));
+    }
+    make_text_response(
+        http::StatusCode::CREATED,
+        "application/json",
+        status_json(state).await,
+    )
+}
+
+/// Handles `DELETE /upstreams/{address}`. The upstream gets no new requests from then on, but
+/// requests already forwarded to it finish normally. Its idle pooled connections are closed. An
+/// upstream given by hostname is removed along with every address it resolved to. As with
+/// add_upstream, only the default group (`groups[0]`) is looked in, not routes' groups.
+async fn remove_upstream(state: &ProxyState, address: &str) -> http::Response<Vec<u8>> {
+    {
+        let mut upstreams = state.groups[0].upstreams.write().unwrap();
+        let (removed, kept): (Vec<UpstreamInfo>, Vec<UpstreamInfo>) =
+            std::mem::take(&mut *upstreams)
+                .into_iter()
+                .partition(|info| info.address == address || info.configured_address() == address);
+        *upstreams = kept;
+        if removed.is_empty() {
+            return response::make_http_error(http::StatusCode::NOT_FOUND);
+        }
+        log::info!("Removing upstream {}", address);
+        let mut pool = state.upstream_pool.lock().unwrap();
+        for info in removed {
+            pool.forget(&info.address);
+        }
+    }
+    make_text_response(
+        http::StatusCode::OK,
+        "application/json",
+        status_json(state).await,
+    )
+}
+
+/// Handles `POST /upstreams/{address}/drain` and `POST /upstreams/{address}/undrain`, which take
+/// the upstream out of rotation and put it back, in every group it is in. A draining upstream gets
+/// no new requests, but requests already forwarded to it finish normally, and it is still health
+/// checked. An upstream given by hostname is drained along with every address it resolved to.
+async fn set_draining(
+    state: &ProxyState,
+    address: &str,
+    draining: bool,
+) -> http::Response<Vec<u8>> {
+    let mut found = false;
+    for group in &state.groups {
+        for info in group.upstreams.write().unwrap().iter_mut() {
+            if info.address == address || info.configured_address() == address {
+                info.draining = draining;
+                found = true;
+            }
+        }
+    }
+    if !found {
+        return response::make_http_error(http::StatusCode::NOT_FOUND);
+    }
+    if draining {
+        log::info!("Draining upstream {}", address);
+    } else {
+        log::info!("No longer draining upstream {}", address);
+    }
+    make_text_response(
+        http::StatusCode::OK,
+        "application/json",
+        status_json(state).await,
+    )
+}
+
+/// Answers with the deny list, e.g. `{"denylist":["203.0.113.0/24","2001:db8::1/128"]}`.
+fn denylist_response(state: &ProxyState, status: http::StatusCode) -> http::Response<Vec<u8>> {
+    let ranges: Vec<String> = state
+        .deny_list
+        .ranges()
+        .iter()
+        .map(|range| json_string(&range.to_string()))
+        .collect();
+    make_text_response(
+        status,
+        "application/json",
+        format!("{{\"denylist\":[{}]}}", ranges.join(",")),
+    )
+}
+
+/// Handles `POST /denylist`, whose body is an IP or CIDR range written as for `--deny`. New
+/// connections from it are refused from then on; ones already open are left be.
+fn add_denied(state: &ProxyState, request: &http::Request<Vec<u8>>) -> http::Response<Vec<u8>> {
+    let parsed = std::str::from_utf8(request.body())
+        .map_err(|_| String::from("range must be UTF-8"))
+        .and_then(|body| cidr::parse_ip_or_cidr(body.trim()));
+    let range = match parsed {
+        Ok(range) => range,
+        Err(message) => {
+            return make_text_response(http::StatusCode::BAD_REQUEST, "text/plain", message + "\n")
+        }
+    };
+    if !state.deny_list.add(range) {
+        return make_text_response(
+            http::StatusCode::CONFLICT,
+            "text/plain",
+            format!("{} is already denied\n", range),
+        );
+    }
+    log::info!("Denying connections from {}", range);
+    denylist_response(state, http::StatusCode::CREATED)
+}
+
+/// Handles `DELETE /denylist/{range}`, e.g. `DELETE /denylist/203.0.113.0/24`, for a range written
+/// the way it was added.
+fn remove_denied(state: &ProxyState, range: &str) -> http::Response<Vec<u8>> {
+    match cidr::parse_ip_or_cidr(range) {
+        Ok(range) if state.deny_list.remove(&range) => {
+            log::info!("No longer denying connections from {}", range);
+            denylist_response(state, http::StatusCode::OK)
+        }
+        _ => response::make_http_error(http::StatusCode::NOT_FOUND),
+    }
+}
+
+/// Builds the `/metrics` page in the Prometheus text exposition format. A route's upstreams are
+/// labelled with its name too.
+async fn metrics_text(state: &ProxyState) -> String {
+    let mut labels: Vec<String> = Vec::new();
+    let mut stats = Vec::new();
+    let mut upstreams_healthy = 0;
+    for group in &state.groups {
+        let route = match group.name() {
+            Some(name) => format!(",route={}", label_value(name)),
+            None => String::new(),
+        };
+        let upstreams = group.upstreams.read().unwrap();
+        labels.extend(
+            upstreams
+                .iter()
+                .map(|upstream| format!("upstream={}{}", label_value(&upstream.address), route)),
+        );
+        stats.extend(upstreams.iter().map(|upstream| upstream.stats.clone()));
+        upstreams_healthy += upstreams.iter().filter(|upstream| upstream.healthy).count();
+    }
+    let mut out = String::new();
+
+    out += "# HELP requests_total Responses received from each upstream, by status class.\n";
+    out += "# TYPE requests_total counter\n";
+    for (label, stats) in labels.iter().zip(&stats) {
+        for (class, count) in stats.responses_by_class() {
+            writeln!(
+                out,
+                "requests_total{{{},status_class=\"{}\"}} {}",
+                label, class, count
+            )
+            .unwrap();
+        }
+    }
+
+    out += "# HELP upstream_failures_total Failed connections and requests to each upstream.\n";
+    out += "# TYPE upstream_failures_total counter\n";
+    for (label, stats) in labels.iter().zip(&stats) {
+        writeln!(
+            out,
+            "upstream_failures_total{{{}}} {}",
+            label,
+            stats.failures()
+        )
+        .unwrap();
+    }
+
+    out += "# HELP upstream_bytes_in_total Bytes clients sent for requests forwarded to each upstream.\n";
+    out += "# TYPE upstream_bytes_in_total counter\n";
+    for (label, stats) in labels.iter().zip(&stats) {
+        writeln!(
+            out,
+            "upstream_bytes_in_total{{{}}} {}",
+            label,
+            stats.bytes().bytes_in()
+        )
+        .unwrap();
+    }
+
+    out += "# HELP upstream_bytes_out_total Bytes sent to clients for requests forwarded to each upstream.\n";
+    out += "# TYPE upstream_bytes_out_total counter\n";
+    for (label, stats) in labels.iter().zip(&stats) {
+        writeln!(
+            out,
+            "upstream_bytes_out_total{{{}}} {}",
+            label,
+            stats.bytes().bytes_out()
+        )
+        .unwrap();
+    }
+
+    out += "# HELP bytes_in_total Bytes read from clients.\n";
+    out += "# TYPE bytes_in_total counter\n";
+    writeln!(out, "bytes_in_total {}", state.client_bytes.bytes_in()).unwrap();
+
+    out += "# HELP bytes_out_total Bytes written to clients.\n";
+    out += "# TYPE bytes_out_total counter\n";
+    writeln!(out, "bytes_out_total {}", state.client_bytes.bytes_out()).unwrap();
+
+    out += "# HELP request_duration_seconds Time to forward a request and read its response.\n";
+    out += "# TYPE request_duration_seconds histogram\n";
+    state
+        .request_duration
+        .write_to(&mut out, "request_duration_seconds");
+
+    out += "# HELP rate_limited_total Requests turned away by the rate limiter.\n";
+    out += "# TYPE rate_limited_total counter\n";
+    writeln!(
+        out,
+        "rate_limited_total {}",
+        state.rate_limited.load(Ordering::Relaxed)
+    )
+    .unwrap();
+
+    out += "# HELP denied_total Connections turned away by the deny list.\n";
+    out += "# TYPE denied_total counter\n";
+    writeln!(out, "denied_total {}", state.denied.load(Ordering::Relaxed)).unwrap();
+
+    out += "# HELP upstreams_healthy Upstreams currently considered alive.\n";
+    out += "# TYPE upstreams_healthy gauge\n";
+    writeln!(out, "upstreams_healthy {}", upstreams_healthy).unwrap();
+    out
+}
+
+/// Quotes and escapes `value` as a Prometheus label value.
+fn label_value(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+/// Quotes and escapes `value` as a JSON string.
+pub fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c < ' ' => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("10.0.0.1:80"), "\"10.0.0.1:80\"");
+        assert_eq!(json_string(""), "\"\"");
+        assert_eq!(json_string("a\"b\\c"), "\"a\\\"b\\\\c\"");
+        assert_eq!(json_string("tab\there\n"), "\"tab\\u0009here\\u000a\"");
+    }
+
+    #[test]
+    fn test_label_value() {
+        assert_eq!(label_value("10.0.0.1:80"), "\"10.0.0.1:80\"");
+        assert_eq!(label_value("a\"b\\c\nd"), "\"a\\\"b\\\\c\\nd\"");
+    }
+}
@@ -0,0 +1,299 @@
+use crate::response;
+use std::fmt;
+use std::path::Path;
+
+/// What the admin listener's 401s ask for
+const CHALLENGE: &str = "Basic realm=\"balancebeam admin\", charset=\"UTF-8\"";
+
+/// Prefixes of the hashes htpasswd writes (MD5, bcrypt, SHA-1, SHA-256/512 crypt), none of which
+/// can be checked here
+const HASH_PREFIXES: &[&str] = &["$apr1$", "$2a$", "$2b$", "$2y$", "{SHA}", "$5$", "$6$"];
+
+/// A user and password the admin listener accepts. Its Debug leaves the password out, so logging
+/// the options or state it's part of doesn't give it away.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credential {
+    user: String,
+    password: String,
+}
+
+impl fmt::Debug for Credential {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Credential")
+            .field("user", &self.user)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Parses an `--admin-auth`, `user:password`. The password may have colons of its own, but the user
+/// can't, since Basic auth splits the two at the first one. Errors never repeat the argument, which
+/// has the password in it.
+pub fn parse_credential(arg: &str) -> Result<Credential, String> {
+    let (user, password) = arg
+        .split_once(':')
+        .ok_or_else(|| String::from("the admin credential must be written user:password"))?;
+    if user.is_empty() || password.is_empty() {
+        return Err(String::from(
+            "the admin credential needs both a user and a password",
+        ));
+    }
+    if arg.chars().any(char::is_control) {
+        return Err(String::from(
+            "the admin credential can't have control characters",
+        ));
+    }
+    Ok(Credential {
+        user: user.to_string(),
+        password: password.to_string(),
+    })
+}
+
+/// Reads an `--admin-auth-file`: one `user:password` per line, like an htpasswd file but with the
+/// passwords in plain text. Blank lines and lines starting with `#` are skipped.
+pub fn load_file(path: &Path) -> Result<Vec<Credential>, String> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let mut credentials = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let credential = parse_credential(line)
+            .map_err(|message| format!("{} line {}: {}", path.display(), idx + 1, message))?;
+        if HASH_PREFIXES
+            .iter()
+            .any(|prefix| credential.password.starts_with(prefix))
+        {
+            return Err(format!(
+                "{} line {}: hashed passwords aren't supported, only plain user:password",
+                path.display(),
+                idx + 1
+            ));
+        }
+        credentials.push(credential);
+    }
+    if credentials.is_empty() {
+        return Err(format!("{} has no credentials", path.display()));
+    }
+    Ok(credentials)
+}
+
+/// The credentials the admin listener requires one of, from --admin-auth and --admin-auth-file
+#[derive(Debug)]
+pub struct AdminAuth {
+    credentials: Vec<Credential>,
+}
+
+impl AdminAuth {
+    pub fn new(credentials: Vec<Credential>) -> AdminAuth {
+        AdminAuth { credentials }
+    }
+
+    /// Whether a request with `headers` has an `Authorization: Basic` header for one of the
+    /// credentials. Every credential is compared in full, in constant time, whatever matched, so
+    /// how long this takes says nothing about how close a guess was.
+    pub fn allows(&self, headers: &http::HeaderMap) -> bool {
+        let decoded = match headers
+            .get(http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(basic_token)
+            .and_then(decode_base64)
+        {
+            Some(decoded) => decoded,
+            None => return false,
+        };
+        let (user, password) = match decoded.iter().position(|&byte| byte == b':') {
+            Some(idx) => (&decoded[..idx], &decoded[idx + 1..]),
+            None => return false,
+        };
+        self.credentials.iter().fold(false, |allowed, credential| {
+            let user_matches = constant_time_eq(user, credential.user.as_bytes());
+            let password_matches = constant_time_eq(password, credential.password.as_bytes());
+            allowed | (user_matches & password_matches)
+        })
+    }
+}
+
+/// The 401 for an admin request without the right credentials
+pub fn challenge() -> http::Response<Vec<u8>> {
+    let mut response = response::make_http_error(http::StatusCode::UNAUTHORIZED);
+    response.headers_mut().insert(
+        http::header::WWW_AUTHENTICATE,
+        http::HeaderValue::from_static(CHALLENGE),
+    );
+    response
+}
+
+/// The token in an `Authorization` value using the Basic scheme, whose name is case-insensitive
+fn basic_token(value: &str) -> Option<&str> {
+    let (scheme, token) = value.trim().split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("basic") {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
+/// Decodes standard (RFC 4648) base64, padded or not, or None if it isn't any
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let text = text.as_bytes();
+    let unpadded = match text.iter().position(|&byte| byte == b'=') {
+        Some(idx) => {
+            // Padding only ever comes at the end, and only makes up a whole group
+            if !text.len().is_multiple_of(4)
+                || text.len() - idx > 2
+                || text[idx..].iter().any(|&b| b != b'=')
+            {
+                return None;
+            }
+            &text[..idx]
+        }
+        None => text,
+    };
+    if unpadded.len() % 4 == 1 {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(unpadded.len() * 3 / 4);
+    let mut bits: u32 = 0;
+    let mut num_bits = 0;
+    for &byte in unpadded {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | u32::from(value);
+        num_bits += 6;
+        if num_bits >= 8 {
+            num_bits -= 8;
+            decoded.push((bits >> num_bits) as u8);
+            bits &= (1 << num_bits) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+/// Whether `a` and `b` are the same, looking at every byte of the longer one either way
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let mut difference = a.len() ^ b.len();
+    for idx in 0..a.len().max(b.len()) {
+        let byte_a = a.get(idx).copied().unwrap_or(0);
+        let byte_b = b.get(idx).copied().unwrap_or(0);
+        difference |= usize::from(byte_a ^ byte_b);
+    }
+    difference == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn auth(args: &[&str]) -> AdminAuth {
+        AdminAuth::new(
+            args.iter()
+                .map(|arg| parse_credential(arg).unwrap())
+                .collect(),
+        )
+    }
+
+    fn authorization(value: &'static str) -> http::HeaderMap {
+        let mut headers = http::HeaderMap::new();
+        headers.insert(
+            http::header::AUTHORIZATION,
+            http::HeaderValue::from_static(value),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_parse_credential() {
+        let credential = parse_credential("ops:s3cr:et").unwrap();
+        assert_eq!(credential.user, "ops");
+        assert_eq!(credential.password, "s3cr:et");
+        assert!(parse_credential("ops").is_err());
+        assert!(parse_credential(":s3cret").is_err());
+        assert!(parse_credential("ops:").is_err());
+        assert!(parse_credential("ops:s3c\nret").is_err());
+        // Never echoes the password back
+        let message = parse_credential("ops:s3c\tret").unwrap_err();
+        assert!(!message.contains("s3c"), "{}", message);
+        let debug = format!("{:?}", credential);
+        assert!(debug.contains("ops"), "{}", debug);
+        assert!(!debug.contains("s3cr"), "{}", debug);
+    }
+
+    #[test]
+    fn test_decode_base64() {
+        assert_eq!(decode_base64("b3BzOnMzY3JldA==").unwrap(), b"ops:s3cret");
+        assert_eq!(decode_base64("b3BzOnMzY3JldA").unwrap(), b"ops:s3cret");
+        assert_eq!(decode_base64("YWI=").unwrap(), b"ab");
+        assert_eq!(decode_base64("YWJj").unwrap(), b"abc");
+        assert_eq!(decode_base64("").unwrap(), b"");
+        assert_eq!(decode_base64("+/+/").unwrap(), vec![0xfb, 0xff, 0xbf]);
+        assert!(decode_base64("b3Bz*nMz").is_none());
+        assert!(decode_base64("YWJjZ").is_none());
+        assert!(decode_base64("YW=I").is_none());
+        assert!(decode_base64("Y===").is_none());
+        assert!(decode_base64("YWI=YWI=").is_none());
+    }
+
+    #[test]
+    fn test_allows() {
+        // ops:s3cret and dev:hunter2
+        let auth = auth(&["ops:s3cret", "dev:hunter2"]);
+        assert!(auth.allows(&authorization("Basic b3BzOnMzY3JldA==")));
+        assert!(auth.allows(&authorization("basic ZGV2Omh1bnRlcjI=")));
+        // Missing
+        assert!(!auth.allows(&http::HeaderMap::new()));
+        // Wrong password (ops:s3crex), and the right password for another user (dev:s3cret)
+        assert!(!auth.allows(&authorization("Basic b3BzOnMzY3JleA==")));
+        assert!(!auth.allows(&authorization("Basic ZGV2OnMzY3JldA==")));
+        // A prefix of the password (ops:s3c)
+        assert!(!auth.allows(&authorization("Basic b3BzOnMzYw==")));
+        // Malformed base64, no colon (ops), and another scheme
+        assert!(!auth.allows(&authorization("Basic b3BzOnMzY3JldA=!")));
+        assert!(!auth.allows(&authorization("Basic b3Bz")));
+        assert!(!auth.allows(&authorization("Bearer b3BzOnMzY3JldA==")));
+        assert!(!auth.allows(&authorization("Basic")));
+    }
+
+    #[test]
+    fn test_challenge() {
+        let response = challenge();
+        assert_eq!(response.status(), 401);
+        assert_eq!(
+            response.headers()["www-authenticate"],
+            "Basic realm=\"balancebeam admin\", charset=\"UTF-8\""
+        );
+    }
+
+    #[test]
+    fn test_load_file() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("balancebeam-admin-auth-{}", std::process::id()));
+        std::fs::write(&path, "# admins\nops:s3cret\r\n\ndev:hunter2\n").unwrap();
+        let credentials = load_file(&path).unwrap();
+        assert_eq!(
+            credentials,
+            vec![
+                parse_credential("ops:s3cret").unwrap(),
+                parse_credential("dev:hunter2").unwrap()
+            ]
+        );
+        std::fs::write(&path, "ops:s3cret\nops:$apr1$salt$hash\n").unwrap();
+        let message = load_file(&path).unwrap_err();
+        assert!(message.contains("line 2"), "{}", message);
+        assert!(message.contains("hashed"), "{}", message);
+        std::fs::write(&path, "ops\n").unwrap();
+        assert!(load_file(&path).unwrap_err().contains("line 1"));
+        std::fs::write(&path, "# nobody\n").unwrap();
+        assert!(load_file(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert!(load_file(&path).is_err());
+    }
+}
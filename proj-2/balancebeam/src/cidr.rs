@@ -0,0 +1,151 @@
+use std::fmt;
+use std::net::IpAddr;
+
+/// An IPv4 or IPv6 address range in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Whether `ip` falls inside this range. IPv4 addresses never match IPv6 ranges, and vice
+    /// versa.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => prefix_matches(
+                u32::from(network).into(),
+                u32::from(ip).into(),
+                self.prefix_len,
+                32,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(network.into(), ip.into(), self.prefix_len, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// Whether the top `prefix_len` of the `bits` low-order bits of the two addresses agree.
+fn prefix_matches(network: u128, ip: u128, prefix_len: u8, bits: u8) -> bool {
+    prefix_len == 0 || (network ^ ip) >> (bits - prefix_len) == 0
+}
+
+/// Parses a range like `192.168.1.0/24`. Host bits set in the address are ignored, so
+/// `192.168.1.5/24` means the same range.
+pub fn parse_cidr(arg: &str) -> Result<Cidr, String> {
+    let (address, prefix_len) = arg
+        .split_once('/')
+        .ok_or_else(|| format!("{:?} is not in CIDR notation (e.g. 10.0.0.0/8)", arg))?;
+    let network: IpAddr = address
+        .parse()
+        .map_err(|_| format!("invalid IP address {:?} in {:?}", address, arg))?;
+    let max_prefix_len = if network.is_ipv4() { 32 } else { 128 };
+    let prefix_len = prefix_len
+        .parse::<u8>()
+        .ok()
+        .filter(|&len| len <= max_prefix_len)
+        .ok_or_else(|| {
+            format!(
+                "invalid prefix length {:?} in {:?} (must be 0 to {})",
+                prefix_len, arg, max_prefix_len
+            )
+        })?;
+    Ok(Cidr {
+        network,
+        prefix_len,
+    })
+}
+
+/// Parses either a range or a single address, which is taken as a range of one (`/32` or `/128`).
+pub fn parse_ip_or_cidr(arg: &str) -> Result<Cidr, String> {
+    if arg.contains('/') {
+        return parse_cidr(arg);
+    }
+    let network: IpAddr = arg
+        .parse()
+        .map_err(|_| format!("{:?} is neither an IP address nor a CIDR range", arg))?;
+    Ok(Cidr {
+        network,
+        prefix_len: if network.is_ipv4() { 32 } else { 128 },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn contains(cidr: &str, ip: &str) -> bool {
+        parse_cidr(cidr).unwrap().contains(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn test_ipv4_slash_24_boundaries() {
+        assert!(!contains("192.168.1.0/24", "192.168.0.255"));
+        assert!(contains("192.168.1.0/24", "192.168.1.0"));
+        assert!(contains("192.168.1.0/24", "192.168.1.1"));
+        assert!(contains("192.168.1.0/24", "192.168.1.254"));
+        assert!(contains("192.168.1.0/24", "192.168.1.255"));
+        assert!(!contains("192.168.1.0/24", "192.168.2.0"));
+        assert!(contains("192.168.1.77/24", "192.168.1.0"));
+    }
+
+    #[test]
+    fn test_ipv4_prefix_lengths() {
+        assert!(contains("192.168.1.5/32", "192.168.1.5"));
+        assert!(!contains("192.168.1.5/32", "192.168.1.4"));
+        assert!(contains("10.0.0.0/8", "10.255.255.255"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.0"));
+        assert!(contains("0.0.0.0/0", "203.0.113.9"));
+        assert!(!contains("0.0.0.0/0", "::1"));
+    }
+
+    #[test]
+    fn test_ipv6() {
+        assert!(contains("fd00::/8", "fd12:3456::1"));
+        assert!(!contains("fd00::/8", "fe80::1"));
+        assert!(contains(
+            "2001:db8::/32",
+            "2001:db8:ffff:ffff:ffff:ffff:ffff:ffff"
+        ));
+        assert!(!contains("2001:db8::/32", "2001:db9::"));
+        assert!(contains("::1/128", "::1"));
+        assert!(!contains("::1/128", "::2"));
+        assert!(contains("::/0", "2001:db8::1"));
+        assert!(!contains("::/0", "127.0.0.1"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_cidr("10.0.0.0").is_err());
+        assert!(parse_cidr("10.0.0.0/").is_err());
+        assert!(parse_cidr("10.0.0.0/33").is_err());
+        assert!(parse_cidr("10.0.0/8").is_err());
+        assert!(parse_cidr("::/129").is_err());
+        assert!(parse_cidr("example.com/24").is_err());
+        assert_eq!(parse_cidr("10.0.0.0/8").unwrap().to_string(), "10.0.0.0/8");
+        assert_eq!(parse_cidr("::/128").unwrap().to_string(), "::/128");
+    }
+
+    #[test]
+    fn test_parse_ip_or_cidr() {
+        assert_eq!(
+            parse_ip_or_cidr("203.0.113.7"),
+            parse_cidr("203.0.113.7/32")
+        );
+        assert_eq!(
+            parse_ip_or_cidr("2001:db8::1"),
+            parse_cidr("2001:db8::1/128")
+        );
+        assert_eq!(parse_ip_or_cidr("10.0.0.0/8"), parse_cidr("10.0.0.0/8"));
+        assert!(parse_ip_or_cidr("10.0.0.0/40").is_err());
+        assert!(parse_ip_or_cidr("example.com").is_err());
+    }
+}
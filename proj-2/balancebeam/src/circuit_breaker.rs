@@ -0,0 +1,262 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Where one upstream's circuit stands
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Circuit {
+    /// Taking traffic, with the outcomes of its most recent requests (true for success), oldest
+    /// first
+    Closed(VecDeque<bool>),
+    /// Taking no traffic until the cool-down ends
+    Open { until: Instant },
+    /// Cooled down, and waiting on the one trial request let through since `trial_started`
+    HalfOpen { trial_started: Instant },
+}
+
+impl Default for Circuit {
+    fn default() -> Self {
+        Circuit::Closed(VecDeque::new())
+    }
+}
+
+impl Circuit {
+    pub fn is_closed(&self) -> bool {
+        matches!(self, Circuit::Closed(_))
+    }
+}
+
+impl fmt::Display for Circuit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Circuit::Closed(_) => "closed",
+            Circuit::Open { .. } => "open",
+            Circuit::HalfOpen { .. } => "half-open",
+        })
+    }
+}
+
+/// Stops sending traffic to an upstream whose recent requests have mostly failed: answered with a
+/// 5xx, timed out, or couldn't connect at all. Unlike HealthTracker, which needs failures in a
+/// row, this catches upstreams that flap between working and erroring.
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitBreaker {
+    /// How many recent outcomes the failure ratio is taken over (0 = never open)
+    window: usize,
+    /// Fraction of failures in a full window above which the circuit opens
+    threshold: f64,
+    /// How long an open circuit stays open before a trial request is let through
+    cooldown: Duration,
+}
+
+impl CircuitBreaker {
+    pub fn new(window: usize, threshold: f64, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            window,
+            threshold,
+            cooldown,
+        }
+    }
+
+    /// Whether circuits ever open at all
+    pub fn is_enabled(&self) -> bool {
+        self.window != 0
+    }
+
+    /// Whether an upstream with this circuit may be picked for a request at `now`. A trial that
+    /// never reported back (say the client hung up first) is given up on after a cool-down, so
+    /// that another can go out.
+    pub fn allows(&self, circuit: &Circuit, now: Instant) -> bool {
+        match circuit {
+            Circuit::Closed(_) => true,
+            Circuit::Open { until } => now >= *until,
+            Circuit::HalfOpen { trial_started } => now >= *trial_started + self.cooldown,
+        }
+    }
+
+    /// Notes that a request is going to an upstream `allows` let through, which for a cooled-down
+    /// circuit makes it the trial.
+    pub fn picked(&self, circuit: &mut Circuit, now: Instant) {
+        if !circuit.is_closed() {
+            *circuit = Circuit::HalfOpen { trial_started: now };
+        }
+    }
+
+    /// Records the outcome of a request to an upstream, and returns whether that opened or closed
+    /// its circuit. A half-open circuit closes on the trial succeeding and opens again on it
+    /// failing. Results for an open circuit are from requests sent before it opened, and are
+    /// ignored.
+    pub fn record(&self, circuit: &mut Circuit, success: bool, now: Instant) -> bool {
+        if self.window == 0 {
+            return false;
+        }
+        match circuit {
+            Circuit::Closed(outcomes) => {
+                if outcomes.len() == self.window {
+                    outcomes.pop_front();
+                }
+                outcomes.push_back(success);
+                let failures = outcomes.iter().filter(|&&success| !success).count();
+                if outcomes.len() == self.window
+                    && failures as f64 / self.window as f64 > self.threshold
+                {
+                    *circuit = Circuit::Open {
+                        until: now + self.cooldown,
+                    };
+                    return true;
+                }
+                false
+            }
+            Circuit::Open { .. } => false,
+            Circuit::HalfOpen { .. } => {
+                *circuit = if success {
+                    Circuit::default()
+                } else {
+                    Circuit::Open {
+                        until: now + self.cooldown,
+                    }
+                };
+                true
+            }
+        }
+    }
+}
+
+/// Parses a failure ratio for `--circuit-breaker-threshold`, from 0 (open on any failure) up to
+/// but not including 1 (which could never be exceeded).
+pub fn parse_threshold(arg: &str) -> Result<f64, String> {
+    let threshold = arg
+        .trim()
+        .parse::<f64>()
+        .map_err(|_| format!("{:?} is not a number", arg))?;
+    check_threshold(threshold)
+}
+
+pub fn check_threshold(threshold: f64) -> Result<f64, String> {
+    if (0.0..1.0).contains(&threshold) {
+        Ok(threshold)
+    } else {
+        Err(format!(
+            "failure threshold {} must be at least 0 and below 1",
+            threshold
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_secs(10);
+
+    fn replay(breaker: &CircuitBreaker, circuit: &mut Circuit, now: Instant, results: &[bool]) {
+        for &success in results {
+            breaker.record(circuit, success, now);
+        }
+    }
+
+    #[test]
+    fn test_opens_above_threshold() {
+        let breaker = CircuitBreaker::new(4, 0.5, COOLDOWN);
+        let now = Instant::now();
+        let mut circuit = Circuit::default();
+        // Not judged until the window fills up
+        replay(&breaker, &mut circuit, now, &[false, false, false]);
+        assert!(matches!(circuit, Circuit::Closed(_)));
+        // Two failures out of four is not above half
+        let mut circuit = Circuit::default();
+        replay(&breaker, &mut circuit, now, &[true, false, true, false]);
+        assert!(breaker.allows(&circuit, now));
+        // Three out of four is
+        assert!(breaker.record(&mut circuit, false, now));
+        assert_eq!(
+            circuit,
+            Circuit::Open {
+                until: now + COOLDOWN
+            }
+        );
+        assert!(!breaker.allows(&circuit, now));
+        assert!(!breaker.allows(&circuit, now + COOLDOWN / 2));
+    }
+
+    #[test]
+    fn test_old_outcomes_slide_out() {
+        let breaker = CircuitBreaker::new(3, 0.5, COOLDOWN);
+        let now = Instant::now();
+        let mut circuit = Circuit::default();
+        replay(&breaker, &mut circuit, now, &[false, true, true, true]);
+        replay(
+            &breaker,
+            &mut circuit,
+            now,
+            &[false, true, true, false, true],
+        );
+        assert!(matches!(circuit, Circuit::Closed(_)));
+    }
+
+    #[test]
+    fn test_half_open_trial() {
+        let breaker = CircuitBreaker::new(2, 0.0, COOLDOWN);
+        let now = Instant::now();
+        let mut circuit = Circuit::default();
+        replay(&breaker, &mut circuit, now, &[true, false]);
+        assert!(matches!(circuit, Circuit::Open { .. }));
+
+        // After the cool-down, exactly one request gets through
+        let later = now + COOLDOWN;
+        assert!(breaker.allows(&circuit, later));
+        breaker.picked(&mut circuit, later);
+        assert_eq!(
+            circuit,
+            Circuit::HalfOpen {
+                trial_started: later
+            }
+        );
+        assert!(!breaker.allows(&circuit, later));
+
+        // A failed trial opens the circuit for another cool-down
+        assert!(breaker.record(&mut circuit, false, later));
+        assert!(!breaker.allows(&circuit, later + COOLDOWN / 2));
+        let later = later + COOLDOWN;
+        breaker.picked(&mut circuit, later);
+
+        // A successful one closes it, with a clean slate
+        assert!(breaker.record(&mut circuit, true, later));
+        assert_eq!(circuit, Circuit::default());
+        assert!(!breaker.record(&mut circuit, false, later));
+        assert!(breaker.allows(&circuit, later));
+    }
+
+    #[test]
+    fn test_lost_trial_is_retried() {
+        let breaker = CircuitBreaker::new(1, 0.0, COOLDOWN);
+        let now = Instant::now();
+        let circuit = Circuit::HalfOpen { trial_started: now };
+        assert!(!breaker.allows(&circuit, now + COOLDOWN / 2));
+        assert!(breaker.allows(&circuit, now + COOLDOWN));
+    }
+
+    #[test]
+    fn test_disabled_and_stragglers() {
+        let now = Instant::now();
+        let disabled = CircuitBreaker::new(0, 0.0, COOLDOWN);
+        let mut circuit = Circuit::default();
+        replay(&disabled, &mut circuit, now, &[false; 10]);
+        assert_eq!(circuit, Circuit::default());
+
+        let breaker = CircuitBreaker::new(1, 0.0, COOLDOWN);
+        let mut circuit = Circuit::Open { until: now };
+        assert!(!breaker.record(&mut circuit, true, now));
+        assert_eq!(circuit, Circuit::Open { until: now });
+    }
+
+    #[test]
+    fn test_parse_threshold() {
+        assert_eq!(parse_threshold("0.5"), Ok(0.5));
+        assert_eq!(parse_threshold("0"), Ok(0.0));
+        assert!(parse_threshold("1").is_err());
+        assert!(parse_threshold("-0.1").is_err());
+        assert!(parse_threshold("half").is_err());
+        assert!(parse_threshold("NaN").is_err());
+    }
+}
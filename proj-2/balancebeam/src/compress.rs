@@ -0,0 +1,248 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::Write;
+
+/// Which upstream responses balancebeam gzips for clients that accept it, from --compress and
+/// friends
+#[derive(Debug, Clone)]
+pub struct CompressionSettings {
+    pub enabled: bool,
+    /// Bodies smaller than this are sent as they are, since gzip would barely shrink them
+    pub min_bytes: usize,
+    /// Content types not to compress, because they already are (e.g. `image/*` or
+    /// `application/zip`)
+    pub skip_types: Vec<String>,
+}
+
+/// Whether a client whose request had these headers accepts gzip-encoded responses, i.e. its
+/// Accept-Encoding lists `gzip` (or `*`) without `q=0`.
+pub fn accepts_gzip(request_headers: &http::HeaderMap) -> bool {
+    let mut accepted = None;
+    let codings = request_headers
+        .get_all(http::header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+    for coding in codings {
+        let mut params = coding.split(';').map(str::trim);
+        let name = params.next().unwrap_or("");
+        let quality = params
+            .find_map(|param| {
+                param
+                    .strip_prefix("q=")
+                    .or_else(|| param.strip_prefix("Q="))
+            })
+            .map_or(1.0, |q| q.parse::<f32>().unwrap_or(0.0));
+        if name.eq_ignore_ascii_case("gzip") {
+            return quality > 0.0;
+        } else if name == "*" {
+            accepted = Some(quality > 0.0);
+        }
+    }
+    accepted.unwrap_or(false)
+}
+
+/// Whether `content_type` (a Content-Type value, parameters and all) matches one of the
+/// `patterns`, which are either exact (`application/zip`) or a whole top-level type
+/// (`image/*`).
+fn matches_type(content_type: &str, patterns: &[String]) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+    patterns
+        .iter()
+        .any(|pattern| match pattern.strip_suffix("/*") {
+            Some(top_level) => essence
+                .split_once('/')
+                .is_some_and(|(ty, _)| ty.eq_ignore_ascii_case(top_level)),
+            None => essence.eq_ignore_ascii_case(pattern),
+        })
+}
+
+impl CompressionSettings {
+    /// Whether a response that was read in full should be gzipped for clients that accept it.
+    /// Responses that are already encoded, that are framed by Transfer-Encoding, that ask not to
+    /// be transformed, or that are too small or of a skipped type, are left alone. So are byte
+    /// ranges (206s, or anything with a Content-Range), whose offsets gzip would throw off.
+    pub fn should_compress(&self, response: &http::Response<Vec<u8>>) -> bool {
+        let headers = response.headers();
+        let no_transform = headers
+            .get_all(http::header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+        let skipped_type = headers
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|content_type| matches_type(content_type, &self.skip_types));
+        self.enabled
+            && response.status() != http::StatusCode::PARTIAL_CONTENT
+            && !headers.contains_key(http::header::CONTENT_RANGE)
+            && response.body().len() >= self.min_bytes.max(1)
+            && !headers.contains_key(http::header::CONTENT_ENCODING)
+            && !headers.contains_key(http::header::TRANSFER_ENCODING)
+            && !no_transform
+            && !skipped_type
+    }
+}
+
+/// Tells caches that a response that could be compressed depends on the request's Accept-Encoding.
+pub fn add_vary(headers: &mut http::HeaderMap) {
+    let varies = headers
+        .get_all(http::header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| {
+            let name = name.trim();
+            name == "*" || name.eq_ignore_ascii_case("accept-encoding")
+        });
+    if !varies {
+        headers.append(
+            http::header::VARY,
+            http::HeaderValue::from_static("Accept-Encoding"),
+        );
+    }
+}
+
+/// Gzips a body. Only ever called on one held in memory, so writing can't fail.
+pub fn gzip(body: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 2), Compression::default());
+    encoder.write_all(body).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Replaces a response's body with `compressed`, its gzipped form, and fixes up the headers to
+/// match. A strong ETag is weakened, since the bytes are no longer the upstream's.
+pub fn set_gzipped_body(response: &mut http::Response<Vec<u8>>, compressed: Vec<u8>) {
+    let headers = response.headers_mut();
+    headers.insert(
+        http::header::CONTENT_ENCODING,
+        http::HeaderValue::from_static("gzip"),
+    );
+    headers.insert(http::header::CONTENT_LENGTH, compressed.len().into());
+    if let Some(etag) = headers.get(http::header::ETAG) {
+        if !etag.as_bytes().starts_with(b"W/") {
+            let mut weak = b"W/".to_vec();
+            weak.extend_from_slice(etag.as_bytes());
+            if let Ok(weak) = http::HeaderValue::from_bytes(&weak) {
+                headers.insert(http::header::ETAG, weak);
+            }
+        }
+    }
+    *response.body_mut() = compressed;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn settings() -> CompressionSettings {
+        CompressionSettings {
+            enabled: true,
+            min_bytes: 10,
+            skip_types: vec![String::from("image/*"), String::from("application/zip")],
+        }
+    }
+
+    fn accept_encoding(value: &'static str) -> http::HeaderMap {
+        let mut headers = http::HeaderMap::new();
+        headers.insert(
+            http::header::ACCEPT_ENCODING,
+            http::HeaderValue::from_static(value),
+        );
+        headers
+    }
+
+    fn response(headers: &[(&str, &str)], body: &[u8]) -> http::Response<Vec<u8>> {
+        let mut response = http::Response::builder().status(200);
+        for (name, value) in headers {
+            response = response.header(*name, *value);
+        }
+        response.body(body.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_accepts_gzip() {
+        assert!(accepts_gzip(&accept_encoding("gzip")));
+        assert!(accepts_gzip(&accept_encoding("br, GZIP;q=0.5")));
+        assert!(accepts_gzip(&accept_encoding("*")));
+        assert!(!accepts_gzip(&accept_encoding("gzip;q=0")));
+        assert!(!accepts_gzip(&accept_encoding("*, gzip;q=0")));
+        assert!(!accepts_gzip(&accept_encoding("br, deflate")));
+        assert!(!accepts_gzip(&accept_encoding("gzipped")));
+        assert!(!accepts_gzip(&http::HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_should_compress() {
+        let body = b"hello hello hello hello";
+        let settings = settings();
+        assert!(settings.should_compress(&response(&[("Content-Type", "text/html")], body)));
+        assert!(settings.should_compress(&response(&[], body)));
+        assert!(!settings.should_compress(&response(&[], b"tiny")));
+        assert!(!settings.should_compress(&response(&[("Content-Encoding", "br")], body)));
+        assert!(!settings.should_compress(&response(&[("Content-Type", "IMAGE/png; q=1")], body)));
+        assert!(!settings.should_compress(&response(&[("Content-Type", "application/zip")], body)));
+        assert!(settings.should_compress(&response(
+            &[("Content-Type", "application/zip-manifest+json")],
+            body
+        )));
+        assert!(!settings.should_compress(&response(
+            &[("Cache-Control", "public, no-transform")],
+            body
+        )));
+        let off = CompressionSettings {
+            enabled: false,
+            ..settings
+        };
+        assert!(!off.should_compress(&response(&[], body)));
+    }
+
+    #[test]
+    fn test_byte_ranges_are_not_compressed() {
+        let body = b"hello hello hello hello";
+        let settings = settings();
+        let mut partial = response(&[("Content-Range", "bytes 0-22/100")], body);
+        assert!(!settings.should_compress(&partial));
+        *partial.status_mut() = http::StatusCode::PARTIAL_CONTENT;
+        assert!(!settings.should_compress(&partial));
+        partial.headers_mut().remove(http::header::CONTENT_RANGE);
+        assert!(!settings.should_compress(&partial));
+    }
+
+    #[test]
+    fn test_add_vary() {
+        let mut headers = http::HeaderMap::new();
+        add_vary(&mut headers);
+        add_vary(&mut headers);
+        assert_eq!(headers.get_all(http::header::VARY).iter().count(), 1);
+        let mut headers = response(&[("Vary", "Cookie")], b"").headers().clone();
+        add_vary(&mut headers);
+        let vary: Vec<_> = headers.get_all(http::header::VARY).iter().collect();
+        assert_eq!(vary, vec!["Cookie", "Accept-Encoding"]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let body = "All work and no play makes Jack a dull boy. ".repeat(100);
+        let mut response = response(
+            &[("Content-Length", "4400"), ("ETag", "\"v1\"")],
+            body.as_bytes(),
+        );
+        set_gzipped_body(&mut response, gzip(body.as_bytes()));
+        assert!(response.body().len() < body.len() / 10);
+        assert_eq!(
+            response.headers()["content-length"],
+            response.body().len().to_string().as_str()
+        );
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        assert_eq!(response.headers()["etag"], "W/\"v1\"");
+        let mut decompressed = String::new();
+        GzDecoder::new(&response.body()[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
+    }
+}
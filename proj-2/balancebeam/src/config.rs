@@ -0,0 +1,797 @@
+use crate::access_log::AccessLogFormat;
+use crate::cidr::{self, Cidr};
+use crate::circuit_breaker;
+use crate::header_rules::{self, SetHeader};
+use crate::health::{self, StatusCodes};
+use crate::mirror;
+use crate::proxy_protocol;
+use crate::rate_limit::{RateLimitKey, RateLimitRule};
+use crate::route::Route;
+use crate::upstream::{self, Upstream};
+use crate::via;
+use crate::{CmdOptions, Strategy};
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use http::header::HeaderName;
+use serde::{de, Deserialize, Deserializer};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+
+/// Settings read from the `--config` file. Each one mirrors the command-line flag of the same name,
+/// except for those grouped into tables:
+///
+/// * `[health_check]`: the health check flags, without their `health_check_` prefix
+/// * `[circuit_breaker]`: the circuit breaker flags, without their `circuit_breaker_` prefix
+/// * `[rate_limit]`: the rate limiting flags, with `--rate-limit-rule` as `[[rate_limit.rules]]`
+/// * `[headers]`: the header rewriting flags, without their `_header` suffix
+/// * `[compression]`: the compression flags
+/// * `[connect]`: the CONNECT tunneling flags, as `enabled` and `ports`
+/// * `[mirror]`: the mirroring flags, as `upstreams` and `percent`
+///
+/// Anything left out keeps the flag's value.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default, deserialize_with = "addresses")]
+    bind: Option<Vec<String>>,
+    admin_bind: Option<String>,
+    admin_auth_file: Option<PathBuf>,
+    upstreams: Option<Vec<Upstream>>,
+    routes: Option<Vec<Route>>,
+    reject_unknown_hosts: Option<bool>,
+    resolve_interval: Option<u64>,
+    strategy: Option<Strategy>,
+    max_retries: Option<usize>,
+    max_connect_attempts: Option<usize>,
+    upstream_connect_timeout: Option<u64>,
+    upstream_response_timeout: Option<u64>,
+    max_pool_idle: Option<usize>,
+    pool_idle_timeout: Option<u64>,
+    client_idle_timeout: Option<u64>,
+    max_header_bytes: Option<usize>,
+    max_header_count: Option<usize>,
+    max_request_body_bytes: Option<usize>,
+    shutdown_grace_period: Option<u64>,
+    max_concurrent_requests: Option<usize>,
+    request_queue_timeout_ms: Option<u64>,
+    max_requests_per_upstream: Option<usize>,
+    access_log_format: Option<AccessLogFormat>,
+    sticky_cookie: Option<String>,
+    #[serde(default, deserialize_with = "instance_id")]
+    instance_id: Option<String>,
+    slow_start: Option<u64>,
+    error_page_dir: Option<PathBuf>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    upstream_ca: Option<PathBuf>,
+    insecure_upstream: Option<bool>,
+    accept_proxy_protocol: Option<bool>,
+    send_proxy_protocol: Option<proxy_protocol::Version>,
+    trust_forwarded_for: Option<bool>,
+    unhealthy_status_threshold: Option<u32>,
+    latency_eject_ms: Option<u64>,
+    drop_interim_responses: Option<bool>,
+    drain_fallback: Option<bool>,
+    self_health_path: Option<String>,
+    self_health_on_main: Option<bool>,
+    #[serde(default, deserialize_with = "ips_or_cidrs")]
+    deny: Option<Vec<Cidr>>,
+    #[serde(default)]
+    health_check: HealthCheckConfig,
+    #[serde(default)]
+    circuit_breaker: CircuitBreakerConfig,
+    #[serde(default)]
+    rate_limit: RateLimitConfig,
+    #[serde(default)]
+    headers: HeadersConfig,
+    #[serde(default)]
+    compression: CompressionConfig,
+    #[serde(default)]
+    connect: ConnectConfig,
+    #[serde(default)]
+    mirror: MirrorConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HealthCheckConfig {
+    interval: Option<usize>,
+    path: Option<String>,
+    #[serde(default, deserialize_with = "method")]
+    method: Option<http::Method>,
+    #[serde(default, deserialize_with = "host")]
+    host: Option<String>,
+    body: Option<String>,
+    failure_threshold: Option<NonZeroU32>,
+    success_threshold: Option<NonZeroU32>,
+    timeout: Option<u64>,
+    #[serde(default, deserialize_with = "status_codes")]
+    expect: Option<StatusCodes>,
+    max_backoff: Option<u64>,
+    startup: Option<bool>,
+    start_degraded: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CircuitBreakerConfig {
+    window: Option<usize>,
+    #[serde(default, deserialize_with = "threshold")]
+    threshold: Option<f64>,
+    cooldown: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RateLimitConfig {
+    max_requests_per_minute: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    #[serde(default, deserialize_with = "cidrs")]
+    exempt: Option<Vec<Cidr>>,
+    rules: Option<Vec<RateLimitRule>>,
+    key: Option<RateLimitKey>,
+    require_key: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HeadersConfig {
+    #[serde(default, deserialize_with = "set_headers")]
+    set_request: Option<Vec<SetHeader>>,
+    #[serde(default, deserialize_with = "header_names")]
+    remove_request: Option<Vec<HeaderName>>,
+    #[serde(default, deserialize_with = "set_headers")]
+    set_response: Option<Vec<SetHeader>>,
+    #[serde(default, deserialize_with = "header_names")]
+    remove_response: Option<Vec<HeaderName>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CompressionConfig {
+    enabled: Option<bool>,
+    min_bytes: Option<usize>,
+    skip_types: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConnectConfig {
+    enabled: Option<bool>,
+    ports: Option<Vec<u16>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MirrorConfig {
+    #[serde(default, deserialize_with = "mirror_upstreams")]
+    upstreams: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "percent")]
+    percent: Option<u32>,
+}
+
+impl Config {
+    /// Reads and parses the config file at `path`. Errors name the file, and for malformed TOML
+    /// also the offending key and line.
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text =
+            std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        Config::parse(&text).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    fn parse(text: &str) -> Result<Config, toml::de::Error> {
+        let config: Config = toml::from_str(text)?;
+        let mut upstreams = config.upstreams.iter().flatten().chain(
+            config
+                .routes
+                .iter()
+                .flatten()
+                .flat_map(|route| &route.upstreams),
+        );
+        if let Err(message) = upstreams.try_for_each(upstream::check_overrides) {
+            return Err(de::Error::custom(message));
+        }
+        Ok(config)
+    }
+
+    /// Copies the settings from the file into `options`, except for those given on the command
+    /// line (as recorded in `matches`), which take precedence.
+    pub fn apply(self, options: &mut CmdOptions, matches: &ArgMatches) {
+        // Clap names each argument after its field, kebab-cased
+        let on_command_line = |field: &str| {
+            matches.value_source(field.replace('_', "-").as_str()) == Some(ValueSource::CommandLine)
+        };
+        macro_rules! merge {
+            ($field:ident, $value:expr) => {
+                if !on_command_line(stringify!($field)) {
+                    if let Some(value) = $value {
+                        options.$field = value;
+                    }
+                }
+            };
+        }
+
+        merge!(bind, self.bind);
+        merge!(admin_bind, self.admin_bind.map(Some));
+        merge!(admin_auth_file, self.admin_auth_file.map(Some));
+        merge!(upstream, self.upstreams);
+        merge!(route, self.routes);
+        merge!(reject_unknown_hosts, self.reject_unknown_hosts);
+        merge!(resolve_interval, self.resolve_interval);
+        merge!(strategy, self.strategy);
+        merge!(max_retries, self.max_retries);
+        merge!(max_connect_attempts, self.max_connect_attempts);
+        merge!(upstream_connect_timeout, self.upstream_connect_timeout);
+        merge!(upstream_response_timeout, self.upstream_response_timeout);
+        merge!(max_pool_idle, self.max_pool_idle);
+        merge!(pool_idle_timeout, self.pool_idle_timeout);
+        merge!(client_idle_timeout, self.client_idle_timeout);
+        merge!(max_header_bytes, self.max_header_bytes);
+        merge!(max_header_count, self.max_header_count);
+        merge!(max_request_body_bytes, self.max_request_body_bytes);
+        merge!(shutdown_grace_period, self.shutdown_grace_period);
+        merge!(max_concurrent_requests, self.max_concurrent_requests);
+        merge!(request_queue_timeout_ms, self.request_queue_timeout_ms);
+        merge!(max_requests_per_upstream, self.max_requests_per_upstream);
+        merge!(access_log_format, self.access_log_format);
+        merge!(sticky_cookie, self.sticky_cookie.map(Some));
+        merge!(instance_id, self.instance_id.map(Some));
+        merge!(slow_start, self.slow_start);
+        merge!(error_page_dir, self.error_page_dir.map(Some));
+        merge!(tls_cert, self.tls_cert.map(Some));
+        merge!(tls_key, self.tls_key.map(Some));
+        merge!(upstream_ca, self.upstream_ca.map(Some));
+        merge!(insecure_upstream, self.insecure_upstream);
+        merge!(accept_proxy_protocol, self.accept_proxy_protocol);
+        merge!(send_proxy_protocol, self.send_proxy_protocol.map(Some));
+        merge!(trust_forwarded_for, self.trust_forwarded_for);
+        merge!(unhealthy_status_threshold, self.unhealthy_status_threshold);
+        merge!(latency_eject_ms, self.latency_eject_ms);
+        merge!(drop_interim_responses, self.drop_interim_responses);
+        merge!(drain_fallback, self.drain_fallback);
+        merge!(self_health_path, self.self_health_path);
+        merge!(self_health_on_main, self.self_health_on_main);
+        merge!(deny, self.deny);
+
+        let health_check = self.health_check;
+        if health_check.interval.is_some() || health_check.path.is_some() {
+            options.active_health_checks_configured = true;
+        }
+        merge!(active_health_check_interval, health_check.interval);
+        merge!(active_health_check_path, health_check.path);
+        merge!(health_check_method, health_check.method);
+        merge!(health_check_host, health_check.host.map(Some));
+        merge!(health_check_body, health_check.body.map(Some));
+        merge!(
+            health_check_failure_threshold,
+            health_check.failure_threshold.map(NonZeroU32::get)
+        );
+        merge!(
+            health_check_success_threshold,
+            health_check.success_threshold.map(NonZeroU32::get)
+        );
+        merge!(health_check_timeout, health_check.timeout);
+        merge!(health_check_expect, health_check.expect);
+        merge!(health_check_max_backoff, health_check.max_backoff);
+        // `startup = false` opts out the way --no-startup-health-check does, unless either flag is
+        // on the command line
+        if !on_command_line("startup_health_check") && !on_command_line("no_startup_health_check") {
+            if let Some(startup) = health_check.startup {
+                options.startup_health_check = startup;
+                options.no_startup_health_check = !startup;
+            }
+        }
+        merge!(start_degraded, health_check.start_degraded);
+
+        let circuit_breaker = self.circuit_breaker;
+        merge!(circuit_breaker_window, circuit_breaker.window);
+        merge!(circuit_breaker_threshold, circuit_breaker.threshold);
+        merge!(circuit_breaker_cooldown, circuit_breaker.cooldown);
+
+        let rate_limit = self.rate_limit;
+        merge!(max_requests_per_minute, rate_limit.max_requests_per_minute);
+        merge!(max_connections_per_ip, rate_limit.max_connections_per_ip);
+        merge!(rate_limit_exempt, rate_limit.exempt);
+        merge!(rate_limit_rule, rate_limit.rules);
+        merge!(rate_limit_key, rate_limit.key);
+        merge!(require_rate_limit_key, rate_limit.require_key);
+
+        let headers = self.headers;
+        merge!(set_request_header, headers.set_request);
+        merge!(remove_request_header, headers.remove_request);
+        merge!(set_response_header, headers.set_response);
+        merge!(remove_response_header, headers.remove_response);
+
+        let compression = self.compression;
+        merge!(compress, compression.enabled);
+        merge!(compress_min_bytes, compression.min_bytes);
+        merge!(compress_skip_type, compression.skip_types);
+
+        let connect = self.connect;
+        merge!(allow_connect, connect.enabled);
+        merge!(connect_port, connect.ports);
+
+        let mirror = self.mirror;
+        merge!(mirror_upstream, mirror.upstreams);
+        merge!(mirror_percent, mirror.percent);
+    }
+}
+
+/// Deserializes `bind`, which is either one address or a list of them.
+fn addresses<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMore {
+        One(String),
+        More(Vec<String>),
+    }
+    match OneOrMore::deserialize(deserializer)? {
+        OneOrMore::One(address) => Ok(Some(vec![address])),
+        OneOrMore::More(addresses) if addresses.is_empty() => Err(de::Error::custom(
+            "there must be at least one address to bind to",
+        )),
+        OneOrMore::More(addresses) => Ok(Some(addresses)),
+    }
+}
+
+/// Deserializes a status code list written the same way as `--health-check-expect`.
+fn status_codes<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<StatusCodes>, D::Error> {
+    let arg = String::deserialize(deserializer)?;
+    health::parse_status_codes(&arg)
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+/// Deserializes a method written the same way as `--health-check-method`.
+fn method<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<http::Method>, D::Error> {
+    health::parse_method(&String::deserialize(deserializer)?)
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+/// Deserializes a host written the same way as `--health-check-host`.
+fn host<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    health::parse_host(&String::deserialize(deserializer)?)
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+/// Deserializes a failure ratio, held to the same range as `--circuit-breaker-threshold`.
+fn threshold<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    circuit_breaker::check_threshold(f64::deserialize(deserializer)?)
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+/// Deserializes an instance ID, held to the same characters as `--instance-id`.
+fn instance_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    via::parse_instance_id(&String::deserialize(deserializer)?)
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+/// Deserializes mirror upstreams, each written the same way as `--mirror-upstream`.
+fn mirror_upstreams<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|arg| mirror::parse_mirror_upstream(arg).map_err(de::Error::custom))
+        .collect::<Result<Vec<String>, D::Error>>()
+        .map(Some)
+}
+
+/// Deserializes the mirror percentage, which like `--mirror-percent` is from 0 to 100.
+fn percent<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    let percent = u32::deserialize(deserializer)?;
+    mirror::parse_percent(&percent.to_string())
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+/// Deserializes a list of CIDR ranges written the same way as `--rate-limit-exempt`.
+fn cidrs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<Cidr>>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|arg| cidr::parse_cidr(arg).map_err(de::Error::custom))
+        .collect::<Result<Vec<Cidr>, D::Error>>()
+        .map(Some)
+}
+
+/// Deserializes a list of IPs and CIDR ranges written the same way as `--deny`.
+fn ips_or_cidrs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<Cidr>>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|arg| cidr::parse_ip_or_cidr(arg).map_err(de::Error::custom))
+        .collect::<Result<Vec<Cidr>, D::Error>>()
+        .map(Some)
+}
+
+/// Deserializes a list of headers written the same way as `--set-request-header`.
+fn set_headers<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<SetHeader>>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|arg| header_rules::parse_set_header(arg).map_err(de::Error::custom))
+        .collect::<Result<Vec<SetHeader>, D::Error>>()
+        .map(Some)
+}
+
+/// Deserializes a list of header names written the same way as `--remove-request-header`.
+fn header_names<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<HeaderName>>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|arg| header_rules::parse_header_name(arg).map_err(de::Error::custom))
+        .collect::<Result<Vec<HeaderName>, D::Error>>()
+        .map(Some)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rate_limit;
+    use crate::route;
+    use clap::{CommandFactory, FromArgMatches};
+
+    const EXAMPLE: &str = include_str!("../tests/fixtures/balancebeam.toml");
+
+    fn options_with(args: &[&str], config: Config) -> CmdOptions {
+        let matches = CmdOptions::command()
+            .try_get_matches_from(std::iter::once("balancebeam").chain(args.iter().copied()))
+            .unwrap();
+        let mut options = CmdOptions::from_arg_matches(&matches).unwrap();
+        config.apply(&mut options, &matches);
+        options
+    }
+
+    fn upstream(address: &str, weight: u32) -> Upstream {
+        Upstream {
+            address: String::from(address),
+            weight,
+            max_requests: None,
+            backup: false,
+            host_override: None,
+            sni_override: None,
+        }
+    }
+
+    #[test]
+    fn test_example_config() {
+        let options = options_with(&[], Config::parse(EXAMPLE).unwrap());
+        assert_eq!(options.bind, vec!["0.0.0.0:8080", "[::]:8080"]);
+        assert_eq!(options.admin_bind.as_deref(), Some("127.0.0.1:9090"));
+        assert_eq!(
+            options.admin_auth_file,
+            Some(PathBuf::from("/etc/balancebeam/admin-users"))
+        );
+        assert_eq!(
+            options.upstream,
+            vec![
+                upstream("10.0.0.1:80", 4),
+                Upstream {
+                    max_requests: Some(50),
+                    host_override: Some(String::from("app.internal")),
+                    ..upstream("10.0.0.2:80", 1)
+                },
+                upstream("10.0.0.3:80", 0),
+                Upstream {
+                    backup: true,
+                    ..upstream("10.0.9.1:80", 1)
+                },
+            ]
+        );
+        assert_eq!(
+            options.route,
+            vec![Route {
+                name: Some(String::from("api")),
+                host: route::parse_route("*.api.example.com=x:80").unwrap().host,
+                upstreams: vec![upstream("10.0.1.1:80", 1), upstream("10.0.1.2:80", 2)],
+            }]
+        );
+        assert!(options.reject_unknown_hosts);
+        assert_eq!(options.resolve_interval, 30);
+        assert_eq!(options.strategy, Strategy::RoundRobin);
+        assert_eq!(options.max_retries, 1);
+        assert_eq!(options.max_connect_attempts, 3);
+        assert_eq!(options.upstream_connect_timeout, 2);
+        assert_eq!(options.upstream_response_timeout, 15);
+        assert_eq!(options.max_pool_idle, 16);
+        assert_eq!(options.pool_idle_timeout, 60);
+        assert_eq!(options.client_idle_timeout, 120);
+        assert_eq!(options.max_header_bytes, 32768);
+        assert_eq!(options.max_header_count, 64);
+        assert_eq!(options.max_request_body_bytes, 1048576);
+        assert_eq!(options.shutdown_grace_period, 10);
+        assert_eq!(options.max_concurrent_requests, 512);
+        assert_eq!(options.request_queue_timeout_ms, 250);
+        assert_eq!(options.max_requests_per_upstream, 100);
+        assert_eq!(options.access_log_format, AccessLogFormat::Json);
+        assert_eq!(
+            options.sticky_cookie.as_deref(),
+            Some("balancebeam_upstream")
+        );
+        assert_eq!(options.instance_id.as_deref(), Some("edge-1"));
+        assert_eq!(options.slow_start, 30);
+        assert_eq!(
+            options.error_page_dir,
+            Some(PathBuf::from("/etc/balancebeam/error-pages"))
+        );
+        assert_eq!(
+            options.tls_cert,
+            Some(PathBuf::from("/etc/balancebeam/cert.pem"))
+        );
+        assert_eq!(
+            options.tls_key,
+            Some(PathBuf::from("/etc/balancebeam/key.pem"))
+        );
+        assert_eq!(
+            options.upstream_ca,
+            Some(PathBuf::from("/etc/balancebeam/upstream-ca.pem"))
+        );
+        assert!(!options.insecure_upstream);
+        assert!(options.accept_proxy_protocol);
+        assert_eq!(
+            options.send_proxy_protocol,
+            Some(proxy_protocol::Version::V2)
+        );
+        assert!(options.trust_forwarded_for);
+        assert!(options.drop_interim_responses);
+        assert!(options.drain_fallback);
+        assert_eq!(options.self_health_path, "/livez");
+        assert!(options.self_health_on_main);
+        assert_eq!(options.unhealthy_status_threshold, 5);
+        assert_eq!(options.latency_eject_ms, 250);
+        assert_eq!(
+            options.deny,
+            vec![
+                cidr::parse_cidr("198.51.100.23/32").unwrap(),
+                cidr::parse_cidr("2001:db8:bad::/48").unwrap(),
+            ]
+        );
+        assert_eq!(options.active_health_check_interval, 5);
+        assert_eq!(options.active_health_check_path, "/healthz");
+        assert_eq!(options.health_check_method, http::Method::POST);
+        assert_eq!(
+            options.health_check_host.as_deref(),
+            Some("health.internal")
+        );
+        assert_eq!(
+            options.health_check_body.as_deref(),
+            Some("{\"deep\": true}")
+        );
+        assert_eq!(options.health_check_failure_threshold, 2);
+        assert_eq!(options.health_check_success_threshold, 3);
+        assert_eq!(options.health_check_timeout, 1);
+        assert_eq!(
+            options.health_check_expect,
+            health::parse_status_codes("200-299,301").unwrap()
+        );
+        assert_eq!(options.health_check_max_backoff, 120);
+        assert!(options.startup_health_check);
+        assert!(!options.no_startup_health_check);
+        assert!(options.active_health_checks_configured);
+        assert!(options.start_degraded);
+        assert_eq!(options.circuit_breaker_window, 20);
+        assert_eq!(options.circuit_breaker_threshold, 0.25);
+        assert_eq!(options.circuit_breaker_cooldown, 15);
+        assert_eq!(options.max_requests_per_minute, 120);
+        assert_eq!(options.max_connections_per_ip, 64);
+        assert_eq!(
+            options.rate_limit_exempt,
+            vec![
+                cidr::parse_cidr("10.0.0.0/8").unwrap(),
+                cidr::parse_cidr("192.168.1.7/32").unwrap(),
+            ]
+        );
+        assert_eq!(
+            options.rate_limit_rule,
+            vec![
+                RateLimitRule::new(
+                    Some(String::from("login")),
+                    &[String::from("POST")],
+                    Some(String::from("/login")),
+                    5
+                )
+                .unwrap(),
+                rate_limit::parse_rule("/static=0").unwrap(),
+            ]
+        );
+        assert_eq!(
+            options.rate_limit_key,
+            rate_limit::parse_key("header:X-Api-Key").unwrap()
+        );
+        assert!(options.require_rate_limit_key);
+        assert_eq!(
+            options.set_request_header,
+            vec![header_rules::parse_set_header("X-Env:prod").unwrap()]
+        );
+        assert_eq!(
+            options.remove_request_header,
+            vec![HeaderName::from_static("x-debug")]
+        );
+        assert_eq!(
+            options.set_response_header,
+            vec![header_rules::parse_set_header("X-Served-By:balancebeam").unwrap()]
+        );
+        assert_eq!(
+            options.remove_response_header,
+            vec![
+                HeaderName::from_static("x-internal-auth"),
+                HeaderName::from_static("server"),
+            ]
+        );
+        assert!(options.compress);
+        assert_eq!(options.compress_min_bytes, 256);
+        assert_eq!(
+            options.compress_skip_type,
+            vec!["image/*", "video/*", "application/zip"]
+        );
+        assert!(options.allow_connect);
+        assert_eq!(options.connect_port, vec![443, 8443]);
+        assert_eq!(
+            options.mirror_upstream,
+            vec!["10.0.3.1:8080", "https://canary.internal:8443"]
+        );
+        assert_eq!(options.mirror_percent, 5);
+    }
+
+    #[test]
+    fn test_command_line_overrides_file() {
+        let options = options_with(
+            &[
+                "--bind",
+                "127.0.0.1:1100",
+                "--upstream",
+                "10.0.0.9:80",
+                "--strategy",
+                "random",
+                "--health-check-timeout",
+                "5",
+                "--rate-limit-exempt",
+                "127.0.0.1/32",
+                "--rate-limit-rule",
+                "GET /api=30",
+            ],
+            Config::parse(EXAMPLE).unwrap(),
+        );
+        assert_eq!(options.bind, vec!["127.0.0.1:1100"]);
+        assert_eq!(options.upstream, vec![upstream("10.0.0.9:80", 1)]);
+        assert_eq!(options.strategy, Strategy::Random);
+        // Passing a flag's default value explicitly still overrides the file
+        assert_eq!(options.health_check_timeout, 5);
+        assert_eq!(
+            options.rate_limit_exempt,
+            vec![cidr::parse_cidr("127.0.0.1/32").unwrap()]
+        );
+        assert_eq!(
+            options.rate_limit_rule,
+            vec![rate_limit::parse_rule("GET /api=30").unwrap()]
+        );
+        // Everything else still comes from the file
+        assert_eq!(options.admin_bind.as_deref(), Some("127.0.0.1:9090"));
+        assert_eq!(options.active_health_check_interval, 5);
+        assert_eq!(options.max_requests_per_minute, 120);
+    }
+
+    #[test]
+    fn test_startup_health_check_opt_out() {
+        let config = || Config::parse("[health_check]\ninterval = 5\nstartup = false\n").unwrap();
+        let options = options_with(&["--upstream", "10.0.0.1:80"], config());
+        assert!(options.active_health_checks_configured);
+        assert!(!options.startup_health_check);
+        assert!(options.no_startup_health_check);
+        // The command line still wins
+        let options = options_with(
+            &["--upstream", "10.0.0.1:80", "--startup-health-check"],
+            config(),
+        );
+        assert!(options.startup_health_check);
+        assert!(!options.no_startup_health_check);
+    }
+
+    #[test]
+    fn test_flag_defaults_fill_in_missing_settings() {
+        let config = Config::parse("[health_check]\npath = \"/ping\"\n").unwrap();
+        let options = options_with(&["--upstream", "10.0.0.1:80"], config);
+        assert_eq!(options.active_health_check_path, "/ping");
+        assert_eq!(options.active_health_check_interval, 10);
+        assert_eq!(options.health_check_method, http::Method::GET);
+        assert_eq!(options.health_check_host, None);
+        assert_eq!(options.health_check_body, None);
+        assert!(!options.startup_health_check);
+        assert!(!options.no_startup_health_check);
+        // Setting the path counts as setting up active health checks
+        assert!(options.active_health_checks_configured);
+        assert!(!options.start_degraded);
+        assert_eq!(options.bind, vec!["0.0.0.0:1100"]);
+        assert_eq!(options.admin_bind, None);
+        assert!(options.admin_auth.is_empty());
+        assert_eq!(options.admin_auth_file, None);
+        assert_eq!(options.upstream, vec![upstream("10.0.0.1:80", 1)]);
+        assert_eq!(options.strategy, Strategy::Random);
+        assert!(options.rate_limit_exempt.is_empty());
+        assert!(options.rate_limit_rule.is_empty());
+        assert_eq!(options.rate_limit_key, RateLimitKey::Ip);
+        assert!(!options.require_rate_limit_key);
+        assert!(options.deny.is_empty());
+        assert!(!options.compress);
+        assert_eq!(
+            options.compress_skip_type,
+            vec!["image/*", "video/*", "audio/*"]
+        );
+        assert!(!options.allow_connect);
+        assert_eq!(options.connect_port, vec![443]);
+        assert!(options.mirror_upstream.is_empty());
+        assert_eq!(options.mirror_percent, 100);
+        assert!(!options.drop_interim_responses);
+        assert!(!options.drain_fallback);
+        assert_eq!(options.self_health_path, "/healthz");
+        assert!(!options.self_health_on_main);
+        assert_eq!(options.unhealthy_status_threshold, 0);
+        assert_eq!(options.latency_eject_ms, 0);
+        assert_eq!(options.error_page_dir, None);
+        // A lone address doesn't need to be written as a list
+        let options = options_with(&[], Config::parse("bind = \"[::1]:80\"\n").unwrap());
+        assert_eq!(options.bind, vec!["[::1]:80"]);
+    }
+
+    #[test]
+    fn test_malformed_config_errors() {
+        let error = |text: &str| Config::parse(text).unwrap_err().to_string();
+        let message = error("bind = \"0.0.0.0:80\"\n[health_check]\ninterval = \"soon\"\n");
+        assert!(message.contains("health_check.interval"), "{}", message);
+        assert!(message.contains("line 3"), "{}", message);
+        let message = error("[rate_limit]\nexempt = [\"10.0.0.0/40\"]\n");
+        assert!(message.contains("rate_limit.exempt"), "{}", message);
+        let message = error("[health_check]\nfailure_threshold = 0\n");
+        assert!(
+            message.contains("health_check.failure_threshold"),
+            "{}",
+            message
+        );
+        let message = error("[circuit_breaker]\nthreshold = 1.5\n");
+        assert!(message.contains("circuit_breaker.threshold"), "{}", message);
+        let message = error("[headers]\nremove_response = [\"Content-Length\"]\n");
+        assert!(message.contains("headers.remove_response"), "{}", message);
+        let message = error("deny = [\"bad.example.com\"]\n");
+        assert!(message.contains("deny"), "{}", message);
+        let message = error("instance_id = \"edge 1\"\n");
+        assert!(message.contains("instance_id"), "{}", message);
+        let message = error("strategy = \"fastest\"\n");
+        assert!(message.contains("strategy"), "{}", message);
+        let message = error("[[upstreams]]\naddress = \"10.0.0.1:80\"\nweigth = 2\n");
+        assert!(message.contains("weigth"), "{}", message);
+        let message = error("[[upstreams]]\naddress = \"10.0.0.1:80\"\nhost_override = \"a b\"\n");
+        assert!(message.contains("host_override"), "{}", message);
+        let message = error("[[upstreams]]\naddress = \"10.0.0.1:80\"\nsni_override = \"app\"\n");
+        assert!(message.contains("isn't an https:// one"), "{}", message);
+        let message = error(
+            "[[routes]]\nhost = \"*.example.com\"\nupstreams = [{ address = \"10.0.0.1:80\", sni_override = \"app\" }]\n",
+        );
+        assert!(message.contains("isn't an https:// one"), "{}", message);
+        let message = error("[[routes]]\nhost = \"*.*.com\"\nupstreams = []\n");
+        assert!(message.contains("invalid host pattern"), "{}", message);
+        let message =
+            error("[[rate_limit.rules]]\npath_prefix = \"login\"\nmax_requests_per_minute = 5\n");
+        assert!(message.contains("should start with /"), "{}", message);
+        let message = error("[rate_limit]\nkey = \"cookie\"\n");
+        assert!(message.contains("rate_limit.key"), "{}", message);
+        let message = error("[health_check]\nmethod = \"connect\"\n");
+        assert!(message.contains("health_check.method"), "{}", message);
+        let message = error("[health_check]\nhost = \"health internal\"\n");
+        assert!(message.contains("health_check.host"), "{}", message);
+        let message = error("[mirror]\nupstreams = [\"10.0.3.1:8080=2\"]\n");
+        assert!(message.contains("mirror.upstreams"), "{}", message);
+        let message = error("[mirror]\npercent = 150\n");
+        assert!(message.contains("mirror.percent"), "{}", message);
+        let message = error("bind = []\n");
+        assert!(message.contains("at least one address"), "{}", message);
+        let message = error("bind = \"0.0.0.0:80\nmax_retries = 1\n");
+        assert!(message.contains("line 1"), "{}", message);
+    }
+}
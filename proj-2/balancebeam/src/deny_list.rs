@@ -0,0 +1,117 @@
+use crate::cidr::Cidr;
+use std::net::IpAddr;
+use std::sync::RwLock;
+
+/// Client IP ranges whose connections are refused outright, from `--deny` and the admin
+/// `/denylist` endpoints. It's read once per connection and rarely written, and never held across
+/// an await, hence a blocking read-write lock.
+#[derive(Debug, Default)]
+pub struct DenyList {
+    ranges: RwLock<Vec<Cidr>>,
+}
+
+impl DenyList {
+    pub fn new(ranges: Vec<Cidr>) -> Self {
+        DenyList {
+            ranges: RwLock::new(ranges),
+        }
+    }
+
+    /// The first range that denies `ip`, if any. An IPv4 address that a dual-stack listener saw as
+    /// IPv4-mapped IPv6 (`::ffff:203.0.113.7`) is judged as the IPv4 address it is.
+    pub fn denies(&self, ip: IpAddr) -> Option<Cidr> {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+        self.ranges
+            .read()
+            .unwrap()
+            .iter()
+            .find(|range| range.contains(ip))
+            .copied()
+    }
+
+    /// Adds `range`, unless it's already there. Returns whether it was added.
+    pub fn add(&self, range: Cidr) -> bool {
+        let mut ranges = self.ranges.write().unwrap();
+        if ranges.contains(&range) {
+            return false;
+        }
+        ranges.push(range);
+        true
+    }
+
+    /// Removes `range`, written exactly as it was added. Addresses it covers stay denied if
+    /// another range covers them too. Returns whether it was there.
+    pub fn remove(&self, range: &Cidr) -> bool {
+        let mut ranges = self.ranges.write().unwrap();
+        match ranges.iter().position(|denied| denied == range) {
+            Some(idx) => {
+                ranges.remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The ranges currently denied, in the order they were added
+    pub fn ranges(&self) -> Vec<Cidr> {
+        self.ranges.read().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cidr::parse_ip_or_cidr;
+
+    fn range(arg: &str) -> Cidr {
+        parse_ip_or_cidr(arg).unwrap()
+    }
+
+    fn denies(list: &DenyList, ip: &str) -> bool {
+        list.denies(ip.parse().unwrap()).is_some()
+    }
+
+    #[test]
+    fn test_ipv4_and_ipv6() {
+        let list = DenyList::new(vec![range("203.0.113.0/24"), range("2001:db8::1")]);
+        assert!(denies(&list, "203.0.113.77"));
+        assert!(!denies(&list, "203.0.114.1"));
+        assert!(denies(&list, "2001:db8::1"));
+        assert!(!denies(&list, "2001:db8::2"));
+        assert!(denies(&list, "::ffff:203.0.113.77"));
+        assert!(!denies(&list, "::ffff:198.51.100.1"));
+        assert!(!denies(&DenyList::default(), "203.0.113.77"));
+    }
+
+    #[test]
+    fn test_overlapping_ranges() {
+        let list = DenyList::new(vec![range("10.0.0.0/8"), range("10.1.0.0/16")]);
+        assert_eq!(
+            list.denies("10.1.2.3".parse().unwrap()),
+            Some(range("10.0.0.0/8"))
+        );
+        assert!(list.remove(&range("10.0.0.0/8")));
+        // Still covered by the narrower range
+        assert!(denies(&list, "10.1.2.3"));
+        assert!(!denies(&list, "10.2.0.1"));
+        assert!(!list.remove(&range("10.0.0.0/8")));
+        assert!(list.remove(&range("10.1.0.0/16")));
+        assert!(!denies(&list, "10.1.2.3"));
+    }
+
+    #[test]
+    fn test_add() {
+        let list = DenyList::default();
+        assert!(list.add(range("fd00::/8")));
+        assert!(!list.add(range("fd00::/8")));
+        assert!(list.add(range("192.0.2.1")));
+        assert!(denies(&list, "fd12::1"));
+        assert_eq!(
+            list.ranges(),
+            vec![range("fd00::/8"), range("192.0.2.1/32")]
+        );
+    }
+}
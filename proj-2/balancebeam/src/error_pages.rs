@@ -0,0 +1,167 @@
+use crate::response;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Largest error page that is loaded. They're all held in memory and sent in place of a one-line
+/// message, so anything bigger is more likely a mistake than a page.
+const MAX_PAGE_BYTES: u64 = 64 * 1024;
+
+/// Pages from `--error-page-dir` to send instead of the plain-text bodies of the error responses
+/// balancebeam makes itself, by status. Loaded once at startup, so a page that changes on disk
+/// only takes effect on restart.
+#[derive(Debug, Default)]
+pub struct ErrorPages {
+    pages: HashMap<http::StatusCode, Vec<u8>>,
+}
+
+/// The error status a file in the error page directory is the page for, if it's named like one,
+/// e.g. `502.html`.
+fn page_status(file_name: &str) -> Option<http::StatusCode> {
+    let code = file_name.strip_suffix(".html")?;
+    if code.len() != 3 || !code.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let status = http::StatusCode::from_bytes(code.as_bytes()).ok()?;
+    if status.is_client_error() || status.is_server_error() {
+        Some(status)
+    } else {
+        None
+    }
+}
+
+impl ErrorPages {
+    /// Loads `<code>.html` from `dir` for every 4xx and 5xx status that has one; statuses without
+    /// a file keep their plain-text body. Other files are ignored, but a page that can't be read or
+    /// is over the size limit is an error.
+    pub fn load(dir: &Path) -> Result<ErrorPages, String> {
+        let entries =
+            std::fs::read_dir(dir).map_err(|err| format!("{}: {}", dir.display(), err))?;
+        let mut pages = HashMap::new();
+        for entry in entries {
+            let path = entry
+                .map_err(|err| format!("{}: {}", dir.display(), err))?
+                .path();
+            let status = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => match page_status(name) {
+                    Some(status) => status,
+                    None => continue,
+                },
+                None => continue,
+            };
+            let size = std::fs::metadata(&path)
+                .map_err(|err| format!("{}: {}", path.display(), err))?
+                .len();
+            if size > MAX_PAGE_BYTES {
+                return Err(format!(
+                    "{} is {} bytes, but error pages can be at most {}",
+                    path.display(),
+                    size,
+                    MAX_PAGE_BYTES
+                ));
+            }
+            let page =
+                std::fs::read(&path).map_err(|err| format!("{}: {}", path.display(), err))?;
+            pages.insert(status, page);
+        }
+        Ok(ErrorPages { pages })
+    }
+
+    /// The statuses that have a page, lowest first
+    pub fn statuses(&self) -> Vec<u16> {
+        let mut statuses: Vec<u16> = self.pages.keys().map(|status| status.as_u16()).collect();
+        statuses.sort_unstable();
+        statuses
+    }
+
+    /// Like response::make_http_error, but with the status's page as the body if it has one.
+    pub fn make_http_error(&self, status: http::StatusCode) -> http::Response<Vec<u8>> {
+        let mut response = response::make_http_error(status);
+        self.apply(&mut response);
+        response
+    }
+
+    /// Replaces the body of an error response balancebeam made with the page for its status, if
+    /// there is one, fixing up Content-Type and Content-Length to match.
+    pub fn apply(&self, response: &mut http::Response<Vec<u8>>) {
+        if let Some(page) = self.pages.get(&response.status()) {
+            let headers = response.headers_mut();
+            headers.insert(
+                http::header::CONTENT_TYPE,
+                http::HeaderValue::from_static("text/html"),
+            );
+            headers.insert(http::header::CONTENT_LENGTH, page.len().into());
+            *response.body_mut() = page.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// An empty directory of its own for each test
+    fn page_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "balancebeam-error-pages-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_page_status() {
+        assert_eq!(page_status("502.html"), Some(http::StatusCode::BAD_GATEWAY));
+        assert_eq!(
+            page_status("429.html"),
+            Some(http::StatusCode::TOO_MANY_REQUESTS)
+        );
+        assert_eq!(page_status("200.html"), None);
+        assert_eq!(page_status("302.html"), None);
+        assert_eq!(page_status("502.htm"), None);
+        assert_eq!(page_status("5020.html"), None);
+        assert_eq!(page_status("+50.html"), None);
+        assert_eq!(page_status("index.html"), None);
+    }
+
+    #[test]
+    fn test_load_and_apply() {
+        let dir = page_dir("apply");
+        std::fs::write(dir.join("502.html"), "<h1>Back soon</h1>").unwrap();
+        std::fs::write(dir.join("200.html"), "<h1>Fine</h1>").unwrap();
+        std::fs::write(dir.join("README"), "not a page").unwrap();
+        let pages = ErrorPages::load(&dir).unwrap();
+        assert_eq!(pages.statuses(), vec![502]);
+
+        let response = pages.make_http_error(http::StatusCode::BAD_GATEWAY);
+        assert_eq!(response.status(), 502);
+        assert_eq!(response.headers()["content-type"], "text/html");
+        assert_eq!(response.headers()["content-length"], "18");
+        assert_eq!(response.body(), b"<h1>Back soon</h1>");
+        // Statuses without a page keep their plain text
+        let response = pages.make_http_error(http::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["content-type"], "text/plain");
+        assert_eq!(response.body(), b"HTTP 503 Service Unavailable");
+        let response = ErrorPages::default().make_http_error(http::StatusCode::BAD_GATEWAY);
+        assert_eq!(response.body(), b"HTTP 502 Bad Gateway");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let dir = page_dir("errors");
+        std::fs::write(
+            dir.join("503.html"),
+            vec![b'x'; MAX_PAGE_BYTES as usize + 1],
+        )
+        .unwrap();
+        let message = ErrorPages::load(&dir).unwrap_err();
+        assert!(message.contains("503.html"), "{}", message);
+        assert!(message.contains("at most"), "{}", message);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(ErrorPages::load(&dir).is_err());
+    }
+}
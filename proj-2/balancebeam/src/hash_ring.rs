@@ -0,0 +1,163 @@
+/// Points each unit of upstream weight gets on the ring. More points even out the share of keys
+/// each upstream gets, at the cost of a bigger ring.
+const VIRTUAL_NODES: u32 = 160;
+
+/// A consistent hash ring mapping keys (client IPs) to upstreams. Each upstream owns the arcs of
+/// the ring ending at its points, so adding or removing one upstream only moves the keys on its own
+/// arcs.
+#[derive(Debug, Default)]
+pub struct HashRing {
+    /// The `(address, weight)` pairs the ring was built from
+    members: Vec<(String, u32)>,
+    /// `(hash, index into members)`, sorted by hash
+    points: Vec<(u64, usize)>,
+}
+
+impl HashRing {
+    /// Builds a ring over `members`, given as `(address, weight)` pairs. Members with weight zero
+    /// get no points, so they are never picked.
+    pub fn new(members: &[(&str, u32)]) -> HashRing {
+        let mut points = Vec::new();
+        for (idx, &(address, weight)) in members.iter().enumerate() {
+            for vnode in 0..weight * VIRTUAL_NODES {
+                points.push((hash(format!("{}#{}", address, vnode).as_bytes()), idx));
+            }
+        }
+        points.sort_unstable();
+        HashRing {
+            members: members
+                .iter()
+                .map(|&(address, weight)| (String::from(address), weight))
+                .collect(),
+            points,
+        }
+    }
+
+    /// Whether the ring was built from exactly these members, in which case it needn't be rebuilt.
+    pub fn built_from(&self, members: &[(&str, u32)]) -> bool {
+        self.members.len() == members.len()
+            && self.members.iter().zip(members).all(
+                |((address, weight), &(other_address, other_weight))| {
+                    address == other_address && *weight == other_weight
+                },
+            )
+    }
+
+    /// The address of the upstream owning `key`, or None if the ring is empty.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        if self.points.is_empty() {
+            return None;
+        }
+        let key_hash = hash(key.as_bytes());
+        let idx = self.points.partition_point(|&(point, _)| point < key_hash);
+        let (_, member) = self.points[idx % self.points.len()];
+        Some(&self.members[member].0)
+    }
+}
+
+/// 64-bit FNV-1a, with MurmurHash3's finalizer mixed in so that similar inputs (like the points of
+/// one upstream) land far apart
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash = bytes.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn client_ips() -> impl Iterator<Item = String> {
+        (0..20000).map(|i| format!("10.{}.{}.{}", i / 65536, (i / 256) % 256, i % 256))
+    }
+
+    fn assignments(ring: &HashRing) -> HashMap<String, String> {
+        client_ips()
+            .map(|ip| {
+                let upstream = String::from(ring.get(&ip).unwrap());
+                (ip, upstream)
+            })
+            .collect()
+    }
+
+    fn shares(ring: &HashRing) -> HashMap<String, usize> {
+        let mut shares = HashMap::new();
+        for upstream in assignments(ring).into_values() {
+            *shares.entry(upstream).or_insert(0) += 1;
+        }
+        shares
+    }
+
+    #[test]
+    fn test_distribution_is_roughly_even() {
+        let members = [
+            ("a:80", 1),
+            ("b:80", 1),
+            ("c:80", 1),
+            ("d:80", 1),
+            ("e:80", 1),
+        ];
+        let shares = shares(&HashRing::new(&members));
+        assert_eq!(shares.len(), 5);
+        for (upstream, &share) in &shares {
+            // Each should get about 4000, give or take 15%
+            assert!((3400..=4600).contains(&share), "{} got {}", upstream, share);
+        }
+    }
+
+    #[test]
+    fn test_distribution_follows_weights() {
+        let members = [("a:80", 1), ("b:80", 3), ("canary:80", 0)];
+        let shares = shares(&HashRing::new(&members));
+        assert_eq!(shares.get("canary:80"), None);
+        let (a, b) = (shares["a:80"], shares["b:80"]);
+        assert!((4000..=6000).contains(&a), "a got {}", a);
+        assert_eq!(a + b, 20000);
+    }
+
+    #[test]
+    fn test_removing_a_member_only_moves_its_keys() {
+        let before = assignments(&HashRing::new(&[
+            ("a:80", 1),
+            ("b:80", 1),
+            ("c:80", 1),
+            ("d:80", 1),
+            ("e:80", 1),
+        ]));
+        let after = assignments(&HashRing::new(&[
+            ("a:80", 1),
+            ("b:80", 1),
+            ("d:80", 1),
+            ("e:80", 1),
+        ]));
+        let mut moved = 0;
+        for (ip, upstream) in &before {
+            if upstream == "c:80" {
+                assert_ne!(after[ip], "c:80");
+                moved += 1;
+            } else {
+                assert_eq!(&after[ip], upstream, "{} moved", ip);
+            }
+        }
+        // About a fifth of the clients were on c
+        assert!((3400..=4600).contains(&moved), "{} moved", moved);
+    }
+
+    #[test]
+    fn test_empty_ring_and_rebuild_check() {
+        let ring = HashRing::default();
+        assert_eq!(ring.get("10.0.0.1"), None);
+        assert!(ring.built_from(&[]));
+        let ring = HashRing::new(&[("a:80", 1), ("b:80", 2)]);
+        assert!(ring.built_from(&[("a:80", 1), ("b:80", 2)]));
+        assert!(!ring.built_from(&[("a:80", 1), ("b:80", 1)]));
+        assert!(!ring.built_from(&[("a:80", 1)]));
+        assert_eq!(ring.get("10.0.0.1"), ring.get("10.0.0.1"));
+    }
+}
@@ -0,0 +1,149 @@
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+
+/// A header that `--set-request-header` / `--set-response-header` puts in messages, e.g.
+/// `X-Served-By:balancebeam`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetHeader {
+    pub name: HeaderName,
+    pub value: HeaderValue,
+}
+
+/// Headers that frame the message, which balancebeam looks after itself and won't let a rule touch
+const FRAMING_HEADERS: [&str; 3] = ["connection", "content-length", "transfer-encoding"];
+
+/// Parses a header name for a rule. Names are case-insensitive, and kept lowercased.
+pub fn parse_header_name(arg: &str) -> Result<HeaderName, String> {
+    let name = HeaderName::from_bytes(arg.trim().as_bytes())
+        .map_err(|_| format!("invalid header name {:?}", arg))?;
+    if FRAMING_HEADERS.contains(&name.as_str()) {
+        return Err(format!("the {} header can't be rewritten", name));
+    }
+    Ok(name)
+}
+
+/// Parses a `--set-*-header` value of the form `NAME:VALUE`.
+pub fn parse_set_header(arg: &str) -> Result<SetHeader, String> {
+    let (name, value) = arg
+        .split_once(':')
+        .ok_or_else(|| format!("header {:?} should be NAME:VALUE", arg))?;
+    Ok(SetHeader {
+        name: parse_header_name(name)?,
+        value: HeaderValue::from_str(value.trim())
+            .map_err(|_| format!("invalid value for header {}", name.trim()))?,
+    })
+}
+
+/// The rewrites applied to every message going one way (requests to upstreams, or responses to
+/// clients).
+#[derive(Debug, Clone, Default)]
+pub struct HeaderRules {
+    pub remove: Vec<HeaderName>,
+    pub set: Vec<SetHeader>,
+}
+
+impl HeaderRules {
+    /// Removes every instance of the headers to remove or set, then adds the ones to set, in
+    /// order. Setting the same header more than once therefore gives it each of those values.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        for name in self
+            .remove
+            .iter()
+            .chain(self.set.iter().map(|set| &set.name))
+        {
+            headers.remove(name);
+        }
+        for set in &self.set {
+            headers.append(set.name.clone(), set.value.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for &(name, value) in pairs {
+            let name = HeaderName::from_bytes(name.as_bytes()).unwrap();
+            headers.append(name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn values<'a>(headers: &'a HeaderMap, name: &str) -> Vec<&'a str> {
+        headers
+            .get_all(name)
+            .iter()
+            .map(|value| value.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse_set_header("X-Served-By: balancebeam"),
+            Ok(SetHeader {
+                name: HeaderName::from_static("x-served-by"),
+                value: HeaderValue::from_static("balancebeam"),
+            })
+        );
+        // Only the first colon separates the name from the value
+        assert_eq!(
+            parse_set_header("Link:<https://example.com>")
+                .unwrap()
+                .value,
+            "<https://example.com>"
+        );
+        assert!(parse_set_header("X-Served-By").is_err());
+        assert!(parse_set_header("Bad Name:x").is_err());
+        assert!(parse_set_header("Content-Length:0").is_err());
+        assert_eq!(
+            parse_header_name("X-Internal-Auth"),
+            Ok(HeaderName::from_static("x-internal-auth"))
+        );
+        assert!(parse_header_name("Transfer-Encoding").is_err());
+    }
+
+    #[test]
+    fn test_remove_every_instance() {
+        let rules = HeaderRules {
+            remove: vec![parse_header_name("X-INTERNAL-AUTH").unwrap()],
+            set: Vec::new(),
+        };
+        let mut rewritten = headers(&[
+            ("x-internal-auth", "a"),
+            ("host", "example.com"),
+            ("X-Internal-Auth", "b"),
+        ]);
+        rules.apply(&mut rewritten);
+        assert_eq!(rewritten, headers(&[("host", "example.com")]));
+    }
+
+    #[test]
+    fn test_set_in_order() {
+        let rules = HeaderRules {
+            remove: vec![parse_header_name("x-debug").unwrap()],
+            set: vec![
+                parse_set_header("X-Served-By:one").unwrap(),
+                parse_set_header("x-env:prod").unwrap(),
+                parse_set_header("x-served-by:two").unwrap(),
+            ],
+        };
+        let mut rewritten = headers(&[
+            ("x-served-by", "upstream"),
+            ("x-served-by", "upstream-2"),
+            ("x-debug", "1"),
+            ("vary", "accept"),
+        ]);
+        rules.apply(&mut rewritten);
+        assert_eq!(values(&rewritten, "x-served-by"), vec!["one", "two"]);
+        assert_eq!(values(&rewritten, "x-env"), vec!["prod"]);
+        assert_eq!(values(&rewritten, "vary"), vec!["accept"]);
+        assert!(values(&rewritten, "x-debug").is_empty());
+        // Applying the rules again changes nothing
+        let once = rewritten.clone();
+        rules.apply(&mut rewritten);
+        assert_eq!(rewritten, once);
+    }
+}
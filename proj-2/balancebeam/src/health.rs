@@ -0,0 +1,570 @@
+use rand::Rng;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How many of an upstream's most recent probe latencies are kept
+pub const LATENCY_SAMPLES: usize = 20;
+
+/// Consecutive health results seen for one upstream
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Streak {
+    failures: u32,
+    successes: u32,
+}
+
+/// Decides when an upstream should be marked down or back up, so that a single lost probe or
+/// refused connection doesn't take a healthy upstream out of rotation.
+#[derive(Debug, Clone, Copy)]
+pub struct HealthTracker {
+    failure_threshold: u32,
+    success_threshold: u32,
+}
+
+impl HealthTracker {
+    pub fn new(failure_threshold: u32, success_threshold: u32) -> Self {
+        HealthTracker {
+            failure_threshold,
+            success_threshold,
+        }
+    }
+
+    /// Records the outcome of a health check or connection attempt in the `streak` of an upstream
+    /// that is currently `alive` (or not), and returns whether it should be alive from now on. An
+    /// upstream goes down after `failure_threshold` failures in a row, and comes back after
+    /// `success_threshold` successes in a row.
+    pub fn record(&self, streak: &mut Streak, alive: bool, success: bool) -> bool {
+        if success {
+            streak.failures = 0;
+            streak.successes = streak.successes.saturating_add(1);
+            alive || streak.successes >= self.success_threshold
+        } else {
+            streak.successes = 0;
+            streak.failures = streak.failures.saturating_add(1);
+            alive && streak.failures < self.failure_threshold
+        }
+    }
+}
+
+/// Marks an upstream down once enough requests to it in a row have failed, i.e. been answered
+/// with a 5xx or not answered at all. This catches upstreams that accept connections (so that
+/// HealthTracker only ever hears good things of them) but can't serve anything. Only the active
+/// health checker brings them back.
+#[derive(Debug, Clone, Copy)]
+pub struct PassiveHealth {
+    /// Failed requests in a row that mark an upstream down (0 = never)
+    threshold: u32,
+}
+
+impl PassiveHealth {
+    pub fn new(threshold: u32) -> Self {
+        PassiveHealth { threshold }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.threshold != 0
+    }
+
+    pub fn threshold(&self) -> u32 {
+        self.threshold
+    }
+
+    /// Records the outcome of a request in `failures`, the upstream's count of requests failed in
+    /// a row, and returns whether it should be marked down now. Any success starts the count over,
+    /// as does marking the upstream down.
+    pub fn record(&self, failures: &mut u32, success: bool) -> bool {
+        if success || !self.is_enabled() {
+            *failures = 0;
+            return false;
+        }
+        *failures = failures.saturating_add(1);
+        if *failures < self.threshold {
+            return false;
+        }
+        *failures = 0;
+        true
+    }
+}
+
+/// How long an upstream's most recent active health checks took to be answered (connecting
+/// included), oldest first. Probes that got no answer have no latency to record.
+#[derive(Debug, Default, Clone)]
+pub struct ProbeLatencies {
+    samples: VecDeque<Duration>,
+}
+
+/// Percentiles of an upstream's recent probe latencies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySummary {
+    pub p50: Duration,
+    pub p95: Duration,
+    pub max: Duration,
+}
+
+impl ProbeLatencies {
+    /// Records a probe's latency, forgetting the oldest once there are LATENCY_SAMPLES of them.
+    pub fn record(&mut self, latency: Duration) {
+        if self.samples.len() == LATENCY_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(latency);
+    }
+
+    /// The recent latencies' percentiles, or None before the first answered probe
+    pub fn summary(&self) -> Option<LatencySummary> {
+        let mut sorted: Vec<Duration> = self.samples.iter().copied().collect();
+        sorted.sort();
+        Some(LatencySummary {
+            p50: percentile(&sorted, 50)?,
+            p95: percentile(&sorted, 95)?,
+            max: *sorted.last()?,
+        })
+    }
+}
+
+/// The `p`th percentile of `sorted` by nearest rank, i.e. the smallest sample that at least `p`%
+/// of the samples are no greater than
+fn percentile(sorted: &[Duration], p: usize) -> Option<Duration> {
+    let rank = (sorted.len() * p).div_ceil(100).max(1);
+    sorted.get(rank - 1).copied()
+}
+
+/// Marks an upstream down once its probes get too slow, from --latency-eject-ms. While the 95th
+/// percentile of its recent latencies is over the threshold, even a probe the upstream passes
+/// counts as failed, so HealthTracker takes it down (and brings it back) just as it would an
+/// upstream failing its probes.
+#[derive(Debug, Clone, Copy)]
+pub struct LatencyEjection {
+    /// None if latency never ejects anything
+    threshold: Option<Duration>,
+}
+
+impl LatencyEjection {
+    /// `threshold_ms` of 0 turns latency ejection off.
+    pub fn new(threshold_ms: u64) -> Self {
+        LatencyEjection {
+            threshold: Some(Duration::from_millis(threshold_ms)).filter(|_| threshold_ms != 0),
+        }
+    }
+
+    pub fn is_too_slow(&self, latencies: &ProbeLatencies) -> bool {
+        match (self.threshold, latencies.summary()) {
+            (Some(threshold), Some(summary)) => summary.p95 > threshold,
+            _ => false,
+        }
+    }
+}
+
+/// When an upstream is next actively health checked. Each upstream keeps its own deadline, moved
+/// by a random fifth either way every time, so that the probes of several balancebeams (or of
+/// upstreams added at once) drift apart instead of arriving together.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProbeSchedule {
+    /// None until the health checker has first seen the upstream
+    next: Option<Instant>,
+    /// Probes failed in a row while the upstream is down, each doubling the wait for the next
+    failed_while_down: u32,
+}
+
+impl ProbeSchedule {
+    pub fn next(&self) -> Option<Instant> {
+        self.next
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.next.is_some_and(|next| now >= next)
+    }
+
+    /// Schedules the first probe of an upstream the health checker hasn't seen before.
+    pub fn start<R: Rng>(&mut self, now: Instant, interval: Duration, rng: &mut R) {
+        self.next = Some(now + jittered(interval, rng));
+    }
+
+    /// Schedules a probe right away, for an upstream that mustn't get traffic until it passes one.
+    pub fn start_now(&mut self, now: Instant) {
+        self.next = Some(now);
+    }
+
+    /// Schedules the probe after one that `passed` (or not), leaving the upstream `alive` (or
+    /// not). While an upstream stays down, the wait doubles with every failed probe, up to
+    /// `max_backoff`; a passed probe goes straight back to `interval`.
+    pub fn record<R: Rng>(
+        &mut self,
+        now: Instant,
+        passed: bool,
+        alive: bool,
+        interval: Duration,
+        max_backoff: Duration,
+        rng: &mut R,
+    ) {
+        if passed || alive {
+            self.failed_while_down = 0;
+        } else {
+            self.failed_while_down = self.failed_while_down.saturating_add(1);
+        }
+        let backoff = 2_u32
+            .checked_pow(self.failed_while_down)
+            .and_then(|factor| interval.checked_mul(factor))
+            .map_or(max_backoff, |backoff| backoff.min(max_backoff))
+            .max(interval);
+        self.next = Some(now + jittered(backoff, rng));
+    }
+}
+
+/// `delay`, give or take up to 20%
+fn jittered<R: Rng>(delay: Duration, rng: &mut R) -> Duration {
+    delay.mul_f64(rng.gen_range(0.8, 1.2))
+}
+
+/// The HTTP status codes an active health check accepts as healthy, e.g. `200-299,301`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusCodes {
+    /// Inclusive ranges; a single code is a range of one
+    ranges: Vec<(u16, u16)>,
+}
+
+impl StatusCodes {
+    pub fn contains(&self, status: http::StatusCode) -> bool {
+        let status = status.as_u16();
+        self.ranges
+            .iter()
+            .any(|&(low, high)| low <= status && status <= high)
+    }
+}
+
+/// Parses a comma-separated list of status codes and inclusive ranges, like `200-299,301`.
+pub fn parse_status_codes(arg: &str) -> Result<StatusCodes, String> {
+    let parse_code = |code: &str| {
+        code.trim()
+            .parse::<u16>()
+            .ok()
+            .filter(|code| (100..=599).contains(code))
+            .ok_or_else(|| format!("{:?} is not an HTTP status code", code.trim()))
+    };
+    let mut ranges = Vec::new();
+    for part in arg.split(',') {
+        let range = match part.split_once('-') {
+            Some((low, high)) => (parse_code(low)?, parse_code(high)?),
+            None => {
+                let code = parse_code(part)?;
+                (code, code)
+            }
+        };
+        if range.0 > range.1 {
+            return Err(format!("status code range {:?} is backwards", part.trim()));
+        }
+        ranges.push(range);
+    }
+    Ok(StatusCodes { ranges })
+}
+
+/// Parses a `--health-check-method`, e.g. `POST`. Written in any case, since methods in the wild
+/// are all upper case anyway; CONNECT is refused, as a probe can't open a tunnel.
+pub fn parse_method(arg: &str) -> Result<http::Method, String> {
+    let method = http::Method::from_bytes(arg.trim().to_ascii_uppercase().as_bytes())
+        .map_err(|_| format!("{:?} is not an HTTP method", arg))?;
+    if method == http::Method::CONNECT {
+        return Err(String::from("health checks can't use CONNECT"));
+    }
+    Ok(method)
+}
+
+/// Parses a `--health-check-host`, a host with an optional port as it would go in a Host header,
+/// e.g. `health.internal` or `10.0.0.1:8080`.
+pub fn parse_host(arg: &str) -> Result<String, String> {
+    match arg.parse::<http::uri::Authority>() {
+        Ok(authority) if !arg.contains('@') && !authority.host().is_empty() => Ok(arg.to_string()),
+        _ => Err(format!("{:?} is not a host (or host:port)", arg)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    /// Feeds `results` through a tracker, returning whether the upstream was alive after each.
+    fn replay(
+        tracker: &HealthTracker,
+        streak: &mut Streak,
+        mut alive: bool,
+        results: &[bool],
+    ) -> Vec<bool> {
+        results
+            .iter()
+            .map(|&success| {
+                alive = tracker.record(streak, alive, success);
+                alive
+            })
+            .collect()
+    }
+
+    fn latencies(millis: &[u64]) -> ProbeLatencies {
+        let mut latencies = ProbeLatencies::default();
+        for &ms in millis {
+            latencies.record(Duration::from_millis(ms));
+        }
+        latencies
+    }
+
+    #[test]
+    fn test_latency_percentiles() {
+        assert_eq!(ProbeLatencies::default().summary(), None);
+        let ms = Duration::from_millis;
+        assert_eq!(
+            latencies(&[7]).summary(),
+            Some(LatencySummary {
+                p50: ms(7),
+                p95: ms(7),
+                max: ms(7)
+            })
+        );
+        // Nearest rank: of 1..=20ms, p50 is the 10th and p95 the 19th
+        let ascending: Vec<u64> = (1..=20).rev().collect();
+        let summary = latencies(&ascending).summary().unwrap();
+        assert_eq!(
+            (summary.p50, summary.p95, summary.max),
+            (ms(10), ms(19), ms(20))
+        );
+        let summary = latencies(&[5, 1, 3, 2, 4]).summary().unwrap();
+        assert_eq!(
+            (summary.p50, summary.p95, summary.max),
+            (ms(3), ms(5), ms(5))
+        );
+        // Only the most recent samples count
+        let mut recent = latencies(&[1000; LATENCY_SAMPLES]);
+        for _ in 0..LATENCY_SAMPLES {
+            recent.record(ms(2));
+        }
+        assert_eq!(recent.summary().unwrap().max, ms(2));
+    }
+
+    #[test]
+    fn test_latency_ejection() {
+        let ejection = LatencyEjection::new(100);
+        assert!(!ejection.is_too_slow(&ProbeLatencies::default()));
+        assert!(!ejection.is_too_slow(&latencies(&[50, 100])));
+        // One slow probe in twenty is within the 95th percentile; two aren't
+        let mut samples = vec![20; LATENCY_SAMPLES - 1];
+        samples.push(500);
+        assert!(!ejection.is_too_slow(&latencies(&samples)));
+        samples[0] = 500;
+        assert!(ejection.is_too_slow(&latencies(&samples)));
+        assert!(!LatencyEjection::new(0).is_too_slow(&latencies(&samples)));
+
+        // Slow probes are failures to the tracker, so it takes two in a row to eject, and two good
+        // ones in a row to come back
+        let tracker = HealthTracker::new(2, 2);
+        let mut streak = Streak::default();
+        let mut alive = true;
+        let mut recent = latencies(&[10; LATENCY_SAMPLES]);
+        let mut probe = |ms: u64| {
+            recent.record(Duration::from_millis(ms));
+            alive = tracker.record(&mut streak, alive, !ejection.is_too_slow(&recent));
+            alive
+        };
+        assert_eq!([probe(300), probe(300), probe(300)], [true, true, false]);
+        // Fast probes only count as passed once enough of them push the slow ones out
+        let recovering: Vec<bool> = (0..LATENCY_SAMPLES).map(|_| probe(10)).collect();
+        assert_eq!(
+            recovering.iter().position(|&alive| alive),
+            Some(LATENCY_SAMPLES - 1)
+        );
+    }
+
+    #[test]
+    fn test_one_off_flaps_keep_upstream_up() {
+        let tracker = HealthTracker::new(3, 1);
+        let results = [true, false, true, false, false, true, false, true];
+        assert_eq!(
+            replay(&tracker, &mut Streak::default(), true, &results),
+            vec![true; results.len()]
+        );
+    }
+
+    #[test]
+    fn test_sustained_outage_marks_down_at_threshold() {
+        let tracker = HealthTracker::new(3, 1);
+        let mut streak = Streak::default();
+        assert_eq!(
+            replay(&tracker, &mut streak, true, &[false, false, false, false]),
+            vec![true, true, false, false]
+        );
+        // Back up on the first success with the default success threshold
+        assert_eq!(replay(&tracker, &mut streak, false, &[true]), vec![true]);
+    }
+
+    #[test]
+    fn test_recovery_needs_consecutive_successes() {
+        let tracker = HealthTracker::new(1, 3);
+        let mut streak = Streak::default();
+        assert_eq!(replay(&tracker, &mut streak, true, &[false]), vec![false]);
+        assert_eq!(
+            replay(
+                &tracker,
+                &mut streak,
+                false,
+                &[true, true, false, true, true, true]
+            ),
+            vec![false, false, false, false, false, true]
+        );
+    }
+
+    #[test]
+    fn test_passive_health() {
+        let passive = PassiveHealth::new(3);
+        let mut failures = 0;
+        let marked_down: Vec<bool> = [false, false, true, false, false, false, false]
+            .iter()
+            .map(|&success| passive.record(&mut failures, success))
+            .collect();
+        assert_eq!(
+            marked_down,
+            vec![false, false, false, false, false, true, false]
+        );
+        let disabled = PassiveHealth::new(0);
+        assert!((0..10).all(|_| !disabled.record(&mut failures, false)));
+    }
+
+    #[test]
+    fn test_upstreams_tracked_separately() {
+        let tracker = HealthTracker::new(2, 1);
+        let mut streaks = [Streak::default(); 2];
+        assert!(tracker.record(&mut streaks[0], true, false));
+        assert!(tracker.record(&mut streaks[1], true, false));
+        assert!(tracker.record(&mut streaks[1], true, true));
+        assert!(!tracker.record(&mut streaks[0], true, false));
+        assert!(tracker.record(&mut streaks[1], true, false));
+    }
+
+    /// Feeds probe results through a schedule, returning the wait before the probe after each
+    fn probe_waits(alive: &[bool], passed: &[bool]) -> Vec<Duration> {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut schedule = ProbeSchedule::default();
+        let now = Instant::now();
+        let interval = Duration::from_secs(10);
+        let max_backoff = Duration::from_secs(60);
+        schedule.start(now, interval, &mut rng);
+        alive
+            .iter()
+            .zip(passed)
+            .map(|(&alive, &passed)| {
+                schedule.record(now, passed, alive, interval, max_backoff, &mut rng);
+                schedule.next().unwrap() - now
+            })
+            .collect()
+    }
+
+    fn assert_near(waits: &[Duration], expected_secs: &[u64]) {
+        for (wait, &expected) in waits.iter().zip(expected_secs) {
+            let expected = Duration::from_secs(expected);
+            assert!(
+                *wait >= expected.mul_f64(0.8) && *wait <= expected.mul_f64(1.2),
+                "expected {:?} (give or take 20%), got {:?}",
+                expected,
+                waits
+            );
+        }
+    }
+
+    #[test]
+    fn test_probe_backoff_while_down() {
+        let waits = probe_waits(
+            &[true, false, false, false, false, false, false],
+            &[false, false, false, false, false, false, true],
+        );
+        // Failing while still up doesn't back off; once down, each failure doubles the wait
+        assert_near(&waits, &[10, 20, 40, 60, 60, 60, 10]);
+    }
+
+    #[test]
+    fn test_passed_probe_resets_backoff_before_upstream_is_up() {
+        // With a success threshold above one, an upstream stays down for a few passed probes
+        let waits = probe_waits(
+            &[false, false, false, false, false],
+            &[false, false, true, true, false],
+        );
+        assert_near(&waits, &[20, 40, 10, 10, 20]);
+    }
+
+    #[test]
+    fn test_probes_are_jittered() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let now = Instant::now();
+        let mut waits: Vec<Duration> = (0..20)
+            .map(|_| {
+                let mut schedule = ProbeSchedule::default();
+                schedule.start(now, Duration::from_secs(10), &mut rng);
+                assert!(!schedule.is_due(now));
+                schedule.next().unwrap() - now
+            })
+            .collect();
+        assert_near(&waits, &[10; 20]);
+        waits.dedup();
+        assert!(waits.len() > 1, "{:?}", waits);
+        assert!(!ProbeSchedule::default().is_due(now));
+    }
+
+    fn status(code: u16) -> http::StatusCode {
+        http::StatusCode::from_u16(code).unwrap()
+    }
+
+    #[test]
+    fn test_parse_status_codes() {
+        let codes = parse_status_codes("200-299,301").unwrap();
+        assert!(!codes.contains(status(199)));
+        assert!(codes.contains(status(200)));
+        assert!(codes.contains(status(204)));
+        assert!(codes.contains(status(299)));
+        assert!(!codes.contains(status(300)));
+        assert!(codes.contains(status(301)));
+        assert!(!codes.contains(status(302)));
+
+        let codes = parse_status_codes("200").unwrap();
+        assert!(codes.contains(status(200)));
+        assert!(!codes.contains(status(204)));
+        assert_eq!(
+            parse_status_codes(" 204 , 300 - 302 ").unwrap(),
+            parse_status_codes("204,300-302").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_status_codes_errors() {
+        assert!(parse_status_codes("").is_err());
+        assert!(parse_status_codes("200,").is_err());
+        assert!(parse_status_codes("ok").is_err());
+        assert!(parse_status_codes("299-200").is_err());
+        assert!(parse_status_codes("200-").is_err());
+        assert!(parse_status_codes("99").is_err());
+        assert!(parse_status_codes("600").is_err());
+        assert!(parse_status_codes("200-299-301").is_err());
+    }
+
+    #[test]
+    fn test_parse_method() {
+        assert_eq!(parse_method("POST"), Ok(http::Method::POST));
+        assert_eq!(parse_method("head"), Ok(http::Method::HEAD));
+        assert_eq!(parse_method("PURGE").unwrap().as_str(), "PURGE");
+        assert!(parse_method("").is_err());
+        assert!(parse_method("GET /").is_err());
+        assert!(parse_method("connect").is_err());
+    }
+
+    #[test]
+    fn test_parse_host() {
+        assert_eq!(
+            parse_host("health.internal"),
+            Ok(String::from("health.internal"))
+        );
+        assert_eq!(
+            parse_host("10.0.0.1:8080"),
+            Ok(String::from("10.0.0.1:8080"))
+        );
+        assert_eq!(parse_host("[::1]:80"), Ok(String::from("[::1]:80")));
+        assert!(parse_host("").is_err());
+        assert!(parse_host("two words").is_err());
+        assert!(parse_host("user@health.internal").is_err());
+        assert!(parse_host("health.internal/path").is_err());
+    }
+}
@@ -0,0 +1,147 @@
+/// Whether `headers` have a Connection header listing `option` (e.g. `close`)
+fn has_connection_option(headers: &http::HeaderMap, option: &str) -> bool {
+    headers
+        .get_all(http::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|listed| listed.trim().eq_ignore_ascii_case(option))
+}
+
+/// Whether the sender of a message with this version and these headers will hang up after it (or
+/// wants the other side to). Only HTTP/1.0 defaults to closing.
+pub fn wants_close(version: http::Version, headers: &http::HeaderMap) -> bool {
+    if version == http::Version::HTTP_10 {
+        !has_connection_option(headers, "keep-alive")
+    } else {
+        has_connection_option(headers, "close")
+    }
+}
+
+/// Headers a Connection header can't make hop-by-hop: dropping them would change how the message
+/// is framed or where it's going, which is what smuggling a request past a proxy takes
+const PROTECTED_HEADERS: &[&str] = &["content-length", "host", "transfer-encoding"];
+
+/// Removes what applies only to a message's sender's own connection, so it isn't passed on to the
+/// other side's: the Keep-Alive header, and the Connection header along with the headers it lists
+/// (RFC 7230 section 6.1). `Upgrade` is the exception, left listed with its header still there, so
+/// that upgrades can go through; and so are the headers in PROTECTED_HEADERS, which are kept even
+/// if listed.
+pub fn strip(headers: &mut http::HeaderMap) {
+    headers.remove("keep-alive");
+    let options: Vec<String> = headers
+        .get_all(http::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|option| option.trim().to_ascii_lowercase())
+        .filter(|option| !option.is_empty())
+        .collect();
+    headers.remove(http::header::CONNECTION);
+    let mut upgrade = false;
+    for option in options {
+        if option == "upgrade" {
+            upgrade = true;
+        } else if !PROTECTED_HEADERS.contains(&option.as_str()) {
+            headers.remove(option.as_str());
+        }
+    }
+    if upgrade {
+        headers.insert(
+            http::header::CONNECTION,
+            http::HeaderValue::from_static("Upgrade"),
+        );
+    }
+}
+
+/// Tells the client of a response whether its connection (over HTTP `version`) stays open: with
+/// `close` if it won't, and with `keep-alive` to an HTTP/1.0 client, which otherwise assumes it
+/// won't.
+pub fn set_connection_header(headers: &mut http::HeaderMap, version: http::Version, closing: bool) {
+    if closing {
+        headers.insert(
+            http::header::CONNECTION,
+            http::HeaderValue::from_static("close"),
+        );
+    } else if version == http::Version::HTTP_10 {
+        headers.insert(
+            http::header::CONNECTION,
+            http::HeaderValue::from_static("keep-alive"),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> http::HeaderMap {
+        let mut headers = http::HeaderMap::new();
+        for &(name, value) in pairs {
+            headers.append(name, http::HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_wants_close() {
+        let v10 = http::Version::HTTP_10;
+        let v11 = http::Version::HTTP_11;
+        assert!(!wants_close(v11, &headers(&[])));
+        assert!(wants_close(v11, &headers(&[("connection", "Close")])));
+        assert!(wants_close(
+            v11,
+            &headers(&[("connection", "upgrade, close")])
+        ));
+        assert!(!wants_close(v11, &headers(&[("connection", "closed")])));
+        assert!(wants_close(v10, &headers(&[])));
+        assert!(!wants_close(v10, &headers(&[("connection", "Keep-Alive")])));
+    }
+
+    #[test]
+    fn test_strip() {
+        let mut stripped = headers(&[
+            ("connection", "keep-alive"),
+            ("keep-alive", "timeout=5"),
+            ("host", "example.com"),
+        ]);
+        strip(&mut stripped);
+        assert_eq!(stripped, headers(&[("host", "example.com")]));
+
+        let mut stripped = headers(&[
+            ("connection", "close, Upgrade"),
+            ("connection", "X-Foo"),
+            ("upgrade", "websocket"),
+            ("x-foo", "bar"),
+        ]);
+        strip(&mut stripped);
+        assert_eq!(
+            stripped,
+            headers(&[("connection", "Upgrade"), ("upgrade", "websocket")])
+        );
+
+        // Listing framing headers doesn't get them dropped
+        let mut stripped = headers(&[
+            ("connection", "Content-Length, Transfer-Encoding, host, te"),
+            ("content-length", "5"),
+            ("host", "example.com"),
+            ("te", "trailers"),
+        ]);
+        strip(&mut stripped);
+        assert_eq!(
+            stripped,
+            headers(&[("content-length", "5"), ("host", "example.com")])
+        );
+    }
+
+    #[test]
+    fn test_set_connection_header() {
+        let mut set = http::HeaderMap::new();
+        set_connection_header(&mut set, http::Version::HTTP_11, false);
+        assert!(set.is_empty());
+        set_connection_header(&mut set, http::Version::HTTP_10, false);
+        assert_eq!(set, headers(&[("connection", "keep-alive")]));
+        set_connection_header(&mut set, http::Version::HTTP_10, true);
+        assert_eq!(set, headers(&[("connection", "close")]));
+    }
+}
@@ -0,0 +1,3678 @@
+mod access_log;
+mod admin;
+mod admin_auth;
+mod cidr;
+mod circuit_breaker;
+mod compress;
+mod config;
+mod deny_list;
+mod error_pages;
+mod hash_ring;
+mod header_rules;
+mod health;
+mod keep_alive;
+mod metrics;
+mod mirror;
+mod pool;
+mod proxy_protocol;
+mod rate_limit;
+mod request;
+mod request_id;
+mod resolve;
+mod response;
+mod route;
+mod tls;
+mod upstream;
+mod via;
+
+use clap::{CommandFactory, FromArgMatches, Parser};
+use rand::{Rng, SeedableRng};
+// use std::net::{TcpListener, TcpStream};
+use std::io;
+use tokio::net::TcpListener;
+
+use access_log::{AccessLogEntry, AccessLogFormat};
+use admin_auth::AdminAuth;
+use cidr::Cidr;
+use circuit_breaker::CircuitBreaker;
+use compress::CompressionSettings;
+use deny_list::DenyList;
+use error_pages::ErrorPages;
+use hash_ring::HashRing;
+use header_rules::{HeaderRules, SetHeader};
+use health::{HealthTracker, LatencyEjection, PassiveHealth, StatusCodes, Streak};
+use http::header::HeaderName;
+use metrics::{ByteCounters, CountingStream, DurationHistogram, UpstreamStats};
+use mirror::Mirror;
+use pool::ConnectionPool;
+use proxy_protocol::ConnectionAddresses;
+use rate_limit::{RateLimitKey, RateLimitRule, RateLimiter};
+use resolve::{Resolver, SystemResolver};
+use route::{Route, UpstreamGroup};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tls::{UpstreamConnector, UpstreamStream};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::{mpsc, watch, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{delay_for, timeout};
+use tokio_rustls::TlsAcceptor;
+use upstream::{Upstream, UpstreamChange};
+
+/// How many independently locked maps the rate limiter spreads clients over
+const RATE_LIMIT_SHARDS: usize = 16;
+
+/// How long, and for how many bytes, discard_rest reads the rest of a refused request
+const DISCARD_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_DISCARD_BYTES: usize = 1 << 20;
+
+/// How long a client holding back its body for `Expect: 100-continue` waits on the upstream before
+/// we tell it to go ahead ourselves (curl gives up waiting after as long)
+const EXPECT_CONTINUE_WAIT: Duration = Duration::from_secs(1);
+
+/// The log target --trace-http dumps go to, at trace level, so RUST_LOG can turn them on too
+const HTTP_TRACE: &str = "balancebeam::http_trace";
+
+/// How balancebeam picks an upstream server for each new client connection.
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum Strategy {
+    /// Pick a live upstream at random, in proportion to its weight
+    Random,
+    /// Cycle through the upstreams in order, skipping the ones that are down (and the ones with
+    /// weight zero, unless those are all that's left)
+    RoundRobin,
+    /// Send each client IP to the same live upstream, by consistent hashing (weighted like Random)
+    IpHash,
+}
+
+/// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
+/// provide a fancy way to automatically construct a command-line argument parser.
+#[derive(Parser, Debug)]
+#[clap(about = "Fun with load balancing")]
+pub struct CmdOptions {
+    #[clap(
+        long,
+        help = "TOML file to read settings from; flags given on the command line override it"
+    )]
+    config: Option<PathBuf>,
+    #[clap(
+        short,
+        long,
+        help = "IP/port to bind to; may be repeated to listen on several, e.g. an IPv4 and an IPv6 address",
+        default_values = &["0.0.0.0:1100"]
+    )]
+    bind: Vec<String>,
+    #[clap(
+        long,
+        help = "IP/port to serve admin endpoints (/status, /metrics) on; off unless given"
+    )]
+    admin_bind: Option<String>,
+    #[clap(
+        long,
+        value_parser = admin_auth::parse_credential,
+        help = "Require this user:password, by HTTP Basic auth, for every request to the admin listener; may be repeated"
+    )]
+    admin_auth: Vec<admin_auth::Credential>,
+    #[clap(
+        long,
+        help = "File of user:password lines (plain text, # comments allowed) to require one of, as for --admin-auth"
+    )]
+    admin_auth_file: Option<PathBuf>,
+    #[clap(
+        short,
+        long,
+        value_parser = upstream::parse_upstream,
+        help = "Upstream host to forward requests to, optionally weighted as host:port=weight"
+    )]
+    upstream: Vec<Upstream>,
+    #[clap(
+        long,
+        value_parser = upstream::parse_backup_upstream,
+        help = "Upstream host, written as for --upstream, that only gets requests while none of the --upstream ones can take them; may be repeated"
+    )]
+    upstream_backup: Vec<Upstream>,
+    #[clap(
+        long,
+        value_parser = route::parse_route,
+        help = "Send requests for Hosts matching a pattern (e.g. *.example.com) to their own upstreams instead, given as host=upstream,upstream..."
+    )]
+    route: Vec<Route>,
+    #[clap(
+        long,
+        help = "Answer requests for Hosts no --route matches with 421 Misdirected Request, instead of forwarding them to the --upstream servers"
+    )]
+    reject_unknown_hosts: bool,
+    #[clap(
+        long,
+        help = "Re-resolve upstream hostnames this often (in seconds), balancing over and health checking each address separately (0 = resolve on every connection instead)",
+        default_value = "0"
+    )]
+    resolve_interval: u64,
+    #[clap(
+        long,
+        help = "Perform active health checks on this interval (in seconds)",
+        default_value = "10"
+    )]
+    active_health_check_interval: usize,
+    #[clap(
+        long,
+        help = "Path to send request to for active health checks",
+        default_value = "/"
+    )]
+    active_health_check_path: String,
+    #[clap(
+        long,
+        value_parser = health::parse_method,
+        help = "HTTP method to send active health checks with",
+        default_value = "GET"
+    )]
+    health_check_method: http::Method,
+    #[clap(
+        long,
+        value_parser = health::parse_host,
+        help = "Host header to send active health checks with, instead of the upstream's address"
+    )]
+    health_check_host: Option<String>,
+    #[clap(
+        long,
+        help = "Body to send active health checks with (e.g. a JSON document); none unless given"
+    )]
+    health_check_body: Option<String>,
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Consecutive failed health checks or connections before an upstream is marked down",
+        default_value = "3"
+    )]
+    health_check_failure_threshold: u32,
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Consecutive passed health checks before a down upstream is marked up again",
+        default_value = "1"
+    )]
+    health_check_success_threshold: u32,
+    #[clap(
+        long,
+        help = "Seconds a health check may take (connecting, sending and reading) before it fails",
+        default_value = "5"
+    )]
+    health_check_timeout: u64,
+    #[clap(
+        long,
+        value_parser = health::parse_status_codes,
+        help = "Status codes that pass a health check, e.g. 200-299,301",
+        default_value = "200"
+    )]
+    health_check_expect: StatusCodes,
+    #[clap(
+        long,
+        help = "Health check every upstream once before accepting connections, and start those that fail out down; exits if none pass, unless --start-degraded. On by default when --active-health-check-interval or --active-health-check-path is given"
+    )]
+    startup_health_check: bool,
+    #[clap(
+        long,
+        conflicts_with = "startup-health-check",
+        help = "Skip the startup health check, even with active health checks configured"
+    )]
+    no_startup_health_check: bool,
+    /// Whether the config file sets the active health check interval or path (see
+    /// wants_startup_health_check)
+    #[clap(skip)]
+    active_health_checks_configured: bool,
+    #[clap(
+        long,
+        help = "Start even if no upstream passes the startup health check, instead of exiting"
+    )]
+    start_degraded: bool,
+    #[clap(
+        long,
+        help = "Most seconds to wait between health checks of an upstream that is down, as the wait doubles after each failed one",
+        default_value = "60"
+    )]
+    health_check_max_backoff: u64,
+    #[clap(
+        long,
+        help = "Consecutive 5xx responses (or requests that got no response) from an upstream before it is marked down, as failed connections would, until active health checks bring it back (0 = never)",
+        default_value = "0"
+    )]
+    unhealthy_status_threshold: u32,
+    #[clap(
+        long,
+        help = "Count an upstream's health checks as failed while the 95th percentile of its last 20 health check latencies is over this many milliseconds, so that --health-check-failure-threshold of them in a row mark it down (0 = never)",
+        default_value = "0"
+    )]
+    latency_eject_ms: u64,
+    #[clap(
+        long,
+        help = "Seconds over which an upstream marked back up ramps from almost none to its full share of traffic (0 = no slow start; not applied under ip-hash)",
+        default_value = "0"
+    )]
+    slow_start: u64,
+    #[clap(
+        long,
+        help = "Send requests to upstreams drained over the admin listener when no other upstream can take them, rather than answering 503"
+    )]
+    drain_fallback: bool,
+    #[clap(
+        long,
+        help = "Path on the admin listener (no credentials needed) at which balancebeam answers whether it has a healthy upstream: 200 if so, 503 if not",
+        default_value = "/healthz"
+    )]
+    self_health_path: String,
+    #[clap(
+        long,
+        help = "Answer --self-health-path on the main listener too, ahead of rate limiting and upstream selection"
+    )]
+    self_health_on_main: bool,
+    #[clap(
+        long,
+        help = "How many recent requests to each upstream its circuit breaker judges it by (0 = no circuit breaking)",
+        default_value = "0"
+    )]
+    circuit_breaker_window: usize,
+    #[clap(
+        long,
+        value_parser = circuit_breaker::parse_threshold,
+        help = "Fraction of an upstream's recent requests that may fail (5xx, timeout or connect error) before it stops getting traffic",
+        default_value = "0.5"
+    )]
+    circuit_breaker_threshold: f64,
+    #[clap(
+        long,
+        help = "Seconds an upstream's open circuit stays open before a trial request is let through",
+        default_value = "30"
+    )]
+    circuit_breaker_cooldown: u64,
+    #[clap(
+        long,
+        help = "Maximum number of requests to accept per IP per minute (0 = unlimited)",
+        default_value = "0"
+    )]
+    max_requests_per_minute: usize,
+    #[clap(
+        long,
+        value_parser = rate_limit::parse_rule,
+        help = "Per-IP limit for some requests instead of --max-requests-per-minute, as [METHOD,METHOD... ]PATH_PREFIX=REQUESTS_PER_MINUTE (e.g. \"POST /login=5\", or \"/static=0\" for unlimited); may be repeated, and the first rule a request matches applies"
+    )]
+    rate_limit_rule: Vec<RateLimitRule>,
+    #[clap(
+        long,
+        value_parser = rate_limit::parse_key,
+        help = "What rate limits are counted per: ip, or header:NAME (e.g. header:X-Api-Key) for the value of that request header, falling back to the IP for requests without it",
+        default_value = "ip"
+    )]
+    rate_limit_key: RateLimitKey,
+    #[clap(
+        long,
+        help = "With --rate-limit-key header:NAME, answer requests without the header with a 401 instead of counting them by IP"
+    )]
+    require_rate_limit_key: bool,
+    #[clap(
+        long,
+        help = "Maximum number of connections open at once per IP (0 = unlimited)",
+        default_value = "0"
+    )]
+    max_connections_per_ip: usize,
+    #[clap(
+        long,
+        help = "Maximum number of requests being forwarded at once, beyond which clients get a 503 (0 = unlimited)",
+        default_value = "0"
+    )]
+    max_concurrent_requests: usize,
+    #[clap(
+        long,
+        help = "Milliseconds a request may wait for one of --max-concurrent-requests to finish before it gets a 503",
+        default_value = "100"
+    )]
+    request_queue_timeout_ms: u64,
+    #[clap(
+        long,
+        help = "Maximum number of requests being forwarded to any one upstream at once, beyond which others are preferred (0 = unlimited; [[upstreams]] in the config file can set their own)",
+        default_value = "0"
+    )]
+    max_requests_per_upstream: usize,
+    #[clap(
+        long,
+        value_parser = cidr::parse_cidr,
+        help = "Client IP range (CIDR, e.g. 10.0.0.0/8) that is never rate or connection limited; may be repeated"
+    )]
+    rate_limit_exempt: Vec<Cidr>,
+    #[clap(
+        long,
+        value_parser = cidr::parse_ip_or_cidr,
+        help = "Client IP or range (CIDR) whose connections are refused with a 403; may be repeated"
+    )]
+    deny: Vec<Cidr>,
+    #[clap(
+        long,
+        value_enum,
+        help = "How to pick an upstream server for each connection",
+        default_value = "random"
+    )]
+    strategy: Strategy,
+    #[clap(
+        long,
+        help = "How many other upstreams to try when forwarding an idempotent request fails",
+        default_value = "2"
+    )]
+    max_retries: usize,
+    #[clap(
+        long,
+        help = "Most upstreams to try connecting to for one request (0 = each live upstream once)",
+        default_value = "0"
+    )]
+    max_connect_attempts: usize,
+    #[clap(
+        long,
+        help = "Seconds to wait for an upstream to accept a connection before giving up with a 504",
+        default_value = "5"
+    )]
+    upstream_connect_timeout: u64,
+    #[clap(
+        long,
+        help = "Seconds to wait for an upstream's response to a request before giving up with a 504",
+        default_value = "30"
+    )]
+    upstream_response_timeout: u64,
+    #[clap(
+        long,
+        help = "Idle connections to keep open to each upstream for reuse (0 = no pooling)",
+        default_value = "8"
+    )]
+    max_pool_idle: usize,
+    #[clap(
+        long,
+        help = "Seconds an upstream connection may sit idle in the pool before it is closed",
+        default_value = "30"
+    )]
+    pool_idle_timeout: u64,
+    #[clap(
+        long,
+        help = "Seconds a client connection may sit between requests (or before its first) before it is closed (0 = never)",
+        default_value = "60"
+    )]
+    client_idle_timeout: u64,
+    #[clap(
+        long,
+        help = "Most bytes a request's (or an upstream response's) first line and headers may take up, beyond which it gets a 431 (or the client a 502)",
+        default_value = "16384"
+    )]
+    max_header_bytes: usize,
+    #[clap(
+        long,
+        help = "Most header fields a request (or an upstream response) may have, beyond which it gets a 431 (or the client a 502)",
+        default_value = "100"
+    )]
+    max_header_count: usize,
+    #[clap(
+        long,
+        help = "Most bytes a request body may have, beyond which it gets a 413 (0 = unlimited)",
+        default_value = "10000000"
+    )]
+    max_request_body_bytes: usize,
+    #[clap(
+        long,
+        help = "Seconds to let open connections finish their requests after SIGTERM or SIGINT",
+        default_value = "30"
+    )]
+    shutdown_grace_period: u64,
+    #[clap(
+        long,
+        value_enum,
+        help = "How to log each answered request",
+        default_value = "plain"
+    )]
+    access_log_format: AccessLogFormat,
+    #[clap(
+        long,
+        help = "Pin each client to one upstream with a cookie of this name; off unless given"
+    )]
+    sticky_cookie: Option<String>,
+    #[clap(
+        long,
+        help = "Directory of pages named for their status (e.g. 502.html) to send as the body of the error responses balancebeam makes itself, instead of the plain text; read once at startup"
+    )]
+    error_page_dir: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "PEM certificate chain to serve clients over TLS with; requires --tls-key"
+    )]
+    tls_cert: Option<PathBuf>,
+    #[clap(long, help = "PEM private key for --tls-cert")]
+    tls_key: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "PEM root certificates to verify https:// upstreams against, instead of the system's"
+    )]
+    upstream_ca: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Don't verify the certificates of https:// upstreams (for development only)"
+    )]
+    insecure_upstream: bool,
+    #[clap(
+        long,
+        help = "Expect a PROXY protocol (v1 or v2) header on each connection, and take the client address from it"
+    )]
+    accept_proxy_protocol: bool,
+    #[clap(
+        long,
+        value_enum,
+        help = "Start each upstream connection with a PROXY protocol header naming the client; turns off connection pooling"
+    )]
+    send_proxy_protocol: Option<proxy_protocol::Version>,
+    #[clap(
+        long,
+        help = "Trust clients' X-Forwarded-For (append to it, and rate limit by its first address) instead of replacing it"
+    )]
+    trust_forwarded_for: bool,
+    #[clap(
+        long,
+        help = "Don't pass upstreams' 1xx interim responses (e.g. 100 Continue, 103 Early Hints) on to clients"
+    )]
+    drop_interim_responses: bool,
+    #[clap(
+        long,
+        help = "Log every request and response in full (the head and the start of the body), as RUST_LOG=balancebeam::http_trace=trace also does"
+    )]
+    trace_http: bool,
+    #[clap(
+        long,
+        help = "How many bytes of each body --trace-http shows",
+        default_value = "1024"
+    )]
+    trace_body_bytes: usize,
+    #[clap(
+        long,
+        help = "Show Authorization, Cookie and Set-Cookie values in --trace-http dumps instead of redacting them"
+    )]
+    trace_unredacted: bool,
+    #[clap(
+        long,
+        value_parser = header_rules::parse_set_header,
+        help = "Header (NAME:VALUE) to put in requests sent to upstreams, replacing any the client sent; may be repeated"
+    )]
+    set_request_header: Vec<SetHeader>,
+    #[clap(
+        long,
+        value_parser = header_rules::parse_header_name,
+        help = "Header to take out of requests before they are sent to upstreams; may be repeated"
+    )]
+    remove_request_header: Vec<HeaderName>,
+    #[clap(
+        long,
+        value_parser = header_rules::parse_set_header,
+        help = "Header (NAME:VALUE) to put in responses sent to clients, replacing any the upstream sent; may be repeated"
+    )]
+    set_response_header: Vec<SetHeader>,
+    #[clap(
+        long,
+        value_parser = header_rules::parse_header_name,
+        help = "Header to take out of upstream responses before they are sent to clients; may be repeated"
+    )]
+    remove_response_header: Vec<HeaderName>,
+    #[clap(
+        long,
+        value_parser = via::parse_instance_id,
+        help = "Name this balancebeam goes by in Via headers, which is how it notices requests that loop back to it [default: hostname-pid]"
+    )]
+    instance_id: Option<String>,
+    #[clap(
+        long,
+        help = "Act as a forward proxy for CONNECT requests, tunneling them to the host and port they name"
+    )]
+    allow_connect: bool,
+    #[clap(
+        long,
+        help = "Port that --allow-connect tunnels may go to; may be repeated",
+        default_values = &["443"]
+    )]
+    connect_port: Vec<u16>,
+    #[clap(long, help = "Gzip upstream responses for clients that accept it")]
+    compress: bool,
+    #[clap(
+        long,
+        help = "Smallest response body (in bytes) that --compress gzips",
+        default_value = "1024"
+    )]
+    compress_min_bytes: usize,
+    #[clap(
+        long,
+        help = "Content type (e.g. image/* or application/zip) that --compress leaves alone; may be repeated",
+        default_values = &["image/*", "video/*", "audio/*"]
+    )]
+    compress_skip_type: Vec<String>,
+    #[clap(
+        long,
+        value_parser = mirror::parse_mirror_upstream,
+        help = "Upstream to send a copy of requests to, whose responses are thrown away (e.g. to try out a new backend); may be repeated, to take turns"
+    )]
+    mirror_upstream: Vec<String>,
+    #[clap(
+        long,
+        value_parser = mirror::parse_percent,
+        help = "Percentage of requests that --mirror-upstream gets a copy of",
+        default_value = "100"
+    )]
+    mirror_percent: u32,
+}
+
+/// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
+/// to, what servers have failed, rate limiting counts, etc.)
+///
+/// You should add fields to this struct in later milestones.
+#[derive(Debug)]
+struct ProxyState {
+    /// How frequently we check whether upstream servers are alive (Milestone 4). Can change when
+    /// the config file is reloaded.
+    active_health_check_interval: AtomicUsize,
+    /// Where we should send requests when doing active health checks (Milestone 4)
+    active_health_check_path: String,
+    /// The method, Host (if not the upstream's own) and body active health checks send
+    health_check_method: http::Method,
+    health_check_host: Option<String>,
+    health_check_body: Option<String>,
+    /// How long an active health check may take before it counts as a failure
+    health_check_timeout: Duration,
+    /// Status codes an active health check accepts as healthy
+    health_check_expect: StatusCodes,
+    /// The longest an upstream that is down goes between active health checks
+    health_check_max_backoff: Duration,
+    /// Maximum number of requests an individual IP can make in a minute (Milestone 5), for
+    /// requests no rate limit rule matches
+    max_requests_per_minute: usize,
+    /// Limits of their own for the requests they match, tried in order
+    rate_limit_rules: Vec<RateLimitRule>,
+    /// What requests are counted per
+    rate_limit_key: RateLimitKey,
+    /// Whether requests without a --rate-limit-key header are turned away
+    require_rate_limit_key: bool,
+    /// The servers that we are proxying to: the default group from --upstream, then one group per
+    /// route, each picked from separately
+    groups: Vec<UpstreamGroup>,
+    /// Whether requests for Hosts that no route matches are refused rather than sent to the
+    /// default group
+    reject_unknown_hosts: bool,
+    /// Looks up the addresses of upstreams given by hostname, when --resolve-interval is set
+    resolver: Box<dyn Resolver>,
+    /// Wakes the active health checker early, when there are new upstreams to probe before they
+    /// get traffic
+    probe_now: Notify,
+    /// Decides when consecutive health results flip an upstream's health
+    health_tracker: HealthTracker,
+    /// Decides when consecutive failed requests mark an upstream down
+    passive_health: PassiveHealth,
+    /// Decides when an upstream's probe latency counts against its health
+    latency_ejection: LatencyEjection,
+    /// How long an upstream that came back up takes to get back to its full share of traffic
+    slow_start: Duration,
+    /// Decides when recent request outcomes open or close an upstream's circuit
+    circuit_breaker: CircuitBreaker,
+    /// Whether draining upstreams still get requests when no other upstream can take them
+    drain_fallback: bool,
+    /// Where balancebeam answers for its own health (see admin::self_health)
+    self_health_path: String,
+    /// Whether the main listener answers self_health_path as well as the admin listener
+    self_health_on_main: bool,
+    /// Each client's request count in its current rate-limiting window, per rule
+    rate_limiter: RateLimiter,
+    /// Maximum number of connections an individual IP can have open at once (0 = unlimited)
+    max_connections_per_ip: usize,
+    /// How many connections each client IP has open, for those that have any. A plain mutex,
+    /// since ConnectionSlot has to release its count in drop.
+    connections_per_ip: Mutex<HashMap<IpAddr, usize>>,
+    /// Maximum number of requests being forwarded at once (0 = unlimited)
+    max_concurrent_requests: usize,
+    /// One permit per request that may be in flight, when max_concurrent_requests is set
+    request_permits: Semaphore,
+    /// How long a request waits for a permit before it is shed
+    request_queue_timeout: Duration,
+    /// The request limit of upstreams that don't set their own, including ones added at runtime
+    max_requests_per_upstream: usize,
+    /// Client IP ranges that skip rate limiting (and the connection limit) entirely
+    rate_limit_exempt: Vec<Cidr>,
+    /// Client IP ranges that are turned away before anything else; the admin listener can change
+    /// them
+    deny_list: DenyList,
+    /// Whether clients are proxies whose X-Forwarded-For can be believed
+    trust_forwarded_for: bool,
+    /// Whether upstreams' 1xx responses are swallowed, leaving clients only the final response
+    drop_interim_responses: bool,
+    /// How much of each message --trace-http dumps show
+    trace_settings: request::TraceSettings,
+    /// How the headers of requests are rewritten on their way to upstreams
+    request_header_rules: HeaderRules,
+    /// How the headers of upstreams' responses are rewritten on their way to clients
+    response_header_rules: HeaderRules,
+    /// What this balancebeam calls itself in Via headers (see via::pseudonym)
+    via_pseudonym: String,
+    /// Whether CONNECT requests are tunneled, rather than refused with a 501
+    allow_connect: bool,
+    /// The ports CONNECT tunnels may go to
+    connect_ports: Vec<u16>,
+    /// Which responses are gzipped for clients
+    compression: CompressionSettings,
+    /// How upstream servers are picked for new connections
+    strategy: Strategy,
+    /// How many times a failed idempotent request is replayed on another upstream
+    max_retries: usize,
+    /// How many upstreams connect_to_upstream tries before giving up (0 = every live one)
+    max_connect_attempts: usize,
+    /// How long connecting to an upstream may take before the upstream counts as failed
+    upstream_connect_timeout: Duration,
+    /// How long an upstream may take to send its response before the client gets a 504
+    upstream_response_timeout: Duration,
+    /// How big the headers of requests and upstream responses may be
+    header_limits: request::HeaderLimits,
+    /// How big a request body may be (zero = any size)
+    max_request_body_bytes: usize,
+    /// How long a client may take to start its next request before its connection is closed
+    /// (zero = forever)
+    client_idle_timeout: Duration,
+    /// Idle keep-alive connections to each upstream, waiting for the next request. Always locked
+    /// after upstreams, and like them never held across an await.
+    upstream_pool: Mutex<ConnectionPool<UpstreamStream>>,
+    /// Opens new connections to upstreams, over TLS for `https://` ones
+    upstream_connector: UpstreamConnector,
+    /// Where copies of requests go, if anywhere
+    mirror: Option<Arc<Mirror>>,
+    /// Bodies for the error responses balancebeam makes itself, from --error-page-dir
+    error_pages: ErrorPages,
+    /// How long forwarding requests and reading their responses has taken
+    request_duration: DurationHistogram,
+    /// Bytes read from and written to clients, over every connection
+    client_bytes: ByteCounters,
+    /// How many requests the rate limiter has turned away
+    rate_limited: AtomicUsize,
+    /// How many connections the deny list has turned away
+    denied: AtomicUsize,
+    /// How answered requests are logged
+    access_log_format: AccessLogFormat,
+    /// Name of the cookie pinning clients to an upstream, if sticky sessions are on
+    sticky_cookie: Option<String>,
+}
+
+impl ProxyState {
+    fn new(
+        options: &CmdOptions,
+        upstream_connector: UpstreamConnector,
+        error_pages: ErrorPages,
+    ) -> ProxyState {
+        ProxyState {
+            groups: {
+                let mut upstreams = default_upstreams(options);
+                let mut routes = options.route.clone();
+                upstream::default_max_requests(
+                    upstreams
+                        .iter_mut()
+                        .chain(routes.iter_mut().flat_map(|route| &mut route.upstreams)),
+                    options.max_requests_per_upstream,
+                );
+                UpstreamGroup::all(upstreams, routes)
+            },
+            reject_unknown_hosts: options.reject_unknown_hosts,
+            resolver: Box::new(SystemResolver),
+            probe_now: Notify::new(),
+            active_health_check_interval: AtomicUsize::new(options.active_health_check_interval),
+            active_health_check_path: options.active_health_check_path.clone(),
+            health_check_method: options.health_check_method.clone(),
+            health_check_host: options.health_check_host.clone(),
+            health_check_body: options.health_check_body.clone(),
+            health_check_timeout: Duration::from_secs(options.health_check_timeout),
+            health_check_expect: options.health_check_expect.clone(),
+            health_check_max_backoff: Duration::from_secs(options.health_check_max_backoff),
+            max_requests_per_minute: options.max_requests_per_minute,
+            rate_limit_rules: options.rate_limit_rule.clone(),
+            rate_limit_key: options.rate_limit_key.clone(),
+            require_rate_limit_key: options.require_rate_limit_key,
+            health_tracker: HealthTracker::new(
+                options.health_check_failure_threshold,
+                options.health_check_success_threshold,
+            ),
+            passive_health: PassiveHealth::new(options.unhealthy_status_threshold),
+            latency_ejection: LatencyEjection::new(options.latency_eject_ms),
+            slow_start: Duration::from_secs(options.slow_start),
+            circuit_breaker: CircuitBreaker::new(
+                options.circuit_breaker_window,
+                options.circuit_breaker_threshold,
+                Duration::from_secs(options.circuit_breaker_cooldown),
+            ),
+            drain_fallback: options.drain_fallback,
+            self_health_path: options.self_health_path.clone(),
+            self_health_on_main: options.self_health_on_main,
+            rate_limiter: RateLimiter::new(RATE_LIMIT_SHARDS),
+            max_connections_per_ip: options.max_connections_per_ip,
+            connections_per_ip: Mutex::new(HashMap::new()),
+            max_concurrent_requests: options.max_concurrent_requests,
+            request_permits: Semaphore::new(options.max_concurrent_requests),
+            request_queue_timeout: Duration::from_millis(options.request_queue_timeout_ms),
+            max_requests_per_upstream: options.max_requests_per_upstream,
+            rate_limit_exempt: options.rate_limit_exempt.clone(),
+            deny_list: DenyList::new(options.deny.clone()),
+            trust_forwarded_for: options.trust_forwarded_for,
+            drop_interim_responses: options.drop_interim_responses,
+            trace_settings: request::TraceSettings {
+                body_bytes: options.trace_body_bytes,
+                redact: !options.trace_unredacted,
+            },
+            request_header_rules: HeaderRules {
+                remove: options.remove_request_header.clone(),
+                set: options.set_request_header.clone(),
+            },
+            via_pseudonym: via::pseudonym(
+                &options
+                    .instance_id
+                    .clone()
+                    .unwrap_or_else(via::default_instance_id),
+            ),
+            allow_connect: options.allow_connect,
+            connect_ports: options.connect_port.clone(),
+            response_header_rules: HeaderRules {
+                remove: options.remove_response_header.clone(),
+                set: options.set_response_header.clone(),
+            },
+            compression: CompressionSettings {
+                enabled: options.compress,
+                min_bytes: options.compress_min_bytes,
+                skip_types: options.compress_skip_type.clone(),
+            },
+            strategy: options.strategy,
+            max_retries: options.max_retries,
+            max_connect_attempts: options.max_connect_attempts,
+            upstream_connect_timeout: Duration::from_secs(options.upstream_connect_timeout),
+            upstream_response_timeout: Duration::from_secs(options.upstream_response_timeout),
+            client_idle_timeout: Duration::from_secs(options.client_idle_timeout),
+            header_limits: request::HeaderLimits {
+                max_bytes: options.max_header_bytes,
+                max_count: options.max_header_count,
+            },
+            max_request_body_bytes: options.max_request_body_bytes,
+            // Each upstream connection's PROXY header names the client it was opened for, so it can't
+            // be handed to another
+            upstream_pool: Mutex::new(ConnectionPool::new(
+                if options.send_proxy_protocol.is_some() {
+                    0
+                } else {
+                    options.max_pool_idle
+                },
+                Duration::from_secs(options.pool_idle_timeout),
+            )),
+            mirror: if options.mirror_upstream.is_empty() {
+                None
+            } else {
+                Some(Arc::new(Mirror::new(
+                    options.mirror_upstream.clone(),
+                    options.mirror_percent,
+                    upstream_connector.clone(),
+                    Duration::from_secs(options.upstream_connect_timeout),
+                    Duration::from_secs(options.upstream_response_timeout),
+                    request::HeaderLimits {
+                        max_bytes: options.max_header_bytes,
+                        max_count: options.max_header_count,
+                    },
+                )))
+            },
+            upstream_connector,
+            error_pages,
+            request_duration: DurationHistogram::default(),
+            client_bytes: ByteCounters::default(),
+            rate_limited: AtomicUsize::new(0),
+            denied: AtomicUsize::new(0),
+            access_log_format: options.access_log_format,
+            sticky_cookie: options.sticky_cookie.clone(),
+        }
+    }
+}
+
+impl CmdOptions {
+    /// Parses `args` (the program name first) as balancebeam's command line. The matches come back
+    /// too, since the config file is merged against them to tell which flags were given.
+    pub fn parse_from_args<I, T>(args: I) -> Result<(CmdOptions, clap::ArgMatches), clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let matches = CmdOptions::command().try_get_matches_from(args)?;
+        let options = CmdOptions::from_arg_matches(&matches)?;
+        Ok((options, matches))
+    }
+}
+
+/// Initializes the logging library. You can print log messages using the `log` macros:
+/// https://docs.rs/log/0.4.8/log/ You are welcome to continue using print! statements; this just
+/// looks a little prettier.
+pub fn init_logging(options: &CmdOptions) {
+    let mut logger = pretty_env_logger::formatted_builder();
+    logger.parse_filters(&std::env::var("RUST_LOG").unwrap_or_else(|_| String::from("debug")));
+    if options.trace_http {
+        logger.filter_module(HTTP_TRACE, log::LevelFilter::Trace);
+    }
+    logger.init();
+}
+
+/// Runs the proxy described by `options` (parsed from `matches`, see CmdOptions::parse_from_args)
+/// until `shutdown` completes, then drains the open connections for up to --shutdown-grace-period.
+/// Returns an error if it could not start, or if accepting connections failed.
+pub async fn run<F>(
+    mut options: CmdOptions,
+    matches: clap::ArgMatches,
+    shutdown: F,
+) -> Result<(), String>
+where
+    F: std::future::Future<Output = ()>,
+{
+    // Fill in whatever the command line arguments leave out from the config file
+    if let Some(path) = options.config.clone() {
+        match config::Config::load(&path) {
+            Ok(config) => config.apply(&mut options, &matches),
+            Err(err) => return Err(format!("Invalid config file {}", err)),
+        }
+    }
+    if options.upstream.is_empty() && options.route.is_empty() {
+        return Err(String::from("At least one upstream server must be specified using the --upstream (or --route) option or the config file."));
+    }
+
+    if !options.self_health_path.starts_with('/') {
+        return Err(String::from("--self-health-path must start with a /."));
+    }
+
+    if options.require_rate_limit_key && options.rate_limit_key == RateLimitKey::Ip {
+        return Err(String::from(
+            "--require-rate-limit-key needs a --rate-limit-key header:NAME to require.",
+        ));
+    }
+
+    // Load the certificate before binding, so a bad one fails fast
+    let tls_acceptor = match (&options.tls_cert, &options.tls_key) {
+        (Some(cert), Some(key)) => match tls::load_acceptor(cert, key) {
+            Ok(acceptor) => Some(acceptor),
+            Err(err) => return Err(format!("Could not load TLS certificate: {}", err)),
+        },
+        (None, None) => None,
+        _ => {
+            return Err(String::from(
+                "--tls-cert and --tls-key must be given together.",
+            ));
+        }
+    };
+
+    // The admin listener's credentials, from the flag and the file together
+    let mut admin_credentials = options.admin_auth.clone();
+    if let Some(path) = &options.admin_auth_file {
+        match admin_auth::load_file(path) {
+            Ok(credentials) => admin_credentials.extend(credentials),
+            Err(err) => return Err(format!("Could not load admin credentials: {}", err)),
+        }
+    }
+    let admin_auth = if admin_credentials.is_empty() {
+        None
+    } else {
+        Some(Arc::new(AdminAuth::new(admin_credentials)))
+    };
+    if admin_auth.is_none() && options.admin_bind.is_some() {
+        log::warn!("The admin listener is unauthenticated (see --admin-auth)");
+    }
+
+    let error_pages = match &options.error_page_dir {
+        Some(dir) => match ErrorPages::load(dir) {
+            Ok(pages) => {
+                log::info!(
+                    "Loaded error pages for {:?} from {}",
+                    pages.statuses(),
+                    dir.display()
+                );
+                pages
+            }
+            Err(err) => return Err(format!("Could not load error pages: {}", err)),
+        },
+        None => ErrorPages::default(),
+    };
+
+    if options.insecure_upstream {
+        log::warn!("Not verifying the certificates of TLS upstreams (--insecure-upstream)");
+    }
+    let upstream_connector = match UpstreamConnector::new(
+        options.upstream_ca.as_deref(),
+        options.insecure_upstream,
+        options.send_proxy_protocol,
+    ) {
+        Ok(connector) => connector,
+        Err(err) => return Err(format!("Could not load upstream CA: {}", err)),
+    };
+
+    // Start listening for connections, on every address or none of them
+    let mut listeners = Vec::with_capacity(options.bind.len());
+    for bind in &options.bind {
+        match TcpListener::bind(bind).await {
+            Ok(listener) => listeners.push(listener),
+            Err(err) => return Err(format!("Could not bind to {}: {}", bind, err)),
+        }
+    }
+    log::info!(
+        "Listening for {} requests on {}",
+        if tls_acceptor.is_some() {
+            "HTTPS"
+        } else {
+            "HTTP"
+        },
+        options.bind.join(", ")
+    );
+
+    // Handle incoming connections
+    let state = Arc::new(ProxyState::new(&options, upstream_connector, error_pages));
+
+    log::info!("ProxyState {:?}", state);
+    log::info!("Load balancing strategy: {:?}", state.strategy);
+
+    if options.resolve_interval != 0 {
+        // Upstreams are in service from the start, so their first addresses are too
+        resolve_upstreams(&state, true).await;
+        let state = state.clone();
+        let interval = Duration::from_secs(options.resolve_interval);
+        tokio::spawn(async move {
+            loop {
+                delay_for(interval).await;
+                resolve_upstreams(&state, false).await;
+            }
+        });
+    }
+
+    if wants_startup_health_check(&options, &matches) {
+        let passed = check_upstreams_at_startup(&state).await;
+        if passed == 0 {
+            if !options.start_degraded {
+                return Err(String::from(
+                    "No upstream passed its startup health check (see --start-degraded).",
+                ));
+            }
+            log::warn!("No upstream passed its startup health check; starting anyway");
+        }
+    }
+
+    {
+        // activate health check
+        let state = state.clone();
+        tokio::spawn(async move {
+            active_health_check(state).await;
+        });
+    }
+
+    if let Some(path) = options.config {
+        let hangups = signal(SignalKind::hangup())
+            .map_err(|err| format!("Could not listen for SIGHUP: {}", err))?;
+        tokio::spawn(reload_on_hangup(state.clone(), hangups, path, matches));
+    }
+
+    if let Some(admin_bind) = &options.admin_bind {
+        let admin_listener = match TcpListener::bind(admin_bind).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                return Err(format!(
+                    "Could not bind admin listener to {}: {}",
+                    admin_bind, err
+                ));
+            }
+        };
+        log::info!("Serving admin endpoints on {}", admin_bind);
+        tokio::spawn(admin::serve(admin_listener, state.clone(), admin_auth));
+    }
+
+    if state.max_requests_per_minute != 0
+        || state
+            .rate_limit_rules
+            .iter()
+            .any(|rule| rule.max_requests_per_minute != 0)
+    {
+        // Rate limiting
+        let state = state.clone();
+        tokio::spawn(async move {
+            rate_limiting_refresh(state).await;
+        });
+    }
+
+    // Connection tasks watch for shutdown, and each holds a clone of drained_tx, so drained_rx
+    // only sees the channel close once every one of them has finished
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (drained_tx, mut drained_rx) = mpsc::channel::<()>(1);
+    // An accept loop that fails takes the whole proxy down, whichever listener it was
+    let (failed_tx, mut failed_rx) = mpsc::channel::<io::Error>(1);
+    for listener in listeners {
+        let accepting = accept_connections(
+            listener,
+            state.clone(),
+            tls_acceptor.clone(),
+            options.accept_proxy_protocol,
+            shutdown_rx.clone(),
+            drained_tx.clone(),
+        );
+        let mut failed_tx = failed_tx.clone();
+        tokio::spawn(async move {
+            if let Err(err) = accepting.await {
+                let _ = failed_tx.send(err).await;
+            }
+        });
+    }
+    tokio::select! {
+        Some(err) = failed_rx.recv() => {
+            return Err(format!("Could not accept connections: {}", err));
+        }
+        _ = shutdown => {}
+    }
+
+    // The accept loops drop their listeners as soon as they see this
+    log::info!("Shutting down: no longer accepting connections, draining the open ones");
+    let _ = shutdown_tx.broadcast(true);
+    drop(drained_tx);
+    let grace_period = Duration::from_secs(options.shutdown_grace_period);
+    if timeout(grace_period, drained_rx.recv()).await.is_err() {
+        log::warn!(
+            "Connections still open after the {:?} grace period; closing them",
+            grace_period
+        );
+    }
+    Ok(())
+}
+
+/// The default group's upstreams: those from --upstream (or the config file), then the
+/// --upstream-backup ones.
+fn default_upstreams(options: &CmdOptions) -> Vec<Upstream> {
+    options
+        .upstream
+        .iter()
+        .chain(&options.upstream_backup)
+        .cloned()
+        .collect()
+}
+
+/// Re-reads the config file at `path` on every SIGHUP. Only the (default) upstream list and the
+/// active health check interval are picked up; other settings, routes included, need a restart.
+async fn reload_on_hangup(
+    state: Arc<ProxyState>,
+    mut hangups: Signal,
+    path: PathBuf,
+    matches: clap::ArgMatches,
+) {
+    while hangups.recv().await.is_some() {
+        log::info!("Reloading config file {}", path.display());
+        if let Err(err) = reload_config(&state, &path, &matches).await {
+            log::error!(
+                "Keeping the old config, since the new one is invalid: {}",
+                err
+            );
+        }
+    }
+}
+
+/// Swaps in the upstreams from the config file (command-line flags still take precedence) in one
+/// go, so connect_to_upstream sees either the old list or the new one. Connections already checked
+/// out to removed upstreams finish their requests normally.
+async fn reload_config(
+    state: &ProxyState,
+    path: &Path,
+    matches: &clap::ArgMatches,
+) -> Result<(), String> {
+    let config = config::Config::load(path)?;
+    let mut options = CmdOptions::from_arg_matches(matches).map_err(|err| err.to_string())?;
+    config.apply(&mut options, matches);
+    if options.upstream.is_empty() && state.groups.len() == 1 {
+        return Err(format!("{}: no upstreams given", path.display()));
+    }
+
+    let mut wanted = default_upstreams(&options);
+    upstream::default_max_requests(&mut wanted, state.max_requests_per_upstream);
+    let changes = {
+        let mut upstreams = state.groups[0].upstreams.write().unwrap();
+        let changes = upstream::sync_upstreams(&mut upstreams, wanted);
+        let mut pool = state.upstream_pool.lock().unwrap();
+        for change in &changes {
+            if let UpstreamChange::Removed(address) = change {
+                pool.forget(address);
+            }
+        }
+        changes
+    };
+    for change in &changes {
+        log::info!("Config reload: {}", change);
+    }
+    let interval = options.active_health_check_interval;
+    let old_interval = state
+        .active_health_check_interval
+        .swap(interval, Ordering::Relaxed);
+    if old_interval != interval {
+        log::info!(
+            "Config reload: active health check interval {}s -> {}s",
+            old_interval,
+            interval
+        );
+    } else if changes.is_empty() {
+        log::info!("Config reload: nothing changed");
+    }
+    Ok(())
+}
+
+/// Looks up every upstream configured by hostname again, in each group, and makes the group's
+/// entries for it one per address it resolved to (see resolve::apply_resolution). New addresses
+/// are probed before they get traffic unless `trust_new`; ones that have gone get no new requests,
+/// while those already forwarded to them finish normally. An upstream whose lookup fails keeps
+/// the addresses it had.
+async fn resolve_upstreams(state: &ProxyState, trust_new: bool) {
+    for group in &state.groups {
+        // Each upstream once, however many addresses it currently has
+        let mut names: Vec<String> = Vec::new();
+        for info in group.upstreams.read().unwrap().iter() {
+            let name = info.configured_address();
+            if resolve::hostname(name).is_some() && !names.iter().any(|seen| seen == name) {
+                names.push(name.to_string());
+            }
+        }
+        for name in names {
+            let (host, port) = resolve::hostname(&name).expect("only hostnames were kept");
+            let lookup = timeout(
+                state.upstream_connect_timeout,
+                state.resolver.resolve(host, port),
+            );
+            let peers = match lookup.await {
+                Ok(Ok(peers)) if !peers.is_empty() => peers,
+                Ok(Ok(_)) => {
+                    log::warn!("Upstream {} resolved to no addresses", name);
+                    continue;
+                }
+                Ok(Err(error)) => {
+                    log::warn!("Could not resolve upstream {}: {}", name, error);
+                    continue;
+                }
+                Err(_elapsed) => {
+                    log::warn!("Timed out resolving upstream {}", name);
+                    continue;
+                }
+            };
+            let changes = {
+                let mut upstreams = group.upstreams.write().unwrap();
+                let changes = resolve::apply_resolution(
+                    &mut upstreams,
+                    &name,
+                    &peers,
+                    trust_new,
+                    Instant::now(),
+                );
+                let mut pool = state.upstream_pool.lock().unwrap();
+                for change in &changes {
+                    if let UpstreamChange::Removed(address) = change {
+                        pool.forget(address);
+                    }
+                }
+                changes
+            };
+            if !trust_new
+                && changes
+                    .iter()
+                    .any(|change| matches!(change, UpstreamChange::Added(_)))
+            {
+                state.probe_now.notify();
+            }
+            for change in &changes {
+                log::info!("Resolving {}: {}", name, change);
+            }
+        }
+    }
+}
+
+/// Accepts connections on one of the --bind listeners until shutdown starts, handling each in a
+/// task of its own. Every listener's connections share `state`, and each task (this one
+/// included) holds a clone of `drained_tx` until it's done.
+async fn accept_connections(
+    mut listener: TcpListener,
+    state: Arc<ProxyState>,
+    tls_acceptor: Option<TlsAcceptor>,
+    accept_proxy_protocol: bool,
+    shutdown: watch::Receiver<bool>,
+    drained_tx: mpsc::Sender<()>,
+) -> io::Result<()> {
+    let mut stopping = shutdown.clone();
+    loop {
+        let (mut socket, mut client_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown_started(&mut stopping) => return Ok(()),
+        };
+        let local_addr = socket.local_addr()?;
+        let state = state.clone();
+        let shutdown = shutdown.clone();
+        let drained_tx = drained_tx.clone();
+        let tls_acceptor = tls_acceptor.clone();
+        tokio::spawn(async move {
+            // The load balancer in front says who the client really is before anything else, TLS
+            // included
+            if accept_proxy_protocol {
+                match proxy_protocol::read_header(&mut socket).await {
+                    Ok(Some(conveyed)) => client_addr = conveyed,
+                    Ok(None) => {}
+                    Err(error) => {
+                        log::info!("Bad PROXY protocol header from {}: {}", client_addr, error);
+                        return;
+                    }
+                }
+            }
+            let addresses = ConnectionAddresses {
+                source: client_addr,
+                destination: local_addr,
+            };
+            match tls_acceptor {
+                Some(acceptor) => match acceptor.accept(socket).await {
+                    Ok(socket) => {
+                        handle_connection(socket, addresses, true, &state, shutdown).await
+                    }
+                    Err(error) => {
+                        log::info!("TLS handshake with {} failed: {}", client_addr, error)
+                    }
+                },
+                None => handle_connection(socket, addresses, false, &state, shutdown).await,
+            }
+            drop(drained_tx);
+        });
+    }
+}
+
+/// Resolves once balancebeam has started shutting down.
+async fn shutdown_started(shutdown: &mut watch::Receiver<bool>) {
+    while let Some(false) = shutdown.recv().await {}
+}
+
+/// Resolves once a client has been idle for `limit`, or never if `limit` is zero.
+async fn idle_for(limit: Duration) {
+    if limit == Duration::from_secs(0) {
+        std::future::pending().await
+    } else {
+        delay_for(limit).await
+    }
+}
+
+/// A connection to an upstream, checked out for one request
+struct UpstreamConn {
+    /// The upstream's address, which identifies it in its UpstreamGroup
+    address: String,
+    /// The Host the upstream wants requests sent with, if not the client's
+    host_override: Option<String>,
+    stats: Arc<UpstreamStats>,
+    stream: UpstreamStream,
+    /// Whether the connection came out of the pool, in which case the upstream may have closed it
+    /// without us noticing yet
+    reused: bool,
+    /// The request's place under the upstream's --max-requests-per-upstream, if it has one, given
+    /// back when the connection is returned to the pool or dropped
+    slot: Option<OwnedSemaphorePermit>,
+}
+
+/// Why connect_to_upstream couldn't check out a connection to an upstream
+#[derive(Debug)]
+enum UpstreamError {
+    /// No upstream was up (or let through by its circuit breaker) to try in the first place
+    AllDown,
+    /// The only upstreams left to try are at their --max-requests-per-upstream
+    Saturated,
+    /// Connecting to `addr`, the last upstream tried, failed, and no other was left to try
+    ConnectFailed {
+        addr: String,
+        source: std::io::Error,
+    },
+    /// The upstream at `addr` didn't accept within the connect timeout
+    Timeout { addr: String },
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpstreamError::AllDown => write!(f, "All the upstream servers are down!"),
+            UpstreamError::Saturated => write!(
+                f,
+                "All the live upstreams are at their --max-requests-per-upstream"
+            ),
+            UpstreamError::ConnectFailed { addr, source } => {
+                write!(f, "Could not connect to upstream {}: {}", addr, source)
+            }
+            UpstreamError::Timeout { addr } => {
+                write!(f, "Timed out connecting to upstream {}", addr)
+            }
+        }
+    }
+}
+
+/// The upstream pick_upstream chose for a request
+#[derive(Debug)]
+struct Picked {
+    address: String,
+    /// The hostname to give TLS handshakes, if not the one in `address` (see
+    /// UpstreamInfo::server_name)
+    server_name: Option<String>,
+    /// The Host to send requests with, if not the client's
+    host_override: Option<String>,
+    stats: Arc<UpstreamStats>,
+    slot: Option<OwnedSemaphorePermit>,
+}
+
+/// Checks out a connection to one of `group`'s upstreams for the client connected over `client`,
+/// preferring the one whose session key is `pinned_to` as long as it is alive and accepts the
+/// connection.
+///
+/// Otherwise, connects to a live upstream picked according to the configured strategy, reusing
+/// an idle connection from the pool if there is one (and `use_pool` is set). Upstreams that
+/// refuse the connection are marked dead, and another is tried, but each only once (and no more
+/// than --max-connect-attempts in all), so upstreams that keep failing without being marked down
+/// can't keep the client waiting; giving up then is a `ConnectFailed` naming the last one tried.
+/// If the chosen upstream doesn't accept within the connect timeout, it is marked failed too, and
+/// this gives up with a `Timeout` rather than keep the client waiting on yet another upstream.
+async fn connect_to_upstream(
+    state: &ProxyState,
+    group: &UpstreamGroup,
+    client: ConnectionAddresses,
+    mut pinned_to: Option<&str>,
+    use_pool: bool,
+) -> Result<UpstreamConn, UpstreamError> {
+    let client_ip = client.source.ip().to_string();
+    let mut rng = rand::rngs::StdRng::from_entropy();
+    let mut attempted: Vec<String> = Vec::new();
+    // Why the last upstream tried refused, for when there's none left to try
+    let mut last_failure: Option<(String, std::io::Error)> = None;
+    loop {
+        let picked =
+            if state.max_connect_attempts != 0 && attempted.len() == state.max_connect_attempts {
+                Err(UpstreamError::AllDown)
+            } else {
+                pick_upstream(state, group, &client_ip, pinned_to, &attempted, &mut rng)
+            };
+        let Picked {
+            address,
+            server_name,
+            host_override,
+            stats,
+            slot,
+        } = match (picked, last_failure.take()) {
+            (Ok(picked), _) => picked,
+            // Upstreams were up, they just all refused
+            (Err(UpstreamError::AllDown), Some((addr, source))) => {
+                return Err(UpstreamError::ConnectFailed { addr, source })
+            }
+            (Err(error), _) => return Err(error),
+        };
+        // Prefer an idle connection from the pool, discarding any the upstream has closed
+        loop {
+            let pooled = if use_pool {
+                state.upstream_pool.lock().unwrap().checkout(&address)
+            } else {
+                None
+            };
+            match pooled {
+                Some(mut stream) => {
+                    if is_still_open(&mut stream).await {
+                        return Ok(UpstreamConn {
+                            address,
+                            host_override,
+                            stats,
+                            stream,
+                            reused: true,
+                            slot,
+                        });
+                    }
+                }
+                None => break,
+            }
+        }
+        let connect =
+            state
+                .upstream_connector
+                .connect(&address, server_name.as_deref(), Some(client));
+        match timeout(state.upstream_connect_timeout, connect).await {
+            Ok(Ok(stream)) => {
+                return Ok(UpstreamConn {
+                    address,
+                    host_override,
+                    stats,
+                    stream,
+                    reused: false,
+                    slot,
+                })
+            }
+            Ok(Err(error)) => {
+                log::warn!("Could not connect to upstream {}: {}", address, error);
+                record_upstream_failure(state, group, &address, &stats);
+                attempted.push(address.clone());
+                last_failure = Some((address, error));
+                pinned_to = None;
+            }
+            Err(_elapsed) => {
+                log::warn!("Timed out connecting to upstream {}", address);
+                record_upstream_failure(state, group, &address, &stats);
+                return Err(UpstreamError::Timeout { addr: address });
+            }
+        }
+    }
+}
+
+/// Picks the one of `group`'s upstreams connect_to_upstream should try next for the client at
+/// `client_ip`: the one whose session key is `pinned_to` if it is alive, otherwise one chosen by
+/// the configured strategy among those that are alive, not cut off by their circuit breaker, not
+/// draining, not already `attempted`, and not at their --max-requests-per-upstream. Claims one of
+/// the chosen upstream's request slots. If the only upstreams left are at capacity, the error is
+/// `Saturated`, and if there are none left at all, `AllDown`.
+fn pick_upstream(
+    state: &ProxyState,
+    group: &UpstreamGroup,
+    client_ip: &str,
+    pinned_to: Option<&str>,
+    attempted: &[String],
+    rng: &mut rand::rngs::StdRng,
+) -> Result<Picked, UpstreamError> {
+    let upstreams = group.upstreams.read().unwrap();
+    // Upstreams whose circuit is open are passed over just like those that are down, and so are
+    // those that are busy enough already
+    let now = Instant::now();
+    let usable: Vec<bool> = upstreams
+        .iter()
+        .map(|upstream| {
+            upstream.healthy
+                && state.circuit_breaker.allows(&upstream.circuit, now)
+                && !attempted.contains(&upstream.address)
+        })
+        .collect();
+    // Backups stand in only while no primary is usable, not when the primaries are merely busy,
+    // and draining upstreams only (with --drain-fallback) while nothing else is usable
+    let backup: Vec<bool> = upstreams.iter().map(|upstream| upstream.backup).collect();
+    let draining: Vec<bool> = upstreams.iter().map(|upstream| upstream.draining).collect();
+    let usable = upstream::eligible(&usable, &backup, &draining, state.drain_fallback);
+    let alive: Vec<bool> = upstreams
+        .iter()
+        .zip(&usable)
+        .map(|(upstream, &usable)| usable && upstream.has_capacity())
+        .collect();
+    if usable.contains(&true) && !alive.contains(&true) {
+        return Err(UpstreamError::Saturated);
+    }
+    if !alive.contains(&true) {
+        return Err(UpstreamError::AllDown);
+    }
+    let num_upstreams = upstreams.len();
+    let weights: Vec<u32> = upstreams.iter().map(|upstream| upstream.weight).collect();
+    // Upstreams that recently came back up get only part of their share
+    let warm_up: Vec<f64> = upstreams
+        .iter()
+        .map(|upstream| upstream.slow_start_factor(state.slow_start, now))
+        .collect();
+    let pinned_idx = pinned_to.and_then(|key| {
+        (0..num_upstreams)
+            .find(|&idx| alive[idx] && upstream::session_key(&upstreams[idx].address) == key)
+    });
+    let upstream_idx = match (pinned_idx, state.strategy) {
+        (Some(idx), _) => idx,
+        (None, Strategy::Random) => {
+            let ramped: Vec<u32> = (0..num_upstreams)
+                .map(|idx| upstream::ramped_weight(weights[idx], warm_up[idx]))
+                .collect();
+            upstream::pick_weighted(&ramped, &alive, rng).expect("an upstream is alive")
+        }
+        // At least one upstream is alive, so this finds one within num_upstreams steps,
+        // give or take the turns a warming up upstream passes on.
+        (None, Strategy::RoundRobin) => {
+            let skip_zero_weight = (0..num_upstreams).any(|idx| alive[idx] && weights[idx] > 0);
+            loop {
+                let idx = group.next_upstream.fetch_add(1, Ordering::Relaxed) % num_upstreams;
+                if alive[idx]
+                    && (weights[idx] > 0 || !skip_zero_weight)
+                    && (warm_up[idx] >= 1.0 || rng.gen_bool(warm_up[idx]))
+                {
+                    break idx;
+                }
+            }
+        }
+        (None, Strategy::IpHash) => {
+            // Like pick_weighted, upstreams with weight zero (which get no points on the
+            // ring) are only used, evenly, when they are all that's alive
+            let positive = (0..num_upstreams).any(|idx| alive[idx] && weights[idx] > 0);
+            let members: Vec<(&str, u32)> = (0..num_upstreams)
+                .filter(|&idx| alive[idx])
+                .map(|idx| {
+                    let weight = if positive { weights[idx] } else { 1 };
+                    (upstreams[idx].address.as_str(), weight)
+                })
+                .collect();
+            let position = |ring: &HashRing| {
+                let address = ring.get(client_ip).expect("an upstream is alive");
+                upstreams
+                    .iter()
+                    .position(|upstream| upstream.address == address)
+                    .expect("the ring only has current upstreams")
+            };
+            // Only rebuilding the ring, when the live upstreams change, needs the write lock
+            let ring = group.hash_ring.read().unwrap();
+            if ring.built_from(&members) {
+                position(&ring)
+            } else {
+                drop(ring);
+                let mut ring = group.hash_ring.write().unwrap();
+                if !ring.built_from(&members) {
+                    *ring = HashRing::new(&members);
+                }
+                position(&ring)
+            }
+        }
+    };
+    let upstream = &upstreams[upstream_idx];
+    // Another request may have taken the last slot since has_capacity said there was one
+    let slot = match upstream.claim_slot() {
+        Ok(slot) => slot,
+        Err(_) => {
+            drop(upstreams);
+            return pick_upstream(state, group, client_ip, pinned_to, attempted, rng);
+        }
+    };
+    let picked = Picked {
+        address: upstream.address.clone(),
+        server_name: upstream.server_name(),
+        host_override: upstream.host_override.clone(),
+        stats: upstream.stats.clone(),
+        slot,
+    };
+    // Only one request gets to be a cooled-down circuit's trial, so claiming it is a write. If
+    // another request claimed it first (or the upstream went away), pick again.
+    if !upstream.circuit.is_closed() {
+        drop(upstreams);
+        let mut upstreams = group.upstreams.write().unwrap();
+        match upstreams
+            .iter_mut()
+            .find(|info| info.address == picked.address)
+        {
+            Some(upstream) if state.circuit_breaker.allows(&upstream.circuit, now) => {
+                state.circuit_breaker.picked(&mut upstream.circuit, now);
+            }
+            _ => {
+                drop(upstreams);
+                return pick_upstream(state, group, client_ip, pinned_to, attempted, rng);
+            }
+        }
+    }
+    Ok(picked)
+}
+
+/// Puts a connection that can take another request back in the pool, unless its upstream has
+/// been removed from `group` in the meantime.
+fn return_to_pool(state: &ProxyState, group: &UpstreamGroup, upstream: UpstreamConn) {
+    let upstreams = group.upstreams.read().unwrap();
+    if upstreams
+        .iter()
+        .any(|info| info.address == upstream.address)
+    {
+        state
+            .upstream_pool
+            .lock()
+            .unwrap()
+            .checkin(&upstream.address, upstream.stream);
+    }
+}
+
+/// What came of expect_continue
+enum Handshake {
+    /// The body went out to the upstream, whose response is still to come
+    BodySent,
+    /// The upstream answered without the body, which the client has yet to send (if it ever will)
+    Answered(http::Response<Vec<u8>>, Option<response::StreamedBody>),
+}
+
+/// Why expect_continue failed
+#[derive(Debug)]
+enum HandshakeError {
+    Upstream(response::Error),
+    /// The client hung up or sent a bad body
+    Client(request::Error),
+}
+
+/// Forwards a request whose client is holding back the body until it hears `100 Continue`. The
+/// headers go to the upstream first, and the body follows once the upstream says to go ahead,
+/// which is passed on to the client. If the upstream hasn't said anything within
+/// EXPECT_CONTINUE_WAIT, as one that doesn't know about 100-continue won't, we tell the client to
+/// go ahead ourselves; and a client that tires of waiting sends the body regardless.
+async fn expect_continue<S>(
+    state: &ProxyState,
+    client_conn: &mut BufReader<S>,
+    upstream: &mut UpstreamStream,
+    request: &mut http::Request<Vec<u8>>,
+    interim: &mut Vec<http::Response<Vec<u8>>>,
+) -> Result<Handshake, HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let upstream_error = |error| HandshakeError::Upstream(response::Error::ConnectionError(error));
+    interim.clear();
+    request::write_to_stream(request, upstream)
+        .await
+        .map_err(upstream_error)?;
+    // Wait on both sides without reading from either, so that neither loses anything if the
+    // other goes first
+    let upstream_spoke = tokio::select! {
+        _ = is_readable(upstream) => true,
+        _ = request::wait_for_request(client_conn) => false,
+        _ = delay_for(EXPECT_CONTINUE_WAIT) => false,
+    };
+    let go_ahead = if upstream_spoke {
+        let answer = response::read_continue_answer(
+            upstream,
+            request.method(),
+            state.header_limits,
+            interim,
+        );
+        let answer = timeout(state.upstream_response_timeout, answer)
+            .await
+            .map_err(|_elapsed| upstream_error(io::ErrorKind::TimedOut.into()))?
+            .map_err(HandshakeError::Upstream)?;
+        match answer {
+            response::ContinueAnswer::Continue(mut go_ahead) => {
+                keep_alive::strip(go_ahead.headers_mut());
+                *go_ahead.version_mut() = http::Version::HTTP_11;
+                Some(go_ahead)
+            }
+            response::ContinueAnswer::Final(response, streamed) => {
+                return Ok(Handshake::Answered(response, streamed));
+            }
+        }
+    } else if client_conn.buffer().is_empty() {
+        Some(response::make_continue())
+    } else {
+        // The client is sending the body anyway
+        None
+    };
+    if let Some(go_ahead) = go_ahead {
+        response::write_to_stream(&go_ahead, client_conn.get_mut())
+            .await
+            .map_err(|error| HandshakeError::Client(request::Error::ConnectionError(error)))?;
+    }
+    request::read_body(client_conn, request, state.max_request_body_bytes)
+        .await
+        .map_err(HandshakeError::Client)?;
+    request.headers_mut().remove(http::header::EXPECT);
+    upstream
+        .write_all(request.body())
+        .await
+        .map_err(upstream_error)?;
+    upstream.flush().await.map_err(upstream_error)?;
+    Ok(Handshake::BodySent)
+}
+
+/// Waits until an upstream has sent something (or hung up), without reading it.
+async fn is_readable(stream: &mut UpstreamStream) {
+    let mut buffer = [0_u8; 1];
+    let _ = std::future::poll_fn(|cx| stream.poll_peek(cx, &mut buffer)).await;
+}
+
+/// Whether an idle connection still looks usable, i.e. the upstream hasn't hung up on it (or sent
+/// something unprompted) while it sat in the pool.
+async fn is_still_open(stream: &mut UpstreamStream) -> bool {
+    let mut buffer = [0_u8; 1];
+    std::future::poll_fn(|cx| Poll::Ready(stream.poll_peek(cx, &mut buffer).is_pending())).await
+}
+
+/// The error response for a client whose request couldn't be forwarded because connecting to an
+/// upstream failed: 503 if every upstream is down, asking the client to come back after the next
+/// round of health checks, or if every upstream has as many requests as it may, asking it to come
+/// back in a second; 504 if the upstream was too slow to accept; 502 if it refused.
+fn make_connect_error_response(
+    state: &ProxyState,
+    error: &UpstreamError,
+) -> http::Response<Vec<u8>> {
+    log::error!("Could not connect to an upstream: {}", error);
+    let (status, retry_after) = match error {
+        UpstreamError::AllDown => {
+            let interval = state.active_health_check_interval.load(Ordering::Relaxed);
+            (http::StatusCode::SERVICE_UNAVAILABLE, Some(interval.max(1)))
+        }
+        UpstreamError::Saturated => (http::StatusCode::SERVICE_UNAVAILABLE, Some(1)),
+        UpstreamError::Timeout { .. } => (http::StatusCode::GATEWAY_TIMEOUT, None),
+        UpstreamError::ConnectFailed { .. } => (http::StatusCode::BAD_GATEWAY, None),
+    };
+    let mut response = state.error_pages.make_http_error(status);
+    if let Some(seconds) = retry_after {
+        response
+            .headers_mut()
+            .insert("Retry-After", http::HeaderValue::from(seconds));
+    }
+    response
+}
+
+/// The access log's reason for answering with make_connect_error_response
+fn connect_error_reason(error: &UpstreamError) -> &'static str {
+    match error {
+        UpstreamError::AllDown => "all_upstreams_down",
+        UpstreamError::Saturated => "upstreams_saturated",
+        UpstreamError::Timeout { .. } => "upstream_connect_timeout",
+        UpstreamError::ConnectFailed { .. } => "upstream_unavailable",
+    }
+}
+
+/// Counts a failed connection to an upstream, or a failed request over one, against it.
+fn record_upstream_failure(
+    state: &ProxyState,
+    group: &UpstreamGroup,
+    address: &str,
+    stats: &UpstreamStats,
+) {
+    stats.record_failure();
+    record_upstream_health(state, group, address, false);
+    record_request_outcome(state, group, address, false);
+}
+
+/// Feeds the outcome of a request (or attempt to connect for one) into the upstream's circuit
+/// breaker, and its count of failed requests in a row, which may mark it down. Upstreams that have
+/// been removed in the meantime are ignored.
+fn record_request_outcome(state: &ProxyState, group: &UpstreamGroup, address: &str, success: bool) {
+    // Without a circuit breaker or passive health checks, there's nothing to record, so no need to
+    // take the write lock
+    if !state.circuit_breaker.is_enabled() && !state.passive_health.is_enabled() {
+        return;
+    }
+    let mut upstreams = group.upstreams.write().unwrap();
+    let upstream = match upstreams.iter_mut().find(|info| info.address == address) {
+        Some(upstream) => upstream,
+        None => return,
+    };
+    if state
+        .passive_health
+        .record(&mut upstream.failed_requests, success)
+        && upstream.healthy
+    {
+        // Just as if enough connections had failed, so it takes as many passed health checks to
+        // come back
+        upstream.healthy = false;
+        upstream.streak = Streak::default();
+        log::info!(
+            "Marking upstream {} down after {} failed requests in a row",
+            address,
+            state.passive_health.threshold()
+        );
+    }
+    if state.circuit_breaker.is_enabled()
+        && state
+            .circuit_breaker
+            .record(&mut upstream.circuit, success, Instant::now())
+    {
+        log::info!(
+            "Circuit to upstream {} is now {}",
+            address,
+            upstream.circuit
+        );
+    }
+}
+
+/// Feeds the outcome of a health check or connection attempt into the upstream's health counters,
+/// marking it down or back up once enough consecutive results agree. Upstreams that have been
+/// removed in the meantime are ignored.
+fn record_upstream_health(state: &ProxyState, group: &UpstreamGroup, address: &str, success: bool) {
+    let mut upstreams = group.upstreams.write().unwrap();
+    let upstream = match upstreams.iter_mut().find(|info| info.address == address) {
+        Some(upstream) => upstream,
+        None => return,
+    };
+    let was_alive = upstream.healthy;
+    upstream.healthy = state
+        .health_tracker
+        .record(&mut upstream.streak, was_alive, success);
+    if upstream.healthy != was_alive {
+        if upstream.healthy {
+            upstream.recovered_at = Some(Instant::now());
+        }
+        log::info!(
+            "Marking upstream {} {}",
+            address,
+            if upstream.healthy { "up" } else { "down" }
+        );
+    }
+}
+
+/// The Host and X-Forwarded-Host a client's request came with, kept so that whichever upstream the
+/// request goes to (it may be retried on another) gets them as the client sent them, or rewritten
+/// for its host_override.
+struct ClientHost {
+    host: Option<http::HeaderValue>,
+    forwarded_host: Option<http::HeaderValue>,
+}
+
+impl ClientHost {
+    fn of(headers: &http::HeaderMap) -> ClientHost {
+        ClientHost {
+            host: headers.get(http::header::HOST).cloned(),
+            forwarded_host: headers.get("x-forwarded-host").cloned(),
+        }
+    }
+
+    /// Sets the Host in `headers` to `host_override`, with the client's moved to X-Forwarded-Host,
+    /// or without one, puts back the client's own.
+    fn apply(&self, headers: &mut http::HeaderMap, host_override: Option<&str>) {
+        let restore = |headers: &mut http::HeaderMap, name, value: &Option<http::HeaderValue>| {
+            match value {
+                Some(value) => headers.insert(name, value.clone()),
+                None => headers.remove(name),
+            };
+        };
+        let host_override = host_override.and_then(|host| http::HeaderValue::from_str(host).ok());
+        match host_override {
+            Some(host) => {
+                headers.insert(http::header::HOST, host);
+                match &self.host {
+                    Some(client_host) => {
+                        headers.insert("x-forwarded-host", client_host.clone());
+                    }
+                    None => restore(headers, "x-forwarded-host", &self.forwarded_host),
+                }
+            }
+            None => {
+                restore(headers, "host", &self.host);
+                restore(headers, "x-forwarded-host", &self.forwarded_host);
+            }
+        }
+    }
+}
+
+/// Whether a request can safely be sent again if the upstream failed partway through it.
+fn is_idempotent(method: &http::Method) -> bool {
+    method == http::Method::GET || method == http::Method::HEAD || method == http::Method::OPTIONS
+}
+
+/// Sends the response `entry` is about, then logs it, along with the bytes that went back and
+/// forth for it. Returns those, as count_request_bytes does.
+async fn send_response<S>(
+    client_conn: &mut BufReader<CountingStream<S>>,
+    state: &ProxyState,
+    entry: AccessLogEntry<'_>,
+) -> (u64, u64)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if let Err(error) = response::write_to_stream(entry.response(), client_conn.get_mut()).await {
+        log::warn!(
+            "{}Failed to send response to client: {}",
+            entry.log_prefix(),
+            error
+        );
+    }
+    let (bytes_in, bytes_out) = count_request_bytes(state, client_conn);
+    entry
+        .bytes(bytes_in, bytes_out)
+        .log(state.access_log_format);
+    (bytes_in, bytes_out)
+}
+
+/// Adds what went through `client_conn` since the last request was answered, i.e. the request
+/// that just was and everything sent back for it, to the totals, and returns it as `(in, out)`.
+/// Bytes already read ahead into the buffer belong to the next request, so they wait for it.
+fn count_request_bytes<S>(
+    state: &ProxyState,
+    client_conn: &mut BufReader<CountingStream<S>>,
+) -> (u64, u64)
+where
+    S: AsyncRead + Unpin,
+{
+    let unconsumed = client_conn.buffer().len();
+    let (bytes_in, bytes_out) = client_conn.get_mut().take_counts(unconsumed);
+    state.client_bytes.record(bytes_in, bytes_out);
+    (bytes_in, bytes_out)
+}
+
+/// One of a client IP's connections, counted against --max-connections-per-ip for as long as it
+/// lives
+struct ConnectionSlot<'a> {
+    state: &'a ProxyState,
+    client_addr: IpAddr,
+}
+
+impl<'a> ConnectionSlot<'a> {
+    /// Counts a new connection from `client_addr`, or returns None if it already has as many as
+    /// it may.
+    fn claim(state: &'a ProxyState, client_addr: IpAddr) -> Option<ConnectionSlot<'a>> {
+        let mut connections = state.connections_per_ip.lock().unwrap();
+        let count = connections.get(&client_addr).copied().unwrap_or(0);
+        if count >= state.max_connections_per_ip {
+            return None;
+        }
+        connections.insert(client_addr, count + 1);
+        Some(ConnectionSlot { state, client_addr })
+    }
+}
+
+impl Drop for ConnectionSlot<'_> {
+    fn drop(&mut self) {
+        let mut connections = self.state.connections_per_ip.lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.client_addr) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.client_addr);
+            }
+        }
+    }
+}
+
+/// Counts a request from the client `key` picks out (its API key, say, or its IP) at `client_ip`
+/// against `limit`, that of `rule` (or the global one). If that puts it over the limit, returns
+/// how long until the client's window resets.
+fn over_rate_limit(
+    state: &ProxyState,
+    rule: Option<usize>,
+    limit: usize,
+    key: &str,
+    client_ip: &str,
+) -> Option<Duration> {
+    let (count, window_left) = state.rate_limiter.record(rule, key, Instant::now());
+    if count > limit {
+        log::warn!(
+            "[rate limit] {} (client {}) is at {} requests this minute, over its limit of {}",
+            key,
+            client_ip,
+            count,
+            limit
+        );
+        Some(window_left)
+    } else {
+        None
+    }
+}
+
+/// Serves the requests a client sends over `client_conn`, which is a plain TCP connection or one
+/// balancebeam has terminated TLS on (`tls`).
+async fn handle_connection<S>(
+    client_conn: S,
+    addresses: ConnectionAddresses,
+    tls: bool,
+    state: &ProxyState,
+    mut shutdown: watch::Receiver<bool>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let client_addr = addresses.source.ip();
+    let client_ip = client_addr.to_string();
+    log::info!("Connection received from {}", client_ip);
+    // Requests are read through a buffer that lives as long as the connection, so that pipelined
+    // requests read along with an earlier one aren't lost. Underneath it, what goes through the
+    // connection is counted.
+    let mut client_conn = BufReader::new(CountingStream::new(client_conn));
+    if let Some(range) = state.deny_list.denies(client_addr) {
+        log::info!("Denying connection from {} (in {})", client_ip, range);
+        state.denied.fetch_add(1, Ordering::Relaxed);
+        let mut response = state
+            .error_pages
+            .make_http_error(http::StatusCode::FORBIDDEN);
+        response
+            .headers_mut()
+            .insert("Connection", http::HeaderValue::from_static("close"));
+        let entry = AccessLogEntry::new(&client_ip, &response).error("denied");
+        send_response(&mut client_conn, state, entry).await;
+        return;
+    }
+    let limit_connections = state.max_connections_per_ip != 0
+        && !state
+            .rate_limit_exempt
+            .iter()
+            .any(|range| range.contains(client_addr));
+    // Held until this function returns, however it does
+    let _slot = if limit_connections {
+        match ConnectionSlot::claim(state, client_addr) {
+            Some(slot) => Some(slot),
+            None => {
+                let mut response = state
+                    .error_pages
+                    .make_http_error(http::StatusCode::SERVICE_UNAVAILABLE);
+                response
+                    .headers_mut()
+                    .insert("Connection", http::HeaderValue::from_static("close"));
+                let entry =
+                    AccessLogEntry::new(&client_ip, &response).error("too_many_connections");
+                send_response(&mut client_conn, state, entry).await;
+                return;
+            }
+        }
+    } else {
+        None
+    };
+    // The client may now send us one or more requests. Keep trying to read requests until the
+    // client hangs up or we get an error.
+    loop {
+        // Wait for the client's next request. Connections sitting between requests are closed
+        // instead once they've been idle too long, or once we're shutting down. (Once a request
+        // has started arriving, it is read however long it takes.)
+        tokio::select! {
+            _ = request::wait_for_request(&mut client_conn) => {}
+            _ = idle_for(state.client_idle_timeout) => {
+                log::debug!("Closing connection from {}, idle for {:?}", client_ip, state.client_idle_timeout);
+                return;
+            }
+            _ = shutdown_started(&mut shutdown) => {
+                log::debug!("Shutting down. Closing idle connection from {}", client_ip);
+                return;
+            }
+        }
+
+        // Read a request from the client. One that sent `Expect: 100-continue` holds back its body
+        // until it hears that the upstream will take it, so that's left to expect_continue. A
+        // chunked body's length isn't known until all of it is in, and the upstream has to be told
+        // it up front, so for those we give the go-ahead ourselves.
+        let read = async {
+            let limits = state.header_limits;
+            let mut request =
+                request::read_head(&mut client_conn, limits, state.max_request_body_bytes).await?;
+            let expecting = request::expects_continue(&request);
+            if expecting {
+                if !request::has_chunked_body(&request) {
+                    return Ok(request);
+                }
+                let go_ahead = response::make_continue();
+                response::write_to_stream(&go_ahead, client_conn.get_mut())
+                    .await
+                    .map_err(request::Error::ConnectionError)?;
+            }
+            request::read_body(&mut client_conn, &mut request, state.max_request_body_bytes)
+                .await?;
+            if expecting {
+                request.headers_mut().remove(http::header::EXPECT);
+            }
+            Ok(request)
+        };
+        let mut request = match read.await {
+            Ok(request) => request,
+            // Handle case where client closed connection and is no longer sending requests
+            Err(request::Error::IncompleteRequest(0)) => {
+                log::debug!("Client finished sending requests. Shutting down connection");
+                return;
+            }
+            // Handle I/O error in reading from the client
+            Err(request::Error::ConnectionError(io_err)) => {
+                log::info!("Error reading request from client stream: {}", io_err);
+                return;
+            }
+            Err(error) => {
+                log::debug!("Error parsing request: {:?}", error);
+                // The rest of an oversized request is still unread, and mustn't be taken for the
+                // start of the next one; nor can where a request with broken framing ends be
+                // trusted, lest what's left of it smuggle in a request of its own
+                let closing = matches!(
+                    error,
+                    request::Error::HeadersTooLarge
+                        | request::Error::RequestBodyTooLarge
+                        | request::Error::MalformedRequest(_)
+                        | request::Error::InvalidContentLength
+                        | request::Error::UnsupportedTransferEncoding
+                        | request::Error::MalformedChunkedBody
+                );
+                let mut response = state.error_pages.make_http_error(match error {
+                    request::Error::IncompleteRequest(_)
+                    | request::Error::MalformedRequest(_)
+                    | request::Error::InvalidContentLength
+                    | request::Error::ContentLengthMismatch
+                    | request::Error::MalformedChunkedBody => http::StatusCode::BAD_REQUEST,
+                    request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
+                    request::Error::HeadersTooLarge => {
+                        http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+                    }
+                    request::Error::UnsupportedTransferEncoding
+                    | request::Error::UnsupportedTarget => http::StatusCode::NOT_IMPLEMENTED,
+                    request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
+                });
+                keep_alive::set_connection_header(
+                    response.headers_mut(),
+                    http::Version::HTTP_11,
+                    closing,
+                );
+                let entry = AccessLogEntry::new(&client_ip, &response).error("bad_request");
+                send_response(&mut client_conn, state, entry).await;
+                if closing {
+                    discard_rest(&mut client_conn).await;
+                    return;
+                }
+                continue;
+            }
+        };
+        if log::log_enabled!(target: HTTP_TRACE, log::Level::Trace) {
+            log::trace!(
+                target: HTTP_TRACE,
+                "Request from {}:\n{}",
+                client_ip,
+                request::dump(&request, &state.trace_settings)
+            );
+        }
+        // Whether the client wants this to be its last request on the connection. What it said
+        // about that is between it and us, so it isn't passed on; we speak HTTP/1.1 to upstreams
+        // whatever it used, so that their connections can be pooled.
+        let client_version = request.version();
+        let client_wants_close = keep_alive::wants_close(client_version, request.headers());
+        // Whether the client is still holding back the body, because the request still expects
+        // 100-continue. Answering without it means hanging up after, since there's no telling
+        // whether the client will go on to send it.
+        let mut body_pending = request::expects_continue(&request);
+        let mut client_closing = client_wants_close || body_pending;
+        let client_accepts_gzip = compress::accepts_gzip(request.headers());
+        keep_alive::strip(request.headers_mut());
+        *request.version_mut() = http::Version::HTTP_11;
+        // Every request can be traced by an ID, which the upstream gets and the client gets back:
+        // the client's own, if it sent one we can use
+        let request_id = request_id::from_request(&request)
+            .unwrap_or_else(|| request_id::generate(&mut rand::thread_rng()));
+        request_id::set(request.headers_mut(), &request_id);
+        // A request that has been through us before was sent back by an upstream (or a chain of
+        // them) that forwards to us, and would go round forever
+        if via::has_passed_through(request.headers(), &state.via_pseudonym) {
+            let mut response = state
+                .error_pages
+                .make_http_error(http::StatusCode::LOOP_DETECTED);
+            keep_alive::set_connection_header(
+                response.headers_mut(),
+                client_version,
+                client_closing,
+            );
+            request_id::set(response.headers_mut(), &request_id);
+            let entry = AccessLogEntry::new(&client_ip, &response)
+                .request(&request)
+                .request_id(&request_id)
+                .error("forwarding_loop");
+            send_response(&mut client_conn, state, entry).await;
+            if client_closing {
+                return;
+            }
+            continue;
+        }
+        // Checks on balancebeam itself are answered by it, whatever the client's rate limit and
+        // however the upstreams are doing
+        if state.self_health_on_main && request.uri().path() == state.self_health_path {
+            let mut response = admin::self_health(state, request.method());
+            keep_alive::set_connection_header(
+                response.headers_mut(),
+                client_version,
+                client_closing,
+            );
+            request_id::set(response.headers_mut(), &request_id);
+            let entry = AccessLogEntry::new(&client_ip, &response)
+                .request(&request)
+                .request_id(&request_id);
+            send_response(&mut client_conn, state, entry).await;
+            if client_closing {
+                return;
+            }
+            continue;
+        }
+        // Behind a trusted proxy, each request is counted against the client it was made for
+        let limited_addr = if state.trust_forwarded_for {
+            request::forwarded_for(&request).unwrap_or(client_addr)
+        } else {
+            client_addr
+        };
+        // The first rule the request matches sets its limit, with a count of its own
+        let rule = rate_limit::find_rule(
+            &state.rate_limit_rules,
+            request.method(),
+            request.uri().path(),
+        );
+        let limit = rule.map_or(state.max_requests_per_minute, |idx| {
+            state.rate_limit_rules[idx].max_requests_per_minute
+        });
+        let exempt = state
+            .rate_limit_exempt
+            .iter()
+            .any(|range| range.contains(limited_addr));
+        // Requests carrying a key are counted per key, wherever they come from
+        let key = state.rate_limit_key.key_for(&request);
+        if key.is_none() && state.require_rate_limit_key && !exempt {
+            let mut response = state
+                .error_pages
+                .make_http_error(http::StatusCode::UNAUTHORIZED);
+            keep_alive::set_connection_header(
+                response.headers_mut(),
+                client_version,
+                client_closing,
+            );
+            request_id::set(response.headers_mut(), &request_id);
+            let entry = AccessLogEntry::new(&client_ip, &response)
+                .request(&request)
+                .request_id(&request_id)
+                .error("missing_rate_limit_key");
+            send_response(&mut client_conn, state, entry).await;
+            if client_closing {
+                return;
+            }
+            continue;
+        }
+        let retry_after = if limit != 0 && !exempt {
+            let limited_ip = limited_addr.to_string();
+            let key = key.as_deref().unwrap_or(&limited_ip);
+            over_rate_limit(state, rule, limit, key, &limited_ip)
+        } else {
+            None
+        };
+        if let Some(retry_after) = retry_after {
+            state.rate_limited.fetch_add(1, Ordering::Relaxed);
+            let rule_name = rule.map(|idx| state.rate_limit_rules[idx].name.as_str());
+            let mut response = response::make_rate_limit_response(limit, 0, retry_after, rule_name);
+            state.error_pages.apply(&mut response);
+            keep_alive::set_connection_header(
+                response.headers_mut(),
+                client_version,
+                client_closing,
+            );
+            request_id::set(response.headers_mut(), &request_id);
+            let entry = AccessLogEntry::new(&client_ip, &response)
+                .request(&request)
+                .request_id(&request_id)
+                .error("rate_limited");
+            send_response(&mut client_conn, state, entry).await;
+            if client_closing {
+                return;
+            }
+            continue;
+        }
+
+        // A CONNECT counts against the rate limit like any other request, but then isn't proxied
+        // so much as passed through, and takes over the connection
+        if request.method() == http::Method::CONNECT {
+            let destination = request.uri().to_string();
+            let connect_started = Instant::now();
+            match open_connect_tunnel(state, &request).await {
+                Ok(stream) => {
+                    log::info!(
+                        "[{}] {} -> {}: tunnel opened",
+                        request_id,
+                        client_ip,
+                        destination
+                    );
+                    let response = response::make_connect_established();
+                    let entry = AccessLogEntry::new(&client_ip, &response)
+                        .request(&request)
+                        .request_id(&request_id)
+                        .upstream(&destination)
+                        .elapsed(connect_started.elapsed());
+                    send_response(&mut client_conn, state, entry).await;
+                    tunnel(&mut client_conn, stream, state.client_idle_timeout).await;
+                    count_request_bytes(state, &mut client_conn);
+                    return;
+                }
+                Err((status, reason)) => {
+                    let mut response = state.error_pages.make_http_error(status);
+                    keep_alive::set_connection_header(
+                        response.headers_mut(),
+                        client_version,
+                        client_closing,
+                    );
+                    request_id::set(response.headers_mut(), &request_id);
+                    let entry = AccessLogEntry::new(&client_ip, &response)
+                        .request(&request)
+                        .request_id(&request_id)
+                        .elapsed(connect_started.elapsed())
+                        .error(reason);
+                    send_response(&mut client_conn, state, entry).await;
+                    if client_closing {
+                        return;
+                    }
+                    continue;
+                }
+            }
+        }
+
+        // Wait (briefly) for a turn to be forwarded. The permit is held until the response has
+        // been sent, i.e. until the end of this iteration.
+        let permit = if state.max_concurrent_requests != 0 {
+            match timeout(state.request_queue_timeout, state.request_permits.acquire()).await {
+                Ok(permit) => Some(permit),
+                Err(_elapsed) => {
+                    let mut response = state
+                        .error_pages
+                        .make_http_error(http::StatusCode::SERVICE_UNAVAILABLE);
+                    response
+                        .headers_mut()
+                        .insert("Retry-After", http::HeaderValue::from(1));
+                    keep_alive::set_connection_header(
+                        response.headers_mut(),
+                        client_version,
+                        client_closing,
+                    );
+                    request_id::set(response.headers_mut(), &request_id);
+                    let entry = AccessLogEntry::new(&client_ip, &response)
+                        .request(&request)
+                        .request_id(&request_id)
+                        .error("overloaded");
+                    send_response(&mut client_conn, state, entry).await;
+                    if client_closing {
+                        return;
+                    }
+                    continue;
+                }
+            }
+        } else {
+            None
+        };
+
+        // Requests for a routed Host go to that route's upstreams, the rest to the default ones
+        let host = request::host(&request);
+        let group =
+            match route::find_group(&state.groups, host.as_deref(), !state.reject_unknown_hosts) {
+                Some(idx) => &state.groups[idx],
+                None => {
+                    let mut response = state
+                        .error_pages
+                        .make_http_error(http::StatusCode::MISDIRECTED_REQUEST);
+                    keep_alive::set_connection_header(
+                        response.headers_mut(),
+                        client_version,
+                        client_closing,
+                    );
+                    request_id::set(response.headers_mut(), &request_id);
+                    let entry = AccessLogEntry::new(&client_ip, &response)
+                        .request(&request)
+                        .request_id(&request_id)
+                        .error("unknown_host");
+                    send_response(&mut client_conn, state, entry).await;
+                    if client_closing {
+                        return;
+                    }
+                    continue;
+                }
+            };
+
+        // Check out a connection to an upstream for this request, going back to the client's
+        // upstream from earlier if it has a sticky session
+        let session = state
+            .sticky_cookie
+            .as_deref()
+            .and_then(|name| request::get_cookie(&request, name))
+            .map(String::from);
+        let forward_started = Instant::now();
+        let mut upstream =
+            match connect_to_upstream(state, group, addresses, session.as_deref(), true).await {
+                Ok(upstream) => upstream,
+                Err(error) => {
+                    let mut response = make_connect_error_response(state, &error);
+                    request_id::set(response.headers_mut(), &request_id);
+                    let entry = AccessLogEntry::new(&client_ip, &response)
+                        .request(&request)
+                        .request_id(&request_id)
+                        .elapsed(forward_started.elapsed())
+                        .error(connect_error_reason(&error));
+                    send_response(&mut client_conn, state, entry).await;
+                    return;
+                }
+            };
+        // Taken from the address rather than the socket, which may already have been reset
+        let mut upstream_ip = upstream::ip_of(&upstream.address);
+        log::info!(
+            "[{}] {} -> {}: {}",
+            request_id,
+            client_ip,
+            upstream_ip,
+            request::format_request_line(&request)
+        );
+
+        // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
+        // (We're the ones connecting directly to the upstream server, so without this header, the
+        // upstream server will only know our IP, not the client's.) Unless the client is trusted,
+        // whatever list it sent may be made up, so it is replaced rather than added to.
+        if !state.trust_forwarded_for {
+            request.headers_mut().remove("x-forwarded-for");
+        }
+        request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
+        let headers = request.headers_mut();
+        headers.insert(
+            "x-real-ip",
+            http::HeaderValue::from_str(&client_ip).unwrap(),
+        );
+        // Likewise, the upstream only sees plain HTTP from us, so tell it what the client used
+        headers.insert(
+            "x-forwarded-proto",
+            http::HeaderValue::from_static(if tls { "https" } else { "http" }),
+        );
+        via::append(headers, client_version, &state.via_pseudonym);
+        // Upstreams behind their own virtual hosting may want a Host of their own
+        let client_host = ClientHost::of(headers);
+        client_host.apply(headers, upstream.host_override.as_deref());
+        // The operator's rewrites come last, so they can override ours too
+        state.request_header_rules.apply(headers);
+
+        // Mirrors get the request as the upstream does. One whose body the client is still waiting
+        // for the go-ahead to send isn't mirrored, as there's no telling yet what it will be.
+        if let Some(mirror) = &state.mirror {
+            if !body_pending && mirror.samples(&mut rand::thread_rng()) {
+                mirror.send(&request, addresses);
+            }
+        }
+
+        // Forward the request to the server and read its response. If that fails, idempotent
+        // requests are replayed on another upstream, up to max_retries times.
+        let mut retries = 0;
+        let mut reconnected = false;
+        let mut interim = Vec::new();
+        let (mut response, mut streamed) = loop {
+            // The upstream gets only the headers while the client waits to be told to send the body
+            let handshake = if body_pending {
+                let handshake = expect_continue(
+                    state,
+                    &mut client_conn,
+                    &mut upstream.stream,
+                    &mut request,
+                    &mut interim,
+                );
+                Some(handshake.await)
+            } else {
+                None
+            };
+            let handshook = handshake.is_some();
+            let sent = match handshake {
+                None => request::write_to_stream(&request, &mut upstream.stream).await,
+                Some(Ok(Handshake::BodySent)) => {
+                    body_pending = false;
+                    client_closing = client_wants_close;
+                    Ok(())
+                }
+                Some(Ok(Handshake::Answered(response, streamed))) => break (response, streamed),
+                Some(Err(HandshakeError::Client(error))) => {
+                    log::info!(
+                        "[{}] Error reading request body from client: {:?}",
+                        request_id,
+                        error
+                    );
+                    return;
+                }
+                // Nothing the upstream said can have been acted on without the body, so this is no
+                // different from the request not having got to it
+                Some(Err(HandshakeError::Upstream(error))) => {
+                    body_pending = request::expects_continue(&request);
+                    Err(io::Error::other(format!("{:?}", error)))
+                }
+            };
+            // Whether the failure looks like the upstream had closed the connection before the
+            // request got to it, so the request can safely go out again
+            let not_received = match sent {
+                Ok(()) => {
+                    log::debug!("[{}] Forwarded request to server", request_id);
+                    // Anything the upstream said before the go-ahead is still to be passed on
+                    if !handshook {
+                        interim.clear();
+                    }
+                    let response = timeout(
+                        state.upstream_response_timeout,
+                        response::read_head(
+                            &mut upstream.stream,
+                            request.method(),
+                            state.header_limits,
+                            &mut interim,
+                        ),
+                    );
+                    match response.await {
+                        Ok(Ok(response)) => break response,
+                        Ok(Err(error)) => {
+                            log::error!(
+                                "[{}] Error reading response from server: {:?}",
+                                request_id,
+                                error
+                            );
+                            // Not a byte came back, as when the upstream had already closed the
+                            // connection. Idempotent requests can go out again whatever happened.
+                            matches!(error, response::Error::IncompleteResponse(0))
+                                || is_idempotent(request.method())
+                                    && matches!(
+                                        error,
+                                        response::Error::IncompleteResponse(_)
+                                            | response::Error::ConnectionError(_)
+                                    )
+                        }
+                        // The upstream may still answer later, so this connection is out of step
+                        // with the client's. Hang up on the client rather than risk handing that
+                        // late response to its next request.
+                        Err(_elapsed) => {
+                            log::error!(
+                                "[{}] Upstream {} did not respond within {:?}",
+                                request_id,
+                                upstream_ip,
+                                state.upstream_response_timeout
+                            );
+                            upstream.stats.record_failure();
+                            record_request_outcome(state, group, &upstream.address, false);
+                            let mut response = state
+                                .error_pages
+                                .make_http_error(http::StatusCode::GATEWAY_TIMEOUT);
+                            request_id::set(response.headers_mut(), &request_id);
+                            let entry = AccessLogEntry::new(&client_ip, &response)
+                                .request(&request)
+                                .request_id(&request_id)
+                                .upstream(&upstream.address)
+                                .elapsed(forward_started.elapsed())
+                                .error("upstream_response_timeout");
+                            send_response(&mut client_conn, state, entry).await;
+                            return;
+                        }
+                    }
+                }
+                Err(error) => {
+                    log::error!(
+                        "[{}] Failed to send request to upstream {}: {}",
+                        request_id,
+                        upstream_ip,
+                        error
+                    );
+                    true
+                }
+            };
+            // Upstreams close idle connections whenever they like, so a pooled one failing this
+            // way says nothing about the upstream's health. Try once more on a fresh connection
+            // to the same upstream (if it's still up), whatever the request's method.
+            let stale = upstream.reused && not_received && !reconnected;
+            let pinned_to = if stale {
+                log::info!(
+                    "[{}] Pooled connection to upstream {} was closed, reconnecting",
+                    request_id,
+                    upstream_ip
+                );
+                reconnected = true;
+                Some(upstream::session_key(&upstream.address))
+            } else {
+                if !is_idempotent(request.method()) || retries == state.max_retries {
+                    upstream.stats.record_failure();
+                    record_request_outcome(state, group, &upstream.address, false);
+                    let mut response = state
+                        .error_pages
+                        .make_http_error(http::StatusCode::BAD_GATEWAY);
+                    request_id::set(response.headers_mut(), &request_id);
+                    let entry = AccessLogEntry::new(&client_ip, &response)
+                        .request(&request)
+                        .request_id(&request_id)
+                        .upstream(&upstream.address)
+                        .elapsed(forward_started.elapsed())
+                        .error("upstream_error");
+                    send_response(&mut client_conn, state, entry).await;
+                    return;
+                }
+                retries += 1;
+                record_upstream_failure(state, group, &upstream.address, &upstream.stats);
+                None
+            };
+            // The failed attempt no longer counts against the upstream, which may be the one
+            // reconnected to
+            drop(upstream.slot.take());
+            match connect_to_upstream(state, group, addresses, pinned_to.as_deref(), !stale).await {
+                Ok(next_upstream) => {
+                    upstream = next_upstream;
+                    upstream_ip = upstream::ip_of(&upstream.address);
+                    let headers = request.headers_mut();
+                    client_host.apply(headers, upstream.host_override.as_deref());
+                    state.request_header_rules.apply(headers);
+                }
+                // The upstream that just failed the request was the last one up, and the failure
+                // took it down. That's its doing, not a lack of upstreams to begin with.
+                Err(UpstreamError::AllDown) if !stale => {
+                    log::warn!(
+                        "[{}] No upstream left to retry on after {} failed",
+                        request_id,
+                        upstream.address
+                    );
+                    let mut response = state
+                        .error_pages
+                        .make_http_error(http::StatusCode::BAD_GATEWAY);
+                    request_id::set(response.headers_mut(), &request_id);
+                    let entry = AccessLogEntry::new(&client_ip, &response)
+                        .request(&request)
+                        .request_id(&request_id)
+                        .upstream(&upstream.address)
+                        .elapsed(forward_started.elapsed())
+                        .error("upstream_error");
+                    send_response(&mut client_conn, state, entry).await;
+                    return;
+                }
+                Err(error) => {
+                    let mut response = make_connect_error_response(state, &error);
+                    request_id::set(response.headers_mut(), &request_id);
+                    let entry = AccessLogEntry::new(&client_ip, &response)
+                        .request(&request)
+                        .request_id(&request_id)
+                        .elapsed(forward_started.elapsed())
+                        .error(connect_error_reason(&error));
+                    send_response(&mut client_conn, state, entry).await;
+                    return;
+                }
+            }
+            if !stale {
+                log::info!(
+                    "[{}] Retrying {} on {} (retry {} of {})",
+                    request_id,
+                    request::format_request_line(&request),
+                    upstream.address,
+                    retries,
+                    state.max_retries
+                );
+            }
+        };
+        // Nor do they know about chunked bodies, so one is read in full and sent with a
+        // Content-Length instead, its trailers (which would otherwise be lost) becoming headers
+        if client_version == http::Version::HTTP_10
+            && response::has_body(request.method(), response.status())
+            && response::is_chunked(&response)
+        {
+            let unchunked = response::read_unchunked(
+                &mut upstream.stream,
+                &mut response,
+                streamed.take(),
+                state.upstream_response_timeout,
+            )
+            .await;
+            if let Err(error) = unchunked {
+                log::error!(
+                    "[{}] Error reading chunked response from upstream {}: {:?}",
+                    request_id,
+                    upstream_ip,
+                    error
+                );
+                upstream.stats.record_failure();
+                record_request_outcome(state, group, &upstream.address, false);
+                let mut response = state
+                    .error_pages
+                    .make_http_error(http::StatusCode::BAD_GATEWAY);
+                request_id::set(response.headers_mut(), &request_id);
+                let entry = AccessLogEntry::new(&client_ip, &response)
+                    .request(&request)
+                    .request_id(&request_id)
+                    .upstream(&upstream.address)
+                    .elapsed(forward_started.elapsed())
+                    .error("upstream_error");
+                send_response(&mut client_conn, state, entry).await;
+                return;
+            }
+        }
+        if log::log_enabled!(target: HTTP_TRACE, log::Level::Trace) {
+            log::trace!(
+                target: HTTP_TRACE,
+                "[{}] Response from {}:\n{}",
+                request_id,
+                upstream.address,
+                response::dump(&response, &state.trace_settings)
+            );
+        }
+        // HTTP/1.0 clients don't know about interim responses, so they never get them
+        if !state.drop_interim_responses && client_version == http::Version::HTTP_11 {
+            for mut early in interim {
+                keep_alive::strip(early.headers_mut());
+                *early.version_mut() = http::Version::HTTP_11;
+                if let Err(error) = response::write_to_stream(&early, client_conn.get_mut()).await {
+                    log::warn!(
+                        "[{}] Failed to send interim response to client: {}",
+                        request_id,
+                        error
+                    );
+                }
+            }
+        }
+        upstream.stats.record_response(response.status());
+        record_request_outcome(
+            state,
+            group,
+            &upstream.address,
+            !response.status().is_server_error(),
+        );
+        let upstream_address = upstream.address.clone();
+        let upstream_stats = upstream.stats.clone();
+        // An upstream that answered without the body may still be waiting for it
+        let reusable = !body_pending && pool::can_reuse(&request, &response);
+        // (Re-)issue the sticky session cookie if the client isn't already pinned to this upstream
+        if let Some(name) = &state.sticky_cookie {
+            let key = upstream::session_key(&upstream_address);
+            if session.as_deref() != Some(key.as_str()) {
+                let cookie = format!("{}={}; Path=/; HttpOnly", name, key);
+                match http::HeaderValue::from_str(&cookie) {
+                    Ok(value) => {
+                        response
+                            .headers_mut()
+                            .append(http::header::SET_COOKIE, value);
+                    }
+                    Err(_) => log::warn!(
+                        "[{}] Invalid sticky session cookie {:?}",
+                        request_id,
+                        cookie
+                    ),
+                }
+            }
+        }
+        request_id::set(response.headers_mut(), &request_id);
+        let upstream_version = response.version();
+        via::append(
+            response.headers_mut(),
+            upstream_version,
+            &state.via_pseudonym,
+        );
+        state.response_header_rules.apply(response.headers_mut());
+        // The upstream agreed to switch to another protocol (e.g. WebSocket), so from here on the
+        // connection is no longer HTTP, and no longer rate limited
+        if response.status() == http::StatusCode::SWITCHING_PROTOCOLS {
+            state.request_duration.observe(forward_started.elapsed());
+            let entry = AccessLogEntry::new(&client_ip, &response)
+                .request(&request)
+                .request_id(&request_id)
+                .upstream(&upstream_address)
+                .elapsed(forward_started.elapsed());
+            let (bytes_in, bytes_out) = send_response(&mut client_conn, state, entry).await;
+            upstream_stats.record_bytes(bytes_in, bytes_out);
+            // The tunnel may stay open indefinitely, and isn't a request any more
+            drop(permit);
+            drop(upstream.slot);
+            tunnel(&mut client_conn, upstream.stream, Duration::from_secs(0)).await;
+            let (bytes_in, bytes_out) = count_request_bytes(state, &mut client_conn);
+            upstream_stats.record_bytes(bytes_in, bytes_out);
+            return;
+        }
+        // Whether the upstream keeps its connection open is up to it and us (see `reusable`); the
+        // client's connection stays open unless the client asked otherwise, we're shutting down,
+        // or the end of the body can only be marked by hanging up.
+        keep_alive::strip(response.headers_mut());
+        *response.version_mut() = http::Version::HTTP_11;
+        let closing = client_closing
+            || *shutdown.borrow()
+            || matches!(streamed, Some(response::StreamedBody::UntilClose));
+        keep_alive::set_connection_header(response.headers_mut(), client_version, closing);
+        // Forward the response to the client
+        match streamed {
+            None => {
+                let elapsed = forward_started.elapsed();
+                state.request_duration.observe(elapsed);
+                if reusable {
+                    return_to_pool(state, group, upstream);
+                }
+                // Only bodies read in full are compressed, in a blocking task since big ones
+                // take a while. If gzip doesn't make one smaller, it is sent as it was.
+                if state.compression.should_compress(&response) {
+                    compress::add_vary(response.headers_mut());
+                    if client_accepts_gzip {
+                        let body = std::mem::take(response.body_mut());
+                        let (body, compressed) = tokio::task::spawn_blocking(move || {
+                            let compressed = compress::gzip(&body);
+                            (body, compressed)
+                        })
+                        .await
+                        .expect("gzip panicked");
+                        if compressed.len() < body.len() {
+                            compress::set_gzipped_body(&mut response, compressed);
+                        } else {
+                            *response.body_mut() = body;
+                        }
+                    }
+                }
+                let entry = AccessLogEntry::new(&client_ip, &response)
+                    .request(&request)
+                    .request_id(&request_id)
+                    .upstream(&upstream_address)
+                    .elapsed(elapsed);
+                let (bytes_in, bytes_out) = send_response(&mut client_conn, state, entry).await;
+                upstream_stats.record_bytes(bytes_in, bytes_out);
+            }
+            // Send the headers now, and the rest of the body as it comes in from the upstream
+            Some(body) => {
+                if let Err(error) =
+                    response::write_to_stream(&response, client_conn.get_mut()).await
+                {
+                    log::warn!(
+                        "[{}] Failed to send response to client: {}",
+                        request_id,
+                        error
+                    );
+                    return;
+                }
+                let copied = response::copy_body(
+                    body,
+                    &mut upstream.stream,
+                    client_conn.get_mut(),
+                    state.upstream_response_timeout,
+                )
+                .await;
+                let elapsed = forward_started.elapsed();
+                state.request_duration.observe(elapsed);
+                let (bytes_in, bytes_out) = count_request_bytes(state, &mut client_conn);
+                upstream_stats.record_bytes(bytes_in, bytes_out);
+                let entry = AccessLogEntry::new(&client_ip, &response)
+                    .request(&request)
+                    .request_id(&request_id)
+                    .upstream(&upstream_address)
+                    .elapsed(elapsed)
+                    .bytes(bytes_in, bytes_out);
+                match copied {
+                    Ok(copied) => {
+                        entry
+                            .response_bytes(response.body().len() + copied)
+                            .log(state.access_log_format);
+                        if reusable {
+                            return_to_pool(state, group, upstream);
+                        }
+                    }
+                    // The client already has the headers, so all we can do is hang up on it
+                    Err(error) => {
+                        log::error!(
+                            "[{}] Failed to stream response body from upstream {}: {:?}",
+                            request_id,
+                            upstream_address,
+                            error
+                        );
+                        entry
+                            .error("response_cut_short")
+                            .log(state.access_log_format);
+                        return;
+                    }
+                }
+            }
+        }
+        log::debug!("[{}] Forwarded response to client", request_id);
+        if body_pending {
+            discard_rest(&mut client_conn).await;
+        }
+        if closing {
+            return;
+        }
+    }
+}
+
+/// Stops sending on a connection whose client may still be sending a request we won't read, then
+/// reads and throws away whatever else it sends, for a little while. Hanging up with data unread
+/// would reset the connection, which can destroy the response before the client has read it.
+async fn discard_rest<S>(client_conn: &mut BufReader<S>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if client_conn.get_mut().shutdown().await.is_err() {
+        return;
+    }
+    let mut buffer = [0_u8; 8192];
+    let mut discarded = 0;
+    let _ = timeout(DISCARD_TIMEOUT, async {
+        while discarded < MAX_DISCARD_BYTES {
+            match client_conn.read(&mut buffer).await {
+                Ok(0) | Err(_) => return,
+                Ok(bytes_read) => discarded += bytes_read,
+            }
+        }
+    })
+    .await;
+}
+
+/// Connects to the `host:port` a CONNECT request names, if --allow-connect lets it through.
+/// Otherwise returns the status to answer with and the reason to log.
+async fn open_connect_tunnel(
+    state: &ProxyState,
+    request: &http::Request<Vec<u8>>,
+) -> Result<tokio::net::TcpStream, (http::StatusCode, &'static str)> {
+    if !state.allow_connect {
+        return Err((http::StatusCode::NOT_IMPLEMENTED, "connect_not_allowed"));
+    }
+    // The parser only lets through CONNECTs to a host and port
+    let host = request.uri().host().unwrap_or("");
+    let port = request.uri().port_u16().unwrap_or(0);
+    if !state.connect_ports.contains(&port) {
+        return Err((http::StatusCode::FORBIDDEN, "connect_port_denied"));
+    }
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match timeout(
+        state.upstream_connect_timeout,
+        tokio::net::TcpStream::connect((host, port)),
+    )
+    .await
+    {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(error)) => {
+            log::info!("Could not open a tunnel to {}: {}", request.uri(), error);
+            Err((http::StatusCode::BAD_GATEWAY, "connect_failed"))
+        }
+        Err(_elapsed) => Err((http::StatusCode::GATEWAY_TIMEOUT, "connect_timeout")),
+    }
+}
+
+/// Copies bytes both ways between the client and the upstream, untouched, until either side hangs
+/// up, or (unless `idle_timeout` is zero) neither has sent anything for `idle_timeout`.
+async fn tunnel<S, U>(client_conn: &mut BufReader<S>, mut upstream: U, idle_timeout: Duration)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    // Whatever the client sent right behind its upgrade (or CONNECT) request has been read into
+    // the buffer
+    let buffered = client_conn.buffer().to_vec();
+    Pin::new(&mut *client_conn).consume(buffered.len());
+    let client = client_conn.get_mut();
+    if let Err(error) = upstream.write_all(&buffered).await {
+        log::info!("Failed to forward to upgraded connection: {}", error);
+        return;
+    }
+    let last_active = Mutex::new(Instant::now());
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut upstream_read, mut upstream_write) = tokio::io::split(upstream);
+    let (direction, copied) = tokio::select! {
+        copied = pass_through(&mut client_read, &mut upstream_write, &last_active) => ("client", copied),
+        copied = pass_through(&mut upstream_read, &mut client_write, &last_active) => ("upstream", copied),
+        _ = tunnel_idle_for(&last_active, idle_timeout) => {
+            log::debug!("Closing tunnel, idle for {:?}", idle_timeout);
+            return;
+        }
+    };
+    match copied {
+        Ok(bytes) => log::debug!(
+            "Upgraded connection closed by the {} after {} bytes from it",
+            direction,
+            bytes
+        ),
+        Err(error) => log::info!("Upgraded connection failed: {}", error),
+    }
+}
+
+/// Resolves once nothing has gone through a tunnel for `limit` since `last_active`, or never if
+/// `limit` is zero.
+async fn tunnel_idle_for(last_active: &Mutex<Instant>, limit: Duration) {
+    if limit == Duration::from_secs(0) {
+        return std::future::pending().await;
+    }
+    loop {
+        let idle = last_active.lock().unwrap().elapsed();
+        if idle >= limit {
+            return;
+        }
+        delay_for(limit - idle).await;
+    }
+}
+
+/// Copies `from` into `to` until `from` hits EOF, returning how many bytes were copied, and noting
+/// in `last_active` when anything last went by. Unlike `tokio::io::copy`, this flushes after every
+/// write, so nothing sits in a TLS session's buffer while waiting for the other side.
+async fn pass_through<R, W>(
+    from: &mut R,
+    to: &mut W,
+    last_active: &Mutex<Instant>,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = [0_u8; 8192];
+    let mut copied = 0;
+    loop {
+        let bytes_read = from.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            return Ok(copied);
+        }
+        *last_active.lock().unwrap() = Instant::now();
+        to.write_all(&buffer[..bytes_read]).await?;
+        to.flush().await?;
+        copied += bytes_read as u64;
+    }
+}
+
+/// The request an active health check of the upstream at `authority` sends, as --health-check-method
+/// and friends describe it. Its Host is the upstream's host_override, if it has one, or else
+/// --health-check-host or the authority. The connection is only for the one probe, so it asks to
+/// close it.
+fn make_probe_request(
+    state: &ProxyState,
+    authority: &str,
+    host_override: Option<&str>,
+) -> http::Request<Vec<u8>> {
+    let body = state
+        .health_check_body
+        .clone()
+        .map(String::into_bytes)
+        .unwrap_or_default();
+    let mut request = http::Request::builder()
+        .method(state.health_check_method.clone())
+        .uri(&state.active_health_check_path)
+        .header(
+            "Host",
+            host_override
+                .or(state.health_check_host.as_deref())
+                .unwrap_or(authority),
+        )
+        .header("Connection", "close");
+    // Methods that usually have a body get a length even without one, since some servers want it
+    let method = &state.health_check_method;
+    if !body.is_empty()
+        || method == http::Method::POST
+        || method == http::Method::PUT
+        || method == http::Method::PATCH
+    {
+        request = request.header("Content-Length", body.len());
+    }
+    request.body(body).unwrap()
+}
+
+/// An upstream for probe_all to probe: which group's it is, and what check_server needs to reach it
+/// the way requests do.
+#[derive(Clone)]
+struct ProbeTarget {
+    group: usize,
+    address: String,
+    name: Option<String>,
+    server_name: Option<String>,
+    host_override: Option<String>,
+}
+
+impl ProbeTarget {
+    fn of(group: usize, upstream: &upstream::UpstreamInfo) -> ProbeTarget {
+        ProbeTarget {
+            group,
+            address: upstream.address.clone(),
+            name: upstream.name.clone(),
+            server_name: upstream.server_name(),
+            host_override: upstream.host_override.clone(),
+        }
+    }
+}
+
+/// Probes an upstream with a GET of the health check path. Returns whether it passed, and how long
+/// it took to answer, connecting included, if it answered at all.
+async fn check_server(target: &ProbeTarget, state: &ProxyState) -> (bool, Option<Duration>) {
+    let started = Instant::now();
+    let connect =
+        state
+            .upstream_connector
+            .connect(&target.address, target.server_name.as_deref(), None);
+    if let Ok(mut stream) = connect.await {
+        let (_, authority) =
+            upstream::split_scheme(target.name.as_deref().unwrap_or(&target.address));
+        let request = make_probe_request(state, authority, target.host_override.as_deref());
+        if request::write_to_stream(&request, &mut stream)
+            .await
+            .is_ok()
+        {
+            if let Ok(resp) =
+                response::read_from_stream(&mut stream, request.method(), state.header_limits).await
+            {
+                let passed = state.health_check_expect.contains(resp.status());
+                return (passed, Some(started.elapsed()));
+            }
+        }
+    }
+    (false, None)
+}
+
+/// Probes each upstream whenever its own ProbeSchedule says so. Upstreams the checker hasn't seen
+/// before (including newly added ones) get their first probe an interval after it notices them,
+/// which it does at least once an interval. Newly resolved addresses are the exception: they are
+/// due a probe right away, and resolve_upstreams wakes the checker for them.
+async fn active_health_check(state: Arc<ProxyState>) {
+    let mut rng = rand::rngs::StdRng::from_entropy();
+    let mut wake = Instant::now();
+    loop {
+        tokio::select! {
+            _ = delay_for(wake.saturating_duration_since(Instant::now())) => {}
+            _ = state.probe_now.notified() => {}
+        }
+        let interval =
+            Duration::from_secs(state.active_health_check_interval.load(Ordering::Relaxed) as u64);
+        let now = Instant::now();
+        // Each group's upstreams are probed (and marked up or down) separately, even an address
+        // that is in several groups
+        let mut targets: Vec<ProbeTarget> = Vec::new();
+        for (idx, group) in state.groups.iter().enumerate() {
+            let mut upstreams = group.upstreams.write().unwrap();
+            for upstream in upstreams.iter_mut() {
+                if upstream.probes.next().is_none() {
+                    upstream.probes.start(now, interval, &mut rng);
+                }
+            }
+            targets.extend(
+                upstreams
+                    .iter()
+                    .filter(|upstream| upstream.probes.is_due(now))
+                    .map(|upstream| ProbeTarget::of(idx, upstream)),
+            );
+        }
+        let results = probe_all(&state, &targets).await;
+        for (target, (passed, latency)) in targets.iter().zip(results) {
+            let (group, address) = (&state.groups[target.group], &target.address);
+            let too_slow = record_probe_latency(&state, group, address, latency);
+            let healthy = passed && !too_slow;
+            record_upstream_health(&state, group, address, healthy);
+            let mut upstreams = group.upstreams.write().unwrap();
+            if let Some(upstream) = upstreams.iter_mut().find(|info| &info.address == address) {
+                upstream.probes.record(
+                    Instant::now(),
+                    healthy,
+                    upstream.healthy,
+                    interval,
+                    state.health_check_max_backoff,
+                    &mut rng,
+                );
+            }
+        }
+
+        wake = state
+            .groups
+            .iter()
+            .flat_map(|group| {
+                let upstreams = group.upstreams.read().unwrap();
+                upstreams
+                    .iter()
+                    .filter_map(|upstream| upstream.probes.next())
+                    .collect::<Vec<_>>()
+            })
+            .chain(std::iter::once(Instant::now() + interval))
+            .min()
+            .unwrap();
+    }
+}
+
+/// Probes every one of `targets` at once, without holding the lock, so a slow upstream holds up
+/// neither the other probes nor connect_to_upstream. Returns each one's check_server result, in
+/// order; one that times out failed.
+async fn probe_all(
+    state: &Arc<ProxyState>,
+    targets: &[ProbeTarget],
+) -> Vec<(bool, Option<Duration>)> {
+    let probes: Vec<_> = targets
+        .iter()
+        .map(|target| {
+            let state = state.clone();
+            let target = target.clone();
+            tokio::spawn(async move {
+                let check = check_server(&target, &state);
+                timeout(state.health_check_timeout, check)
+                    .await
+                    .unwrap_or((false, None))
+            })
+        })
+        .collect();
+    let mut results = Vec::with_capacity(probes.len());
+    for probe in probes {
+        results.push(probe.await.unwrap_or((false, None)));
+    }
+    results
+}
+
+/// Whether to run check_upstreams_at_startup: if --startup-health-check says so, or else unless
+/// --no-startup-health-check says not to, when active health checks were set up (their interval or
+/// path given on the command line or in the config file).
+fn wants_startup_health_check(options: &CmdOptions, matches: &clap::ArgMatches) -> bool {
+    let configured = options.active_health_checks_configured
+        || ["active-health-check-interval", "active-health-check-path"]
+            .iter()
+            .any(|id| matches.value_source(id) == Some(clap::parser::ValueSource::CommandLine));
+    options.startup_health_check || configured && !options.no_startup_health_check
+}
+
+/// Health checks every upstream once, all together, so the whole round takes at most
+/// --health-check-timeout. Those that fail start out down, to come back the way any down upstream
+/// does, rather than being found out by the first requests sent to them. Returns how many passed.
+async fn check_upstreams_at_startup(state: &Arc<ProxyState>) -> usize {
+    let mut targets: Vec<ProbeTarget> = Vec::new();
+    for (idx, group) in state.groups.iter().enumerate() {
+        let upstreams = group.upstreams.read().unwrap();
+        targets.extend(
+            upstreams
+                .iter()
+                .map(|upstream| ProbeTarget::of(idx, upstream)),
+        );
+    }
+    let results = probe_all(state, &targets).await;
+    let mut passed = 0;
+    for (target, (ok, latency)) in targets.iter().zip(results) {
+        let (group, address) = (&state.groups[target.group], &target.address);
+        let too_slow = record_probe_latency(state, group, address, latency);
+        let healthy = ok && !too_slow;
+        let mut upstreams = group.upstreams.write().unwrap();
+        if let Some(upstream) = upstreams.iter_mut().find(|info| &info.address == address) {
+            if healthy {
+                passed += 1;
+            } else {
+                log::warn!(
+                    "Upstream {} failed its startup health check; starting it out down",
+                    address
+                );
+                upstream.healthy = false;
+                upstream.streak = Streak::default();
+            }
+        }
+    }
+    log::info!(
+        "{} of {} upstreams passed their startup health check",
+        passed,
+        targets.len()
+    );
+    passed
+}
+
+/// Records how long a probe of `address` took, if it was answered at all, and logs the upstream's
+/// recent latencies. Returns whether they are too slow for --latency-eject-ms, in which case the
+/// probe counts as failed.
+fn record_probe_latency(
+    state: &ProxyState,
+    group: &UpstreamGroup,
+    address: &str,
+    latency: Option<Duration>,
+) -> bool {
+    let mut upstreams = group.upstreams.write().unwrap();
+    let upstream = match upstreams.iter_mut().find(|info| info.address == address) {
+        Some(upstream) => upstream,
+        None => return false,
+    };
+    if let Some(latency) = latency {
+        upstream.probe_latencies.record(latency);
+    }
+    let summary = match upstream.probe_latencies.summary() {
+        Some(summary) => summary,
+        None => return false,
+    };
+    let too_slow = state
+        .latency_ejection
+        .is_too_slow(&upstream.probe_latencies);
+    log::info!(
+        "Upstream {} health check latency: p50 {:?}, p95 {:?}, max {:?}{}",
+        address,
+        summary.p50,
+        summary.p95,
+        summary.max,
+        if too_slow { " (too slow)" } else { "" }
+    );
+    too_slow
+}
+
+/// Windows reset on their own when a client's next request comes in; this just forgets clients
+/// whose window has run out, so the map doesn't grow forever.
+async fn rate_limiting_refresh(state: Arc<ProxyState>) {
+    loop {
+        delay_for(rate_limit::WINDOW).await;
+        state.rate_limiter.forget_expired(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn state_with_args(args: &[&str]) -> ProxyState {
+        let options =
+            CmdOptions::try_parse_from(std::iter::once("balancebeam").chain(args.iter().copied()))
+                .unwrap();
+        let connector = UpstreamConnector::new(None, true, None).unwrap();
+        ProxyState::new(&options, connector, ErrorPages::default())
+    }
+
+    /// Not a test so much as a benchmark of upstream selection under contention, run with `cargo
+    /// test --release -- --ignored --nocapture bench_`.
+    #[tokio::test(threaded_scheduler)]
+    #[ignore]
+    async fn bench_pick_upstream() {
+        const TASKS: usize = 100;
+        const PICKS: usize = 2000;
+        let upstreams: Vec<String> = (0..8).map(|n| format!("10.0.0.{}:80", n)).collect();
+        for strategy in &["random", "round-robin", "ip-hash"] {
+            let mut args = vec!["--strategy", strategy];
+            for upstream in &upstreams {
+                args.extend_from_slice(&["--upstream", upstream]);
+            }
+            let state = Arc::new(state_with_args(&args));
+            let started = Instant::now();
+            let tasks: Vec<_> = (0..TASKS)
+                .map(|task| {
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        let mut rng = rand::rngs::StdRng::seed_from_u64(task as u64);
+                        let client_ip = format!("192.168.0.{}", task);
+                        for _ in 0..PICKS {
+                            pick_upstream(
+                                &state,
+                                &state.groups[0],
+                                &client_ip,
+                                None,
+                                &[],
+                                &mut rng,
+                            )
+                            .unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for task in tasks {
+                task.await.unwrap();
+            }
+            let per_pick = started.elapsed() / (TASKS * PICKS) as u32;
+            println!(
+                "{}: {:?} per pick, {} tasks picking at once",
+                strategy, per_pick, TASKS
+            );
+        }
+    }
+
+    #[test]
+    fn test_pick_upstream_skips_attempted() {
+        let state = state_with_args(&["--upstream", "10.0.0.1:80", "--upstream", "10.0.0.2:80"]);
+        let group = &state.groups[0];
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let attempted = vec![String::from("10.0.0.1:80")];
+        for _ in 0..20 {
+            let picked =
+                pick_upstream(&state, group, "127.0.0.1", None, &attempted, &mut rng).unwrap();
+            assert_eq!(picked.address, "10.0.0.2:80");
+        }
+        let attempted = vec![String::from("10.0.0.1:80"), String::from("10.0.0.2:80")];
+        assert!(matches!(
+            pick_upstream(&state, group, "127.0.0.1", None, &attempted, &mut rng),
+            Err(UpstreamError::AllDown)
+        ));
+    }
+
+    #[test]
+    fn test_pick_upstream_skips_saturated() {
+        let state = state_with_args(&[
+            "--upstream",
+            "10.0.0.1:80",
+            "--upstream",
+            "10.0.0.2:80",
+            "--max-requests-per-upstream",
+            "1",
+        ]);
+        let group = &state.groups[0];
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let first = pick_upstream(&state, group, "127.0.0.1", None, &[], &mut rng).unwrap();
+        let second = pick_upstream(&state, group, "127.0.0.1", None, &[], &mut rng).unwrap();
+        assert_ne!(first.address, second.address);
+        let error = pick_upstream(&state, group, "127.0.0.1", None, &[], &mut rng).unwrap_err();
+        assert!(matches!(error, UpstreamError::Saturated));
+        let response = make_connect_error_response(&state, &error);
+        assert_eq!(response.status(), 503);
+        assert_eq!(response.headers()["retry-after"], "1");
+        // Finishing a request frees its upstream's slot
+        let freed = first.address.clone();
+        drop(first);
+        for _ in 0..5 {
+            let picked = pick_upstream(&state, group, "127.0.0.1", None, &[], &mut rng).unwrap();
+            assert_eq!(picked.address, freed);
+        }
+        // Upstreams that are down don't count as saturated
+        drop(second);
+        group.upstreams.write().unwrap()[0].healthy = false;
+        group.upstreams.write().unwrap()[1].healthy = false;
+        let error = pick_upstream(&state, group, "127.0.0.1", None, &[], &mut rng).unwrap_err();
+        assert!(matches!(error, UpstreamError::AllDown));
+    }
+
+    #[test]
+    fn test_pick_upstream_skips_draining() {
+        let args = ["--upstream", "10.0.0.1:80", "--upstream", "10.0.0.2:80"];
+        let state = state_with_args(&args);
+        let group = &state.groups[0];
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        group.upstreams.write().unwrap()[0].draining = true;
+        for _ in 0..20 {
+            let picked = pick_upstream(&state, group, "127.0.0.1", None, &[], &mut rng).unwrap();
+            assert_eq!(picked.address, "10.0.0.2:80");
+        }
+        // Even for clients pinned to it
+        let pinned = upstream::session_key("10.0.0.1:80");
+        let picked =
+            pick_upstream(&state, group, "127.0.0.1", Some(&pinned), &[], &mut rng).unwrap();
+        assert_eq!(picked.address, "10.0.0.2:80");
+        // With nothing else left, it's only used as a last resort
+        group.upstreams.write().unwrap()[1].healthy = false;
+        let error = pick_upstream(&state, group, "127.0.0.1", None, &[], &mut rng).unwrap_err();
+        assert!(matches!(error, UpstreamError::AllDown));
+        let state = state_with_args(&[&args[..], &["--drain-fallback"]].concat());
+        let group = &state.groups[0];
+        group.upstreams.write().unwrap()[0].draining = true;
+        group.upstreams.write().unwrap()[1].healthy = false;
+        let picked = pick_upstream(&state, group, "127.0.0.1", None, &[], &mut rng).unwrap();
+        assert_eq!(picked.address, "10.0.0.1:80");
+    }
+
+    #[tokio::test]
+    async fn test_connect_error_all_down_vs_refused() {
+        // Nothing listens on a port the OS just handed out and took back
+        let refusing = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let state = state_with_args(&[
+            "--upstream",
+            &refusing,
+            "--active-health-check-interval",
+            "7",
+        ]);
+        let group = &state.groups[0];
+        let client = ConnectionAddresses {
+            source: "127.0.0.1:40000".parse().unwrap(),
+            destination: "127.0.0.1:1100".parse().unwrap(),
+        };
+
+        // The upstream was up, but wouldn't take the connection
+        let refused = match connect_to_upstream(&state, group, client, None, false).await {
+            Err(error) => error,
+            Ok(_) => panic!("connected to {}", refusing),
+        };
+        match &refused {
+            UpstreamError::ConnectFailed { addr, .. } => assert_eq!(addr, &refusing),
+            other => panic!("expected ConnectFailed, got {:?}", other),
+        }
+        let response = make_connect_error_response(&state, &refused);
+        assert_eq!(response.status(), 502);
+        assert!(!response.headers().contains_key("retry-after"));
+        assert_eq!(connect_error_reason(&refused), "upstream_unavailable");
+
+        // Now that it's marked down, there's nothing to try at all
+        group.upstreams.write().unwrap()[0].healthy = false;
+        let down = match connect_to_upstream(&state, group, client, None, false).await {
+            Err(error) => error,
+            Ok(_) => panic!("connected to {}", refusing),
+        };
+        assert!(matches!(down, UpstreamError::AllDown));
+        let response = make_connect_error_response(&state, &down);
+        assert_eq!(response.status(), 503);
+        // Back after the next round of health checks
+        assert_eq!(response.headers()["retry-after"], "7");
+        assert_eq!(connect_error_reason(&down), "all_upstreams_down");
+    }
+
+    /// Answers lookups from records the test can change as it goes
+    #[derive(Debug)]
+    struct MockResolver {
+        records: Arc<Mutex<HashMap<String, Vec<IpAddr>>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Resolver for MockResolver {
+        async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<std::net::SocketAddr>> {
+            match self.records.lock().unwrap().get(host) {
+                Some(ips) => Ok(ips
+                    .iter()
+                    .map(|&ip| std::net::SocketAddr::new(ip, port))
+                    .collect()),
+                None => Err(io::Error::new(io::ErrorKind::NotFound, "no such host")),
+            }
+        }
+    }
+
+    /// The addresses pick_upstream picks over 50 tries, avoiding `attempted`
+    fn picked_addresses(state: &ProxyState, attempted: &[String]) -> Vec<String> {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut picked: Vec<String> = (0..50)
+            .map(|_| {
+                let picked = pick_upstream(
+                    state,
+                    &state.groups[0],
+                    "127.0.0.1",
+                    None,
+                    attempted,
+                    &mut rng,
+                )
+                .unwrap();
+                picked.address
+            })
+            .collect();
+        picked.sort();
+        picked.dedup();
+        picked
+    }
+
+    #[tokio::test]
+    async fn test_resolve_upstreams() {
+        let records = Arc::new(Mutex::new(HashMap::new()));
+        let set_records = |ips: &[&str]| {
+            records.lock().unwrap().insert(
+                String::from("api.internal"),
+                ips.iter().map(|ip| ip.parse().unwrap()).collect(),
+            );
+        };
+        let mut state = state_with_args(&[
+            "--upstream",
+            "api.internal:80=2",
+            "--upstream",
+            "10.0.0.9:80",
+        ]);
+        state.resolver = Box::new(MockResolver {
+            records: records.clone(),
+        });
+        let addresses = |state: &ProxyState| -> Vec<(String, bool)> {
+            state.groups[0]
+                .upstreams
+                .read()
+                .unwrap()
+                .iter()
+                .map(|info| (info.address.clone(), info.healthy))
+                .collect()
+        };
+        let not_ip = vec![String::from("10.0.0.9:80")];
+
+        // At startup, every address is in service right away, with the upstream's weight
+        set_records(&["10.0.1.1", "10.0.1.2"]);
+        resolve_upstreams(&state, true).await;
+        assert_eq!(
+            addresses(&state),
+            vec![
+                (String::from("10.0.1.1:80"), true),
+                (String::from("10.0.1.2:80"), true),
+                (String::from("10.0.0.9:80"), true),
+            ]
+        );
+        assert_eq!(state.groups[0].upstreams.read().unwrap()[1].weight, 2);
+        assert_eq!(
+            picked_addresses(&state, &not_ip),
+            vec!["10.0.1.1:80", "10.0.1.2:80"]
+        );
+
+        // A changed record drops the old address, and the new one waits for its probe
+        set_records(&["10.0.1.2", "10.0.1.3"]);
+        resolve_upstreams(&state, false).await;
+        assert_eq!(
+            addresses(&state),
+            vec![
+                (String::from("10.0.1.2:80"), true),
+                (String::from("10.0.1.3:80"), false),
+                (String::from("10.0.0.9:80"), true),
+            ]
+        );
+        assert_eq!(picked_addresses(&state, &not_ip), vec!["10.0.1.2:80"]);
+        record_upstream_health(&state, &state.groups[0], "10.0.1.3:80", true);
+        assert_eq!(
+            picked_addresses(&state, &not_ip),
+            vec!["10.0.1.2:80", "10.0.1.3:80"]
+        );
+        let picked = pick_upstream(
+            &state,
+            &state.groups[0],
+            "127.0.0.1",
+            None,
+            &not_ip,
+            &mut rand::rngs::StdRng::seed_from_u64(0),
+        )
+        .unwrap();
+        assert_eq!(picked.server_name.as_deref(), Some("api.internal:80"));
+
+        // A failed lookup changes nothing
+        records.lock().unwrap().clear();
+        resolve_upstreams(&state, false).await;
+        assert_eq!(addresses(&state).len(), 3);
+    }
+
+    #[test]
+    fn test_wants_startup_health_check() {
+        let wants = |args: &[&str]| {
+            let args = ["balancebeam", "--upstream", "10.0.0.1:80"]
+                .iter()
+                .chain(args);
+            let matches = CmdOptions::command().try_get_matches_from(args).unwrap();
+            let options = CmdOptions::from_arg_matches(&matches).unwrap();
+            wants_startup_health_check(&options, &matches)
+        };
+        assert!(!wants(&[]));
+        assert!(wants(&["--startup-health-check"]));
+        assert!(wants(&["--active-health-check-interval", "5"]));
+        assert!(wants(&["--active-health-check-path", "/healthz"]));
+        assert!(!wants(&[
+            "--active-health-check-interval",
+            "5",
+            "--no-startup-health-check"
+        ]));
+        assert!(CmdOptions::try_parse_from([
+            "balancebeam",
+            "--startup-health-check",
+            "--no-startup-health-check"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_probe_request() {
+        let state = state_with_args(&["--upstream", "10.0.0.1:80"]);
+        let request = make_probe_request(&state, "10.0.0.1:80", None);
+        assert_eq!(request.method(), http::Method::GET);
+        assert_eq!(request.uri(), "/");
+        assert_eq!(request.headers()["host"], "10.0.0.1:80");
+        assert_eq!(request.headers()["connection"], "close");
+        assert!(!request.headers().contains_key("content-length"));
+        assert!(request.body().is_empty());
+
+        let state = state_with_args(&[
+            "--upstream",
+            "10.0.0.1:80",
+            "--active-health-check-path",
+            "/healthz",
+            "--health-check-method",
+            "post",
+            "--health-check-host",
+            "health.internal",
+            "--health-check-body",
+            "{\"deep\": true}",
+        ]);
+        let request = make_probe_request(&state, "10.0.0.1:80", None);
+        assert_eq!(request.method(), http::Method::POST);
+        assert_eq!(request.uri(), "/healthz");
+        assert_eq!(request.headers()["host"], "health.internal");
+        assert_eq!(request.headers()["content-length"], "14");
+        assert_eq!(request.body(), b"{\"deep\": true}");
+        // An upstream's own host_override wins over --health-check-host
+        let request = make_probe_request(&state, "10.0.0.1:80", Some("app.internal"));
+        assert_eq!(request.headers()["host"], "app.internal");
+        assert!(
+            CmdOptions::try_parse_from(["balancebeam", "--health-check-method", "CONNECT"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_client_host_override() {
+        let mut headers = http::HeaderMap::new();
+        headers.insert("host", http::HeaderValue::from_static("example.com"));
+        let client_host = ClientHost::of(&headers);
+
+        client_host.apply(&mut headers, Some("app.internal"));
+        assert_eq!(headers["host"], "app.internal");
+        assert_eq!(headers["x-forwarded-host"], "example.com");
+        // Retried on an upstream without one, the request goes back to how the client sent it
+        client_host.apply(&mut headers, None);
+        assert_eq!(headers["host"], "example.com");
+        assert!(!headers.contains_key("x-forwarded-host"));
+
+        // Without a Host of its own, the client's X-Forwarded-Host is all there is to pass on
+        let mut headers = http::HeaderMap::new();
+        headers.insert(
+            "x-forwarded-host",
+            http::HeaderValue::from_static("edge.example.com"),
+        );
+        let client_host = ClientHost::of(&headers);
+        client_host.apply(&mut headers, Some("app.internal"));
+        assert_eq!(headers["host"], "app.internal");
+        assert_eq!(headers["x-forwarded-host"], "edge.example.com");
+    }
+}
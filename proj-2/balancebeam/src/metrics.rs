@@ -0,0 +1,259 @@
+use std::fmt::Write;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Upper bounds (in seconds) of the request duration histogram's buckets
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Traffic counters for one upstream, reported on the admin listener
+#[derive(Debug, Default)]
+pub struct UpstreamStats {
+    /// Responses the upstream sent, by status class (1xx through 5xx)
+    responses: [AtomicUsize; 5],
+    /// Connections to the upstream, and requests forwarded over them, that failed
+    failures: AtomicUsize,
+    /// What clients sent for the requests forwarded to the upstream, and were sent in answer
+    bytes: ByteCounters,
+}
+
+impl UpstreamStats {
+    pub fn record_response(&self, status: http::StatusCode) {
+        let class = (status.as_u16() / 100).clamp(1, 5) as usize;
+        self.responses[class - 1].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_failure(&self) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// How many requests the upstream has answered
+    pub fn requests(&self) -> usize {
+        self.responses
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// `(status class, count)` pairs, e.g. `("2xx", 10)`, including classes with no responses
+    pub fn responses_by_class(&self) -> impl Iterator<Item = (String, usize)> + '_ {
+        self.responses
+            .iter()
+            .enumerate()
+            .map(|(idx, count)| (format!("{}xx", idx + 1), count.load(Ordering::Relaxed)))
+    }
+
+    pub fn failures(&self) -> usize {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    pub fn record_bytes(&self, bytes_in: u64, bytes_out: u64) {
+        self.bytes.record(bytes_in, bytes_out);
+    }
+
+    pub fn bytes(&self) -> &ByteCounters {
+        &self.bytes
+    }
+}
+
+/// Bytes read from clients (in) and written to them (out), on the wire, so headers and all
+#[derive(Debug, Default)]
+pub struct ByteCounters {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+impl ByteCounters {
+    pub fn record(&self, bytes_in: u64, bytes_out: u64) {
+        self.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes_out, Ordering::Relaxed);
+    }
+
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+}
+
+/// Wraps a client connection to count the bytes read from and written to it. The counts are
+/// plain integers, since only the connection's own task touches them; they're handed over to
+/// ByteCounters a request at a time with `take_counts`.
+#[derive(Debug)]
+pub struct CountingStream<S> {
+    inner: S,
+    bytes_in: u64,
+    bytes_out: u64,
+    /// How much of each had already been taken
+    taken_in: u64,
+    taken_out: u64,
+}
+
+impl<S> CountingStream<S> {
+    pub fn new(inner: S) -> Self {
+        CountingStream {
+            inner,
+            bytes_in: 0,
+            bytes_out: 0,
+            taken_in: 0,
+            taken_out: 0,
+        }
+    }
+
+    /// The bytes read and written since the last call, as `(in, out)`, except for the last
+    /// `unconsumed` bytes read, which are sitting in a buffer for whatever reads next (say, a
+    /// pipelined request) and are left to be taken along with it.
+    pub fn take_counts(&mut self, unconsumed: usize) -> (u64, u64) {
+        let consumed_in = self.bytes_in - (unconsumed as u64).min(self.bytes_in);
+        let taken = (
+            consumed_in.saturating_sub(self.taken_in),
+            self.bytes_out - self.taken_out,
+        );
+        self.taken_in = self.taken_in.max(consumed_in);
+        self.taken_out = self.bytes_out;
+        taken
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let polled = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(bytes_read)) = polled {
+            self.bytes_in += bytes_read as u64;
+        }
+        polled
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let polled = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(bytes_written)) = polled {
+            self.bytes_out += bytes_written as u64;
+        }
+        polled
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// A Prometheus-style histogram of how long proxied requests take.
+#[derive(Debug, Default)]
+pub struct DurationHistogram {
+    /// Observations per bucket (not cumulative), plus one for those above the last bound
+    buckets: [AtomicU64; DURATION_BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl DurationHistogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Appends the histogram's `_bucket`, `_sum` and `_count` series to `out`.
+    pub fn write_to(&self, out: &mut String, name: &str) {
+        let mut cumulative = 0;
+        let bounds = DURATION_BUCKETS.iter().map(|bound| bound.to_string());
+        for (bound, count) in bounds
+            .chain(std::iter::once(String::from("+Inf")))
+            .zip(&self.buckets)
+        {
+            cumulative += count.load(Ordering::Relaxed);
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative).unwrap();
+        }
+        let sum = Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed));
+        writeln!(out, "{}_sum {}", name, sum.as_secs_f64()).unwrap();
+        writeln!(out, "{}_count {}", name, cumulative).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_responses_by_class() {
+        let stats = UpstreamStats::default();
+        for &status in &[200, 204, 301, 404, 500, 503, 502] {
+            stats.record_response(http::StatusCode::from_u16(status).unwrap());
+        }
+        let counts: Vec<(String, usize)> = stats.responses_by_class().collect();
+        assert_eq!(
+            counts,
+            vec![
+                (String::from("1xx"), 0),
+                (String::from("2xx"), 2),
+                (String::from("3xx"), 1),
+                (String::from("4xx"), 1),
+                (String::from("5xx"), 3),
+            ]
+        );
+        assert_eq!(stats.requests(), 7);
+    }
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let histogram = DurationHistogram::default();
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_millis(10));
+        histogram.observe(Duration::from_millis(700));
+        histogram.observe(Duration::from_secs(60));
+        let mut out = String::new();
+        histogram.write_to(&mut out, "duration");
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), DURATION_BUCKETS.len() + 3);
+        assert_eq!(lines[0], "duration_bucket{le=\"0.005\"} 1");
+        assert_eq!(lines[1], "duration_bucket{le=\"0.01\"} 2");
+        assert_eq!(lines[6], "duration_bucket{le=\"0.5\"} 2");
+        assert_eq!(lines[7], "duration_bucket{le=\"1\"} 3");
+        assert_eq!(lines[10], "duration_bucket{le=\"10\"} 3");
+        assert_eq!(lines[11], "duration_bucket{le=\"+Inf\"} 4");
+        assert_eq!(lines[12], "duration_sum 60.713");
+        assert_eq!(lines[13], "duration_count 4");
+    }
+
+    #[tokio::test]
+    async fn test_counting_stream() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut counted = CountingStream::new(client);
+        server.write_all(b"first\nsecond\n").await.unwrap();
+        let mut buffer = [0_u8; 13];
+        counted.read_exact(&mut buffer).await.unwrap();
+        counted.write_all(b"ok\n").await.unwrap();
+        // "second\n" was read along with the first line, but is still to be handled
+        assert_eq!(counted.take_counts(7), (6, 3));
+        assert_eq!(counted.take_counts(7), (0, 0));
+        counted.write_all(b"ok again\n").await.unwrap();
+        assert_eq!(counted.take_counts(0), (7, 9));
+        assert_eq!(counted.take_counts(0), (0, 0));
+    }
+}
@@ -0,0 +1,214 @@
+use crate::proxy_protocol::ConnectionAddresses;
+use crate::request::{self, HeaderLimits};
+use crate::response;
+use crate::tls::UpstreamConnector;
+use crate::upstream;
+use rand::Rng;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
+
+/// Most mirrored requests that may be waiting on mirrors at once. A mirror that is slow or hangs
+/// would otherwise pile up a task per request; past this, requests just aren't mirrored.
+const MAX_IN_FLIGHT: usize = 256;
+
+/// Parses a `--mirror-upstream`, written like an `--upstream` but without a weight, since
+/// requests aren't balanced across mirrors by anything more than turns.
+pub fn parse_mirror_upstream(arg: &str) -> Result<String, String> {
+    if arg.contains('=') {
+        return Err(format!("mirror upstream {} can't have a weight", arg));
+    }
+    Ok(upstream::parse_upstream(arg)?.address)
+}
+
+/// Parses a `--mirror-percent`, a whole percentage.
+pub fn parse_percent(arg: &str) -> Result<u32, String> {
+    match arg.parse::<u32>() {
+        Ok(percent) if percent <= 100 => Ok(percent),
+        _ => Err(format!("{:?} is not a percentage from 0 to 100", arg)),
+    }
+}
+
+/// Where copies of requests go to from --mirror-upstream, so a new backend can be tried out on
+/// real traffic. Copies are sent on their own connections, in tasks of their own, and whatever
+/// the mirrors answer (or don't) is thrown away: it never reaches the client, and never counts
+/// for or against the health of the upstreams that served the request.
+#[derive(Debug)]
+pub struct Mirror {
+    upstreams: Vec<String>,
+    /// Share of requests that are mirrored
+    percent: u32,
+    connector: UpstreamConnector,
+    connect_timeout: Duration,
+    response_timeout: Duration,
+    header_limits: HeaderLimits,
+    /// Which mirror gets the next copy, taken in turns
+    next: AtomicUsize,
+    in_flight: Arc<Semaphore>,
+}
+
+impl Mirror {
+    pub fn new(
+        upstreams: Vec<String>,
+        percent: u32,
+        connector: UpstreamConnector,
+        connect_timeout: Duration,
+        response_timeout: Duration,
+        header_limits: HeaderLimits,
+    ) -> Mirror {
+        Mirror {
+            upstreams,
+            percent,
+            connector,
+            connect_timeout,
+            response_timeout,
+            header_limits,
+            next: AtomicUsize::new(0),
+            in_flight: Arc::new(Semaphore::new(MAX_IN_FLIGHT)),
+        }
+    }
+
+    /// Whether to mirror the next request, drawing against --mirror-percent
+    pub fn samples<R: Rng>(&self, rng: &mut R) -> bool {
+        self.percent != 0 && rng.gen_range(0, 100) < self.percent
+    }
+
+    /// Sends a copy of `request`, made on behalf of the client at `client`, to the next mirror in
+    /// the background, unless too many copies are already out. Returns straight away.
+    pub fn send(self: &Arc<Self>, request: &http::Request<Vec<u8>>, client: ConnectionAddresses) {
+        let permit = match self.in_flight.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                log::debug!("Too many mirrored requests in flight, not mirroring");
+                return;
+            }
+        };
+        let idx = self.next.fetch_add(1, Ordering::Relaxed) % self.upstreams.len();
+        let address = self.upstreams[idx].clone();
+        let copy = copy_request(request);
+        let mirror = Arc::clone(self);
+        tokio::spawn(async move {
+            if let Err(error) = mirror.forward(&address, &copy, client).await {
+                log::debug!("Mirrored request to {} failed: {}", address, error);
+            }
+            drop(permit);
+        });
+    }
+
+    /// Sends `request` to the mirror at `address` and reads and discards its response
+    async fn forward(
+        &self,
+        address: &str,
+        request: &http::Request<Vec<u8>>,
+        client: ConnectionAddresses,
+    ) -> Result<(), String> {
+        let connect = self.connector.connect(address, None, Some(client));
+        let mut stream = match timeout(self.connect_timeout, connect).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(error)) => return Err(error.to_string()),
+            Err(_elapsed) => return Err(String::from("timed out connecting")),
+        };
+        request::write_to_stream(request, &mut stream)
+            .await
+            .map_err(|error| error.to_string())?;
+        let read = response::read_from_stream(&mut stream, request.method(), self.header_limits);
+        match timeout(self.response_timeout, read).await {
+            Ok(Ok(response)) => {
+                log::debug!("Mirror {} answered {}", address, response.status());
+                Ok(())
+            }
+            Ok(Err(error)) => Err(format!("{:?}", error)),
+            Err(_elapsed) => Err(String::from("timed out waiting for the response")),
+        }
+    }
+}
+
+/// The copy of a request that goes to a mirror: the same, but marked with `X-Mirrored: true` so
+/// the mirror can tell it apart from real traffic, and on a connection of its own that the mirror
+/// should close once it has answered.
+fn copy_request(request: &http::Request<Vec<u8>>) -> http::Request<Vec<u8>> {
+    let mut copy = http::Request::new(request.body().clone());
+    *copy.method_mut() = request.method().clone();
+    *copy.uri_mut() = request.uri().clone();
+    *copy.version_mut() = request.version();
+    *copy.headers_mut() = request.headers().clone();
+    let headers = copy.headers_mut();
+    headers.insert("x-mirrored", http::HeaderValue::from_static("true"));
+    headers.insert(
+        http::header::CONNECTION,
+        http::HeaderValue::from_static("close"),
+    );
+    copy
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn mirror(percent: u32) -> Mirror {
+        Mirror::new(
+            vec![String::from("127.0.0.1:1")],
+            percent,
+            UpstreamConnector::new(None, true, None).unwrap(),
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+            HeaderLimits {
+                max_bytes: 8000,
+                max_count: 100,
+            },
+        )
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse_mirror_upstream("10.0.0.1:80"),
+            Ok(String::from("10.0.0.1:80"))
+        );
+        assert_eq!(
+            parse_mirror_upstream("https://canary.internal:443"),
+            Ok(String::from("https://canary.internal:443"))
+        );
+        assert!(parse_mirror_upstream("10.0.0.1:80=2").is_err());
+        assert!(parse_mirror_upstream("ftp://10.0.0.1:21").is_err());
+        assert_eq!(parse_percent("0"), Ok(0));
+        assert_eq!(parse_percent("100"), Ok(100));
+        assert!(parse_percent("101").is_err());
+        assert!(parse_percent("12.5").is_err());
+    }
+
+    #[test]
+    fn test_samples() {
+        let mut rng = StdRng::seed_from_u64(0);
+        assert!((0..1000).all(|_| mirror(100).samples(&mut rng)));
+        assert!((0..1000).all(|_| !mirror(0).samples(&mut rng)));
+        let sampled = (0..1000).filter(|_| mirror(25).samples(&mut rng)).count();
+        assert!((150..350).contains(&sampled), "{}", sampled);
+    }
+
+    #[test]
+    fn test_copy_request() {
+        let request = http::Request::builder()
+            .method("POST")
+            .uri("/orders?id=7")
+            .header("Content-Length", "5")
+            .header("Connection", "keep-alive")
+            .header("X-Forwarded-For", "203.0.113.7")
+            .body(b"hello".to_vec())
+            .unwrap();
+        let copy = copy_request(&request);
+        assert_eq!(copy.method(), http::Method::POST);
+        assert_eq!(copy.uri(), "/orders?id=7");
+        assert_eq!(copy.body(), b"hello");
+        assert_eq!(copy.headers()["x-mirrored"], "true");
+        assert_eq!(copy.headers()["connection"], "close");
+        assert_eq!(copy.headers()["x-forwarded-for"], "203.0.113.7");
+        assert_eq!(copy.headers()["content-length"], "5");
+        // The original goes to the upstream as it was
+        assert!(!request.headers().contains_key("x-mirrored"));
+    }
+}
@@ -0,0 +1,170 @@
+use crate::keep_alive;
+use crate::response;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// A connection sitting in the pool, and when it was put there
+#[derive(Debug)]
+struct Idle<C> {
+    conn: C,
+    since: Instant,
+}
+
+/// Idle keep-alive connections to each upstream, so that a request can reuse one instead of
+/// opening a fresh TCP connection.
+#[derive(Debug)]
+pub struct ConnectionPool<C> {
+    /// How many idle connections to keep per upstream; any more are closed when checked in
+    max_idle: usize,
+    /// Connections idle for longer than this are closed instead of reused
+    idle_timeout: Duration,
+    /// Idle connections per upstream address, oldest first
+    idle: HashMap<String, Vec<Idle<C>>>,
+}
+
+impl<C> ConnectionPool<C> {
+    pub fn new(max_idle: usize, idle_timeout: Duration) -> Self {
+        ConnectionPool {
+            max_idle,
+            idle_timeout,
+            idle: HashMap::new(),
+        }
+    }
+
+    /// Takes the most recently used idle connection to an upstream out of the pool, closing any
+    /// that have been idle for too long. The upstream may still have closed the one returned.
+    pub fn checkout(&mut self, address: &str) -> Option<C> {
+        let idle_timeout = self.idle_timeout;
+        let idle = self.idle.get_mut(address)?;
+        idle.retain(|idle| idle.since.elapsed() < idle_timeout);
+        idle.pop().map(|idle| idle.conn)
+    }
+
+    /// Returns a connection that is done with its request and can take another. If the upstream
+    /// already has max_idle connections waiting, the oldest one is closed to make room.
+    pub fn checkin(&mut self, address: &str, conn: C) {
+        if self.max_idle == 0 {
+            return;
+        }
+        let idle = self.idle.entry(address.to_string()).or_default();
+        if idle.len() == self.max_idle {
+            idle.remove(0);
+        }
+        idle.push(Idle {
+            conn,
+            since: Instant::now(),
+        });
+    }
+
+    /// Closes every idle connection to an upstream that is going away.
+    pub fn forget(&mut self, address: &str) {
+        self.idle.remove(address);
+    }
+}
+
+/// Whether the upstream connection a request and its response went over can carry another
+/// request: neither side asked to close it (or, for HTTP/1.0, failed to ask to keep it), and the
+/// end of the response wasn't marked by the upstream hanging up.
+pub fn can_reuse(request: &http::Request<Vec<u8>>, response: &http::Response<Vec<u8>>) -> bool {
+    let has_body = response::has_body(request.method(), response.status());
+    !keep_alive::wants_close(request.version(), request.headers())
+        && !keep_alive::wants_close(response.version(), response.headers())
+        && (!has_body || response.headers().contains_key("content-length"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_checkout_reuses_most_recent_connection() {
+        let mut pool = ConnectionPool::new(4, Duration::from_secs(60));
+        assert_eq!(pool.checkout("one:80"), None);
+        pool.checkin("one:80", "a");
+        pool.checkin("one:80", "b");
+        pool.checkin("two:80", "c");
+        assert_eq!(pool.checkout("one:80"), Some("b"));
+        assert_eq!(pool.checkout("one:80"), Some("a"));
+        assert_eq!(pool.checkout("one:80"), None);
+        assert_eq!(pool.checkout("two:80"), Some("c"));
+    }
+
+    #[test]
+    fn test_max_idle_closes_oldest() {
+        let mut pool = ConnectionPool::new(2, Duration::from_secs(60));
+        pool.checkin("one:80", 1);
+        pool.checkin("one:80", 2);
+        pool.checkin("one:80", 3);
+        assert_eq!(pool.checkout("one:80"), Some(3));
+        assert_eq!(pool.checkout("one:80"), Some(2));
+        assert_eq!(pool.checkout("one:80"), None);
+
+        let mut pool = ConnectionPool::new(0, Duration::from_secs(60));
+        pool.checkin("one:80", 1);
+        assert_eq!(pool.checkout("one:80"), None);
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let mut pool = ConnectionPool::new(4, Duration::from_millis(50));
+        pool.checkin("one:80", 1);
+        std::thread::sleep(Duration::from_millis(100));
+        pool.checkin("one:80", 2);
+        assert_eq!(pool.checkout("one:80"), Some(2));
+        assert_eq!(pool.checkout("one:80"), None);
+    }
+
+    #[test]
+    fn test_forget_closes_idle_connections() {
+        let mut pool = ConnectionPool::new(4, Duration::from_secs(60));
+        pool.checkin("one:80", 1);
+        pool.checkin("two:80", 2);
+        pool.forget("one:80");
+        assert_eq!(pool.checkout("one:80"), None);
+        assert_eq!(pool.checkout("two:80"), Some(2));
+    }
+
+    fn request(method: http::Method, connection: Option<&str>) -> http::Request<Vec<u8>> {
+        let mut request = http::Request::builder().method(method).uri("/");
+        if let Some(connection) = connection {
+            request = request.header("Connection", connection);
+        }
+        request.body(Vec::new()).unwrap()
+    }
+
+    fn response(status: u16, headers: &[(&str, &str)]) -> http::Response<Vec<u8>> {
+        let mut response = http::Response::builder().status(status);
+        for (name, value) in headers {
+            response = response.header(*name, *value);
+        }
+        response.body(Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn test_can_reuse() {
+        let get = request(http::Method::GET, None);
+        assert!(can_reuse(&get, &response(200, &[("Content-Length", "0")])));
+        assert!(can_reuse(&get, &response(204, &[])));
+        assert!(can_reuse(
+            &request(http::Method::HEAD, Some("keep-alive")),
+            &response(200, &[])
+        ));
+        // Body runs until the upstream hangs up
+        assert!(!can_reuse(&get, &response(200, &[])));
+        assert!(!can_reuse(
+            &request(http::Method::GET, Some("TE, Close")),
+            &response(200, &[("Content-Length", "0")])
+        ));
+        assert!(!can_reuse(
+            &get,
+            &response(200, &[("Content-Length", "0"), ("Connection", "close")])
+        ));
+        // An HTTP/1.0 upstream closes unless it says otherwise
+        let mut old = response(200, &[("Content-Length", "0")]);
+        *old.version_mut() = http::Version::HTTP_10;
+        assert!(!can_reuse(&get, &old));
+        old.headers_mut()
+            .insert("Connection", http::HeaderValue::from_static("keep-alive"));
+        assert!(can_reuse(&get, &old));
+    }
+}
@@ -0,0 +1,451 @@
+use serde::Deserialize;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The longest a v1 header can be, CRLF included
+const MAX_V1_HEADER_SIZE: usize = 107;
+/// Every v2 header starts with this
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Which PROXY protocol header balancebeam sends to upstreams
+#[derive(clap::ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Version {
+    /// The human-readable text header
+    V1,
+    /// The binary header
+    V2,
+}
+
+/// The two ends of a client's connection to balancebeam
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectionAddresses {
+    /// The client (as conveyed by a PROXY header of its own, if the listener accepts those)
+    pub source: SocketAddr,
+    /// The address the client connected to
+    pub destination: SocketAddr,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The connection didn't start with a valid PROXY protocol header
+    MalformedHeader(&'static str),
+    /// Encountered an I/O error when reading the header (including the client hanging up partway)
+    ConnectionError(std::io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::MalformedHeader(reason) => write!(f, "{}", reason),
+            Error::ConnectionError(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::ConnectionError(error)
+    }
+}
+
+/// Reads the PROXY protocol header (v1 or v2) that a load balancer in front of balancebeam sends
+/// at the start of each connection, and returns the client address it conveys. That is None when
+/// the header doesn't name a client (v1 `UNKNOWN`, v2 `LOCAL` or a non-IP address family), in
+/// which case the connection's own peer address stands. Exactly the header is consumed, leaving
+/// the client's first request unread on the stream.
+pub async fn read_header<S>(stream: &mut S) -> Result<Option<SocketAddr>, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    // "PROXY " and the start of the v2 signature are the same length
+    let mut start = [0_u8; 6];
+    stream.read_exact(&mut start).await?;
+    if &start == b"PROXY " {
+        read_v1(stream).await
+    } else if start == V2_SIGNATURE[..6] {
+        read_v2(stream).await
+    } else {
+        Err(Error::MalformedHeader("missing PROXY protocol signature"))
+    }
+}
+
+/// Reads the rest of a v1 header, e.g. `TCP4 203.0.113.7 10.0.0.1 56324 80\r\n`.
+async fn read_v1<S>(stream: &mut S) -> Result<Option<SocketAddr>, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    // Byte by byte, so as not to read past the CRLF into the request
+    let mut line = Vec::new();
+    let mut byte = [0_u8; 1];
+    while !line.ends_with(b"\r\n") {
+        if line.len() + "PROXY ".len() >= MAX_V1_HEADER_SIZE {
+            return Err(Error::MalformedHeader("v1 header too long"));
+        }
+        stream.read_exact(&mut byte).await?;
+        line.push(byte[0]);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| Error::MalformedHeader("v1 header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    let parse_ip = |field: &str| {
+        field
+            .parse::<IpAddr>()
+            .map_err(|_| Error::MalformedHeader("invalid v1 address"))
+    };
+    let parse_port = |field: &str| {
+        field
+            .parse::<u16>()
+            .map_err(|_| Error::MalformedHeader("invalid v1 port"))
+    };
+    match fields[..] {
+        ["UNKNOWN", ..] => Ok(None),
+        [protocol @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            let source = parse_ip(source)?;
+            let destination = parse_ip(destination)?;
+            parse_port(destination_port)?;
+            if source.is_ipv4() != (protocol == "TCP4") || destination.is_ipv4() != source.is_ipv4()
+            {
+                return Err(Error::MalformedHeader("v1 address doesn't match protocol"));
+            }
+            Ok(Some(SocketAddr::new(source, parse_port(source_port)?)))
+        }
+        _ => Err(Error::MalformedHeader("malformed v1 header")),
+    }
+}
+
+/// Reads the rest of a v2 header, whose first six bytes have been read already.
+async fn read_v2<S>(stream: &mut S) -> Result<Option<SocketAddr>, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    // The rest of the signature, version and command, family and transport, and length
+    let mut header = [0_u8; 10];
+    stream.read_exact(&mut header).await?;
+    if header[..6] != V2_SIGNATURE[6..] {
+        return Err(Error::MalformedHeader("missing PROXY protocol signature"));
+    }
+    let (version, command) = (header[6] >> 4, header[6] & 0x0f);
+    let (family, transport) = (header[7] >> 4, header[7] & 0x0f);
+    let len = u16::from_be_bytes([header[8], header[9]]) as usize;
+    if version != 2 {
+        return Err(Error::MalformedHeader("unsupported version"));
+    }
+    // The addresses, followed by TLVs we have no use for
+    let mut addresses = vec![0_u8; len];
+    stream.read_exact(&mut addresses).await?;
+    match command {
+        // A health check from the load balancer itself
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(Error::MalformedHeader("unsupported command")),
+    }
+    if transport > 2 {
+        return Err(Error::MalformedHeader("unsupported transport"));
+    }
+    let port = |offset: usize| u16::from_be_bytes([addresses[offset], addresses[offset + 1]]);
+    match family {
+        // AF_INET: source and destination addresses, then source and destination ports
+        0x1 if len >= 12 => {
+            let mut source = [0_u8; 4];
+            source.copy_from_slice(&addresses[..4]);
+            Ok(Some(SocketAddr::new(
+                Ipv4Addr::from(source).into(),
+                port(8),
+            )))
+        }
+        // AF_INET6, laid out the same way
+        0x2 if len >= 36 => {
+            let mut source = [0_u8; 16];
+            source.copy_from_slice(&addresses[..16]);
+            Ok(Some(SocketAddr::new(
+                Ipv6Addr::from(source).into(),
+                port(32),
+            )))
+        }
+        0x1 | 0x2 => Err(Error::MalformedHeader("v2 address block too short")),
+        // AF_UNSPEC or AF_UNIX, neither of which names a client we can use
+        0x0 | 0x3 => Ok(None),
+        _ => Err(Error::MalformedHeader("unsupported address family")),
+    }
+}
+
+/// Builds the header that starts each connection to an upstream, conveying the client's
+/// `addresses`, or if None, that the connection is balancebeam's own (e.g. a health check).
+pub fn encode_header(version: Version, addresses: Option<ConnectionAddresses>) -> Vec<u8> {
+    // Both ends go in the same family, so an IPv4 client on an IPv6 listener is sent mapped
+    let addresses = addresses.map(
+        |addresses| match (addresses.source, addresses.destination) {
+            (SocketAddr::V4(_), SocketAddr::V6(_)) | (SocketAddr::V6(_), SocketAddr::V4(_)) => {
+                let to_v6 = |address: SocketAddr| match address.ip() {
+                    IpAddr::V4(ip) => SocketAddr::new(ip.to_ipv6_mapped().into(), address.port()),
+                    IpAddr::V6(_) => address,
+                };
+                (to_v6(addresses.source), to_v6(addresses.destination))
+            }
+            (source, destination) => (source, destination),
+        },
+    );
+    match version {
+        Version::V1 => match addresses {
+            Some((source, destination)) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes(),
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        Version::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            let mut block = Vec::new();
+            match addresses {
+                Some((source, destination)) => {
+                    // PROXY over TCP, from AF_INET or AF_INET6
+                    header.push(0x21);
+                    match (source.ip(), destination.ip()) {
+                        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                            header.push(0x11);
+                            block.extend_from_slice(&source.octets());
+                            block.extend_from_slice(&destination.octets());
+                        }
+                        (IpAddr::V6(source), IpAddr::V6(destination)) => {
+                            header.push(0x21);
+                            block.extend_from_slice(&source.octets());
+                            block.extend_from_slice(&destination.octets());
+                        }
+                        _ => unreachable!("both ends are in the same family"),
+                    }
+                    block.extend_from_slice(&source.port().to_be_bytes());
+                    block.extend_from_slice(&destination.port().to_be_bytes());
+                }
+                None => {
+                    // LOCAL, with no addresses
+                    header.push(0x20);
+                    header.push(0x00);
+                }
+            }
+            header.extend_from_slice(&(block.len() as u16).to_be_bytes());
+            header.extend_from_slice(&block);
+            header
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Hands out its data a few bytes per read, like a header split across TCP segments would.
+    struct Trickle(VecDeque<Vec<u8>>);
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let mut chunk = match self.0.pop_front() {
+                Some(chunk) => chunk,
+                None => return Poll::Ready(Ok(0)),
+            };
+            let len = chunk.len().min(buf.len());
+            buf[..len].copy_from_slice(&chunk[..len]);
+            if len < chunk.len() {
+                self.0.push_front(chunk.split_off(len));
+            }
+            Poll::Ready(Ok(len))
+        }
+    }
+
+    /// Reads a header from `data`, delivered `chunk_size` bytes at a time, returning what it
+    /// conveyed and whatever was left on the stream.
+    async fn parse(data: &[u8], chunk_size: usize) -> Result<(Option<SocketAddr>, Vec<u8>), Error> {
+        let mut stream = Trickle(data.chunks(chunk_size).map(<[u8]>::to_vec).collect());
+        let address = read_header(&mut stream).await?;
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await?;
+        Ok((address, rest))
+    }
+
+    fn v2_header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family << 4 | 0x1);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    fn addresses(source: &str, destination: &str) -> ConnectionAddresses {
+        ConnectionAddresses {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_encode_v1() {
+        let encode = |addresses| String::from_utf8(encode_header(Version::V1, addresses)).unwrap();
+        assert_eq!(
+            encode(Some(addresses("203.0.113.7:56324", "10.0.0.1:80"))),
+            "PROXY TCP4 203.0.113.7 10.0.0.1 56324 80\r\n"
+        );
+        assert_eq!(
+            encode(Some(addresses("[2001:db8::7]:56324", "[2001:db8::1]:80"))),
+            "PROXY TCP6 2001:db8::7 2001:db8::1 56324 80\r\n"
+        );
+        assert_eq!(
+            encode(Some(addresses("203.0.113.7:56324", "[2001:db8::1]:80"))),
+            "PROXY TCP6 ::ffff:203.0.113.7 2001:db8::1 56324 80\r\n"
+        );
+        assert_eq!(encode(None), "PROXY UNKNOWN\r\n");
+    }
+
+    #[test]
+    fn test_encode_v2() {
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0x00, 0x0c]);
+        expected.extend_from_slice(&[203, 0, 113, 7, 10, 0, 0, 1, 0xdc, 0x04, 0x00, 0x50]);
+        assert_eq!(
+            encode_header(
+                Version::V2,
+                Some(addresses("203.0.113.7:56324", "10.0.0.1:80"))
+            ),
+            expected
+        );
+
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x21, 0x00, 0x24]);
+        expected.extend_from_slice(&"2001:db8::7".parse::<Ipv6Addr>().unwrap().octets());
+        expected.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        expected.extend_from_slice(&[0xdc, 0x04, 0x00, 0x50]);
+        assert_eq!(
+            encode_header(
+                Version::V2,
+                Some(addresses("[2001:db8::7]:56324", "[::1]:80"))
+            ),
+            expected
+        );
+
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        assert_eq!(encode_header(Version::V2, None), expected);
+    }
+
+    #[tokio::test]
+    async fn test_encoded_headers_parse() {
+        for version in &[Version::V1, Version::V2] {
+            for (source, destination) in &[
+                ("203.0.113.7:56324", "10.0.0.1:80"),
+                ("[2001:db8::7]:56324", "[2001:db8::1]:80"),
+            ] {
+                let header = encode_header(*version, Some(addresses(source, destination)));
+                let (address, rest) = parse(&header, 1024).await.unwrap();
+                assert_eq!(address, Some(source.parse().unwrap()));
+                assert!(rest.is_empty());
+            }
+            let (address, rest) = parse(&encode_header(*version, None), 1024).await.unwrap();
+            assert_eq!(address, None);
+            assert!(rest.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_v1() {
+        let (address, rest) = parse(
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 80\r\nGET / HTTP/1.1\r\n\r\n",
+            1024,
+        )
+        .await
+        .unwrap();
+        assert_eq!(address, Some("203.0.113.7:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n\r\n");
+
+        let (address, _) = parse(b"PROXY TCP6 2001:db8::7 2001:db8::1 443 80\r\n", 1024)
+            .await
+            .unwrap();
+        assert_eq!(address, Some("[2001:db8::7]:443".parse().unwrap()));
+        let (address, rest) = parse(b"PROXY UNKNOWN ff ff\r\nGET", 1024).await.unwrap();
+        assert_eq!(address, None);
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn test_v2() {
+        let mut addresses = vec![203, 0, 113, 7, 10, 0, 0, 1];
+        addresses.extend_from_slice(&56324_u16.to_be_bytes());
+        addresses.extend_from_slice(&80_u16.to_be_bytes());
+        // A TLV (here, PP2_TYPE_AUTHORITY) after the addresses is skipped
+        addresses.extend_from_slice(&[0x02, 0x00, 0x04]);
+        addresses.extend_from_slice(b"test");
+        let mut data = v2_header(0x1, 0x1, &addresses);
+        data.extend_from_slice(b"GET / HTTP/1.1\r\n\r\n");
+        let (address, rest) = parse(&data, 1024).await.unwrap();
+        assert_eq!(address, Some("203.0.113.7:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n\r\n");
+
+        let mut addresses = Ipv6Addr::LOCALHOST.octets().to_vec();
+        addresses.extend_from_slice(&Ipv6Addr::UNSPECIFIED.octets());
+        addresses.extend_from_slice(&[0x01, 0xbb, 0x00, 0x50]);
+        let (address, _) = parse(&v2_header(0x1, 0x2, &addresses), 1024).await.unwrap();
+        assert_eq!(address, Some("[::1]:443".parse().unwrap()));
+
+        // LOCAL connections carry no client, whatever their address block says
+        let (address, rest) = parse(&v2_header(0x0, 0x0, &[]), 1024).await.unwrap();
+        assert_eq!(address, None);
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_header_split_across_reads() {
+        let mut addresses = vec![198, 51, 100, 9, 10, 0, 0, 1];
+        addresses.extend_from_slice(&[0x04, 0xd2, 0x00, 0x50]);
+        let mut v2 = v2_header(0x1, 0x1, &addresses);
+        v2.extend_from_slice(b"GET");
+        let v1 = b"PROXY TCP4 198.51.100.9 10.0.0.1 1234 80\r\nGET";
+        for chunk_size in 1..8 {
+            for data in &[&v1[..], &v2[..]] {
+                let (address, rest) = parse(data, chunk_size).await.unwrap();
+                assert_eq!(address, Some("198.51.100.9:1234".parse().unwrap()));
+                assert_eq!(rest, b"GET");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_malformed_headers() {
+        let malformed = |data: Vec<u8>| async move {
+            match parse(&data, 1024).await {
+                Err(Error::MalformedHeader(_)) => {}
+                other => panic!("{:?} parsed as {:?}", data, other),
+            }
+        };
+        malformed(b"GET / HTTP/1.1\r\n\r\n".to_vec()).await;
+        malformed(b"PROXY TCP4 203.0.113.7 10.0.0.1 56324\r\n".to_vec()).await;
+        malformed(b"PROXY TCP4 2001:db8::7 10.0.0.1 56324 80\r\n".to_vec()).await;
+        malformed(b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 99999\r\n".to_vec()).await;
+        malformed(b"PROXY TCP5 203.0.113.7 10.0.0.1 56324 80\r\n".to_vec()).await;
+        let mut too_long = b"PROXY UNKNOWN ".to_vec();
+        too_long.extend_from_slice(&[b'x'; 100]);
+        malformed(too_long).await;
+        malformed(v2_header(0x1, 0x1, &[203, 0, 113, 7])).await;
+        malformed(v2_header(0x2, 0x1, &[0; 12])).await;
+        let mut wrong_version = v2_header(0x1, 0x1, &[0; 12]);
+        wrong_version[12] = 0x11;
+        malformed(wrong_version).await;
+
+        // Hanging up partway through isn't the header's fault
+        match parse(b"PROXY TCP4 203.0.113.7", 1024).await {
+            Err(Error::ConnectionError(_)) => {}
+            other => panic!("parsed as {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,447 @@
+use serde::Deserialize;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::hash::BuildHasher;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long each client's rate-limiting window lasts
+pub const WINDOW: Duration = Duration::from_secs(60);
+
+/// What tells rate-limited clients apart, from `--rate-limit-key`: their IP, or the value of a
+/// request header such as an API key, for clients that share an IP (e.g. behind a NAT).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum RateLimitKey {
+    Ip,
+    Header(http::header::HeaderName),
+}
+
+impl TryFrom<String> for RateLimitKey {
+    type Error = String;
+
+    fn try_from(arg: String) -> Result<Self, String> {
+        parse_key(&arg)
+    }
+}
+
+/// Parses a `--rate-limit-key`: `ip`, or `header:NAME`.
+pub fn parse_key(arg: &str) -> Result<RateLimitKey, String> {
+    if arg == "ip" {
+        return Ok(RateLimitKey::Ip);
+    }
+    let name = arg
+        .strip_prefix("header:")
+        .ok_or_else(|| format!("rate limit key {:?} should be ip or header:NAME", arg))?;
+    http::header::HeaderName::from_bytes(name.trim().as_bytes())
+        .map(RateLimitKey::Header)
+        .map_err(|_| format!("invalid header name {:?}", name))
+}
+
+impl RateLimitKey {
+    /// The key a request is counted under if it is keyed by a header and has it (and it isn't
+    /// empty), or None if the client's IP should be used instead. Keys are written `name: value`,
+    /// which no IP looks like, so a client can't pass itself off as an IP.
+    pub fn key_for(&self, request: &http::Request<Vec<u8>>) -> Option<String> {
+        match self {
+            RateLimitKey::Ip => None,
+            RateLimitKey::Header(name) => {
+                let value = request.headers().get(name)?.as_bytes();
+                if value.is_empty() {
+                    return None;
+                }
+                Some(format!("{}: {}", name, String::from_utf8_lossy(value)))
+            }
+        }
+    }
+}
+
+/// A per-minute request limit of its own (0 = unlimited) for the requests it matches, from
+/// `--rate-limit-rule` or a `[[rate_limit.rules]]` table in the config file. A request matches if
+/// its method is one of `methods` (or there are none) and its path starts with `path_prefix` (or
+/// there is none).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "RuleFields")]
+pub struct RateLimitRule {
+    /// Goes in the X-RateLimit-Rule header of the 429s the rule hands out; defaults to the methods
+    /// and path prefix, e.g. `POST /login`
+    pub name: String,
+    pub methods: Vec<http::Method>,
+    pub path_prefix: Option<String>,
+    pub max_requests_per_minute: usize,
+}
+
+/// A `[[rate_limit.rules]]` table as written, before RateLimitRule::new checks it
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFields {
+    name: Option<String>,
+    #[serde(default)]
+    methods: Vec<String>,
+    path_prefix: Option<String>,
+    max_requests_per_minute: usize,
+}
+
+impl TryFrom<RuleFields> for RateLimitRule {
+    type Error = String;
+
+    fn try_from(fields: RuleFields) -> Result<Self, String> {
+        RateLimitRule::new(
+            fields.name,
+            &fields.methods,
+            fields.path_prefix,
+            fields.max_requests_per_minute,
+        )
+    }
+}
+
+impl RateLimitRule {
+    /// Method names are case-insensitive, and are held in upper case, as clients send them.
+    pub fn new(
+        name: Option<String>,
+        methods: &[String],
+        path_prefix: Option<String>,
+        max_requests_per_minute: usize,
+    ) -> Result<Self, String> {
+        let methods = methods
+            .iter()
+            .map(|method| {
+                http::Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                    .map_err(|_| format!("invalid method {:?}", method))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        if let Some(prefix) = &path_prefix {
+            if !prefix.starts_with('/') {
+                return Err(format!("path prefix {:?} should start with /", prefix));
+            }
+        }
+        let name = name.unwrap_or_else(|| {
+            let methods: Vec<&str> = methods.iter().map(http::Method::as_str).collect();
+            match &path_prefix {
+                Some(prefix) if methods.is_empty() => prefix.clone(),
+                Some(prefix) => format!("{} {}", methods.join(","), prefix),
+                None if methods.is_empty() => String::from("*"),
+                None => methods.join(","),
+            }
+        });
+        if name.is_empty() || http::HeaderValue::from_str(&name).is_err() {
+            return Err(format!(
+                "rate limit rule name {:?} can't go in a header",
+                name
+            ));
+        }
+        Ok(RateLimitRule {
+            name,
+            methods,
+            path_prefix,
+            max_requests_per_minute,
+        })
+    }
+
+    pub fn matches(&self, method: &http::Method, path: &str) -> bool {
+        (self.methods.is_empty() || self.methods.contains(method))
+            && self
+                .path_prefix
+                .as_ref()
+                .is_none_or(|prefix| path.starts_with(prefix.as_str()))
+    }
+}
+
+/// Parses a `--rate-limit-rule` value of the form `[METHOD,METHOD... ]PATH_PREFIX=LIMIT`, or
+/// `METHOD,METHOD...=LIMIT` for every path, e.g. `POST /login=5` or `/static=0`. The rule is named
+/// after everything before the `=`.
+pub fn parse_rule(arg: &str) -> Result<RateLimitRule, String> {
+    let (matcher, limit) = arg.rsplit_once('=').ok_or_else(|| {
+        format!(
+            "rate limit rule {:?} should be [METHODS ]PATH_PREFIX=LIMIT",
+            arg
+        )
+    })?;
+    let limit = limit
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid rate limit {:?} in rule {:?}", limit, arg))?;
+    let split_methods = |methods: &str| -> Vec<String> {
+        methods
+            .split(',')
+            .map(|method| method.trim().to_string())
+            .collect()
+    };
+    let (methods, path_prefix) = match matcher.split_whitespace().collect::<Vec<_>>()[..] {
+        [prefix] if prefix.starts_with('/') => (Vec::new(), Some(prefix.to_string())),
+        [methods] => (split_methods(methods), None),
+        [methods, prefix] => (split_methods(methods), Some(prefix.to_string())),
+        _ => {
+            return Err(format!(
+                "rate limit rule {:?} should be [METHODS ]PATH_PREFIX=LIMIT",
+                arg
+            ))
+        }
+    };
+    RateLimitRule::new(
+        Some(matcher.trim().to_string()),
+        &methods,
+        path_prefix,
+        limit,
+    )
+}
+
+/// The index of the first of `rules` that a request with `method` and `path` matches, if any.
+pub fn find_rule(rules: &[RateLimitRule], method: &http::Method, path: &str) -> Option<usize> {
+    rules.iter().position(|rule| rule.matches(method, path))
+}
+
+/// How many requests a client has made in its current rate-limiting window
+#[derive(Debug)]
+struct RateWindow {
+    started: Instant,
+    count: usize,
+}
+
+/// Each client's window under each rule it has made requests under
+type Windows = HashMap<(Option<usize>, String), RateWindow>;
+
+/// Counts each client's requests in fixed windows, separately for each rate limit rule (by its
+/// index) and for the requests no rule matches (None). Every request from every client goes
+/// through here, so clients are spread over several independently locked maps by a hash of their
+/// IP, and only clients that land in the same shard ever wait on each other.
+#[derive(Debug)]
+pub struct RateLimiter {
+    shards: Vec<Mutex<Windows>>,
+    hasher: RandomState,
+}
+
+impl RateLimiter {
+    pub fn new(shards: usize) -> Self {
+        RateLimiter {
+            shards: (0..shards.max(1)).map(|_| Mutex::default()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, client_ip: &str) -> &Mutex<Windows> {
+        &self.shards[self.hasher.hash_one(client_ip) as usize % self.shards.len()]
+    }
+
+    /// Counts a request from `client_ip` at `now` under `rule`. Returns how many requests the
+    /// client has made under that rule in its current window, this one included, and how long
+    /// until that window ends.
+    pub fn record(&self, rule: Option<usize>, client_ip: &str, now: Instant) -> (usize, Duration) {
+        let mut windows = self.shard(client_ip).lock().unwrap();
+        let key = (rule, client_ip.to_string());
+        let window = windows.entry(key).or_insert(RateWindow {
+            started: now,
+            count: 0,
+        });
+        if now.duration_since(window.started) >= WINDOW {
+            *window = RateWindow {
+                started: now,
+                count: 0,
+            };
+        }
+        window.count += 1;
+        (window.count, WINDOW - now.duration_since(window.started))
+    }
+
+    /// How many clients are being tracked, once per rule they've made requests under
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().len())
+            .sum()
+    }
+
+    /// Forgets the clients whose window has run out by `now`, one shard at a time.
+    pub fn forget_expired(&self, now: Instant) {
+        for shard in &self.shards {
+            shard
+                .lock()
+                .unwrap()
+                .retain(|_, window| now.duration_since(window.started) < WINDOW);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    const THREADS: usize = 8;
+
+    fn client_ip(client: usize) -> String {
+        format!("10.0.{}.{}", client / 256, client % 256)
+    }
+
+    /// Has THREADS threads each make `per_client` requests from every one of `clients` IPs.
+    fn hammer(limiter: &Arc<RateLimiter>, clients: usize, per_client: usize, now: Instant) {
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let limiter = limiter.clone();
+                thread::spawn(move || {
+                    let ips: Vec<String> = (0..clients).map(client_ip).collect();
+                    for _ in 0..per_client {
+                        for ip in &ips {
+                            limiter.record(None, ip, now);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_windows() {
+        let limiter = RateLimiter::new(4);
+        let now = Instant::now();
+        assert_eq!(limiter.record(None, "10.0.0.1", now), (1, WINDOW));
+        let later = now + Duration::from_secs(20);
+        assert_eq!(
+            limiter.record(None, "10.0.0.1", later),
+            (2, WINDOW - (later - now))
+        );
+        assert_eq!(limiter.record(None, "10.0.0.2", later), (1, WINDOW));
+        assert_eq!(limiter.len(), 2);
+
+        // The first client's window started earlier, so it runs out first
+        limiter.forget_expired(now + WINDOW);
+        assert_eq!(limiter.len(), 1);
+        assert_eq!(
+            limiter.record(None, "10.0.0.2", now + WINDOW),
+            (2, later - now)
+        );
+        assert_eq!(
+            limiter.record(None, "10.0.0.2", later + WINDOW),
+            (1, WINDOW)
+        );
+    }
+
+    #[test]
+    fn test_rules_counted_separately() {
+        let limiter = RateLimiter::new(4);
+        let now = Instant::now();
+        for _ in 0..3 {
+            limiter.record(Some(0), "10.0.0.1", now);
+        }
+        assert_eq!(limiter.record(Some(0), "10.0.0.1", now).0, 4);
+        assert_eq!(limiter.record(Some(1), "10.0.0.1", now).0, 1);
+        assert_eq!(limiter.record(None, "10.0.0.1", now).0, 1);
+        assert_eq!(limiter.record(Some(0), "10.0.0.2", now).0, 1);
+        assert_eq!(limiter.len(), 4);
+    }
+
+    #[test]
+    fn test_keys() {
+        assert_eq!(parse_key("ip"), Ok(RateLimitKey::Ip));
+        let key = parse_key("header:X-Api-Key").unwrap();
+        assert_eq!(
+            key,
+            RateLimitKey::Header(http::header::HeaderName::from_static("x-api-key"))
+        );
+        assert!(parse_key("header:").is_err());
+        assert!(parse_key("header:two words").is_err());
+        assert!(parse_key("cookie").is_err());
+
+        let request = |api_key: Option<&str>| {
+            let mut request = http::Request::builder();
+            if let Some(api_key) = api_key {
+                request = request.header("X-API-KEY", api_key);
+            }
+            request.body(Vec::new()).unwrap()
+        };
+        assert_eq!(
+            key.key_for(&request(Some("tenant-1"))).as_deref(),
+            Some("x-api-key: tenant-1")
+        );
+        assert_eq!(key.key_for(&request(Some(""))), None);
+        assert_eq!(key.key_for(&request(None)), None);
+        assert_eq!(RateLimitKey::Ip.key_for(&request(Some("tenant-1"))), None);
+    }
+
+    #[test]
+    fn test_parse_rule() {
+        let rule = parse_rule("post,PUT /login=5").unwrap();
+        assert_eq!(rule.name, "post,PUT /login");
+        assert_eq!(rule.methods, vec![http::Method::POST, http::Method::PUT]);
+        assert_eq!(rule.path_prefix.as_deref(), Some("/login"));
+        assert_eq!(rule.max_requests_per_minute, 5);
+        let rule = parse_rule("/static=0").unwrap();
+        assert!(rule.methods.is_empty());
+        assert_eq!(rule.max_requests_per_minute, 0);
+        let rule = parse_rule("DELETE=2").unwrap();
+        assert_eq!(rule.path_prefix, None);
+        assert!(parse_rule("/login").is_err());
+        assert!(parse_rule("/login=many").is_err());
+        assert!(parse_rule("POST login=5").is_err());
+        assert!(parse_rule("GET /a /b=5").is_err());
+        let unnamed = |methods: &[&str], prefix: Option<&str>| {
+            let methods: Vec<String> = methods.iter().map(|method| method.to_string()).collect();
+            RateLimitRule::new(None, &methods, prefix.map(String::from), 1)
+                .unwrap()
+                .name
+        };
+        assert_eq!(unnamed(&["get", "HEAD"], Some("/api")), "GET,HEAD /api");
+        assert_eq!(unnamed(&[], Some("/api")), "/api");
+        assert_eq!(unnamed(&["POST"], None), "POST");
+        assert_eq!(unnamed(&[], None), "*");
+        assert!(RateLimitRule::new(Some(String::from("bad\nname")), &[], None, 1).is_err());
+    }
+
+    #[test]
+    fn test_first_matching_rule() {
+        let rules: Vec<RateLimitRule> = [
+            "POST /api/login=5",
+            "/api/admin=10",
+            "/api=100",
+            "/static=0",
+        ]
+        .iter()
+        .map(|arg| parse_rule(arg).unwrap())
+        .collect();
+        let find = |method: http::Method, path: &str| find_rule(&rules, &method, path);
+        assert_eq!(find(http::Method::POST, "/api/login"), Some(0));
+        // The narrower prefix only wins for the methods it names
+        assert_eq!(find(http::Method::GET, "/api/login"), Some(2));
+        assert_eq!(find(http::Method::GET, "/api/admin/users"), Some(1));
+        assert_eq!(find(http::Method::GET, "/api"), Some(2));
+        assert_eq!(find(http::Method::GET, "/static/app.js"), Some(3));
+        assert_eq!(find(http::Method::GET, "/"), None);
+        assert_eq!(find(http::Method::GET, "/ap"), None);
+    }
+
+    #[test]
+    fn test_concurrent_clients() {
+        let limiter = Arc::new(RateLimiter::new(16));
+        let now = Instant::now();
+        hammer(&limiter, 1000, 5, now);
+        assert_eq!(limiter.len(), 1000);
+        for client in 0..1000 {
+            let ip = client_ip(client);
+            assert_eq!(limiter.record(None, &ip, now).0, THREADS * 5 + 1, "{}", ip);
+        }
+    }
+
+    /// Not a test so much as a benchmark of one map against a sharded one, run with `cargo test
+    /// --release -- --ignored --nocapture bench_`.
+    #[test]
+    #[ignore]
+    fn bench_rate_limiter() {
+        const CLIENTS: usize = 1000;
+        const PER_CLIENT: usize = 200;
+        for &shards in &[1, 16] {
+            let limiter = Arc::new(RateLimiter::new(shards));
+            let started = Instant::now();
+            hammer(&limiter, CLIENTS, PER_CLIENT, Instant::now());
+            let per_request = started.elapsed() / (THREADS * CLIENTS * PER_CLIENT) as u32;
+            println!(
+                "{} shard(s): {:?} per request, {} threads counting at once",
+                shards, per_request, THREADS
+            );
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use rand::Rng;
+
+/// The header carrying a request's ID, from the client or from us, to the upstream and back
+pub const HEADER: &str = "x-request-id";
+
+/// Longest client-supplied ID that is passed on rather than replaced
+const MAX_LEN: usize = 128;
+
+/// A fresh random (version 4) UUID, e.g. `9b2f6c1e-58d4-4c3a-a0e1-7f5d2b8c9e01`.
+pub fn generate<R: Rng>(rng: &mut R) -> String {
+    let mut bytes: [u8; 16] = rng.gen();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// The ID the client gave the request, if it gave one we're willing to put in logs: short, and
+/// only printable ASCII without spaces.
+pub fn from_request(request: &http::Request<Vec<u8>>) -> Option<String> {
+    let id = request.headers().get(HEADER)?.to_str().ok()?;
+    if id.is_empty() || id.len() > MAX_LEN || !id.bytes().all(|byte| byte.is_ascii_graphic()) {
+        return None;
+    }
+    Some(id.to_string())
+}
+
+/// Tags a message with the request ID `id`, which from_request or generate vouched for.
+pub fn set(headers: &mut http::HeaderMap, id: &str) {
+    headers.insert(HEADER, http::HeaderValue::from_str(id).unwrap());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_generate() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let id = generate(&mut rng);
+        assert_eq!(id.len(), 36);
+        let groups: Vec<&str> = id.split('-').collect();
+        assert_eq!(
+            groups.iter().map(|group| group.len()).collect::<Vec<_>>(),
+            vec![8, 4, 4, 4, 12]
+        );
+        assert!(groups[2].starts_with('4'));
+        assert!("89ab".contains(&groups[3][..1]));
+        assert_ne!(generate(&mut rng), id);
+    }
+
+    #[test]
+    fn test_from_request() {
+        let id = |value: &str| {
+            let request = http::Request::builder()
+                .header("X-Request-Id", value)
+                .body(Vec::new())
+                .unwrap();
+            from_request(&request)
+        };
+        assert_eq!(id("abc-123"), Some(String::from("abc-123")));
+        assert_eq!(id(""), None);
+        assert_eq!(id("two words"), None);
+        assert_eq!(id(&"x".repeat(MAX_LEN + 1)), None);
+        let request = http::Request::builder().body(Vec::new()).unwrap();
+        assert_eq!(from_request(&request), None);
+    }
+}
@@ -0,0 +1,302 @@
+use crate::upstream::{self, Upstream, UpstreamChange, UpstreamInfo};
+use async_trait::async_trait;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+
+/// Looks up the addresses an upstream's hostname currently resolves to. ProxyState holds one as a
+/// trait object, so tests can stand in their own DNS records.
+#[async_trait]
+pub trait Resolver: fmt::Debug + Send + Sync {
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// Resolves through the system's resolver, like TcpStream::connect does
+#[derive(Debug)]
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok(tokio::net::lookup_host((host, port)).await?.collect())
+    }
+}
+
+/// The hostname and port in a configured upstream address, or None if it gives an IP instead (or
+/// no port), so that there's nothing to resolve.
+pub fn hostname(address: &str) -> Option<(&str, u16)> {
+    let (_, authority) = upstream::split_scheme(address);
+    let (host, port) = authority.rsplit_once(':')?;
+    if host.starts_with('[') || host.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+    Some((host, port.parse().ok()?))
+}
+
+/// The address of `peer`, one of the addresses the upstream configured as `name` resolved to, in
+/// the same scheme, e.g. `https://10.0.0.5:443` for `https://api.internal:443`.
+pub fn resolved_address(name: &str, peer: SocketAddr) -> String {
+    match upstream::split_scheme(name) {
+        (true, _) => format!("https://{}", peer),
+        (false, _) => peer.to_string(),
+    }
+}
+
+/// Makes the entries in `current` for the upstream configured as `name` one per address in
+/// `peers`, in the upstream's place in the list and each with its weight, request limit, backup
+/// status and overrides, and draining if all its addresses were. Addresses it already had
+/// keep their health and counters. New ones start out down with a probe due at `now`, so they
+/// only get traffic once they pass it, unless `trust_new` (as at startup, when everything starts
+/// out healthy). An address that is another upstream's already stays that upstream's alone.
+///
+/// Returns the changes made, which are none if `name` is no longer configured.
+pub fn apply_resolution(
+    current: &mut Vec<UpstreamInfo>,
+    name: &str,
+    peers: &[SocketAddr],
+    trust_new: bool,
+    now: Instant,
+) -> Vec<UpstreamChange> {
+    let (position, template) = match current
+        .iter()
+        .position(|info| info.configured_address() == name)
+    {
+        Some(idx) => (
+            idx,
+            Upstream {
+                address: String::new(),
+                weight: current[idx].weight,
+                max_requests: Some(current[idx].max_requests),
+                backup: current[idx].backup,
+                host_override: current[idx].host_override.clone(),
+                sni_override: current[idx].sni_override.clone(),
+            },
+        ),
+        None => return Vec::new(),
+    };
+    // New addresses of an upstream that is being drained are drained too
+    let draining = current
+        .iter()
+        .filter(|info| info.configured_address() == name)
+        .all(|info| info.draining);
+    let mut old = Vec::new();
+    let mut rest = Vec::new();
+    for info in std::mem::take(current) {
+        if info.configured_address() == name {
+            old.push(info);
+        } else {
+            rest.push(info);
+        }
+    }
+    let mut entries: Vec<UpstreamInfo> = Vec::new();
+    let mut changes = Vec::new();
+    for &peer in peers {
+        let address = resolved_address(name, peer);
+        if entries
+            .iter()
+            .chain(rest.iter())
+            .any(|info| info.address == address)
+        {
+            continue;
+        }
+        match old.iter().position(|info| info.address == address) {
+            Some(idx) => entries.push(old.remove(idx)),
+            None => {
+                let mut info = UpstreamInfo::new(Upstream {
+                    address: address.clone(),
+                    ..template.clone()
+                });
+                info.name = Some(name.to_string());
+                info.draining = draining;
+                if !trust_new {
+                    info.healthy = false;
+                    info.probes.start_now(now);
+                }
+                changes.push(UpstreamChange::Added(address));
+                entries.push(info);
+            }
+        }
+    }
+    if entries.is_empty() {
+        // Every address was someone else's; better to keep what the upstream had than lose it
+        entries = old;
+        changes.clear();
+    } else {
+        changes.extend(
+            old.into_iter()
+                .map(|info| UpstreamChange::Removed(info.address)),
+        );
+    }
+    // Everything ahead of the upstream's first entry is still ahead of it in `rest`
+    rest.splice(position..position, entries);
+    *current = rest;
+    changes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn peers(addresses: &[&str]) -> Vec<SocketAddr> {
+        addresses
+            .iter()
+            .map(|address| address.parse().unwrap())
+            .collect()
+    }
+
+    fn infos(args: &[&str]) -> Vec<UpstreamInfo> {
+        args.iter()
+            .map(|arg| UpstreamInfo::new(upstream::parse_upstream(arg).unwrap()))
+            .collect()
+    }
+
+    fn addresses(current: &[UpstreamInfo]) -> Vec<&str> {
+        current.iter().map(|info| info.address.as_str()).collect()
+    }
+
+    #[test]
+    fn test_hostname() {
+        assert_eq!(hostname("api.internal:80"), Some(("api.internal", 80)));
+        assert_eq!(
+            hostname("https://api.internal:443"),
+            Some(("api.internal", 443))
+        );
+        assert_eq!(hostname("10.0.0.1:80"), None);
+        assert_eq!(hostname("[2001:db8::1]:80"), None);
+        assert_eq!(hostname("api.internal"), None);
+        assert_eq!(
+            resolved_address(
+                "https://api.internal:443",
+                "[2001:db8::1]:443".parse().unwrap()
+            ),
+            "https://[2001:db8::1]:443"
+        );
+        assert_eq!(
+            resolved_address("api.internal:80", "10.0.0.5:80".parse().unwrap()),
+            "10.0.0.5:80"
+        );
+    }
+
+    #[test]
+    fn test_expands_in_place() {
+        let mut current = infos(&["10.0.0.1:80", "api.internal:80=3", "10.0.0.9:80"]);
+        let now = Instant::now();
+        let changes = apply_resolution(
+            &mut current,
+            "api.internal:80",
+            &peers(&["10.0.1.1:80", "10.0.1.2:80"]),
+            true,
+            now,
+        );
+        assert_eq!(
+            changes,
+            vec![
+                UpstreamChange::Added(String::from("10.0.1.1:80")),
+                UpstreamChange::Added(String::from("10.0.1.2:80")),
+                UpstreamChange::Removed(String::from("api.internal:80")),
+            ]
+        );
+        assert_eq!(
+            addresses(&current),
+            vec!["10.0.0.1:80", "10.0.1.1:80", "10.0.1.2:80", "10.0.0.9:80"]
+        );
+        assert_eq!(current[1].name.as_deref(), Some("api.internal:80"));
+        assert_eq!(current[2].weight, 3);
+        // Trusted at startup
+        assert!(current[1].healthy && current[2].healthy);
+    }
+
+    #[test]
+    fn test_record_change() {
+        let mut current = infos(&["api.internal:80"]);
+        let now = Instant::now();
+        apply_resolution(
+            &mut current,
+            "api.internal:80",
+            &peers(&["10.0.1.1:80", "10.0.1.2:80"]),
+            true,
+            now,
+        );
+        current[1].stats.record_failure();
+        let changes = apply_resolution(
+            &mut current,
+            "api.internal:80",
+            &peers(&["10.0.1.2:80", "10.0.1.3:80"]),
+            false,
+            now,
+        );
+        assert_eq!(
+            changes,
+            vec![
+                UpstreamChange::Added(String::from("10.0.1.3:80")),
+                UpstreamChange::Removed(String::from("10.0.1.1:80")),
+            ]
+        );
+        assert_eq!(addresses(&current), vec!["10.0.1.2:80", "10.0.1.3:80"]);
+        // The address it already had keeps its counters; the new one waits for a probe
+        assert_eq!(current[0].stats.failures(), 1);
+        assert!(current[0].healthy);
+        assert!(!current[1].healthy);
+        assert!(current[1].probes.is_due(now));
+        assert!(apply_resolution(
+            &mut current,
+            "api.internal:80",
+            &peers(&["10.0.1.3:80", "10.0.1.2:80"]),
+            false,
+            now
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_new_addresses_of_draining_upstream() {
+        let mut current = infos(&["api.internal:80"]);
+        let now = Instant::now();
+        current[0].draining = true;
+        apply_resolution(
+            &mut current,
+            "api.internal:80",
+            &peers(&["10.0.1.1:80", "10.0.1.2:80"]),
+            true,
+            now,
+        );
+        assert!(current[0].draining && current[1].draining);
+        // Unless only some of its addresses were drained
+        current[1].draining = false;
+        apply_resolution(
+            &mut current,
+            "api.internal:80",
+            &peers(&["10.0.1.1:80", "10.0.1.2:80", "10.0.1.3:80"]),
+            false,
+            now,
+        );
+        assert_eq!(
+            current.iter().map(|info| info.draining).collect::<Vec<_>>(),
+            vec![true, false, false]
+        );
+    }
+
+    #[test]
+    fn test_addresses_of_other_upstreams() {
+        let mut current = infos(&["10.0.1.1:80", "api.internal:80"]);
+        let now = Instant::now();
+        let changes = apply_resolution(
+            &mut current,
+            "api.internal:80",
+            &peers(&["10.0.1.1:80"]),
+            true,
+            now,
+        );
+        assert!(changes.is_empty());
+        assert_eq!(addresses(&current), vec!["10.0.1.1:80", "api.internal:80"]);
+        assert!(apply_resolution(
+            &mut current,
+            "gone.internal:80",
+            &peers(&["10.0.2.1:80"]),
+            true,
+            now
+        )
+        .is_empty());
+    }
+}
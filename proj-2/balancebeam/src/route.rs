@@ -0,0 +1,223 @@
+use crate::hash_ring::HashRing;
+use crate::upstream::{self, Upstream, UpstreamInfo};
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::sync::atomic::AtomicUsize;
+use std::sync::RwLock;
+
+/// The Hosts a route takes requests for: one host name (`api.example.com`), or with `*.` in front
+/// (`*.example.com`), any name under it. Matching ignores case.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum HostPattern {
+    Exact(String),
+    /// The part after the `*`, dot included
+    Subdomains(String),
+}
+
+impl HostPattern {
+    pub fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Exact(name) => host.eq_ignore_ascii_case(name),
+            HostPattern::Subdomains(suffix) => {
+                host.len() > suffix.len()
+                    && host.is_char_boundary(host.len() - suffix.len())
+                    && host[host.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for HostPattern {
+    type Error = String;
+
+    fn try_from(pattern: String) -> Result<Self, String> {
+        let name = pattern.strip_prefix("*.").unwrap_or(&pattern);
+        if name.is_empty() || name.contains(['*', ':', '/']) {
+            return Err(format!("invalid host pattern {:?}", pattern));
+        }
+        Ok(if pattern.starts_with("*.") {
+            HostPattern::Subdomains(pattern[1..].to_string())
+        } else {
+            HostPattern::Exact(pattern)
+        })
+    }
+}
+
+impl std::fmt::Display for HostPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HostPattern::Exact(name) => write!(f, "{}", name),
+            HostPattern::Subdomains(suffix) => write!(f, "*{}", suffix),
+        }
+    }
+}
+
+/// A named group of upstreams that requests for some Hosts go to instead of the `--upstream` ones,
+/// from `--route` or a `[[routes]]` table in the config file (the name defaults to the host
+/// pattern).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    pub name: Option<String>,
+    pub host: HostPattern,
+    pub upstreams: Vec<Upstream>,
+}
+
+/// Parses a `--route` value of the form `host=upstream,upstream...`, where each upstream is
+/// written as for `--upstream` (so `*.example.com=10.0.0.1:80=2,10.0.0.2:80` works).
+pub fn parse_route(arg: &str) -> Result<Route, String> {
+    let (host, upstreams) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("route {:?} should be host=upstream,upstream...", arg))?;
+    let upstreams = upstreams
+        .split(',')
+        .map(|upstream| upstream::parse_upstream(upstream.trim()))
+        .collect::<Result<Vec<Upstream>, String>>()?;
+    Ok(Route {
+        name: Some(host.to_string()),
+        host: HostPattern::try_from(host.to_string())?,
+        upstreams,
+    })
+}
+
+/// The upstreams some requests are balanced over, and what picking one of them needs: either the
+/// default group from `--upstream`, or a route's. Each group's upstreams have their own health,
+/// circuit breakers and counters, even an address that is in several groups.
+#[derive(Debug)]
+pub struct UpstreamGroup {
+    /// The route's name and Host pattern, or None for the default group
+    pub route: Option<(String, HostPattern)>,
+    /// The servers that we are proxying to, with their health and traffic counters. Upstreams may
+    /// be added and removed at runtime, so requests in flight refer to theirs by address. Every
+    /// request reads this to pick an upstream, while writes (health changes, list changes) are
+    /// rare, hence a read-write lock. It's a blocking one, so it's never held across an await.
+    pub upstreams: RwLock<Vec<UpstreamInfo>>,
+    /// Index of the next upstream to try under round-robin (wraps modulo the upstream count)
+    pub next_upstream: AtomicUsize,
+    /// The ip-hash ring over the live upstreams, rebuilt whenever they change. Always locked after
+    /// upstreams.
+    pub hash_ring: RwLock<HashRing>,
+}
+
+impl UpstreamGroup {
+    pub fn new(route: Option<(String, HostPattern)>, upstreams: Vec<Upstream>) -> Self {
+        UpstreamGroup {
+            route,
+            upstreams: RwLock::new(upstreams.into_iter().map(UpstreamInfo::new).collect()),
+            next_upstream: AtomicUsize::new(0),
+            hash_ring: RwLock::new(HashRing::default()),
+        }
+    }
+
+    /// The default group, followed by one per route in the order given
+    pub fn all(default: Vec<Upstream>, routes: Vec<Route>) -> Vec<UpstreamGroup> {
+        std::iter::once(UpstreamGroup::new(None, default))
+            .chain(routes.into_iter().map(|route| {
+                let host = route.host;
+                let name = route.name.unwrap_or_else(|| host.to_string());
+                UpstreamGroup::new(Some((name, host)), route.upstreams)
+            }))
+            .collect()
+    }
+
+    /// The route's name, or None for the default group
+    pub fn name(&self) -> Option<&str> {
+        self.route.as_ref().map(|(name, _)| name.as_str())
+    }
+}
+
+/// Which of `groups` (as made by UpstreamGroup::all) a request for `host` goes to: the first route
+/// whose pattern matches, otherwise the default group. There is none if `fall_through` is off, or
+/// if the default group has no upstreams and is only there for the routes' sake.
+pub fn find_group(
+    groups: &[UpstreamGroup],
+    host: Option<&str>,
+    fall_through: bool,
+) -> Option<usize> {
+    let routed = host.and_then(|host| {
+        groups.iter().position(|group| match &group.route {
+            Some((_, pattern)) => pattern.matches(host),
+            None => false,
+        })
+    });
+    match routed {
+        Some(idx) => Some(idx),
+        None if fall_through
+            && (groups.len() == 1 || !groups[0].upstreams.read().unwrap().is_empty()) =>
+        {
+            Some(0)
+        }
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pattern(pattern: &str) -> HostPattern {
+        HostPattern::try_from(pattern.to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_host_pattern() {
+        assert!(pattern("api.example.com").matches("API.example.com"));
+        assert!(!pattern("api.example.com").matches("example.com"));
+        assert!(pattern("*.example.com").matches("api.Example.com"));
+        assert!(pattern("*.example.com").matches("a.b.example.com"));
+        assert!(!pattern("*.example.com").matches("example.com"));
+        assert!(!pattern("*.example.com").matches("badexample.com"));
+        assert_eq!(pattern("*.example.com").to_string(), "*.example.com");
+        for bad in &["", "*.", "*example.com", "a.*.com", "example.com:80"] {
+            assert!(HostPattern::try_from(bad.to_string()).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_parse_route() {
+        assert_eq!(
+            parse_route("*.example.com=10.0.0.1:80=2, 10.0.0.2:80"),
+            Ok(Route {
+                name: Some(String::from("*.example.com")),
+                host: pattern("*.example.com"),
+                upstreams: vec![
+                    upstream::parse_upstream("10.0.0.1:80=2").unwrap(),
+                    upstream::parse_upstream("10.0.0.2:80").unwrap(),
+                ],
+            })
+        );
+        assert!(parse_route("example.com").is_err());
+        assert!(parse_route("example.com=").is_err());
+        assert!(parse_route("=10.0.0.1:80").is_err());
+    }
+
+    #[test]
+    fn test_find_group() {
+        let route = |host: &str, address: &str| Route {
+            name: None,
+            host: pattern(host),
+            upstreams: vec![upstream::parse_upstream(address).unwrap()],
+        };
+        let default = vec![upstream::parse_upstream("10.0.0.1:80").unwrap()];
+        let routes = vec![
+            route("api.example.com", "10.0.0.2:80"),
+            route("*.example.com", "10.0.0.3:80"),
+        ];
+        let groups = UpstreamGroup::all(default, routes.clone());
+        assert_eq!(groups[2].name(), Some("*.example.com"));
+        // The first matching route wins
+        assert_eq!(find_group(&groups, Some("api.example.com"), true), Some(1));
+        assert_eq!(find_group(&groups, Some("www.example.com"), true), Some(2));
+        assert_eq!(find_group(&groups, Some("example.org"), true), Some(0));
+        assert_eq!(find_group(&groups, None, true), Some(0));
+        assert_eq!(find_group(&groups, Some("example.org"), false), None);
+        assert_eq!(find_group(&groups, Some("www.example.com"), false), Some(2));
+
+        let groups = UpstreamGroup::all(Vec::new(), routes);
+        assert_eq!(find_group(&groups, Some("example.org"), true), None);
+        // Without routes, an empty default group is still where requests go (and fail)
+        let groups = UpstreamGroup::all(Vec::new(), Vec::new());
+        assert_eq!(find_group(&groups, Some("example.org"), true), Some(0));
+    }
+}
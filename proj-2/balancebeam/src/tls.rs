@@ -0,0 +1,296 @@
+use crate::proxy_protocol::{self, ConnectionAddresses};
+use crate::upstream;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, NoClientAuth, RootCertStore, ServerCertVerified, ServerCertVerifier,
+    ServerConfig, TLSError,
+};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::{client, TlsAcceptor, TlsConnector};
+
+/// Builds the acceptor terminating TLS on the client-facing listener from a PEM certificate chain
+/// (leaf first) and a PEM private key, either PKCS#8 or PKCS#1 RSA. Errors name the offending file.
+pub fn load_acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor, String> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|err| format!("{}: {}", path.display(), err))
+    };
+    let certs = pemfile::certs(&mut open(cert_path)?)
+        .map_err(|_| format!("{}: malformed certificate", cert_path.display()))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates found", cert_path.display()));
+    }
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(key_path)?)
+        .map_err(|_| format!("{}: malformed private key", key_path.display()))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(key_path)?)
+            .map_err(|_| format!("{}: malformed private key", key_path.display()))?;
+    }
+    let key = match keys.into_iter().next() {
+        Some(key) => key,
+        None => return Err(format!("{}: no private key found", key_path.display())),
+    };
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(certs, key)
+        .map_err(|err| format!("{}: {}", cert_path.display(), err))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Parses a server name for TLS handshakes with an upstream (an `sni_override`), which must be a
+/// DNS name: no port, and no IP address.
+pub fn parse_server_name(arg: &str) -> Result<String, String> {
+    if arg.parse::<std::net::IpAddr>().is_err()
+        && DNSNameRef::try_from_ascii_str(arg).is_ok()
+        && !arg.contains(':')
+    {
+        Ok(arg.to_string())
+    } else {
+        Err(format!("{:?} is not a DNS name", arg))
+    }
+}
+
+/// Opens connections to upstreams, speaking TLS to those whose address starts with `https://`.
+#[derive(Clone)]
+pub struct UpstreamConnector {
+    tls: TlsConnector,
+    send_proxy_protocol: Option<proxy_protocol::Version>,
+}
+
+// TlsConnector has no Debug of its own, and its config isn't worth printing
+impl fmt::Debug for UpstreamConnector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UpstreamConnector").finish_non_exhaustive()
+    }
+}
+
+impl UpstreamConnector {
+    /// Upstream certificates are checked against the PEM roots in `ca_path` if given, otherwise
+    /// against the system's roots, or not at all if `insecure`. With `send_proxy_protocol`, every
+    /// connection starts with a PROXY protocol header, ahead of any TLS.
+    pub fn new(
+        ca_path: Option<&Path>,
+        insecure: bool,
+        send_proxy_protocol: Option<proxy_protocol::Version>,
+    ) -> Result<UpstreamConnector, String> {
+        let mut config = ClientConfig::new();
+        if insecure {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(NoVerification));
+        } else if let Some(ca_path) = ca_path {
+            let file =
+                File::open(ca_path).map_err(|err| format!("{}: {}", ca_path.display(), err))?;
+            match config.root_store.add_pem_file(&mut BufReader::new(file)) {
+                Ok((0, _)) => return Err(format!("{}: no certificates found", ca_path.display())),
+                Ok(_) => {}
+                Err(()) => return Err(format!("{}: malformed certificate", ca_path.display())),
+            }
+        } else {
+            config.root_store = system_roots();
+        }
+        Ok(UpstreamConnector {
+            tls: TlsConnector::from(Arc::new(config)),
+            send_proxy_protocol,
+        })
+    }
+
+    /// Connects to the upstream at `address` on behalf of `client` (None for balancebeam's own
+    /// connections, like health checks), doing the TLS handshake (with SNI set to the upstream's
+    /// hostname) if it's an `https://` one. The hostname is taken from `name` if given: the
+    /// upstream's configured address, if `address` is one it resolved to, or its sni_override (see
+    /// UpstreamInfo::server_name).
+    pub async fn connect(
+        &self,
+        address: &str,
+        name: Option<&str>,
+        client: Option<ConnectionAddresses>,
+    ) -> io::Result<UpstreamStream> {
+        let (tls, authority) = upstream::split_scheme(address);
+        let mut stream = TcpStream::connect(authority).await?;
+        if let Some(version) = self.send_proxy_protocol {
+            stream
+                .write_all(&proxy_protocol::encode_header(version, client))
+                .await?;
+        }
+        if !tls {
+            return Ok(UpstreamStream::Plain(stream));
+        }
+        let (_, named) = upstream::split_scheme(name.unwrap_or(address));
+        let host = named.rsplit_once(':').map_or(named, |(host, _port)| host);
+        // Certificates are only checked against hostnames, so one is needed even to skip the check
+        let name = DNSNameRef::try_from_ascii_str(host).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("TLS upstream {} must be named by hostname", address),
+            )
+        })?;
+        let stream = self.tls.connect(name, stream).await?;
+        Ok(UpstreamStream::Tls(Box::new(stream)))
+    }
+}
+
+/// The CA bundle the system's OpenSSL would use. Only TLS upstreams need it, so if there's none,
+/// connecting to them fails rather than balancebeam.
+fn system_roots() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    match openssl_probe::probe().cert_file {
+        Some(path) => match File::open(&path) {
+            Ok(file) => {
+                if roots.add_pem_file(&mut BufReader::new(file)).is_err() {
+                    log::warn!("Could not parse system CA bundle {}", path.display());
+                }
+            }
+            Err(err) => log::warn!(
+                "Could not read system CA bundle {}: {}",
+                path.display(),
+                err
+            ),
+        },
+        None => log::warn!("No system CA bundle found; pass --upstream-ca for TLS upstreams"),
+    }
+    roots
+}
+
+/// Accepts any certificate, for `--insecure-upstream`
+struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented_certs: &[Certificate],
+        _dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// A connection to an upstream, over plain TCP or TLS
+#[derive(Debug)]
+pub enum UpstreamStream {
+    Plain(TcpStream),
+    Tls(Box<client::TlsStream<TcpStream>>),
+}
+
+impl UpstreamStream {
+    fn tcp(&mut self) -> &mut TcpStream {
+        match self {
+            UpstreamStream::Plain(stream) => stream,
+            UpstreamStream::Tls(stream) => stream.get_mut().0,
+        }
+    }
+
+    /// Peeks at the underlying socket, which for TLS connections holds encrypted records.
+    pub fn poll_peek(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        self.tcp().poll_peek(cx, buf)
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CERT: &str = "tests/fixtures/tls-cert.pem";
+    const KEY: &str = "tests/fixtures/tls-key.pem";
+    const CA: &str = "tests/fixtures/tls-ca.pem";
+
+    #[test]
+    fn test_load_fixture() {
+        assert!(load_acceptor(Path::new(CERT), Path::new(KEY)).is_ok());
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let error = |cert: &str, key: &str| {
+            load_acceptor(Path::new(cert), Path::new(key))
+                .err()
+                .expect("loading should have failed")
+        };
+        let message = error("tests/fixtures/missing.pem", KEY);
+        assert!(
+            message.starts_with("tests/fixtures/missing.pem: "),
+            "{}",
+            message
+        );
+        assert_eq!(error(KEY, KEY), format!("{}: no certificates found", KEY));
+        assert_eq!(error(CERT, CERT), format!("{}: no private key found", CERT));
+    }
+
+    #[test]
+    fn test_parse_server_name() {
+        assert_eq!(
+            parse_server_name("backend.internal"),
+            Ok(String::from("backend.internal"))
+        );
+        assert!(parse_server_name("backend.internal:443").is_err());
+        assert!(parse_server_name("10.0.0.1").is_err());
+        assert!(parse_server_name("").is_err());
+        assert!(parse_server_name("back end").is_err());
+    }
+
+    #[test]
+    fn test_upstream_ca() {
+        assert!(UpstreamConnector::new(Some(Path::new(CA)), false, None).is_ok());
+        let error = |path: &str| {
+            UpstreamConnector::new(Some(Path::new(path)), false, None)
+                .expect_err("loading should have failed")
+        };
+        assert!(error("tests/fixtures/missing.pem").starts_with("tests/fixtures/missing.pem: "));
+        assert_eq!(error(KEY), format!("{}: no certificates found", KEY));
+        // The CA isn't even read when verification is off
+        assert!(UpstreamConnector::new(Some(Path::new(KEY)), true, None).is_ok());
+    }
+}
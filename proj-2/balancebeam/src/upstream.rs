@@ -0,0 +1,748 @@
+use crate::circuit_breaker::Circuit;
+use crate::health::{self, ProbeLatencies, ProbeSchedule, Streak};
+use crate::metrics::UpstreamStats;
+use crate::tls;
+use rand::Rng;
+use serde::{de, Deserialize, Deserializer};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// An upstream server from the command line, e.g. `10.0.0.1:80=4`, or from an `[[upstreams]]`
+/// table in the config file (the weight defaults to 1, `backup = true` makes it a backup, as
+/// `--upstream-backup` does, and `host_override` and `sni_override` can only be set there).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Upstream {
+    pub address: String,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    /// Most requests forwarded to it at once (0 = unlimited). Only the config file sets this per
+    /// upstream; the rest get --max-requests-per-upstream (see default_max_requests).
+    #[serde(default)]
+    pub max_requests: Option<usize>,
+    /// Only gets traffic while none of its group's other upstreams can take any
+    #[serde(default)]
+    pub backup: bool,
+    /// The Host requests (and health checks) are sent to it with, instead of the client's
+    #[serde(default, deserialize_with = "host_override")]
+    pub host_override: Option<String>,
+    /// The server name its TLS handshakes ask for, instead of the hostname in its address, for an
+    /// `https://` upstream
+    #[serde(default, deserialize_with = "sni_override")]
+    pub sni_override: Option<String>,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// Deserializes a `host_override`, written the same way as `--health-check-host`.
+fn host_override<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    health::parse_host(&String::deserialize(deserializer)?)
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+/// Deserializes an `sni_override`, which must be a DNS name.
+fn sni_override<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    tls::parse_server_name(&String::deserialize(deserializer)?)
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+/// Checks that only `https://` upstreams have an sni_override, since the others have no TLS
+/// handshake to use it in.
+pub fn check_overrides(upstream: &Upstream) -> Result<(), String> {
+    match (&upstream.sni_override, split_scheme(&upstream.address)) {
+        (Some(_), (false, _)) => Err(format!(
+            "upstream {} has an sni_override, but isn't an https:// one",
+            upstream.address
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Gives the upstreams that don't set their own maximum number of concurrent requests `max`.
+pub fn default_max_requests<'a>(upstreams: impl IntoIterator<Item = &'a mut Upstream>, max: usize) {
+    for upstream in upstreams {
+        upstream.max_requests.get_or_insert(max);
+    }
+}
+
+/// The smallest share of its traffic an upstream gets during slow start, so that one that is all
+/// that's left still gets picked
+const SLOW_START_FLOOR: f64 = 0.01;
+
+/// What balancebeam knows about one of the upstreams it is proxying to. Upstreams can come and go
+/// at runtime, so everything about one lives together here rather than in parallel lists.
+#[derive(Debug)]
+pub struct UpstreamInfo {
+    pub address: String,
+    /// The configured address with a hostname that `address` is one of the resolutions of, with
+    /// --resolve-interval; None for an upstream used as configured
+    pub name: Option<String>,
+    /// Relative share of traffic
+    pub weight: u32,
+    /// Whether the upstream only gets traffic when no other (primary) one is up, see in_rotation
+    pub backup: bool,
+    /// The Host its requests are sent with, if not the client's
+    pub host_override: Option<String>,
+    /// The server name its TLS handshakes ask for, if not its hostname
+    pub sni_override: Option<String>,
+    /// Whether the upstream is getting traffic, or has been marked down
+    pub healthy: bool,
+    /// Whether the admin listener has taken the upstream out of rotation, e.g. for a deploy. It
+    /// gets no new requests (see eligible), but is still health checked, and isn't down.
+    pub draining: bool,
+    /// Recent health results, deciding when to flip `healthy`
+    pub streak: Streak,
+    /// When the health checker last marked the upstream back up, for slow start
+    pub recovered_at: Option<Instant>,
+    /// When the active health checker probes the upstream next
+    pub probes: ProbeSchedule,
+    /// How long its recent probes took
+    pub probe_latencies: ProbeLatencies,
+    /// Recent request outcomes, deciding whether the upstream is skipped despite being healthy
+    pub circuit: Circuit,
+    /// Requests in a row that failed, for --unhealthy-status-threshold (see PassiveHealth)
+    pub failed_requests: u32,
+    /// Shared with the connections checked out to this upstream, so they can count traffic without
+    /// taking the upstream list's lock
+    pub stats: Arc<UpstreamStats>,
+    /// Most requests forwarded to the upstream at once (0 = unlimited)
+    pub max_requests: usize,
+    /// One permit per request that may be forwarded to the upstream at once, when max_requests is
+    /// set. Requests hold theirs for as long as they have the upstream's connection checked out.
+    request_slots: Arc<Semaphore>,
+}
+
+impl UpstreamInfo {
+    /// A newly added upstream starts out healthy.
+    pub fn new(upstream: Upstream) -> Self {
+        UpstreamInfo {
+            address: upstream.address,
+            name: None,
+            weight: upstream.weight,
+            backup: upstream.backup,
+            host_override: upstream.host_override,
+            sni_override: upstream.sni_override,
+            healthy: true,
+            draining: false,
+            streak: Streak::default(),
+            recovered_at: None,
+            probes: ProbeSchedule::default(),
+            probe_latencies: ProbeLatencies::default(),
+            circuit: Circuit::default(),
+            failed_requests: 0,
+            stats: Arc::new(UpstreamStats::default()),
+            max_requests: upstream.max_requests.unwrap_or(0),
+            request_slots: Arc::new(Semaphore::new(upstream.max_requests.unwrap_or(0))),
+        }
+    }
+
+    /// Whether the upstream can take another request without going over max_requests
+    pub fn has_capacity(&self) -> bool {
+        self.max_requests == 0 || self.request_slots.available_permits() > 0
+    }
+
+    /// How many requests hold one of the upstream's request slots
+    pub fn requests_in_flight(&self) -> usize {
+        self.max_requests - self.request_slots.available_permits()
+    }
+
+    /// Claims one of the upstream's request slots, which it gets back when the permit is dropped.
+    /// There is no permit to hold if the upstream is unlimited, and a `ResourceBusy` error if it's
+    /// at capacity.
+    pub fn claim_slot(&self) -> std::io::Result<Option<OwnedSemaphorePermit>> {
+        if self.max_requests == 0 {
+            return Ok(None);
+        }
+        match self.request_slots.clone().try_acquire_owned() {
+            Ok(permit) => Ok(Some(permit)),
+            Err(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::ResourceBusy,
+                format!("{} is at its request limit", self.address),
+            )),
+        }
+    }
+
+    /// Changes max_requests. Requests already holding a slot keep it, but don't count against the
+    /// new limit.
+    pub fn set_max_requests(&mut self, max_requests: usize) {
+        if max_requests != self.max_requests {
+            self.max_requests = max_requests;
+            self.request_slots = Arc::new(Semaphore::new(max_requests));
+        }
+    }
+
+    /// The upstream's address as configured, which several resolved entries may share
+    pub fn configured_address(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.address)
+    }
+
+    /// The hostname UpstreamConnector::connect should give TLS handshakes with the upstream: its
+    /// sni_override, or else the hostname it was resolved from, if it was
+    pub fn server_name(&self) -> Option<String> {
+        self.sni_override.clone().or_else(|| self.name.clone())
+    }
+
+    /// How much of its usual share of traffic the upstream should get at `now`, rising linearly
+    /// to all of it over the `slow_start` period after it came back up.
+    pub fn slow_start_factor(&self, slow_start: Duration, now: Instant) -> f64 {
+        match self.recovered_at {
+            Some(recovered_at) if now < recovered_at + slow_start => {
+                let warmed_up = now.duration_since(recovered_at).as_secs_f64();
+                (warmed_up / slow_start.as_secs_f64()).max(SLOW_START_FLOOR)
+            }
+            _ => 1.0,
+        }
+    }
+}
+
+/// Scales `weight` down by a slow start `factor` for pick_weighted, counting in thousandths so
+/// that part of a small weight isn't rounded away. A positive weight stays positive, so a warming
+/// up upstream isn't mistaken for one with weight zero.
+pub fn ramped_weight(weight: u32, factor: f64) -> u32 {
+    if weight == 0 {
+        return 0;
+    }
+    // Casting saturates, should the weight be huge
+    ((weight as f64 * factor * 1000.0).round() as u32).max(1)
+}
+
+/// Parses an `--upstream` value of the form `host:port` or `host:port=weight`, where `host:port`
+/// may be prefixed with `https://` (or `http://`, the default).
+pub fn parse_upstream(arg: &str) -> Result<Upstream, String> {
+    let (address, weight) = match arg.rsplit_once('=') {
+        Some((address, weight)) => {
+            let weight = weight
+                .parse::<u32>()
+                .map_err(|_| format!("invalid weight {:?} for upstream {}", weight, address))?;
+            (address, weight)
+        }
+        None => (arg, 1),
+    };
+    if address.is_empty() {
+        return Err(format!("missing address in upstream {:?}", arg));
+    }
+    if let Some((scheme, _)) = address.split_once("://") {
+        if scheme != "http" && scheme != "https" {
+            return Err(format!(
+                "unsupported scheme {:?} in upstream {}",
+                scheme, address
+            ));
+        }
+    }
+    Ok(Upstream {
+        address: String::from(address),
+        weight,
+        max_requests: None,
+        backup: false,
+        host_override: None,
+        sni_override: None,
+    })
+}
+
+/// Parses an `--upstream-backup` value, written as for `--upstream`.
+pub fn parse_backup_upstream(arg: &str) -> Result<Upstream, String> {
+    Ok(Upstream {
+        backup: true,
+        ..parse_upstream(arg)?
+    })
+}
+
+/// Which upstreams are in rotation, out of those `usable` (up, and with their circuit closed):
+/// the usable primaries, or if there are none, the usable backups.
+pub fn in_rotation(usable: &[bool], backup: &[bool]) -> Vec<bool> {
+    let primary_usable = usable
+        .iter()
+        .zip(backup)
+        .any(|(&usable, &backup)| usable && !backup);
+    usable
+        .iter()
+        .zip(backup)
+        .map(|(&usable, &backup)| usable && backup != primary_usable)
+        .collect()
+}
+
+/// Which upstreams may be picked, out of those `usable`, leaving out the `draining` ones: those in
+/// rotation (see in_rotation) among the rest, or if none of them are and `drain_fallback` is set,
+/// those in rotation with the draining ones back in.
+pub fn eligible(
+    usable: &[bool],
+    backup: &[bool],
+    draining: &[bool],
+    drain_fallback: bool,
+) -> Vec<bool> {
+    let undrained: Vec<bool> = usable
+        .iter()
+        .zip(draining)
+        .map(|(&usable, &draining)| usable && !draining)
+        .collect();
+    let eligible = in_rotation(&undrained, backup);
+    if drain_fallback && !eligible.contains(&true) {
+        in_rotation(usable, backup)
+    } else {
+        eligible
+    }
+}
+
+/// Splits an upstream address into whether it is reached over TLS (`https://host:port`) and its
+/// `host:port`.
+pub fn split_scheme(address: &str) -> (bool, &str) {
+    match address.strip_prefix("https://") {
+        Some(authority) => (true, authority),
+        None => (false, address.strip_prefix("http://").unwrap_or(address)),
+    }
+}
+
+/// The IP an upstream address (`[https://]ip:port`) points at, or its whole `host:port` if that
+/// isn't an IP.
+pub fn ip_of(address: &str) -> String {
+    let (_, authority) = split_scheme(address);
+    match authority.parse::<std::net::SocketAddr>() {
+        Ok(addr) => addr.ip().to_string(),
+        Err(_) => authority.to_string(),
+    }
+}
+
+/// One difference `sync_upstreams` made to the upstream list
+#[derive(Debug, PartialEq, Eq)]
+pub enum UpstreamChange {
+    Added(String),
+    Removed(String),
+    Reweighted { address: String, from: u32, to: u32 },
+}
+
+impl fmt::Display for UpstreamChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpstreamChange::Added(address) => write!(f, "added upstream {}", address),
+            UpstreamChange::Removed(address) => write!(f, "removed upstream {}", address),
+            UpstreamChange::Reweighted { address, from, to } => {
+                write!(f, "upstream {} weight {} -> {}", address, from, to)
+            }
+        }
+    }
+}
+
+/// Makes `current` list exactly the upstreams in `wanted`, in that order. Upstreams in both keep
+/// their health and traffic counters (only their weight, request limit, whether they are a
+/// backup and their overrides are updated), as do all
+/// the addresses an upstream has been resolved to; the rest are added or dropped.
+pub fn sync_upstreams(
+    current: &mut Vec<UpstreamInfo>,
+    wanted: Vec<Upstream>,
+) -> Vec<UpstreamChange> {
+    let mut old = std::mem::take(current);
+    let mut changes = Vec::new();
+    for upstream in wanted {
+        if current
+            .iter()
+            .any(|info| info.configured_address() == upstream.address)
+        {
+            continue;
+        }
+        let (mut kept, rest): (Vec<UpstreamInfo>, Vec<UpstreamInfo>) = old
+            .into_iter()
+            .partition(|info| info.configured_address() == upstream.address);
+        old = rest;
+        match kept.first() {
+            Some(info) => {
+                if info.weight != upstream.weight {
+                    changes.push(UpstreamChange::Reweighted {
+                        address: upstream.address.clone(),
+                        from: info.weight,
+                        to: upstream.weight,
+                    });
+                    for info in &mut kept {
+                        info.weight = upstream.weight;
+                    }
+                }
+                for info in &mut kept {
+                    info.set_max_requests(upstream.max_requests.unwrap_or(0));
+                    info.backup = upstream.backup;
+                    info.host_override = upstream.host_override.clone();
+                    info.sni_override = upstream.sni_override.clone();
+                }
+                current.append(&mut kept);
+            }
+            None => {
+                changes.push(UpstreamChange::Added(upstream.address.clone()));
+                current.push(UpstreamInfo::new(upstream));
+            }
+        }
+    }
+    changes.extend(
+        old.into_iter()
+            .map(|info| UpstreamChange::Removed(info.address)),
+    );
+    changes
+}
+
+/// The sticky session cookie value that pins clients to the upstream at `address`. It is a hash, so
+/// the cookie doesn't give the address away, and it doesn't change as other upstreams come and go.
+pub fn session_key(address: &str) -> String {
+    // 64-bit FNV-1a
+    let hash = address
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+        });
+    format!("{:016x}", hash)
+}
+
+/// Picks one of the upstreams marked alive, with probability proportional to its weight.
+/// Upstreams with weight zero are only considered (uniformly) when no upstream with a positive
+/// weight is alive. Returns None if nothing is alive.
+pub fn pick_weighted<R: Rng>(weights: &[u32], alive: &[bool], rng: &mut R) -> Option<usize> {
+    let live: Vec<usize> = (0..weights.len()).filter(|&idx| alive[idx]).collect();
+    let total: u64 = live.iter().map(|&idx| weights[idx] as u64).sum();
+    if total == 0 {
+        if live.is_empty() {
+            return None;
+        }
+        return Some(live[rng.gen_range(0, live.len())]);
+    }
+    let mut target = rng.gen_range(0, total);
+    for idx in live {
+        let weight = weights[idx] as u64;
+        if target < weight {
+            return Some(idx);
+        }
+        target -= weight;
+    }
+    unreachable!("target is always below the total weight")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn pick_counts(weights: &[u32], alive: &[bool], picks: usize) -> Vec<usize> {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut counts = vec![0; weights.len()];
+        for _ in 0..picks {
+            counts[pick_weighted(weights, alive, &mut rng).unwrap()] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn test_parse_upstream() {
+        assert_eq!(
+            parse_upstream("127.0.0.1:8080"),
+            Ok(Upstream {
+                address: String::from("127.0.0.1:8080"),
+                weight: 1,
+                max_requests: None,
+                backup: false,
+                host_override: None,
+                sni_override: None,
+            })
+        );
+        assert_eq!(
+            parse_upstream("big-box:80=4"),
+            Ok(Upstream {
+                address: String::from("big-box:80"),
+                weight: 4,
+                max_requests: None,
+                backup: false,
+                host_override: None,
+                sni_override: None,
+            })
+        );
+        assert_eq!(parse_upstream("canary:80=0").unwrap().weight, 0);
+        assert!(parse_upstream("canary:80=").is_err());
+        assert!(parse_upstream("canary:80=-1").is_err());
+        assert!(parse_upstream("canary:80=heavy").is_err());
+        assert!(parse_upstream("=3").is_err());
+        assert!(parse_upstream("").is_err());
+        assert_eq!(
+            parse_upstream("https://api.internal:443=2"),
+            Ok(Upstream {
+                address: String::from("https://api.internal:443"),
+                weight: 2,
+                max_requests: None,
+                backup: false,
+                host_override: None,
+                sni_override: None,
+            })
+        );
+        assert!(parse_upstream("ftp://files:21").is_err());
+        assert_eq!(
+            parse_backup_upstream("maintenance:80=2"),
+            Ok(Upstream {
+                address: String::from("maintenance:80"),
+                weight: 2,
+                max_requests: None,
+                backup: true,
+                host_override: None,
+                sni_override: None,
+            })
+        );
+        assert!(parse_backup_upstream("=2").is_err());
+    }
+
+    #[test]
+    fn test_check_overrides() {
+        let upstream = |address: &str| Upstream {
+            sni_override: Some(String::from("backend.internal")),
+            ..parse_upstream(address).unwrap()
+        };
+        assert!(check_overrides(&upstream("https://10.0.0.1:443")).is_ok());
+        assert!(check_overrides(&upstream("10.0.0.1:80")).is_err());
+        assert!(check_overrides(&upstream("http://10.0.0.1:80")).is_err());
+        let host_only = Upstream {
+            host_override: Some(String::from("app.example.com")),
+            ..parse_upstream("10.0.0.1:80").unwrap()
+        };
+        assert!(check_overrides(&host_only).is_ok());
+    }
+
+    #[test]
+    fn test_backups_only_when_no_primary_is_usable() {
+        let backup = [false, false, true, true];
+        assert_eq!(
+            in_rotation(&[true, false, true, true], &backup),
+            vec![true, false, false, false]
+        );
+        assert_eq!(
+            in_rotation(&[false, false, true, false], &backup),
+            vec![false, false, true, false]
+        );
+        assert_eq!(in_rotation(&[false; 4], &backup), vec![false; 4]);
+        // With no backups, it's just the usable upstreams
+        assert_eq!(
+            in_rotation(&[true, false], &[false, false]),
+            vec![true, false]
+        );
+    }
+
+    #[test]
+    fn test_draining_upstreams_are_not_eligible() {
+        let backup = [false, false, true];
+        assert_eq!(
+            eligible(&[true, true, true], &backup, &[true, false, false], false),
+            vec![false, true, false]
+        );
+        // A backup stands in for draining primaries before they are fallen back on
+        assert_eq!(
+            eligible(&[true, false, true], &backup, &[true, false, false], true),
+            vec![false, false, true]
+        );
+        assert_eq!(
+            eligible(&[true, false, false], &backup, &[true, false, false], false),
+            vec![false; 3]
+        );
+        assert_eq!(
+            eligible(&[true, false, false], &backup, &[true, false, false], true),
+            vec![true, false, false]
+        );
+        // Falling back doesn't bring back upstreams that are down
+        assert_eq!(
+            eligible(&[false, false, false], &backup, &[true, false, false], true),
+            vec![false; 3]
+        );
+    }
+
+    #[test]
+    fn test_split_scheme() {
+        assert_eq!(split_scheme("10.0.0.1:80"), (false, "10.0.0.1:80"));
+        assert_eq!(split_scheme("http://10.0.0.1:80"), (false, "10.0.0.1:80"));
+        assert_eq!(
+            split_scheme("https://api.internal:443"),
+            (true, "api.internal:443")
+        );
+        assert_eq!(ip_of("https://10.0.0.1:443"), "10.0.0.1");
+        assert_eq!(ip_of("[::1]:80"), "::1");
+        assert_eq!(ip_of("api.internal:80"), "api.internal:80");
+    }
+
+    #[test]
+    fn test_weighted_distribution() {
+        let picks = 5000;
+        let counts = pick_counts(&[4, 1], &[true, true], picks);
+        let big_share = counts[0] as f64 / picks as f64;
+        assert!(
+            (big_share - 0.8).abs() < 0.03,
+            "expected ~80% on the heavy upstream, got {:?}",
+            counts
+        );
+
+        let counts = pick_counts(&[1, 2, 3], &[true, true, true], 6000);
+        for (idx, &count) in counts.iter().enumerate() {
+            let expected = 1000.0 * (idx + 1) as f64;
+            assert!(
+                (count as f64 - expected).abs() < 0.1 * expected,
+                "expected ~{} picks of upstream {}, got {:?}",
+                expected,
+                idx,
+                counts
+            );
+        }
+    }
+
+    #[test]
+    fn test_weighted_skips_dead_upstreams() {
+        let counts = pick_counts(&[4, 1, 3], &[false, true, true], 4000);
+        assert_eq!(counts[0], 0);
+        assert!(
+            (counts[2] as f64 / 4000.0 - 0.75).abs() < 0.03,
+            "{:?}",
+            counts
+        );
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(pick_weighted(&[1, 1], &[false, false], &mut rng), None);
+    }
+
+    #[test]
+    fn test_zero_weight_only_when_everything_else_is_down() {
+        let counts = pick_counts(&[0, 1], &[true, true], 1000);
+        assert_eq!(counts, vec![0, 1000]);
+        let counts = pick_counts(&[0, 1], &[true, false], 100);
+        assert_eq!(counts, vec![100, 0]);
+        let counts = pick_counts(&[0, 0], &[true, true], 1000);
+        assert!(counts[0] > 400 && counts[1] > 400, "{:?}", counts);
+    }
+
+    #[test]
+    fn test_sync_upstreams() {
+        let mut current: Vec<UpstreamInfo> = ["a:80", "b:80=2", "c:80"]
+            .iter()
+            .map(|arg| UpstreamInfo::new(parse_upstream(arg).unwrap()))
+            .collect();
+        current[1].healthy = false;
+        current[1].stats.record_failure();
+        let wanted = ["d:80", "b:80=5", "a:80", "d:80=3"]
+            .iter()
+            .map(|arg| parse_upstream(arg).unwrap())
+            .collect();
+        let changes = sync_upstreams(&mut current, wanted);
+        assert_eq!(
+            changes,
+            vec![
+                UpstreamChange::Added(String::from("d:80")),
+                UpstreamChange::Reweighted {
+                    address: String::from("b:80"),
+                    from: 2,
+                    to: 5
+                },
+                UpstreamChange::Removed(String::from("c:80")),
+            ]
+        );
+        let addresses: Vec<&str> = current.iter().map(|info| info.address.as_str()).collect();
+        assert_eq!(addresses, vec!["d:80", "b:80", "a:80"]);
+        assert_eq!(current[0].weight, 1);
+        // b keeps its health and counters
+        assert!(!current[1].healthy);
+        assert_eq!(current[1].stats.failures(), 1);
+        assert!(sync_upstreams(&mut current, Vec::new()).len() == 3);
+        assert!(current.is_empty());
+    }
+
+    #[test]
+    fn test_sync_keeps_resolved_addresses() {
+        let mut current: Vec<UpstreamInfo> = ["10.0.1.1:80", "10.0.1.2:80", "c:80"]
+            .iter()
+            .map(|arg| UpstreamInfo::new(parse_upstream(arg).unwrap()))
+            .collect();
+        current[0].name = Some(String::from("api:80"));
+        current[1].name = Some(String::from("api:80"));
+        current[1].healthy = false;
+        let wanted = ["c:80", "api:80=2"]
+            .iter()
+            .map(|arg| parse_upstream(arg).unwrap())
+            .collect();
+        let changes = sync_upstreams(&mut current, wanted);
+        assert_eq!(
+            changes,
+            vec![UpstreamChange::Reweighted {
+                address: String::from("api:80"),
+                from: 1,
+                to: 2
+            }]
+        );
+        let addresses: Vec<&str> = current.iter().map(|info| info.address.as_str()).collect();
+        assert_eq!(addresses, vec!["c:80", "10.0.1.1:80", "10.0.1.2:80"]);
+        assert!(current[1..].iter().all(|info| info.weight == 2));
+        assert!(!current[2].healthy);
+        let changes = sync_upstreams(&mut current, vec![parse_upstream("c:80").unwrap()]);
+        assert_eq!(
+            changes,
+            vec![
+                UpstreamChange::Removed(String::from("10.0.1.1:80")),
+                UpstreamChange::Removed(String::from("10.0.1.2:80")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_request_slots() {
+        let mut upstreams = vec![
+            parse_upstream("a:80").unwrap(),
+            parse_upstream("b:80").unwrap(),
+        ];
+        upstreams[1].max_requests = Some(0);
+        default_max_requests(&mut upstreams, 2);
+        assert_eq!(upstreams[0].max_requests, Some(2));
+        // An explicit 0 is unlimited, not unset
+        assert_eq!(upstreams[1].max_requests, Some(0));
+
+        let mut info = UpstreamInfo::new(upstreams[0].clone());
+        let first = info.claim_slot().unwrap();
+        let second = info.claim_slot().unwrap();
+        assert!(first.is_some() && second.is_some());
+        assert!(!info.has_capacity());
+        assert_eq!(
+            info.claim_slot().unwrap_err().kind(),
+            std::io::ErrorKind::ResourceBusy
+        );
+        drop(first);
+        assert!(info.has_capacity());
+        assert_eq!(info.requests_in_flight(), 1);
+        // Raising the limit starts counting afresh
+        info.set_max_requests(3);
+        assert_eq!(info.requests_in_flight(), 0);
+
+        let unlimited = UpstreamInfo::new(upstreams[1].clone());
+        assert!(unlimited.claim_slot().unwrap().is_none());
+        assert!(unlimited.has_capacity());
+    }
+
+    #[test]
+    fn test_slow_start_factor() {
+        let mut info = UpstreamInfo::new(parse_upstream("a:80").unwrap());
+        let slow_start = Duration::from_secs(10);
+        let now = Instant::now();
+        assert_eq!(info.slow_start_factor(slow_start, now), 1.0);
+        info.recovered_at = Some(now);
+        assert_eq!(info.slow_start_factor(slow_start, now), SLOW_START_FLOOR);
+        let factor = info.slow_start_factor(slow_start, now + Duration::from_secs(3));
+        assert!((factor - 0.3).abs() < 1e-9, "{}", factor);
+        assert_eq!(info.slow_start_factor(slow_start, now + slow_start), 1.0);
+        assert_eq!(info.slow_start_factor(Duration::from_secs(0), now), 1.0);
+    }
+
+    #[test]
+    fn test_ramped_weight() {
+        assert_eq!(ramped_weight(4, 1.0), 4000);
+        assert_eq!(ramped_weight(4, 0.25), 1000);
+        assert_eq!(ramped_weight(1, 0.0001), 1);
+        assert_eq!(ramped_weight(0, 0.5), 0);
+        assert_eq!(ramped_weight(u32::MAX, 1.0), u32::MAX);
+    }
+
+    #[test]
+    fn test_session_key() {
+        assert_eq!(session_key(""), "cbf29ce484222325");
+        assert_eq!(session_key("a"), "af63dc4c8601ec8c");
+        assert_eq!(session_key("10.0.0.1:80"), session_key("10.0.0.1:80"));
+        assert_ne!(session_key("10.0.0.1:80"), session_key("10.0.0.2:80"));
+        assert!(!session_key("10.0.0.1:80").contains("10.0.0.1"));
+    }
+}
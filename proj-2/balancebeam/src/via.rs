@@ -0,0 +1,128 @@
+/// Parses an `--instance-id`. It ends up in Via headers as part of a token, so it may only have
+/// the characters a token can.
+pub fn parse_instance_id(arg: &str) -> Result<String, String> {
+    if arg.is_empty() {
+        return Err(String::from("the instance ID can't be empty"));
+    }
+    if !arg.bytes().all(is_token_byte) {
+        return Err(format!(
+            "instance ID {:?} may only have letters, digits and !#$%&'*+-.^_`|~",
+            arg
+        ));
+    }
+    Ok(arg.to_string())
+}
+
+fn is_token_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+/// The instance ID to use when none is given: this machine's hostname and our PID, e.g.
+/// `web-3-4172`, which no other balancebeam that might forward to us should share.
+pub fn default_instance_id() -> String {
+    let hostname = ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .map(|contents| {
+            contents
+                .trim()
+                .bytes()
+                .filter(|&byte| is_token_byte(byte))
+                .map(char::from)
+                .collect::<String>()
+        })
+        .find(|hostname| !hostname.is_empty())
+        .unwrap_or_else(|| String::from("localhost"));
+    format!("{}-{}", hostname, std::process::id())
+}
+
+/// The name an instance goes by in Via headers, e.g. `balancebeam-web-3-4172`
+pub fn pseudonym(instance_id: &str) -> String {
+    format!("balancebeam-{}", instance_id)
+}
+
+/// Whether a message already passed through the balancebeam going by `pseudonym`, i.e. one of
+/// the entries in its Via headers was added by it.
+pub fn has_passed_through(headers: &http::HeaderMap, pseudonym: &str) -> bool {
+    headers
+        .get_all(http::header::VIA)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        // Each entry is the protocol it was received with, who received it, then maybe a comment
+        .any(|entry| entry.split_whitespace().nth(1) == Some(pseudonym))
+}
+
+/// Records in a message's Via headers that the balancebeam going by `pseudonym` received it over
+/// `version`, after whatever entries it already has.
+pub fn append(headers: &mut http::HeaderMap, version: http::Version, pseudonym: &str) {
+    let protocol = match version {
+        http::Version::HTTP_09 => "0.9",
+        http::Version::HTTP_10 => "1.0",
+        http::Version::HTTP_2 => "2",
+        http::Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+    let entry = format!("{} {}", protocol, pseudonym);
+    // parse_instance_id only let through token characters
+    headers.append(
+        http::header::VIA,
+        http::HeaderValue::from_str(&entry).unwrap(),
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn via(values: &[&'static str]) -> http::HeaderMap {
+        let mut headers = http::HeaderMap::new();
+        for value in values {
+            headers.append(http::header::VIA, http::HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_parse_instance_id() {
+        assert_eq!(parse_instance_id("edge-1"), Ok(String::from("edge-1")));
+        assert!(parse_instance_id("").is_err());
+        assert!(parse_instance_id("two words").is_err());
+        assert!(parse_instance_id("a,b").is_err());
+        let default = default_instance_id();
+        assert!(default.ends_with(&format!("-{}", std::process::id())));
+        assert_eq!(parse_instance_id(&default), Ok(default));
+    }
+
+    #[test]
+    fn test_has_passed_through() {
+        let us = pseudonym("edge-1");
+        assert!(!has_passed_through(&http::HeaderMap::new(), &us));
+        assert!(has_passed_through(
+            &via(&["1.0 fred, 1.1 balancebeam-edge-1 (ours)"]),
+            &us
+        ));
+        assert!(has_passed_through(
+            &via(&["1.1 cache", "HTTP/1.1 balancebeam-edge-1"]),
+            &us
+        ));
+        // Other instances, and comments that merely mention us, don't count
+        assert!(!has_passed_through(&via(&["1.1 balancebeam-edge-10"]), &us));
+        assert!(!has_passed_through(
+            &via(&["1.1 cache (balancebeam-edge-1)"]),
+            &us
+        ));
+    }
+
+    #[test]
+    fn test_append() {
+        let mut headers = via(&["1.0 fred"]);
+        append(&mut headers, http::Version::HTTP_11, &pseudonym("edge-1"));
+        let values: Vec<_> = headers.get_all(http::header::VIA).iter().collect();
+        assert_eq!(values, vec!["1.0 fred", "1.1 balancebeam-edge-1"]);
+        assert!(has_passed_through(&headers, &pseudonym("edge-1")));
+        let mut headers = http::HeaderMap::new();
+        append(&mut headers, http::Version::HTTP_10, &pseudonym("edge-1"));
+        assert_eq!(headers["via"], "1.0 balancebeam-edge-1");
+    }
+}
@@ -1,7 +1,16 @@
 mod common;
 
-use common::{init_logging, BalanceBeam, EchoServer, Server};
+use common::{
+    free_local_address, init_logging, start_slow_upstream, BalanceBeam, EchoServer, Server,
+};
+use flate2::read::GzDecoder;
+use std::io::Read;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{delay_for, timeout};
 
 async fn setup() -> (BalanceBeam, EchoServer) {
     init_logging();
@@ -99,3 +108,2335 @@ async fn test_multiple_requests_per_connection() {
 
     log::info!("All done :)");
 }
+
+/// On SIGTERM, balancebeam should stop accepting connections but finish the request it's in the
+/// middle of (telling the client it's closing the connection), and then exit
+#[tokio::test]
+async fn test_graceful_shutdown_finishes_in_flight_request() {
+    init_logging();
+    let upstream_address = start_slow_upstream(Duration::from_secs(2)).await;
+    let mut balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    let address = balancebeam.address.clone();
+    let in_flight = tokio::spawn(async move {
+        reqwest::Client::new()
+            .get(&format!("http://{}/slow", address))
+            .send()
+            .await
+    });
+    delay_for(Duration::from_millis(500)).await;
+    log::info!("Sending SIGTERM while the request is waiting on the upstream");
+    balancebeam.terminate();
+    delay_for(Duration::from_millis(200)).await;
+    assert!(
+        TcpStream::connect(&balancebeam.address).await.is_err(),
+        "balancebeam is still accepting connections after SIGTERM"
+    );
+
+    let response = in_flight
+        .await
+        .unwrap()
+        .expect("The in-flight request was cut off by the shutdown");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["connection"], "close");
+    assert_eq!(response.text().await.unwrap(), "slow");
+
+    let status = timeout(Duration::from_secs(5), balancebeam.wait())
+        .await
+        .expect("balancebeam didn't exit after draining its connections");
+    assert!(status.success(), "balancebeam exited with {}", status);
+
+    log::info!("All done :)");
+}
+
+/// With --access-log-format json, every answered request (including the ones balancebeam answers
+/// itself) should get one JSON line on stdout
+#[tokio::test]
+async fn test_json_access_log() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--access-log-format",
+            "json",
+            "--max-requests-per-minute",
+            "2",
+        ],
+    )
+    .await;
+
+    balancebeam
+        .get("/first")
+        .await
+        .expect("Error sending request to balancebeam");
+    balancebeam
+        .post("/second?draft=1", "some body")
+        .await
+        .expect("Error sending request to balancebeam");
+    balancebeam
+        .get("/third")
+        .await
+        .expect("Error sending request to balancebeam");
+    delay_for(Duration::from_millis(200)).await;
+
+    let entries: Vec<serde_json::Value> = balancebeam
+        .stdout_lines()
+        .iter()
+        .map(|line| serde_json::from_str(line).expect("Access log line is not JSON"))
+        .collect();
+    assert_eq!(entries.len(), 3);
+    for entry in &entries {
+        let fields: Vec<&str> = entry
+            .as_object()
+            .expect("Access log entry is not an object")
+            .keys()
+            .map(|key| key.as_str())
+            .collect();
+        assert_eq!(fields.len(), 12);
+        for field in &[
+            "client_ip",
+            "method",
+            "path",
+            "request_id",
+            "upstream",
+            "status",
+            "request_bytes",
+            "response_bytes",
+            "bytes_in",
+            "bytes_out",
+            "elapsed_ms",
+            "error",
+        ] {
+            assert!(fields.contains(field), "{} missing from {}", field, entry);
+        }
+        assert_eq!(entry["client_ip"], "127.0.0.1");
+        assert!(entry["request_id"].is_string(), "{}", entry);
+    }
+
+    assert_eq!(entries[0]["method"], "GET");
+    assert_eq!(entries[0]["path"], "/first");
+    assert_eq!(entries[0]["upstream"], upstream.address.as_str());
+    assert_eq!(entries[0]["status"], 200);
+    assert_eq!(entries[0]["request_bytes"], 0);
+    assert!(entries[0]["response_bytes"].as_u64().unwrap() > 0);
+    assert!(entries[0]["elapsed_ms"].as_f64().unwrap() >= 0.0);
+    assert!(entries[0]["error"].is_null());
+
+    assert_eq!(entries[1]["method"], "POST");
+    assert_eq!(entries[1]["path"], "/second");
+    assert_eq!(entries[1]["request_bytes"], "some body".len());
+
+    assert_eq!(entries[2]["path"], "/third");
+    assert_eq!(entries[2]["status"], 429);
+    assert!(entries[2]["upstream"].is_null());
+    assert!(entries[2]["elapsed_ms"].is_null());
+    assert_eq!(entries[2]["error"], "rate_limited");
+
+    log::info!("Sending a request that no upstream can answer");
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&free_local_address()], &["--access-log-format", "json"])
+            .await;
+    balancebeam
+        .get("/unreachable")
+        .await
+        .expect("Error sending request to balancebeam");
+    delay_for(Duration::from_millis(200)).await;
+    let lines = balancebeam.stdout_lines();
+    assert_eq!(lines.len(), 1);
+    let entry: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+    assert_eq!(entry["path"], "/unreachable");
+    assert_eq!(entry["status"], 502);
+    assert_eq!(entry["error"], "upstream_unavailable");
+
+    log::info!("All done :)");
+}
+
+/// A request framed both by Content-Length and by chunks (CL.TE request smuggling) should get a 400
+/// without reaching the upstream, and one listing its custom headers in Connection should reach the
+/// upstream without them
+#[tokio::test]
+async fn test_ambiguous_framing_and_hop_by_hop_headers() {
+    let (balancebeam, upstream) = setup().await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    conn.write_all(
+        b"POST / HTTP/1.1\r\nHost: test\r\nContent-Length: 6\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\nG",
+    )
+    .await
+    .unwrap();
+    let mut response = String::new();
+    timeout(Duration::from_secs(5), conn.read_to_string(&mut response))
+        .await
+        .expect("balancebeam did not answer the smuggling request")
+        .unwrap();
+    assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+
+    let response = reqwest::Client::new()
+        .get(&format!("http://{}/hop", balancebeam.address))
+        .header("Connection", "X-Hop")
+        .header("X-Hop", "secret")
+        .header("X-End-To-End", "kept")
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap()
+        .to_lowercase();
+    assert!(!response.contains("x-hop"), "{}", response);
+    assert!(response.contains("x-end-to-end: kept"), "{}", response);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// A request with a chunked body should reach the upstream whole, with a Content-Length instead
+#[tokio::test]
+async fn test_chunked_request_body() {
+    let (balancebeam, upstream) = setup().await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    conn.write_all(b"POST /upload HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: chunked\r\n\r\n")
+        .await
+        .unwrap();
+    for chunk in &[
+        "7\r\nchunked\r\n",
+        "1;ext=1\r\n \r\n",
+        "4\r\nbody\r\n",
+        "0\r\n\r\n",
+    ] {
+        conn.write_all(chunk.as_bytes()).await.unwrap();
+        delay_for(Duration::from_millis(50)).await;
+    }
+    // Read until the echoed body has come back
+    let mut response = Vec::new();
+    let read_response = async {
+        let mut buffer = [0_u8; 1024];
+        while !response.ends_with(b"chunked body") {
+            let bytes_read = conn.read(&mut buffer).await.unwrap();
+            assert!(bytes_read > 0, "balancebeam hung up early");
+            response.extend_from_slice(&buffer[..bytes_read]);
+        }
+    };
+    timeout(Duration::from_secs(5), read_response)
+        .await
+        .expect("balancebeam did not answer the chunked request");
+    let response = String::from_utf8(response).unwrap();
+    log::info!("Response: {}", response);
+    assert!(response.starts_with("HTTP/1.1 200"));
+    let echoed = response.to_lowercase();
+    assert!(echoed.contains("post /upload"));
+    assert!(echoed.contains("content-length: 12\n"));
+    assert!(!echoed.contains("transfer-encoding"));
+    assert!(response.ends_with("chunked body"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Response bodies too big to hold in memory should be streamed through to the client
+#[tokio::test]
+async fn test_large_response_is_streamed() {
+    init_logging();
+    const BODY_SIZE: usize = 50 * 1024 * 1024;
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0_u8; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+            let bytes_read = conn.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..bytes_read]);
+        }
+        let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", BODY_SIZE);
+        conn.write_all(head.as_bytes()).await.unwrap();
+        let piece = vec![b'x'; 1024 * 1024];
+        for _ in 0..BODY_SIZE / piece.len() {
+            conn.write_all(&piece).await.unwrap();
+        }
+        // Keep the connection open, as a keep-alive upstream would
+        delay_for(Duration::from_secs(30)).await;
+    });
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &["--active-health-check-interval", "3600"],
+    )
+    .await;
+
+    let response = reqwest::get(&format!("http://{}/download", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    let body = response
+        .bytes()
+        .await
+        .expect("Error reading the streamed body");
+    assert_eq!(body.len(), BODY_SIZE);
+    assert!(body.iter().all(|&byte| byte == b'x'));
+
+    log::info!("All done :)");
+}
+
+/// Encodes a single-frame WebSocket message. Client frames must be masked; server frames must not.
+fn websocket_frame(opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+    assert!(payload.len() < 126, "only short frames are supported");
+    let mut frame = vec![0x80 | opcode];
+    match mask {
+        Some(mask) => {
+            frame.push(0x80 | payload.len() as u8);
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+        }
+        None => {
+            frame.push(payload.len() as u8);
+            frame.extend_from_slice(payload);
+        }
+    }
+    frame
+}
+
+/// Reads one short WebSocket frame, returning its opcode and unmasked payload.
+async fn read_websocket_frame(conn: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut header = [0_u8; 2];
+    conn.read_exact(&mut header).await.unwrap();
+    let mut mask = [0_u8; 4];
+    if header[1] & 0x80 != 0 {
+        conn.read_exact(&mut mask).await.unwrap();
+    }
+    let mut payload = vec![0_u8; (header[1] & 0x7f) as usize];
+    conn.read_exact(&mut payload).await.unwrap();
+    for (byte, m) in payload.iter_mut().zip(mask.iter().cycle()) {
+        *byte ^= m;
+    }
+    (header[0] & 0x0f, payload)
+}
+
+/// After an upstream answers with 101 Switching Protocols, balancebeam should step aside and pass
+/// bytes through both ways, without applying the rate limit to them
+#[tokio::test]
+async fn test_websocket_upgrade_is_tunneled() {
+    init_logging();
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0_u8; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+            let bytes_read = conn.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..bytes_read]);
+        }
+        let request = String::from_utf8(request).unwrap().to_lowercase();
+        assert!(request.contains("upgrade: websocket"));
+        conn.write_all(
+            b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n",
+        )
+        .await
+        .unwrap();
+        // Echo text frames until the client asks to close
+        loop {
+            let (opcode, payload) = read_websocket_frame(&mut conn).await;
+            conn.write_all(&websocket_frame(opcode, &payload, None))
+                .await
+                .unwrap();
+            if opcode == 0x8 {
+                return;
+            }
+        }
+    });
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &[
+            "--active-health-check-interval",
+            "3600",
+            "--max-requests-per-minute",
+            "1",
+        ],
+    )
+    .await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    conn.write_all(
+        b"GET /chat HTTP/1.1\r\nHost: test\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+          Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+    )
+    .await
+    .unwrap();
+    let mut head = Vec::new();
+    let mut byte = [0_u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        conn.read_exact(&mut byte).await.unwrap();
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap();
+    log::info!("Handshake response: {}", head);
+    assert!(head.starts_with("HTTP/1.1 101"));
+
+    // Far more messages than the rate limit allows requests, including some that look like HTTP
+    for i in 0..5 {
+        let message = format!("GET /message/{} HTTP/1.1\r\n\r\n", i);
+        let frame = websocket_frame(0x1, message.as_bytes(), Some([1, 2, 3, i]));
+        conn.write_all(&frame).await.unwrap();
+        let (opcode, payload) = timeout(Duration::from_secs(5), read_websocket_frame(&mut conn))
+            .await
+            .expect("Message was not echoed back");
+        assert_eq!(opcode, 0x1);
+        assert_eq!(payload, message.as_bytes());
+    }
+
+    // Closing on the upstream side closes the client connection too
+    conn.write_all(&websocket_frame(0x8, b"", Some([0, 0, 0, 0])))
+        .await
+        .unwrap();
+    let (opcode, _) = read_websocket_frame(&mut conn).await;
+    assert_eq!(opcode, 0x8);
+    let mut rest = Vec::new();
+    timeout(Duration::from_secs(5), conn.read_to_end(&mut rest))
+        .await
+        .expect("balancebeam did not close the tunnel")
+        .unwrap();
+    assert!(rest.is_empty());
+
+    log::info!("All done :)");
+}
+
+/// The path to one of the TLS fixtures: a test CA, and a certificate for localhost and 127.0.0.1
+/// that it signed (see tests/fixtures/make-tls-fixtures.sh)
+fn tls_fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+/// With a certificate configured, balancebeam should serve clients over HTTPS and forward their
+/// requests to the upstream as plain HTTP, marked with X-Forwarded-Proto
+#[tokio::test]
+async fn test_tls_termination() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--tls-cert",
+            &tls_fixture("tls-cert.pem"),
+            "--tls-key",
+            &tls_fixture("tls-key.pem"),
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    for path in &["/first", "/second"] {
+        let response = client
+            .get(&format!("https://{}{}", balancebeam.address, path))
+            .header("x-forwarded-proto", "http")
+            .send()
+            .await
+            .expect("Error sending HTTPS request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+        let echoed = response.text().await.unwrap().to_lowercase();
+        log::info!("Echoed request: {}", echoed);
+        assert!(echoed.starts_with(&format!("get {} http/1.1", path)));
+        assert!(echoed.contains("x-forwarded-proto: https\n"));
+        assert!(!echoed.contains("x-forwarded-proto: http\n"));
+    }
+
+    // Plain HTTP isn't served on a TLS listener
+    let plain = reqwest::get(&format!("http://{}/", balancebeam.address)).await;
+    assert!(plain.is_err());
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// An upstream that only speaks HTTPS, answering every request with its request line. Returns its
+/// port, and the `(SNI hostname, path)` of each request it gets.
+async fn start_tls_upstream() -> (u16, Arc<std::sync::Mutex<Vec<(Option<String>, String)>>>) {
+    use tokio_rustls::rustls::internal::pemfile;
+    use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
+
+    let read_pem =
+        |name: &str| std::io::BufReader::new(std::fs::File::open(tls_fixture(name)).unwrap());
+    let certs = pemfile::certs(&mut read_pem("tls-cert.pem")).unwrap();
+    let key = pemfile::pkcs8_private_keys(&mut read_pem("tls-key.pem"))
+        .unwrap()
+        .remove(0);
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(certs, key).unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let seen = requests.clone();
+    tokio::spawn(async move {
+        loop {
+            let (conn, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            let seen = seen.clone();
+            tokio::spawn(async move {
+                let mut conn = match acceptor.accept(conn).await {
+                    Ok(conn) => conn,
+                    // e.g. a client that doesn't trust our certificate
+                    Err(_) => return,
+                };
+                let sni = conn.get_ref().1.get_sni_hostname().map(String::from);
+                let mut buffer = [0_u8; 1024];
+                loop {
+                    let mut request = Vec::new();
+                    while !request.ends_with(b"\r\n\r\n") {
+                        match conn.read(&mut buffer).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(bytes_read) => request.extend_from_slice(&buffer[..bytes_read]),
+                        }
+                    }
+                    let request = String::from_utf8(request).unwrap();
+                    let request_line = request.lines().next().unwrap().to_string();
+                    let path = request_line.split(' ').nth(1).unwrap().to_string();
+                    seen.lock().unwrap().push((sni.clone(), path));
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                        request_line.len(),
+                        request_line
+                    );
+                    conn.write_all(response.as_bytes()).await.unwrap();
+                    conn.flush().await.unwrap();
+                }
+            });
+        }
+    });
+    (port, requests)
+}
+
+/// `https://` upstreams should be reached over TLS, verified against --upstream-ca, for both
+/// proxied requests and health checks
+#[tokio::test]
+async fn test_tls_upstream() {
+    init_logging();
+    let (port, requests) = start_tls_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&format!("https://localhost:{}", port)],
+        &[
+            "--upstream-ca",
+            &tls_fixture("tls-ca.pem"),
+            "--active-health-check-interval",
+            "1",
+            "--active-health-check-path",
+            "/healthz",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let get = |path: &str| {
+        client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .send()
+    };
+    let response = get("/first")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.text().await.unwrap(), "GET /first HTTP/1.1");
+
+    // The health checks go over TLS too, and keep finding the upstream healthy
+    delay_for(Duration::from_millis(2500)).await;
+    let response = get("/second")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+
+    let requests = requests.lock().unwrap().clone();
+    log::info!("Upstream got {:?}", requests);
+    assert!(requests
+        .iter()
+        .all(|(sni, _)| sni.as_deref() == Some("localhost")));
+    assert!(requests.iter().any(|(_, path)| path == "/healthz"));
+    assert!(requests.iter().any(|(_, path)| path == "/second"));
+
+    log::info!("All done :)");
+}
+
+/// With --allow-connect, balancebeam should be usable as an HTTPS forward proxy: a CONNECT gets a
+/// tunnel to the destination it names, as long as the port is allowed, and the tunnel closes once
+/// it has sat idle for --client-idle-timeout
+#[tokio::test]
+async fn test_connect_tunnel() {
+    init_logging();
+    let (port, requests) = start_tls_upstream().await;
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--allow-connect",
+            "--connect-port",
+            &port.to_string(),
+            "--client-idle-timeout",
+            "1",
+            "--max-requests-per-minute",
+            "3",
+        ],
+    )
+    .await;
+
+    // The client does TLS with the destination itself, through the tunnel
+    let ca = std::fs::read(tls_fixture("tls-ca.pem")).unwrap();
+    let client = reqwest::Client::builder()
+        .proxy(reqwest::Proxy::https(&format!("http://{}", balancebeam.address)).unwrap())
+        .add_root_certificate(reqwest::Certificate::from_pem(&ca).unwrap())
+        .build()
+        .unwrap();
+    let response = client
+        .get(&format!("https://localhost:{}/through-tunnel", port))
+        .send()
+        .await
+        .expect("Error sending request through the tunnel");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        response.text().await.unwrap(),
+        "GET /through-tunnel HTTP/1.1"
+    );
+    assert_eq!(
+        requests.lock().unwrap().clone(),
+        vec![(
+            Some(String::from("localhost")),
+            String::from("/through-tunnel")
+        )]
+    );
+
+    // Only the allowed ports can be tunneled to
+    let response = send_raw_request(
+        &balancebeam.address,
+        "CONNECT localhost:22 HTTP/1.1\r\nHost: localhost:22\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("http/1.1 403"), "{}", response);
+
+    // A tunnel nobody sends anything through is closed
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let connect = format!(
+        "CONNECT 127.0.0.1:{} HTTP/1.1\r\nHost: 127.0.0.1:{}\r\n\r\n",
+        port, port
+    );
+    conn.write_all(connect.as_bytes()).await.unwrap();
+    let head = timeout(Duration::from_secs(5), read_raw_head(&mut conn))
+        .await
+        .expect("balancebeam did not answer the CONNECT");
+    assert_eq!(head, "HTTP/1.1 200 Connection Established\r\n\r\n");
+    let mut rest = Vec::new();
+    timeout(Duration::from_secs(3), conn.read_to_end(&mut rest))
+        .await
+        .expect("The idle tunnel was not closed")
+        .unwrap();
+    assert!(rest.is_empty());
+
+    // Each CONNECT was one request, however much went through its tunnel
+    let response = send_raw_request(
+        &balancebeam.address,
+        "CONNECT localhost:443 HTTP/1.1\r\nHost: localhost:443\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("http/1.1 429"), "{}", response);
+    assert_eq!(Box::new(upstream).stop().await, 0);
+
+    log::info!("All done :)");
+}
+
+/// An upstream whose certificate can't be verified should get no requests, unless verification is
+/// turned off with --insecure-upstream
+#[tokio::test]
+async fn test_tls_upstream_verification() {
+    init_logging();
+    let (port, requests) = start_tls_upstream().await;
+    let upstream = format!("https://localhost:{}", port);
+    let args = ["--active-health-check-interval", "3600"];
+
+    // The test CA isn't among the system's roots
+    let untrusting = BalanceBeam::new_with_args(&[&upstream], &args).await;
+    let response = reqwest::get(&format!("http://{}/", untrusting.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+    assert!(requests.lock().unwrap().is_empty());
+
+    let insecure = BalanceBeam::new_with_args(
+        &[&upstream],
+        &[
+            "--active-health-check-interval",
+            "3600",
+            "--insecure-upstream",
+        ],
+    )
+    .await;
+    let response = reqwest::get(&format!("http://{}/anyway", insecure.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(requests.lock().unwrap().len(), 1);
+
+    log::info!("All done :)");
+}
+
+/// Reads one response with a Content-Length off `conn`, headers and all.
+async fn read_raw_response(conn: &mut TcpStream) -> String {
+    let (head, body) = read_raw_response_bytes(conn).await;
+    head + &String::from_utf8(body).unwrap()
+}
+
+/// Reads a response's status line and headers off `conn`, and nothing more.
+async fn read_raw_head(conn: &mut TcpStream) -> String {
+    let mut head = Vec::new();
+    let mut byte = [0_u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        conn.read_exact(&mut byte).await.unwrap();
+        head.push(byte[0]);
+    }
+    String::from_utf8(head).unwrap()
+}
+
+/// Like read_raw_response, but for a body that may not be text, which is returned apart from the
+/// headers.
+async fn read_raw_response_bytes(conn: &mut TcpStream) -> (String, Vec<u8>) {
+    let head = read_raw_head(conn).await;
+    let content_length = head
+        .lines()
+        .find_map(|line| {
+            line.to_lowercase()
+                .strip_prefix("content-length: ")
+                .map(String::from)
+        })
+        .expect("response has no Content-Length")
+        .parse::<usize>()
+        .unwrap();
+    let mut body = vec![0_u8; content_length];
+    conn.read_exact(&mut body).await.unwrap();
+    (head, body)
+}
+
+/// Behind a load balancer speaking the PROXY protocol, balancebeam should treat the client named in
+/// each connection's header as the client, for both X-Forwarded-For and rate limiting
+#[tokio::test]
+async fn test_proxy_protocol() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--accept-proxy-protocol", "--max-requests-per-minute", "1"],
+    )
+    .await;
+    let request = b"GET /hello HTTP/1.1\r\nHost: test\r\n\r\n";
+    let mut v2_header = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+    v2_header.extend_from_slice(&[203, 0, 113, 8, 127, 0, 0, 1, 0xdc, 0x04, 0x04, 0x4c]);
+
+    for (header, client_ip) in &[
+        (
+            &b"PROXY TCP4 203.0.113.7 127.0.0.1 56324 1100\r\n"[..],
+            "203.0.113.7",
+        ),
+        (&v2_header[..], "203.0.113.8"),
+    ] {
+        let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+        let mut data = header.to_vec();
+        data.extend_from_slice(request);
+        conn.write_all(&data).await.unwrap();
+        let response = timeout(Duration::from_secs(5), read_raw_response(&mut conn))
+            .await
+            .expect("balancebeam did not answer");
+        log::info!("Response: {}", response);
+        assert!(response.starts_with("HTTP/1.1 200"));
+        let echoed = response.to_lowercase();
+        assert!(echoed.contains("get /hello"));
+        assert!(echoed.contains(&format!("x-forwarded-for: {}\n", client_ip)));
+    }
+
+    // Both were allowed, as different clients; a second request from the first is over the limit
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let mut data = b"PROXY TCP4 203.0.113.7 127.0.0.1 56400 1100\r\n".to_vec();
+    data.extend_from_slice(request);
+    conn.write_all(&data).await.unwrap();
+    let response = read_raw_response(&mut conn).await;
+    assert!(response.starts_with("HTTP/1.1 429"), "got {}", response);
+
+    // A connection without a header is turned away without an answer. (Hanging up with the
+    // request unread may reset the connection rather than close it.)
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(request).await.unwrap();
+    let mut rest = Vec::new();
+    match timeout(Duration::from_secs(5), conn.read_to_end(&mut rest))
+        .await
+        .expect("balancebeam did not close the connection")
+    {
+        Ok(_) => assert!(rest.is_empty()),
+        Err(error) => assert_eq!(error.kind(), std::io::ErrorKind::ConnectionReset),
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// The status balancebeam gives a client at `client_ip`, connecting through a load balancer that
+/// speaks the PROXY protocol. A denied client is answered as soon as the header is in; anyone else
+/// is then sent a request.
+async fn status_for_client(address: &str, client_ip: &str) -> String {
+    let (family, destination) = if client_ip.contains(':') {
+        ("TCP6", "::1")
+    } else {
+        ("TCP4", "127.0.0.1")
+    };
+    let mut conn = TcpStream::connect(address).await.unwrap();
+    let header = format!(
+        "PROXY {} {} {} 56324 1100\r\n",
+        family, client_ip, destination
+    );
+    conn.write_all(header.as_bytes()).await.unwrap();
+    let response = match timeout(Duration::from_millis(500), read_raw_response(&mut conn)).await {
+        Ok(response) => response,
+        Err(_) => {
+            conn.write_all(b"GET /hello HTTP/1.1\r\nHost: test\r\n\r\n")
+                .await
+                .unwrap();
+            timeout(Duration::from_secs(5), read_raw_response(&mut conn))
+                .await
+                .expect("balancebeam did not answer")
+        }
+    };
+    response.split_whitespace().nth(1).unwrap().to_string()
+}
+
+/// Connections from denied IPs and ranges, IPv4 or IPv6, should get a 403, and changes made over
+/// the admin listener should apply to the next connection
+#[tokio::test]
+async fn test_deny_list() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = free_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--accept-proxy-protocol",
+            "--admin-bind",
+            &admin_address,
+            "--deny",
+            "2001:db8::/32",
+            "--deny",
+            "2001:db8:1::/48",
+            "--deny",
+            "203.0.113.7",
+        ],
+    )
+    .await;
+    let status = |client_ip: &'static str| status_for_client(&balancebeam.address, client_ip);
+    assert_eq!(status("2001:db8:1::1").await, "403");
+    assert_eq!(status("2001:db9::1").await, "200");
+    assert_eq!(status("203.0.113.7").await, "403");
+    assert_eq!(status("203.0.113.8").await, "200");
+
+    let client = reqwest::Client::new();
+    let admin_url = format!("http://{}/denylist", admin_address);
+    let response = client
+        .delete(&format!("{}/2001:db8::/32", admin_url))
+        .send()
+        .await
+        .expect("Error sending request to the admin listener");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        response.text().await.unwrap(),
+        "{\"denylist\":[\"2001:db8:1::/48\",\"203.0.113.7/32\"]}"
+    );
+    // The overlapping range still covers this one
+    assert_eq!(status("2001:db8:1::1").await, "403");
+    assert_eq!(status("2001:db8:2::1").await, "200");
+
+    let response = client
+        .post(&admin_url)
+        .body("2001:db8:2::/48")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 201);
+    assert_eq!(status("2001:db8:2::1").await, "403");
+    let response = client
+        .post(&admin_url)
+        .body("2001:db8:2::/48")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 409);
+    let response = client
+        .delete(&format!("{}/2001:db8::/32", admin_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+
+    let metrics = reqwest::get(&format!("http://{}/metrics", admin_address))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("\ndenied_total 4\n"), "{}", metrics);
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// With --admin-auth, the admin listener should answer requests without the right Basic
+/// credentials with a 401 and a challenge, and serve the ones with them as usual
+#[tokio::test]
+async fn test_admin_auth() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = free_local_address();
+    let _balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--admin-auth",
+            "ops:s3cr:et",
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let status_url = format!("http://{}/status", admin_address);
+
+    let response = client
+        .get(&status_url)
+        .send()
+        .await
+        .expect("Error sending request to the admin listener");
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(
+        response.headers()["www-authenticate"],
+        "Basic realm=\"balancebeam admin\", charset=\"UTF-8\""
+    );
+    let response = client
+        .get(&status_url)
+        .basic_auth("ops", Some("s3cr:ex"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+    let response = client
+        .get(&status_url)
+        .header("Authorization", "Basic b3BzOnMzY3I6ZXQ!")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+    // Even paths that don't exist need credentials, so they don't give away which ones do
+    let response = client
+        .get(&format!("http://{}/nowhere", admin_address))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+
+    let response = client
+        .get(&status_url)
+        .basic_auth("ops", Some("s3cr:et"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.text().await.unwrap().contains(&upstream.address));
+    log::info!("All done :)");
+}
+
+/// With --send-proxy-protocol, every upstream connection should start with a PROXY header: naming
+/// the client for proxied requests, and UNKNOWN for health checks
+#[tokio::test]
+async fn test_send_proxy_protocol() {
+    init_logging();
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = listener.local_addr().unwrap().to_string();
+    let heads = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let seen = heads.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let seen = seen.clone();
+            tokio::spawn(async move {
+                // One request per connection, as pooling is off
+                let mut head = Vec::new();
+                let mut buffer = [0_u8; 1024];
+                while !head.ends_with(b"\r\n\r\n") {
+                    match conn.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(bytes_read) => head.extend_from_slice(&buffer[..bytes_read]),
+                    }
+                }
+                seen.lock().unwrap().push(String::from_utf8(head).unwrap());
+                let _ = conn
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                    .await;
+            });
+        }
+    });
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &[
+            "--send-proxy-protocol",
+            "v1",
+            "--active-health-check-interval",
+            "1",
+        ],
+    )
+    .await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let client_port = conn.local_addr().unwrap().port();
+    let balancebeam_port = conn.peer_addr().unwrap().port();
+    for _ in 0..2 {
+        conn.write_all(b"GET /hello HTTP/1.1\r\nHost: test\r\n\r\n")
+            .await
+            .unwrap();
+        let response = timeout(Duration::from_secs(5), read_raw_response(&mut conn))
+            .await
+            .expect("balancebeam did not answer");
+        assert!(response.starts_with("HTTP/1.1 200"));
+    }
+    delay_for(Duration::from_millis(1500)).await;
+
+    let heads = heads.lock().unwrap().clone();
+    log::info!("Upstream got {:?}", heads);
+    let proxied_header = format!(
+        "PROXY TCP4 127.0.0.1 127.0.0.1 {} {}\r\nGET /hello HTTP/1.1\r\n",
+        client_port, balancebeam_port
+    );
+    assert_eq!(
+        heads
+            .iter()
+            .filter(|head| head.starts_with(&proxied_header))
+            .count(),
+        2
+    );
+    assert!(heads
+        .iter()
+        .any(|head| head.starts_with("PROXY UNKNOWN\r\nGET / HTTP/1.1\r\n")));
+    assert!(heads.iter().all(|head| head.starts_with("PROXY ")));
+
+    log::info!("All done :)");
+}
+
+/// Sends `request` over a fresh connection and returns the response, lowercased.
+async fn send_raw_request(address: &str, request: &str) -> String {
+    let mut conn = TcpStream::connect(address).await.unwrap();
+    conn.write_all(request.as_bytes()).await.unwrap();
+    let response = timeout(Duration::from_secs(5), read_raw_response(&mut conn))
+        .await
+        .expect("balancebeam did not answer");
+    log::info!("Response: {}", response);
+    response.to_lowercase()
+}
+
+/// By default, a client's own X-Forwarded-For could be made up, so it should be replaced, and not
+/// sway rate limiting
+#[tokio::test]
+async fn test_untrusted_forwarded_for_is_replaced() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, Some(1)).await;
+
+    let response = send_raw_request(
+        &balancebeam.address,
+        "GET / HTTP/1.1\r\nHost: test\r\nX-FORWARDED-FOR: 203.0.113.7\r\nX-Real-IP: 203.0.113.7\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("http/1.1 200"));
+    assert!(response.contains("x-forwarded-for: 127.0.0.1\n"));
+    assert!(response.contains("x-real-ip: 127.0.0.1\n"));
+    assert!(response.contains("x-forwarded-proto: http\n"));
+    assert!(!response.contains("203.0.113.7"));
+
+    // Claiming to be someone else doesn't get around the limit
+    let response = send_raw_request(
+        &balancebeam.address,
+        "GET / HTTP/1.1\r\nHost: test\r\nX-Forwarded-For: 203.0.113.8\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("http/1.1 429"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// The value of the X-Request-Id header among a (lowercased) raw response's headers
+fn response_request_id(response: &str) -> Option<String> {
+    let head = &response[..response.find("\r\n\r\n")?];
+    head.lines()
+        .find_map(|line| line.strip_prefix("x-request-id: "))
+        .map(String::from)
+}
+
+/// A client's X-Request-Id should be passed on to the upstream and back, and requests without one
+/// should get one, even when balancebeam answers them itself
+#[tokio::test]
+async fn test_request_id() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, Some(2)).await;
+
+    let response = send_raw_request(
+        &balancebeam.address,
+        "GET / HTTP/1.1\r\nHost: test\r\nX-Request-Id: trace-123\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("http/1.1 200"));
+    assert_eq!(response_request_id(&response).as_deref(), Some("trace-123"));
+    assert!(response.contains("\nx-request-id: trace-123\n"));
+
+    let response =
+        send_raw_request(&balancebeam.address, "GET / HTTP/1.1\r\nHost: test\r\n\r\n").await;
+    assert!(response.starts_with("http/1.1 200"));
+    let generated = response_request_id(&response).expect("no request ID was generated");
+    assert_eq!(generated.len(), 36);
+    assert!(response.contains(&format!("\nx-request-id: {}\n", generated)));
+
+    let response =
+        send_raw_request(&balancebeam.address, "GET / HTTP/1.1\r\nHost: test\r\n\r\n").await;
+    assert!(response.starts_with("http/1.1 429"));
+    let limited = response_request_id(&response).expect("no request ID on the 429");
+    assert_ne!(limited, generated);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// A request target in absolute form should reach the upstream in origin form, with the Host it
+/// named, and a CONNECT should be refused
+#[tokio::test]
+async fn test_absolute_form_target() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    let response = send_raw_request(
+        &balancebeam.address,
+        "GET http://user@Example.com:8080/abs?x=1 HTTP/1.1\r\nHost: other.test\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("http/1.1 200"), "{}", response);
+    assert!(response.contains("get /abs?x=1 http/1.1\n"), "{}", response);
+    assert!(response.contains("host: example.com:8080\n"));
+    assert!(!response.contains("other.test") && !response.contains("user@"));
+
+    let response = send_raw_request(
+        &balancebeam.address,
+        "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("http/1.1 501"), "{}", response);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    log::info!("All done :)");
+}
+
+/// Requests and responses should be marked with a Via entry naming balancebeam, and a request
+/// that comes back to it should be answered with a 508 instead of going round forever
+#[tokio::test]
+async fn test_via_header_and_forwarding_loop() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--instance-id", "test-1"]).await;
+    let response = send_raw_request(
+        &balancebeam.address,
+        "GET / HTTP/1.1\r\nHost: test\r\nVia: 1.0 corp-proxy\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("http/1.1 200"));
+    // The upstream got the client's entry, then ours
+    assert!(response.contains("via: 1.0 corp-proxy\nvia: 1.1 balancebeam-test-1\n"));
+    assert!(response.contains("\r\nvia: 1.1 balancebeam-test-1\r\n"));
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    // An upstream that is really the proxy itself
+    let address = free_local_address();
+    let looped = BalanceBeam::new_with_bind(
+        &address,
+        &[&address],
+        &[
+            "--instance-id",
+            "test-2",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+    let response = timeout(
+        Duration::from_secs(5),
+        send_raw_request(&looped.address, "GET / HTTP/1.1\r\nHost: test\r\n\r\n"),
+    )
+    .await
+    .expect("The request went round without end");
+    assert!(response.starts_with("http/1.1 508"), "{}", response);
+    // It made it back out through the first pass
+    assert!(response.contains("\r\nvia: 1.1 balancebeam-test-2\r\n"));
+
+    log::info!("All done :)");
+}
+
+/// With --compress, a big enough response should be gzipped for a client that accepts it, and
+/// decompress to exactly what a client that doesn't gets
+#[tokio::test]
+async fn test_gzip_compression() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--compress", "--compress-min-bytes", "1000"],
+    )
+    .await;
+    let payload = "All work and no play makes Jack a dull boy.\n".repeat(100);
+    let send = |accept_encoding: &'static str, payload: String| {
+        let address = balancebeam.address.clone();
+        async move {
+            let mut conn = TcpStream::connect(&address).await.unwrap();
+            let request = format!(
+                "POST /compress HTTP/1.1\r\nHost: test\r\nX-Request-Id: gzip-test\r\n{}\
+                 Content-Length: {}\r\n\r\n{}",
+                accept_encoding,
+                payload.len(),
+                payload
+            );
+            conn.write_all(request.as_bytes()).await.unwrap();
+            let (head, body) = timeout(Duration::from_secs(5), read_raw_response_bytes(&mut conn))
+                .await
+                .expect("balancebeam did not answer");
+            (head.to_lowercase(), body)
+        }
+    };
+
+    let (plain_head, plain_body) = send("", payload.clone()).await;
+    assert!(plain_head.starts_with("http/1.1 200"));
+    assert!(!plain_head.contains("content-encoding"));
+    assert!(plain_head.contains("\r\nvary: accept-encoding\r\n"));
+
+    let (head, body) = send("Accept-Encoding: br, gzip\r\n", payload.clone()).await;
+    assert!(head.contains("\r\ncontent-encoding: gzip\r\n"), "{}", head);
+    assert!(head.contains("\r\nvary: accept-encoding\r\n"));
+    assert!(body.len() < plain_body.len() / 4);
+    let mut decompressed = String::new();
+    GzDecoder::new(&body[..])
+        .read_to_string(&mut decompressed)
+        .unwrap();
+    assert_eq!(
+        decompressed.replace("accept-encoding: br, gzip\n", ""),
+        String::from_utf8(plain_body).unwrap()
+    );
+
+    // Small bodies aren't worth it
+    let (head, _) = send("Accept-Encoding: gzip\r\n", String::new()).await;
+    assert!(!head.contains("content-encoding"));
+
+    log::info!("All done :)");
+}
+
+/// Header rules should rewrite requests on their way to the upstream, and responses on their way
+/// back, whatever case the headers are written in
+#[tokio::test]
+async fn test_header_rules() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--remove-request-header",
+            "X-Internal-Auth",
+            "--set-request-header",
+            "x-env:test",
+            "--remove-response-header",
+            "Date",
+            "--set-response-header",
+            "X-Served-By:balancebeam",
+        ],
+    )
+    .await;
+
+    let response = send_raw_request(
+        &balancebeam.address,
+        "GET / HTTP/1.1\r\nHost: test\r\nx-internal-auth: secret\r\nX-INTERNAL-AUTH: more\r\n\
+         X-Env: dev\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("http/1.1 200"));
+    assert!(!response.contains("secret") && !response.contains("more"));
+    assert!(response.contains("x-env: test\n"));
+    assert!(!response.contains("x-env: dev"));
+    assert!(response.contains("x-served-by: balancebeam\r\n"));
+    assert!(!response.contains("date:"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// With --trust-forwarded-for, the client's X-Forwarded-For should be appended to, and rate
+/// limiting should count requests against the original client it names
+#[tokio::test]
+async fn test_trusted_forwarded_for_is_appended() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--trust-forwarded-for", "--max-requests-per-minute", "1"],
+    )
+    .await;
+
+    let response = send_raw_request(
+        &balancebeam.address,
+        "GET / HTTP/1.1\r\nHost: test\r\nX-Forwarded-For: 203.0.113.7, 198.51.100.2\r\n\
+         x-FORWARDED-for: 10.0.0.2\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("http/1.1 200"));
+    assert!(response.contains("x-forwarded-for: 203.0.113.7, 198.51.100.2, 10.0.0.2, 127.0.0.1\n"));
+    assert!(response.contains("x-real-ip: 127.0.0.1\n"));
+
+    // A different original client has a limit of its own, even over the same proxy
+    let response = send_raw_request(
+        &balancebeam.address,
+        "GET / HTTP/1.1\r\nHost: test\r\nX-Forwarded-For: 203.0.113.8\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("http/1.1 200"));
+    assert!(response.contains("x-forwarded-for: 203.0.113.8, 127.0.0.1\n"));
+    let response = send_raw_request(
+        &balancebeam.address,
+        "GET / HTTP/1.1\r\nHost: test\r\nx-forwarded-for: 203.0.113.7\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("http/1.1 429"));
+    // Without the header, the proxy itself is the client
+    let response =
+        send_raw_request(&balancebeam.address, "GET / HTTP/1.1\r\nHost: test\r\n\r\n").await;
+    assert!(response.starts_with("http/1.1 200"));
+    assert!(response.contains("x-forwarded-for: 127.0.0.1\n"));
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// A client should only get --max-connections-per-ip connections open at once; the one after that
+/// is sent away with a 503, until one of the others closes
+#[tokio::test]
+async fn test_max_connections_per_ip() {
+    init_logging();
+    const LIMIT: usize = 3;
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--max-connections-per-ip", &LIMIT.to_string()],
+    )
+    .await;
+    let request = b"GET / HTTP/1.1\r\nHost: test\r\n\r\n";
+
+    // Make sure each connection has been taken up before opening the next
+    let mut open = Vec::new();
+    for _ in 0..LIMIT {
+        let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+        conn.write_all(request).await.unwrap();
+        assert!(read_raw_response(&mut conn)
+            .await
+            .starts_with("HTTP/1.1 200"));
+        open.push(conn);
+    }
+    let mut over_limit = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let response = timeout(Duration::from_secs(5), read_raw_response(&mut over_limit))
+        .await
+        .expect("balancebeam did not answer");
+    assert!(response.starts_with("HTTP/1.1 503"), "got {}", response);
+    let mut rest = Vec::new();
+    over_limit.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty(), "the connection was not closed");
+
+    // The ones already open are unaffected
+    open[0].write_all(request).await.unwrap();
+    assert!(read_raw_response(&mut open[0])
+        .await
+        .starts_with("HTTP/1.1 200"));
+
+    // Closing one frees up its slot
+    drop(open.pop());
+    delay_for(Duration::from_millis(200)).await;
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(request).await.unwrap();
+    assert!(read_raw_response(&mut conn)
+        .await
+        .starts_with("HTTP/1.1 200"));
+
+    assert_eq!(Box::new(upstream).stop().await, LIMIT + 2);
+    log::info!("All done :)");
+}
+
+/// Past --max-concurrent-requests, requests should be shed with a quick 503 rather than queue up
+/// behind the slow ones
+#[tokio::test]
+async fn test_max_concurrent_requests_sheds_load() {
+    init_logging();
+    let upstream_address = start_slow_upstream(Duration::from_secs(2)).await;
+    let admin_address = free_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &[
+            "--max-concurrent-requests",
+            "2",
+            "--request-queue-timeout-ms",
+            "100",
+            "--admin-bind",
+            &admin_address,
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    let requests: Vec<_> = (0..5)
+        .map(|i| {
+            let address = balancebeam.address.clone();
+            tokio::spawn(async move {
+                // Let the first two in ahead of the rest
+                if i >= 2 {
+                    delay_for(Duration::from_millis(300)).await;
+                }
+                let started = std::time::Instant::now();
+                let response = reqwest::get(&format!("http://{}/request-{}", address, i))
+                    .await
+                    .expect("Error sending request to balancebeam");
+                let retry_after = response
+                    .headers()
+                    .get("retry-after")
+                    .map(|value| value.to_str().unwrap().to_string());
+                (response.status().as_u16(), retry_after, started.elapsed())
+            })
+        })
+        .collect();
+
+    delay_for(Duration::from_millis(1000)).await;
+    let status = reqwest::get(&format!("http://{}/status", admin_address))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    log::info!("Status: {}", status);
+    assert!(status.contains("\"request_permits\":{\"in_use\":2,\"max\":2}"));
+
+    let mut results = Vec::new();
+    for request in requests {
+        results.push(request.await.unwrap());
+    }
+    log::info!("Results: {:?}", results);
+    for (status, _, elapsed) in &results[..2] {
+        assert_eq!(*status, 200);
+        assert!(*elapsed >= Duration::from_secs(2));
+    }
+    for (status, retry_after, elapsed) in &results[2..] {
+        assert_eq!(*status, 503);
+        assert_eq!(retry_after.as_deref(), Some("1"));
+        assert!(*elapsed < Duration::from_millis(1000), "took {:?}", elapsed);
+    }
+
+    // Once the slow ones finish, their permits are free again
+    let status = reqwest::get(&format!("http://{}/status", admin_address))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(status.contains("\"request_permits\":{\"in_use\":0,\"max\":2}"));
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that answers each request with its request line, keeping the connection
+/// open for more, except that it hangs up after answering a path ending in /close (saying so with
+/// Connection: close). Returns its address and the paths it has been sent over each connection.
+async fn start_closing_upstream() -> (String, Arc<std::sync::Mutex<Vec<Vec<String>>>>) {
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let connections = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let seen = connections.clone();
+    tokio::spawn(async move {
+        loop {
+            let (conn, _) = listener.accept().await.unwrap();
+            let idx = {
+                let mut seen = seen.lock().unwrap();
+                seen.push(Vec::new());
+                seen.len() - 1
+            };
+            let seen = seen.clone();
+            tokio::spawn(async move {
+                let mut conn = tokio::io::BufReader::new(conn);
+                loop {
+                    let mut request_line = String::new();
+                    match conn.read_line(&mut request_line).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(_) => {}
+                    }
+                    let mut header = String::new();
+                    while header != "\r\n" {
+                        header.clear();
+                        if conn.read_line(&mut header).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                    }
+                    let path = request_line.split(' ').nth(1).unwrap_or("").to_string();
+                    let close = path.ends_with("/close");
+                    seen.lock().unwrap()[idx].push(path);
+                    let body = request_line.trim_end();
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n{}\r\n{}",
+                        body.len(),
+                        if close { "Connection: close\r\n" } else { "" },
+                        body
+                    );
+                    if conn.get_mut().write_all(response.as_bytes()).await.is_err() || close {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    (address, connections)
+}
+
+/// Whether `conn` is closed (as opposed to waiting for another request) after a response
+async fn is_closed(conn: &mut TcpStream) -> bool {
+    let mut rest = Vec::new();
+    match timeout(Duration::from_millis(500), conn.read_to_end(&mut rest)).await {
+        Ok(Ok(_)) => rest.is_empty(),
+        Ok(Err(_)) => true,
+        Err(_elapsed) => false,
+    }
+}
+
+async fn assert_closed_within(conn: &mut TcpStream, limit: Duration) {
+    let mut rest = Vec::new();
+    timeout(limit, conn.read_to_end(&mut rest))
+        .await
+        .expect("balancebeam did not close the connection")
+        .unwrap();
+    assert!(rest.is_empty());
+}
+
+/// A client's Connection: close should close its connection after the response, and an upstream's
+/// should close only the upstream connection, with the client's kept open (and neither side's
+/// header passed on to the other)
+#[tokio::test]
+async fn test_connection_close_from_either_side() {
+    init_logging();
+    let (upstream_address, connections) = start_closing_upstream().await;
+    let balancebeam = BalanceBeam::new(&[&upstream_address], Some(60), None).await;
+
+    for &(client_closes, upstream_closes) in
+        &[(false, false), (false, true), (true, false), (true, true)]
+    {
+        log::info!(
+            "Client closes: {}, upstream closes: {}",
+            client_closes,
+            upstream_closes
+        );
+        let path = if upstream_closes { "/close" } else { "/open" };
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: test\r\n{}\r\n",
+            path,
+            if client_closes {
+                "Connection: close\r\n"
+            } else {
+                ""
+            }
+        );
+        let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+        conn.write_all(request.as_bytes()).await.unwrap();
+        let response = read_raw_response(&mut conn).await.to_lowercase();
+        log::info!("Response: {}", response);
+        assert!(response.starts_with("http/1.1 200"));
+        assert!(response.ends_with(&format!("get {} http/1.1", path)));
+        assert_eq!(response.contains("connection: close"), client_closes);
+        if client_closes {
+            assert!(is_closed(&mut conn).await);
+        } else {
+            // Still good for another request
+            conn.write_all(request.as_bytes()).await.unwrap();
+            let response = read_raw_response(&mut conn).await.to_lowercase();
+            assert!(response.ends_with(&format!("get {} http/1.1", path)));
+        }
+    }
+
+    // Connections the upstream didn't close were pooled and reused, including the one for the
+    // client that closed its own
+    let connections = connections.lock().unwrap().clone();
+    log::info!("Upstream connections: {:?}", connections);
+    assert_eq!(
+        connections,
+        vec![
+            vec!["/open", "/open", "/close"],
+            vec!["/close"],
+            vec!["/open", "/close"],
+        ]
+    );
+    log::info!("All done :)");
+}
+
+/// An HTTP/1.0 client's connection should be closed after one response unless it asked for
+/// keep-alive, in which case the response says it's being kept
+#[tokio::test]
+async fn test_http_1_0_defaults_to_close() {
+    let (balancebeam, upstream) = setup().await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(b"GET /old HTTP/1.0\r\n\r\n").await.unwrap();
+    let response = read_raw_response(&mut conn).await.to_lowercase();
+    assert!(response.contains("connection: close"), "{}", response);
+    assert!(is_closed(&mut conn).await);
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    for _ in 0..2 {
+        conn.write_all(b"GET /old HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_raw_response(&mut conn).await.to_lowercase();
+        log::info!("Response: {}", response);
+        assert!(response.contains("connection: keep-alive"), "{}", response);
+        // It goes to the upstream as HTTP/1.1, without the client's Connection header (echoed
+        // headers end in a bare \n, unlike the response's own)
+        assert!(response.contains("get /old http/1.1"));
+        assert!(!response.contains("connection: keep-alive\n"));
+    }
+    assert!(!is_closed(&mut conn).await);
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// A client connection that goes quiet before its first request, or between requests, should be
+/// closed after --client-idle-timeout, but one in the middle of sending a request should not
+#[tokio::test]
+async fn test_client_idle_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--client-idle-timeout", "1"]).await;
+    // Never sends anything
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let started = std::time::Instant::now();
+    assert!(!is_closed(&mut conn).await);
+    assert_closed_within(&mut conn, Duration::from_secs(3)).await;
+    assert!(started.elapsed() >= Duration::from_secs(1));
+
+    // Takes longer than the timeout to send one request, then goes quiet
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(b"POST /slow HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello")
+        .await
+        .unwrap();
+    delay_for(Duration::from_millis(1500)).await;
+    conn.write_all(b"world").await.unwrap();
+    let response = timeout(Duration::from_secs(5), read_raw_response(&mut conn))
+        .await
+        .expect("balancebeam did not answer");
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.ends_with("helloworld"));
+    assert_closed_within(&mut conn, Duration::from_secs(3)).await;
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that answers only the first request on each connection, hanging up without
+/// a word when a second one arrives, as if it had closed the connection just as that was sent. A
+/// request for /drop isn't answered even on a fresh connection. Returns its address and how many
+/// connections it has accepted.
+async fn start_one_shot_upstream() -> (String, Arc<AtomicUsize>) {
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+    tokio::spawn(async move {
+        loop {
+            let (conn, _) = listener.accept().await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                let mut conn = tokio::io::BufReader::new(conn);
+                let mut answered = false;
+                loop {
+                    let mut request_line = String::new();
+                    if conn.read_line(&mut request_line).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    let mut header = String::new();
+                    while header != "\r\n" {
+                        header.clear();
+                        if conn.read_line(&mut header).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                    }
+                    if answered || request_line.contains(" /drop ") {
+                        return;
+                    }
+                    let response = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+                    if conn.get_mut().write_all(response.as_bytes()).await.is_err() {
+                        return;
+                    }
+                    answered = true;
+                }
+            });
+        }
+    });
+    (address, accepted)
+}
+
+/// A request that goes out over a pooled upstream connection the upstream has since closed
+/// should be sent again over a fresh one, even if it isn't idempotent, but only the once
+#[tokio::test]
+async fn test_stale_pooled_connection_is_replaced() {
+    init_logging();
+    let (upstream_address, accepted) = start_one_shot_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+    let client = reqwest::Client::new();
+
+    for i in 0..3 {
+        let response = client
+            .post(&format!("http://{}/post-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.text().await.unwrap(), "ok");
+    }
+    // The first request opened a connection, and each one after it found its pooled connection
+    // dead and opened another
+    assert_eq!(accepted.load(Ordering::SeqCst), 3);
+
+    // When the fresh connection fails too, that's the upstream's doing, and the client hears so
+    let response = client
+        .post(&format!("http://{}/drop", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+    assert_eq!(accepted.load(Ordering::SeqCst), 4);
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that answers /continue with a 100 Continue ahead of its response, and
+/// /no-content and /not-modified with a 204 and 304 whose Content-Length has no body behind it,
+/// as it has none in answer to HEAD. Returns its address and how many connections it has accepted.
+async fn start_bodiless_upstream() -> (String, Arc<AtomicUsize>) {
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+    tokio::spawn(async move {
+        loop {
+            let (conn, _) = listener.accept().await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                let mut conn = tokio::io::BufReader::new(conn);
+                loop {
+                    let mut request_line = String::new();
+                    if conn.read_line(&mut request_line).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    let mut header = String::new();
+                    while header != "\r\n" {
+                        header.clear();
+                        if conn.read_line(&mut header).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                    }
+                    let response = if request_line.starts_with("HEAD ") {
+                        "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n"
+                    } else if request_line.contains(" /continue ") {
+                        "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\ndone"
+                    } else if request_line.contains(" /no-content ") {
+                        "HTTP/1.1 204 No Content\r\nContent-Length: 10\r\n\r\n"
+                    } else if request_line.contains(" /not-modified ") {
+                        "HTTP/1.1 304 Not Modified\r\nContent-Length: 10\r\nETag: \"v1\"\r\n\r\n"
+                    } else {
+                        "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"
+                    };
+                    if conn.get_mut().write_all(response.as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    (address, accepted)
+}
+
+/// Interim 1xx responses should be passed on ahead of the final response, and 204s, 304s and
+/// answers to HEAD should be forwarded without waiting on the body their Content-Length
+/// describes, all without throwing the client's or upstream's connection out of step
+#[tokio::test]
+async fn test_responses_without_bodies() {
+    init_logging();
+    let (upstream_address, accepted) = start_bodiless_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    for _ in 0..2 {
+        conn.write_all(b"GET /continue HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let interim = read_raw_head(&mut conn).await;
+        assert!(interim.starts_with("HTTP/1.1 100"), "{}", interim);
+        let response = read_raw_response(&mut conn).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("done"));
+
+        for request in &[
+            "GET /no-content HTTP/1.1\r\n\r\n",
+            "GET /not-modified HTTP/1.1\r\n\r\n",
+            "HEAD /head HTTP/1.1\r\n\r\n",
+        ] {
+            conn.write_all(request.as_bytes()).await.unwrap();
+            let head = timeout(Duration::from_secs(5), read_raw_head(&mut conn))
+                .await
+                .expect("balancebeam did not answer");
+            log::info!("Response: {}", head);
+            assert!(head.contains("content-length: 10"), "{}", head);
+        }
+        // Nothing was left over to be mistaken for this response
+        conn.write_all(b"GET /after HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_raw_response(&mut conn).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("ok"));
+    }
+    // Every response left the upstream connection fit for the next request
+    assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+    // HTTP/1.0 clients, which don't know about interim responses, only get the final one
+    let response = send_raw_request(&balancebeam.address, "GET /continue HTTP/1.0\r\n\r\n").await;
+    assert!(response.starts_with("http/1.1 200"), "{}", response);
+
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &[
+            "--active-health-check-interval",
+            "60",
+            "--drop-interim-responses",
+        ],
+    )
+    .await;
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(b"GET /continue HTTP/1.1\r\n\r\n")
+        .await
+        .unwrap();
+    let response = read_raw_response(&mut conn).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.ends_with("done"));
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that honors `Expect: 100-continue` by answering 100 Continue before reading
+/// the body it then echoes, except for /reject, which it answers with a 413 without reading any
+/// body, and /silent, for which it reads the body without having said anything, like an upstream
+/// that doesn't know about 100-continue.
+async fn start_expecting_upstream() -> String {
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        loop {
+            let (conn, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut conn = tokio::io::BufReader::new(conn);
+                loop {
+                    let mut request_line = String::new();
+                    if conn.read_line(&mut request_line).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    let (mut expecting, mut content_length) = (false, 0);
+                    let mut header = String::new();
+                    while header != "\r\n" {
+                        header.clear();
+                        if conn.read_line(&mut header).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        let header = header.to_lowercase();
+                        expecting |= header == "expect: 100-continue\r\n";
+                        if let Some(value) = header.strip_prefix("content-length: ") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                    if request_line.contains(" /reject ") {
+                        let response =
+                            "HTTP/1.1 413 Payload Too Large\r\nContent-Length: 0\r\n\r\n";
+                        let _ = conn.get_mut().write_all(response.as_bytes()).await;
+                        return;
+                    }
+                    if expecting && !request_line.contains(" /silent ") {
+                        let go_ahead = b"HTTP/1.1 100 Continue\r\n\r\n";
+                        if conn.get_mut().write_all(go_ahead).await.is_err() {
+                            return;
+                        }
+                    }
+                    let mut body = vec![0_u8; content_length];
+                    if conn.read_exact(&mut body).await.is_err() {
+                        return;
+                    }
+                    let mut response =
+                        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len())
+                            .into_bytes();
+                    response.extend_from_slice(&body);
+                    if conn.get_mut().write_all(&response).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// A client sending `Expect: 100-continue` should hear the upstream's 100 Continue before it sends
+/// the body, or the upstream's rejection without having to send it, after which the connection is
+/// closed whether or not the client goes on to send the body anyway
+#[tokio::test]
+async fn test_expect_continue() {
+    init_logging();
+    let upstream_address = start_expecting_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+    let expecting = |path: &str| {
+        format!(
+            "PUT {} HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n",
+            path
+        )
+    };
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    for path in &["/upload", "/silent"] {
+        conn.write_all(expecting(path).as_bytes()).await.unwrap();
+        // Only the headers have been sent, yet the client is told to go ahead, by the upstream
+        // or, failing that, by balancebeam
+        let go_ahead = timeout(Duration::from_secs(5), read_raw_head(&mut conn))
+            .await
+            .expect("balancebeam did not say to go ahead");
+        assert!(go_ahead.starts_with("HTTP/1.1 100"), "{}", go_ahead);
+        conn.write_all(b"hello").await.unwrap();
+        let response = read_raw_response(&mut conn).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("hello"));
+    }
+    // A client that doesn't wait gets its answer all the same, with the connection still usable
+    conn.write_all(format!("{}world", expecting("/eager")).as_bytes())
+        .await
+        .unwrap();
+    // The upstream's go-ahead may still be passed on, if it saw the headers before the body
+    let mut head = read_raw_head(&mut conn).await;
+    if head.starts_with("HTTP/1.1 100") {
+        head = read_raw_head(&mut conn).await;
+    }
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    let mut body = [0_u8; 5];
+    conn.read_exact(&mut body).await.unwrap();
+    assert_eq!(&body, b"world");
+    conn.write_all(b"GET /after HTTP/1.1\r\nContent-Length: 0\r\n\r\n")
+        .await
+        .unwrap();
+    let response = read_raw_response(&mut conn).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    // A rejection comes back without the body having been sent, and the connection is closed
+    // after it, so the body the client may or may not send can't be taken for a request
+    for send_anyway in &[false, true] {
+        let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+        conn.write_all(expecting("/reject").as_bytes())
+            .await
+            .unwrap();
+        let response = timeout(Duration::from_secs(5), read_raw_response(&mut conn))
+            .await
+            .expect("balancebeam did not pass on the rejection");
+        assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+        assert!(response.contains("connection: close"), "{}", response);
+        if *send_anyway {
+            let _ = conn.write_all(b"GET /smuggled HTTP/1.1\r\n\r\n").await;
+        }
+        assert_closed_within(&mut conn, Duration::from_secs(3)).await;
+    }
+    log::info!("All done :)");
+}
+
+/// A request with more headers than --max-header-count should be turned away with a 431, and the
+/// connection closed, since the rest of it can't be trusted to be read right
+#[tokio::test]
+async fn test_too_many_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-header-count", "10"]).await;
+
+    let response = send_raw_request(
+        &balancebeam.address,
+        &format!("GET /a HTTP/1.1\r\n{}\r\n", "x-a: b\r\n".repeat(10)),
+    )
+    .await;
+    assert!(response.starts_with("http/1.1 200"), "{}", response);
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(format!("GET /b HTTP/1.1\r\n{}\r\n", "x-a: b\r\n".repeat(11)).as_bytes())
+        .await
+        .unwrap();
+    let response = read_raw_response(&mut conn).await.to_lowercase();
+    assert!(response.starts_with("http/1.1 431"), "{}", response);
+    assert!(response.contains("connection: close"));
+    assert!(is_closed(&mut conn).await);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// A body over --max-request-body-bytes should get a 413, and whatever the client sent after the
+/// headers mustn't be mistaken for its next request
+#[tokio::test]
+async fn test_oversized_body_is_not_parsed_as_next_request() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--max-request-body-bytes", "1000"])
+            .await;
+
+    // Right at the limit is fine
+    let response = send_raw_request(
+        &balancebeam.address,
+        &format!(
+            "POST /fits HTTP/1.1\r\nContent-Length: 1000\r\n\r\n{}",
+            "x".repeat(1000)
+        ),
+    )
+    .await;
+    assert!(response.starts_with("http/1.1 200"), "{}", response);
+
+    // One byte over, with a body that happens to look like a request of its own
+    let smuggled = "GET /smuggled HTTP/1.1\r\nHost: test\r\n\r\n";
+    let body = format!("{}{}", smuggled, "x".repeat(1001 - smuggled.len()));
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(
+        format!(
+            "POST /too-big HTTP/1.1\r\nContent-Length: 1001\r\n\r\n{}{}",
+            body, smuggled
+        )
+        .as_bytes(),
+    )
+    .await
+    .unwrap();
+    let response = read_raw_response(&mut conn).await.to_lowercase();
+    assert!(response.starts_with("http/1.1 413"), "{}", response);
+    assert!(response.contains("connection: close"));
+    assert!(is_closed(&mut conn).await);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// With --bind given twice, the proxy should forward requests arriving on either address to the
+/// same upstream, and refuse to start at all if one of the addresses can't be bound
+#[tokio::test]
+async fn test_multiple_bind_addresses() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (first, second) = (free_local_address(), free_local_address());
+    let balancebeam =
+        BalanceBeam::new_with_bind(&first, &[&upstream.address], &["--bind", &second]).await;
+
+    for address in &[&first, &second] {
+        log::info!("Sending a request to {}", address);
+        let response = send_raw_request(
+            address,
+            "GET /hello HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("http/1.1 200"), "{}", response);
+        assert!(response.contains("get /hello http/1.1"), "{}", response);
+    }
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("Starting a second balancebeam that wants the first one's address too");
+    let mut clashing = BalanceBeam::new_with_bind(
+        &free_local_address(),
+        &[&balancebeam.address],
+        &["--bind", &second],
+    )
+    .await;
+    let status = timeout(Duration::from_secs(5), clashing.wait())
+        .await
+        .expect("balancebeam didn't give up on an address already in use");
+    assert!(!status.success());
+
+    log::info!("All done :)");
+}
+
+/// With --error-page-dir, the errors balancebeam makes itself should have the page for their
+/// status as the body, and the usual plain text for statuses that don't have one
+#[tokio::test]
+async fn test_error_pages() {
+    init_logging();
+    let dir = std::env::temp_dir().join(format!(
+        "balancebeam-test-error-pages-{}",
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    let page = "<html><body><h1>We'll be right back</h1></body></html>\n";
+    std::fs::write(dir.join("502.html"), page).unwrap();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&free_local_address()],
+        &[
+            "--error-page-dir",
+            dir.to_str().unwrap(),
+            "--max-request-body-bytes",
+            "10",
+        ],
+    )
+    .await;
+
+    log::info!("Sending a request that no upstream can answer");
+    let response = reqwest::get(&format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+    assert_eq!(response.headers()["content-type"], "text/html");
+    assert_eq!(
+        response.headers()["content-length"],
+        page.len().to_string().as_str()
+    );
+    assert_eq!(response.text().await.unwrap(), page);
+
+    log::info!("Sending a request whose error has no page");
+    let response = send_raw_request(
+        &balancebeam.address,
+        "POST /big HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello world",
+    )
+    .await;
+    assert!(response.starts_with("http/1.1 413"), "{}", response);
+    assert!(
+        response.contains("content-type: text/plain"),
+        "{}",
+        response
+    );
+    assert!(
+        response.ends_with("http 413 payload too large"),
+        "{}",
+        response
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+    log::info!("All done :)");
+}
+
+/// Every byte a client sends and is sent should be counted, in the access log against the request
+/// it was for (even when pipelined requests arrive together), in the totals on /status, and on
+/// /metrics for the upstream
+#[tokio::test]
+async fn test_byte_counters() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = free_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--access-log-format",
+            "json",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+
+    let requests = [
+        format!(
+            "POST /first HTTP/1.1\r\nHost: test\r\nContent-Length: 1000\r\n\r\n{}",
+            "x".repeat(1000)
+        ),
+        String::from("POST /second HTTP/1.1\r\nHost: test\r\nContent-Length: 5\r\n\r\nhello"),
+    ];
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(requests.concat().as_bytes()).await.unwrap();
+    let mut responses = Vec::new();
+    for _ in &requests {
+        let (head, body) = read_raw_response_bytes(&mut conn).await;
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        responses.push(head.len() + body.len());
+    }
+    drop(conn);
+    delay_for(Duration::from_millis(200)).await;
+
+    let lines = balancebeam.stdout_lines();
+    assert_eq!(lines.len(), 2);
+    for ((line, request), response) in lines.iter().zip(&requests).zip(&responses) {
+        let entry: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert_eq!(entry["bytes_in"], request.len(), "{}", line);
+        assert_eq!(entry["bytes_out"], *response, "{}", line);
+    }
+
+    let status = reqwest::get(&format!("http://{}/status", admin_address))
+        .await
+        .expect("Error sending request to the admin listener")
+        .text()
+        .await
+        .unwrap();
+    let status: serde_json::Value = serde_json::from_str(&status).unwrap();
+    let bytes_in: usize = requests.iter().map(String::len).sum();
+    let bytes_out: usize = responses.iter().sum();
+    assert_eq!(status["bytes_in"], bytes_in);
+    assert_eq!(status["bytes_out"], bytes_out);
+    let metrics = reqwest::get(&format!("http://{}/metrics", admin_address))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let label = format!("{{upstream=\"{}\"}}", upstream.address);
+    assert!(
+        metrics.contains(&format!("upstream_bytes_in_total{} {}\n", label, bytes_in)),
+        "{}",
+        metrics
+    );
+    assert!(
+        metrics.contains(&format!(
+            "upstream_bytes_out_total{} {}\n",
+            label, bytes_out
+        )),
+        "{}",
+        metrics
+    );
+    assert!(metrics.contains(&format!("bytes_in_total {}\n", bytes_in)));
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that answers with a chunked body, sent in two parts, ending in trailers: two
+/// for /trailers, a malformed one for /bad-trailer, and none for anything else.
+async fn start_trailer_upstream() -> String {
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        loop {
+            let (conn, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut conn = tokio::io::BufReader::new(conn);
+                loop {
+                    let mut request_line = String::new();
+                    if conn.read_line(&mut request_line).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    let mut header = String::new();
+                    while header != "\r\n" {
+                        header.clear();
+                        if conn.read_line(&mut header).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                    }
+                    let (declared, trailers) = if request_line.contains(" /trailers ") {
+                        (
+                            "Trailer: Grpc-Status, Grpc-Message\r\n",
+                            "Grpc-Status: 0\r\nGrpc-Message: fine\r\n",
+                        )
+                    } else if request_line.contains(" /bad-trailer ") {
+                        ("", "Grpc Status 0\r\n")
+                    } else {
+                        ("", "")
+                    };
+                    let head = format!(
+                        "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n{}\r\n4\r\nWiki\r\n",
+                        declared
+                    );
+                    let rest = format!("5\r\npedia\r\n0\r\n{}\r\n", trailers);
+                    for part in &[head, rest] {
+                        if conn.get_mut().write_all(part.as_bytes()).await.is_err() {
+                            return;
+                        }
+                        delay_for(Duration::from_millis(20)).await;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// Trailers on a chunked response should reach HTTP/1.1 clients as they were sent, and HTTP/1.0
+/// ones (which can't take a chunked body) as headers of a response with a Content-Length. A
+/// malformed trailer noticed before anything went to the client should be a 502.
+#[tokio::test]
+async fn test_chunked_response_trailers() {
+    init_logging();
+    let upstream_address = start_trailer_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    for path in &["/trailers", "/plain"] {
+        conn.write_all(format!("GET {} HTTP/1.1\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let head = read_raw_head(&mut conn).await.to_lowercase();
+        log::info!("Response: {}", head);
+        assert!(head.contains("transfer-encoding: chunked\r\n"), "{}", head);
+        let mut body = Vec::new();
+        let mut byte = [0_u8; 1];
+        while !body.ends_with(b"0\r\n\r\n") && !body.ends_with(b"fine\r\n\r\n") {
+            timeout(Duration::from_secs(5), conn.read_exact(&mut byte))
+                .await
+                .expect("balancebeam did not finish the body")
+                .unwrap();
+            body.push(byte[0]);
+        }
+        let body = String::from_utf8(body).unwrap();
+        if *path == "/trailers" {
+            assert!(
+                head.contains("trailer: grpc-status, grpc-message\r\n"),
+                "{}",
+                head
+            );
+            assert_eq!(
+                body,
+                "4\r\nWiki\r\n5\r\npedia\r\n0\r\nGrpc-Status: 0\r\nGrpc-Message: fine\r\n\r\n"
+            );
+        } else {
+            assert_eq!(body, "4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n");
+        }
+    }
+
+    let response = send_raw_request(&balancebeam.address, "GET /trailers HTTP/1.0\r\n\r\n").await;
+    assert!(response.starts_with("http/1.1 200"), "{}", response);
+    assert!(response.contains("content-length: 9\r\n"), "{}", response);
+    assert!(response.contains("grpc-status: 0\r\n"), "{}", response);
+    assert!(response.contains("grpc-message: fine\r\n"), "{}", response);
+    assert!(!response.contains("transfer-encoding"), "{}", response);
+    assert!(!response.contains("trailer:"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nwikipedia"), "{}", response);
+    let response = send_raw_request(&balancebeam.address, "GET /plain HTTP/1.0\r\n\r\n").await;
+    assert!(response.contains("content-length: 9\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nwikipedia"), "{}", response);
+
+    let response =
+        send_raw_request(&balancebeam.address, "GET /bad-trailer HTTP/1.0\r\n\r\n").await;
+    assert!(response.starts_with("http/1.1 502"), "{}", response);
+    log::info!("All done :)");
+}
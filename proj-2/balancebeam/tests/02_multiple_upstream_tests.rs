@@ -1,15 +1,18 @@
 mod common;
 
-use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
+use common::{
+    free_local_address, init_logging, start_slow_upstream, BalanceBeam, EchoServer, ErrorServer,
+    MockUpstream, Reply, Server,
+};
 
-use std::time::Duration;
-use tokio::time::delay_for;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{delay_for, timeout};
 
-async fn setup_with_params(
-    n_upstreams: usize,
-    active_health_check_interval: Option<usize>,
-    max_requests_per_minute: Option<usize>,
-) -> (BalanceBeam, Vec<Box<dyn Server>>) {
+async fn start_upstreams(n_upstreams: usize) -> (Vec<Box<dyn Server>>, Vec<String>) {
     init_logging();
     let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
     for _ in 0..n_upstreams {
@@ -19,6 +22,15 @@ async fn setup_with_params(
         .iter()
         .map(|upstream| upstream.address())
         .collect();
+    (upstreams, upstream_addresses)
+}
+
+async fn setup_with_params(
+    n_upstreams: usize,
+    active_health_check_interval: Option<usize>,
+    max_requests_per_minute: Option<usize>,
+) -> (BalanceBeam, Vec<Box<dyn Server>>) {
+    let (upstreams, upstream_addresses) = start_upstreams(n_upstreams).await;
     let upstream_addresses: Vec<&str> = upstream_addresses
         .iter()
         .map(|addr| addr.as_str())
@@ -32,6 +44,19 @@ async fn setup_with_params(
     (balancebeam, upstreams)
 }
 
+async fn setup_with_args(
+    n_upstreams: usize,
+    extra_args: &[&str],
+) -> (BalanceBeam, Vec<Box<dyn Server>>) {
+    let (upstreams, upstream_addresses) = start_upstreams(n_upstreams).await;
+    let upstream_addresses: Vec<&str> = upstream_addresses
+        .iter()
+        .map(|addr| addr.as_str())
+        .collect();
+    let balancebeam = BalanceBeam::new_with_args(&upstream_addresses, extra_args).await;
+    (balancebeam, upstreams)
+}
+
 async fn setup(n_upstreams: usize) -> (BalanceBeam, Vec<Box<dyn Server>>) {
     setup_with_params(n_upstreams, None, None).await
 }
@@ -80,6 +105,549 @@ async fn test_load_distribution() {
     log::info!("All done :)");
 }
 
+/// With --strategy round-robin, requests should be spread exactly evenly (give or take one) across
+/// the upstreams that are still alive, even if one of the configured upstreams is down
+#[tokio::test]
+async fn test_round_robin_distribution() {
+    let n_upstreams = 4;
+    let n_requests = 90;
+    // Keep health check probes out of the request counts
+    let (balancebeam, mut upstreams) = setup_with_args(
+        n_upstreams,
+        &[
+            "--strategy",
+            "round-robin",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    log::info!("Killing one of the upstream servers before sending any requests");
+    upstreams.pop().unwrap().stop().await;
+
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.insert(0, upstream.stop().await);
+    }
+    log::info!(
+        "Number of requests received by each live upstream: {:?}",
+        request_counters
+    );
+    assert_eq!(request_counters.iter().sum::<usize>(), n_requests);
+    let min_count = *request_counters.iter().min().unwrap();
+    let max_count = *request_counters.iter().max().unwrap();
+    assert!(
+        max_count - min_count <= 1,
+        "Round-robin should spread requests within one of each other, got {:?}",
+        request_counters
+    );
+
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that accepts connections but never responds on them. Returns its address.
+async fn start_silent_upstream() -> String {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let mut connections = Vec::new();
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            connections.push(stream);
+        }
+    });
+    address
+}
+
+/// An upstream that hangs on health check probes shouldn't hold up requests to the healthy ones
+#[tokio::test]
+async fn test_hanging_health_check_does_not_block_traffic() {
+    let (mut upstreams, upstream_addresses) = start_upstreams(1).await;
+    let silent_address = start_silent_upstream().await;
+    // Weight zero keeps client traffic away from the silent upstream while the echo server is up
+    let silent_upstream = format!("{}=0", silent_address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_addresses[0], &silent_upstream],
+        &["--active-health-check-interval", "1"],
+    )
+    .await;
+
+    log::info!("Sending requests while health checks are stuck on the silent upstream");
+    let mut n_requests = 0;
+    let start = std::time::Instant::now();
+    while start.elapsed() < Duration::from_secs(3) {
+        let path = format!("/during-probe-{}", n_requests);
+        let response_text = timeout(Duration::from_secs(2), balancebeam.get(&path))
+            .await
+            .expect("Request stalled. Is a health check holding the upstream lock?")
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        n_requests += 1;
+        delay_for(Duration::from_millis(100)).await;
+    }
+    // The echo server also sees the health checks
+    assert!(upstreams.pop().unwrap().stop().await >= n_requests);
+
+    log::info!("All done :)");
+}
+
+/// An upstream that accepts a request but never answers it should get the client a 504 once
+/// --upstream-response-timeout runs out, after which balancebeam closes the client connection
+#[tokio::test]
+async fn test_upstream_response_timeout() {
+    init_logging();
+    let silent_address = start_silent_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&silent_address],
+        &[
+            "--upstream-response-timeout",
+            "1",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    let mut client = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    client
+        .write_all(b"GET /never-answered HTTP/1.1\r\nHost: balancebeam\r\n\r\n")
+        .await
+        .unwrap();
+    // Read until balancebeam hangs up; that it hangs up at all is part of the test
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(5), client.read_to_end(&mut response))
+        .await
+        .expect("balancebeam didn't give up on the silent upstream and close the connection")
+        .unwrap();
+    assert_eq!(
+        response_status_codes(&String::from_utf8_lossy(&response)),
+        vec![504]
+    );
+
+    log::info!("All done :)");
+}
+
+/// Health checks should pass for any status in --health-check-expect, not just 200
+#[tokio::test]
+async fn test_health_check_expected_status_codes() {
+    init_logging();
+    let no_content = MockUpstream::new(Reply::status(204, "")).await;
+    let no_content_address = no_content.address.clone();
+    let health_check_args = [
+        "--active-health-check-interval",
+        "1",
+        "--health-check-failure-threshold",
+        "1",
+    ];
+    let strict = BalanceBeam::in_process(&[&no_content_address], &health_check_args).await;
+    let mut lenient_args = health_check_args.to_vec();
+    lenient_args.extend(&["--health-check-expect", "200-299"]);
+    let lenient = BalanceBeam::in_process(&[&no_content_address], &lenient_args).await;
+
+    log::info!("Waiting for a few rounds of health checks...");
+    delay_for(Duration::from_secs(3)).await;
+
+    let client = reqwest::Client::new();
+    for (balancebeam, expected_status) in &[(&strict, 503), (&lenient, 204)] {
+        let response = client
+            .get(&format!("http://{}/no-content", balancebeam.address))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), *expected_status);
+    }
+
+    log::info!("All done :)");
+}
+
+/// Sends requests until one is answered by the upstream `is_from` recognizes, for up to 5 seconds.
+async fn wait_for_upstream(balancebeam: &BalanceBeam, is_from: impl Fn(&str) -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        if let Ok(response_text) = balancebeam.get("/wait").await {
+            if is_from(&response_text) {
+                return;
+            }
+        }
+        assert!(
+            Instant::now() < deadline,
+            "Traffic never moved to the expected upstream"
+        );
+        delay_for(Duration::from_millis(100)).await;
+    }
+}
+
+/// An --upstream-backup should get no traffic while a primary is up, all of it once every primary
+/// is down, and none again once one comes back
+#[tokio::test]
+async fn test_backup_upstream() {
+    init_logging();
+    let primaries = vec![EchoServer::new().await, EchoServer::new().await];
+    let primary_addresses: Vec<String> = primaries
+        .iter()
+        .map(|upstream| upstream.address.clone())
+        .collect();
+    // Answers every request with "slow", unlike the echo servers
+    let backup = start_slow_upstream(Duration::from_millis(0)).await;
+    let admin_address = free_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&primary_addresses[0], &primary_addresses[1]],
+        &[
+            "--upstream-backup",
+            &backup,
+            "--active-health-check-interval",
+            "1",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+    let from_primary = |response_text: &str| response_text.contains("GET /");
+
+    for i in 0..6 {
+        let path = format!("/primary-{}", i);
+        let response_text = balancebeam.get(&path).await.unwrap();
+        assert!(from_primary(&response_text), "{}", response_text);
+    }
+    let status = reqwest::get(&format!("http://{}/status", admin_address))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(status.contains(&format!("\"address\":\"{}\",\"alive\":true", backup)));
+    assert!(status.contains("\"backup\":true"), "{}", status);
+
+    log::info!("Killing every primary");
+    for primary in primaries {
+        Box::new(primary).stop().await;
+    }
+    wait_for_upstream(&balancebeam, |response_text| response_text == "slow").await;
+    for _ in 0..4 {
+        assert_eq!(balancebeam.get("/backup").await.unwrap(), "slow");
+    }
+
+    log::info!("Bringing one primary back");
+    let primary = EchoServer::new_at_address(primary_addresses[0].clone()).await;
+    wait_for_upstream(&balancebeam, from_primary).await;
+    for i in 0..4 {
+        let path = format!("/recovered-{}", i);
+        let response_text = balancebeam.get(&path).await.unwrap();
+        assert!(from_primary(&response_text), "{}", response_text);
+    }
+    assert!(Box::new(primary).stop().await >= 4);
+
+    log::info!("All done :)");
+}
+
+/// Draining an upstream over the admin listener should take it out of rotation without marking it
+/// down, and undraining it should put it back; with --drain-fallback, a draining upstream should
+/// still be used once nothing else is up
+#[tokio::test]
+async fn test_drain_upstream() {
+    init_logging();
+    let echo = EchoServer::new().await;
+    let echo_address = echo.address.clone();
+    // Answers every request with "slow", unlike the echo server
+    let slow = start_slow_upstream(Duration::from_millis(0)).await;
+    let admin_address = free_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&echo_address, &slow],
+        &[
+            "--strategy",
+            "round-robin",
+            "--active-health-check-interval",
+            "1",
+            "--admin-bind",
+            &admin_address,
+            "--drain-fallback",
+        ],
+    )
+    .await;
+    let from_echo = |response_text: &str| response_text.contains("GET /");
+    let client = reqwest::Client::new();
+    let admin_post = |path: String| {
+        client
+            .post(&format!("http://{}{}", admin_address, path))
+            .send()
+    };
+
+    let response = admin_post(format!("/upstreams/{}/drain", echo_address))
+        .await
+        .expect("Error sending request to the admin listener");
+    assert_eq!(response.status().as_u16(), 200);
+    let status = response.text().await.unwrap();
+    assert!(
+        status.contains(&format!(
+            "{{\"address\":\"{}\",\"alive\":true,\"circuit\":\"closed\",\"requests\":0,\"failures\":0,\"draining\":true",
+            echo_address
+        )),
+        "{}",
+        status
+    );
+    for _ in 0..4 {
+        assert_eq!(balancebeam.get("/drained").await.unwrap(), "slow");
+    }
+
+    let response = admin_post(format!("/upstreams/{}/undrain", echo_address))
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(!response.text().await.unwrap().contains("draining"));
+    let mut echoed = 0;
+    for i in 0..4 {
+        let path = format!("/undrained-{}", i);
+        if from_echo(&balancebeam.get(&path).await.unwrap()) {
+            echoed += 1;
+        }
+    }
+    assert_eq!(echoed, 2);
+
+    let response = admin_post(String::from("/upstreams/10.0.0.1:80/drain"))
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+    let response = admin_post(format!("/upstreams/{}/pause", echo_address))
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 405);
+
+    log::info!("Draining the other upstream, then killing the echo server");
+    admin_post(format!("/upstreams/{}/drain", slow))
+        .await
+        .unwrap();
+    for i in 0..4 {
+        let path = format!("/other-drained-{}", i);
+        let response_text = balancebeam.get(&path).await.unwrap();
+        assert!(from_echo(&response_text), "{}", response_text);
+    }
+    Box::new(echo).stop().await;
+    wait_for_upstream(&balancebeam, |response_text| response_text == "slow").await;
+    let status = reqwest::get(&format!("http://{}/status", admin_address))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    // Draining isn't down
+    assert!(
+        status.contains(&format!("\"address\":\"{}\",\"alive\":true", slow)),
+        "{}",
+        status
+    );
+    log::info!("All done :)");
+}
+
+/// With --unhealthy-status-threshold, an upstream that accepts connections but answers with 500s
+/// should be marked down after that many in a row (any success starting the count over), and be
+/// brought back by the active health checker once it recovers
+#[tokio::test]
+async fn test_unhealthy_status_threshold() {
+    init_logging();
+    let upstream = MockUpstream::new(Reply::ok("ok")).await;
+    let failing = || upstream.set_reply(Reply::status(500, ""));
+    let recovered = || upstream.set_reply(Reply::ok("ok"));
+    let balancebeam = BalanceBeam::in_process(
+        &[&upstream.address],
+        &[
+            "--unhealthy-status-threshold",
+            "3",
+            "--active-health-check-interval",
+            "1",
+            "--health-check-max-backoff",
+            "1",
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let status = || async {
+        client
+            .get(&format!("http://{}/", balancebeam.address))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .status()
+            .as_u16()
+    };
+
+    failing();
+    assert_eq!(vec![status().await, status().await], vec![500, 500]);
+    recovered();
+    assert_eq!(status().await, 200);
+    failing();
+    assert_eq!(
+        vec![status().await, status().await, status().await],
+        vec![500, 500, 500]
+    );
+    // That made three in a row, so the upstream is down, and stays down while it keeps failing
+    // health checks
+    assert_eq!(status().await, 503);
+    delay_for(Duration::from_millis(1500)).await;
+    assert_eq!(status().await, 503);
+
+    recovered();
+    let started = Instant::now();
+    while status().await != 200 {
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "The active health checker did not bring the upstream back"
+        );
+        delay_for(Duration::from_millis(200)).await;
+    }
+    log::info!("All done :)");
+}
+
+/// If an upstream dies after receiving a request but before responding, an idempotent request
+/// should be replayed on another upstream instead of failing with a 502
+#[tokio::test]
+async fn test_retry_idempotent_request_on_another_upstream() {
+    init_logging();
+    let hang_up = MockUpstream::new(Reply::Close).await;
+    let healthy = MockUpstream::new(Reply::ok("from the healthy one")).await;
+    // Round-robin sends the first connection to the upstream that hangs up
+    let balancebeam = BalanceBeam::in_process(
+        &[&hang_up.address, &healthy.address],
+        &["--strategy", "round-robin"],
+    )
+    .await;
+
+    let response_text = balancebeam
+        .get("/retry-me")
+        .await
+        .expect("Error sending request to balancebeam. Retries may not be working");
+    assert_eq!(
+        response_text, "from the healthy one",
+        "balancebeam returned unexpected response. Retries may not be working"
+    );
+    // Both got the request: the one that hung up first, then the one it was replayed on
+    assert_eq!(hang_up.requests_for("/retry-me").len(), 1);
+    let replayed = healthy.requests_for("/retry-me");
+    assert_eq!(replayed.len(), 1);
+    assert_eq!(replayed[0].method, "GET");
+    assert_eq!(replayed[0].header("x-sent-by"), Some("balancebeam-tests"));
+
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that answers every request with a small 200 and counts the connections it
+/// accepts. With `close_after_response`, it hangs up after each response (without saying so in
+/// the response), like a server with a very short keep-alive timeout. Returns its address and the
+/// connection count.
+async fn start_counting_upstream(close_after_response: bool) -> (String, Arc<AtomicUsize>) {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buffer = [0_u8; 4096];
+                while let Ok(bytes_read) = stream.read(&mut buffer).await {
+                    if bytes_read == 0 {
+                        break;
+                    }
+                    // Requests have no body, so one is complete once its headers are
+                    request.extend_from_slice(&buffer[..bytes_read]);
+                    if !request.ends_with(b"\r\n\r\n") {
+                        continue;
+                    }
+                    request.clear();
+                    if stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\npooled")
+                        .await
+                        .is_err()
+                        || close_after_response
+                    {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    (address, connections)
+}
+
+/// Client connections should share pooled upstream connections instead of each opening their own
+#[tokio::test]
+async fn test_upstream_connections_are_pooled() {
+    init_logging();
+    let n_clients = 20;
+    let (address, connections) = start_counting_upstream(false).await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&address], &["--active-health-check-interval", "3600"]).await;
+
+    // Each get() uses a new client, and so a new connection to balancebeam
+    for i in 0..n_clients {
+        let response_text = balancebeam
+            .get(&format!("/client-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response_text, "pooled");
+    }
+    let n_connections = connections.load(Ordering::SeqCst);
+    log::info!(
+        "{} client connections used {} upstream connections",
+        n_clients,
+        n_connections
+    );
+    assert!(
+        n_connections < n_clients,
+        "expected fewer upstream connections than client connections, got {}",
+        n_connections
+    );
+
+    log::info!("All done :)");
+}
+
+/// A pooled connection the upstream has since closed should be replaced without the client
+/// noticing, and without the upstream being marked down
+#[tokio::test]
+async fn test_closed_pooled_connection_is_replaced() {
+    init_logging();
+    let n_clients = 10;
+    let (address, connections) = start_counting_upstream(true).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&address],
+        &[
+            "--active-health-check-interval",
+            "3600",
+            "--health-check-failure-threshold",
+            "1",
+        ],
+    )
+    .await;
+
+    for i in 0..n_clients {
+        let response_text = balancebeam
+            .get(&format!("/client-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response_text, "pooled");
+        // Give the upstream's hang-up time to reach balancebeam for some of the requests
+        if i % 2 == 0 {
+            delay_for(Duration::from_millis(100)).await;
+        }
+    }
+    assert_eq!(connections.load(Ordering::SeqCst), n_clients);
+
+    log::info!("All done :)");
+}
+
 async fn try_failover(balancebeam: &BalanceBeam, upstreams: &mut Vec<Box<dyn Server>>) {
     // Send some initial requests. Everything should work
     log::info!("Sending some initial requests. These should definitely work.");
@@ -161,8 +729,9 @@ async fn test_active_health_checks_check_http_status() {
     upstreams.pop().unwrap().stop().await;
     upstreams.push(Box::new(ErrorServer::new_at_address(failed_ip).await));
 
+    // It takes three failed checks in a row (one per second) before the upstream is marked down
     log::info!("Waiting for health checks to realize server is dead...");
-    delay_for(Duration::from_secs(3)).await;
+    delay_for(Duration::from_secs(5)).await;
 
     // Make sure we get back successful requests
     for i in 0..8 {
@@ -208,8 +777,10 @@ async fn test_active_health_checks_restore_failed_upstream() {
     log::info!("Re-starting the \"failed\" upstream server...");
     upstreams.push(Box::new(EchoServer::new_at_address(failed_ip).await));
 
+    // The probes back off while the upstream is down, so the next one may be a couple of seconds
+    // out
     log::info!("Waiting a few seconds for the active health check to run...");
-    delay_for(Duration::from_secs(3)).await;
+    delay_for(Duration::from_secs(5)).await;
 
     log::info!("Sending some more requests");
     for i in 0..5 {
@@ -243,11 +814,18 @@ async fn test_active_health_checks_restore_failed_upstream() {
 /// Enable rate limiting and ensure that requests fail after sending more than the threshold
 #[tokio::test]
 async fn test_rate_limiting() {
-    let n_upstreams = 1;
+    init_logging();
     let rate_limit_threshold = 5;
     let num_extra_requests: usize = 3;
-    let (balancebeam, mut upstreams) =
-        setup_with_params(n_upstreams, None, Some(rate_limit_threshold)).await;
+    let upstream = MockUpstream::new(Reply::ok("under the limit")).await;
+    let balancebeam = BalanceBeam::in_process(
+        &[&upstream.address],
+        &[
+            "--max-requests-per-minute",
+            &rate_limit_threshold.to_string(),
+        ],
+    )
+    .await;
 
     log::info!(
         "Sending some basic requests to the server, within the rate limit threshold. These \
@@ -259,9 +837,13 @@ async fn test_rate_limiting() {
             .get(&path)
             .await
             .expect("Error sending request to balancebeam");
-        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
-        assert!(response_text.contains("x-sent-by: balancebeam-tests"));
-        assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+        assert_eq!(response_text, "under the limit");
+    }
+    let forwarded = upstream.requests_for("/request-");
+    assert_eq!(forwarded.len(), rate_limit_threshold);
+    for request in &forwarded {
+        assert_eq!(request.header("x-sent-by"), Some("balancebeam-tests"));
+        assert_eq!(request.header("x-forwarded-for"), Some("127.0.0.1"));
     }
 
     log::info!(
@@ -283,14 +865,1853 @@ async fn test_rate_limiting() {
         log::info!("{:?}", response);
         log::info!("Checking to make sure the server responded with HTTP 429");
         assert_eq!(response.status().as_u16(), 429);
+
+        log::info!("Checking the rate limit headers on the 429");
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .unwrap_or_else(|| panic!("429 response is missing the {} header", name))
+                .to_str()
+                .unwrap()
+                .parse::<usize>()
+                .unwrap()
+        };
+        let retry_after = header("retry-after");
+        assert!(
+            (1..=60).contains(&retry_after),
+            "Retry-After should be within the one-minute window, got {}",
+            retry_after
+        );
+        assert_eq!(header("x-ratelimit-limit"), rate_limit_threshold);
+        assert_eq!(header("x-ratelimit-remaining"), 0);
     }
 
     log::info!("Ensuring the extra requests didn't go through to the upstream servers");
-    let mut total_request_count = 0;
-    while let Some(upstream) = upstreams.pop() {
-        total_request_count += upstream.stop().await;
+    assert!(upstream.requests_for("/overboard").is_empty());
+    assert_eq!(
+        upstream.requests_for("/request-").len(),
+        rate_limit_threshold
+    );
+
+    log::info!("All done :)");
+}
+
+/// Clients inside a --rate-limit-exempt range should never be rate limited
+#[tokio::test]
+async fn test_rate_limit_exempt_range() {
+    let n_upstreams = 1;
+    let rate_limit_threshold = 2;
+    let n_requests = 3 * rate_limit_threshold;
+    let (balancebeam, mut upstreams) = setup_with_args(
+        n_upstreams,
+        &[
+            "--max-requests-per-minute",
+            &rate_limit_threshold.to_string(),
+            "--rate-limit-exempt",
+            "10.0.0.0/8",
+            "--rate-limit-exempt",
+            "127.0.0.0/8",
+        ],
+    )
+    .await;
+
+    for i in 0..n_requests {
+        let path = format!("/exempt-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(
+            response_text.contains(&format!("GET {} HTTP/1.1", path)),
+            "Request from an exempt address was not forwarded: {}",
+            response_text
+        );
+    }
+    assert_eq!(upstreams.pop().unwrap().stop().await, n_requests);
+
+    log::info!("All done :)");
+}
+
+/// Pull the status codes out of a sequence of raw HTTP/1.1 responses, in order
+fn response_status_codes(responses: &str) -> Vec<u16> {
+    responses
+        .split("HTTP/1.1 ")
+        .skip(1)
+        .filter_map(|rest| rest.get(..3)?.parse().ok())
+        .collect()
+}
+
+/// Rate limiting should count every request, even when a client sends them all on one keep-alive
+/// connection. Requests over the limit get a 429, but the connection stays usable
+#[tokio::test]
+async fn test_rate_limiting_counts_pipelined_requests() {
+    let n_upstreams = 1;
+    let rate_limit_threshold = 4;
+    let (balancebeam, mut upstreams) =
+        setup_with_params(n_upstreams, None, Some(rate_limit_threshold)).await;
+
+    log::info!(
+        "Pipelining {} requests on a single connection",
+        2 * rate_limit_threshold
+    );
+    let mut client = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let mut requests = String::new();
+    for i in 0..2 * rate_limit_threshold {
+        requests += &format!("GET /pipelined-{} HTTP/1.1\r\nHost: balancebeam\r\n\r\n", i);
+    }
+    client.write_all(requests.as_bytes()).await.unwrap();
+
+    let mut responses = Vec::new();
+    let mut buffer = [0_u8; 4096];
+    while response_status_codes(&String::from_utf8_lossy(&responses)).len()
+        < 2 * rate_limit_threshold
+    {
+        let bytes_read = timeout(Duration::from_secs(5), client.read(&mut buffer))
+            .await
+            .expect("Timed out waiting for responses from balancebeam")
+            .unwrap();
+        assert!(bytes_read > 0, "balancebeam closed the connection early");
+        responses.extend_from_slice(&buffer[..bytes_read]);
     }
+    let status_codes = response_status_codes(&String::from_utf8_lossy(&responses));
+    log::info!("Status codes: {:?}", status_codes);
+    let mut expected = vec![200; rate_limit_threshold];
+    expected.extend(vec![429; rate_limit_threshold]);
+    assert_eq!(status_codes, expected);
+
+    // Hang up so balancebeam lets go of its upstream connection, then check what got through
+    drop(client);
+    let total_request_count = upstreams.pop().unwrap().stop().await;
     assert_eq!(total_request_count, rate_limit_threshold);
 
     log::info!("All done :)");
 }
+
+/// A request should be held to the first --rate-limit-rule it matches, even where prefixes
+/// overlap, with the global limit for the rest, and every limit counted separately
+#[tokio::test]
+async fn test_rate_limit_rules() {
+    let (balancebeam, mut upstreams) = setup_with_args(
+        1,
+        &[
+            "--max-requests-per-minute",
+            "3",
+            "--rate-limit-rule",
+            "POST /api/login=1",
+            "--rate-limit-rule",
+            "/api=2",
+            "--rate-limit-rule",
+            "/static=0",
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let send = |method: reqwest::Method, path: &str| {
+        client
+            .request(method, &format!("http://{}{}", balancebeam.address, path))
+            .send()
+    };
+    let status_and_rule = |response: reqwest::Response| {
+        let rule = response
+            .headers()
+            .get("x-ratelimit-rule")
+            .map(|rule| rule.to_str().unwrap().to_string());
+        (response.status().as_u16(), rule)
+    };
+
+    log::info!("The login rule only allows one POST a minute");
+    let response = send(reqwest::Method::POST, "/api/login").await.unwrap();
+    assert_eq!(status_and_rule(response), (200, None));
+    let response = send(reqwest::Method::POST, "/api/login").await.unwrap();
+    assert_eq!(
+        status_and_rule(response),
+        (429, Some(String::from("POST /api/login")))
+    );
+
+    log::info!("A GET of the same path falls through to the /api rule, whose count is untouched");
+    for path in &["/api/login", "/api/users"] {
+        let response = send(reqwest::Method::GET, path).await.unwrap();
+        assert_eq!(status_and_rule(response), (200, None), "{}", path);
+    }
+    let response = send(reqwest::Method::GET, "/api/users").await.unwrap();
+    assert_eq!(status_and_rule(response), (429, Some(String::from("/api"))));
+    let response = send(reqwest::Method::POST, "/api/login").await.unwrap();
+    assert_eq!(status_and_rule(response).0, 429);
+
+    log::info!("Other paths get the global limit, counted from zero");
+    for i in 0..3 {
+        let response = send(reqwest::Method::GET, &format!("/page-{}", i))
+            .await
+            .unwrap();
+        assert_eq!(status_and_rule(response), (200, None));
+    }
+    let response = send(reqwest::Method::GET, "/page-3").await.unwrap();
+    let rule = response.headers().get("x-ratelimit-limit").unwrap().clone();
+    assert_eq!(status_and_rule(response), (429, None));
+    assert_eq!(rule, "3");
+
+    log::info!("Static files are never limited");
+    for i in 0..5 {
+        let response = send(reqwest::Method::GET, &format!("/static/{}.css", i))
+            .await
+            .unwrap();
+        assert_eq!(status_and_rule(response).0, 200);
+    }
+
+    assert_eq!(upstreams.pop().unwrap().stop().await, 1 + 2 + 3 + 5);
+    log::info!("All done :)");
+}
+
+/// Sends `requests` down one connection in one go and reads back the status code of each.
+async fn pipelined_status_codes(address: &str, requests: &[String]) -> Vec<u16> {
+    let mut client = TcpStream::connect(address)
+        .await
+        .expect("Could not connect to balancebeam");
+    client
+        .write_all(requests.concat().as_bytes())
+        .await
+        .unwrap();
+    let mut responses = Vec::new();
+    let mut buffer = [0_u8; 4096];
+    while response_status_codes(&String::from_utf8_lossy(&responses)).len() < requests.len() {
+        let bytes_read = timeout(Duration::from_secs(5), client.read(&mut buffer))
+            .await
+            .expect("Timed out waiting for responses from balancebeam")
+            .unwrap();
+        assert!(bytes_read > 0, "balancebeam closed the connection early");
+        responses.extend_from_slice(&buffer[..bytes_read]);
+    }
+    response_status_codes(&String::from_utf8_lossy(&responses))
+}
+
+/// A GET with the given X-Api-Key, or none
+fn keyed_request(api_key: Option<&str>) -> String {
+    match api_key {
+        Some(api_key) => format!(
+            "GET / HTTP/1.1\r\nHost: balancebeam\r\nX-Api-Key: {}\r\n\r\n",
+            api_key
+        ),
+        None => String::from("GET / HTTP/1.1\r\nHost: balancebeam\r\n\r\n"),
+    }
+}
+
+/// With --rate-limit-key header:X-Api-Key, each key should get its own limit even when they all
+/// come from one IP, and requests without a key fall back to being counted by IP, or are turned
+/// away with --require-rate-limit-key
+#[tokio::test]
+async fn test_rate_limit_key_header() {
+    let (balancebeam, mut upstreams) = setup_with_args(
+        1,
+        &[
+            "--max-requests-per-minute",
+            "2",
+            "--rate-limit-key",
+            "header:X-Api-Key",
+        ],
+    )
+    .await;
+    let requests: Vec<String> = [
+        Some("tenant-1"),
+        None,
+        Some("tenant-1"),
+        Some("tenant-1"),
+        Some("tenant-2"),
+        None,
+        None,
+        Some("tenant-2"),
+        Some("tenant-2"),
+    ]
+    .iter()
+    .map(|&api_key| keyed_request(api_key))
+    .collect();
+    let status_codes = pipelined_status_codes(&balancebeam.address, &requests).await;
+    assert_eq!(
+        status_codes,
+        vec![200, 200, 200, 429, 200, 200, 429, 200, 429]
+    );
+    assert_eq!(upstreams.pop().unwrap().stop().await, 6);
+
+    log::info!("Requiring a key");
+    let (balancebeam, mut upstreams) = setup_with_args(
+        1,
+        &[
+            "--max-requests-per-minute",
+            "2",
+            "--rate-limit-key",
+            "header:X-Api-Key",
+            "--require-rate-limit-key",
+        ],
+    )
+    .await;
+    let requests: Vec<String> = [None, Some("tenant-1"), None, Some("tenant-1")]
+        .iter()
+        .map(|&api_key| keyed_request(api_key))
+        .collect();
+    let status_codes = pipelined_status_codes(&balancebeam.address, &requests).await;
+    assert_eq!(status_codes, vec![401, 200, 401, 200]);
+    assert_eq!(upstreams.pop().unwrap().stop().await, 2);
+
+    log::info!("All done :)");
+}
+
+/// /status on the admin listener should report each upstream's health and traffic, and admin
+/// requests shouldn't count against the rate limit
+#[tokio::test]
+async fn test_admin_status() {
+    let (mut upstreams, upstream_addresses) = start_upstreams(2).await;
+    let admin_address = free_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_addresses[0], &upstream_addresses[1]],
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--strategy",
+            "round-robin",
+            "--health-check-failure-threshold",
+            "1",
+            "--active-health-check-interval",
+            "3600",
+            "--max-requests-per-minute",
+            "4",
+        ],
+    )
+    .await;
+
+    log::info!("Killing the second upstream, then sending requests");
+    upstreams.pop().unwrap().stop().await;
+    for i in 0..4 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let client = reqwest::Client::new();
+    let mut status = String::new();
+    for _ in 0..10 {
+        let response = client
+            .get(&format!("http://{}/status", admin_address))
+            .send()
+            .await
+            .expect("Error sending request to the admin listener");
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.headers()["content-type"], "application/json");
+        status = response.text().await.unwrap();
+    }
+    log::info!("Status: {}", status);
+    assert!(status.contains(&format!(
+        "{{\"address\":\"{}\",\"alive\":true,\"circuit\":\"closed\",\"requests\":4,\"failures\":0}}",
+        upstream_addresses[0]
+    )));
+    assert!(status.contains(&format!(
+        "{{\"address\":\"{}\",\"alive\":false,\"circuit\":\"closed\",\"requests\":0,\"failures\":1}}",
+        upstream_addresses[1]
+    )));
+    assert!(status.contains("\"rate_limiter_clients\":1"));
+
+    let response = client
+        .get(&format!("http://{}/request-0", admin_address))
+        .send()
+        .await
+        .expect("Error sending request to the admin listener");
+    assert_eq!(response.status().as_u16(), 404);
+
+    log::info!("All done :)");
+}
+
+/// With --latency-eject-ms, an upstream that answers its health checks too slowly should be
+/// marked down like one failing them, and /status should report each upstream's probe latencies
+#[tokio::test]
+async fn test_latency_ejection() {
+    init_logging();
+    let fast = EchoServer::new().await;
+    let slow = start_slow_upstream(Duration::from_millis(300)).await;
+    let admin_address = free_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&fast.address, &slow],
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--active-health-check-interval",
+            "1",
+            "--health-check-failure-threshold",
+            "2",
+            "--latency-eject-ms",
+            "150",
+        ],
+    )
+    .await;
+
+    log::info!("Waiting for a few rounds of health checks");
+    delay_for(Duration::from_secs(4)).await;
+    let body = reqwest::get(&format!("http://{}/status", admin_address))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    log::info!("Status: {}", body);
+    let status: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let upstream = |address: &str| {
+        status["upstreams"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|upstream| upstream["address"] == address)
+            .unwrap()
+            .clone()
+    };
+    let (fast_status, slow_status) = (upstream(&fast.address), upstream(&slow));
+    assert_eq!(fast_status["alive"], true);
+    assert_eq!(slow_status["alive"], false);
+    let p95 = |upstream: &serde_json::Value| upstream["probe_latency_ms"]["p95"].as_f64().unwrap();
+    assert!(p95(&fast_status) < 150.0, "{}", body);
+    assert!(p95(&slow_status) >= 300.0, "{}", body);
+    assert!(slow_status["probe_latency_ms"]["max"].as_f64().unwrap() >= p95(&slow_status));
+
+    log::info!("Everything should go to the fast upstream now");
+    for i in 0..5 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam.get(&path).await.unwrap();
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    log::info!("All done :)");
+}
+
+/// With --resolve-interval, an upstream given by hostname should be balanced to (and reported by
+/// /status) as the addresses it resolves to
+#[tokio::test]
+async fn test_resolve_upstream_hostnames() {
+    let (mut upstreams, upstream_addresses) = start_upstreams(1).await;
+    let port = upstream_addresses[0].rsplit_once(':').unwrap().1;
+    let by_name = format!("localhost:{}", port);
+    let admin_address = free_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&by_name],
+        &[
+            "--resolve-interval",
+            "1",
+            "--admin-bind",
+            &admin_address,
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    for i in 0..3 {
+        balancebeam
+            .get(&format!("/resolved-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    // Let it re-resolve a couple of times; the address it already had keeps its counters
+    delay_for(Duration::from_millis(2500)).await;
+    let status = reqwest::get(&format!("http://{}/status", admin_address))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    log::info!("Status: {}", status);
+    assert!(status.contains(&format!(
+        "{{\"address\":\"127.0.0.1:{}\",\"alive\":true,\"circuit\":\"closed\",\"requests\":3,\"failures\":0,\"resolved_from\":\"{}\"}}",
+        port, by_name
+    )));
+
+    assert_eq!(upstreams.pop().unwrap().stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// /metrics on the admin listener should count proxied responses by upstream and status class,
+/// along with rate-limited requests and healthy upstreams
+#[tokio::test]
+async fn test_admin_metrics() {
+    init_logging();
+    let echo_upstream = EchoServer::new().await;
+    let error_upstream = ErrorServer::new().await;
+    let admin_address = free_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&echo_upstream.address, &error_upstream.address],
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--strategy",
+            "round-robin",
+            "--active-health-check-interval",
+            "3600",
+            "--max-requests-per-minute",
+            "4",
+        ],
+    )
+    .await;
+
+    log::info!("Sending requests, alternating between the echo and error upstreams");
+    let client = reqwest::Client::new();
+    let mut status_codes = Vec::new();
+    for i in 0..5 {
+        let response = client
+            .get(&format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        status_codes.push(response.status().as_u16());
+    }
+    assert_eq!(status_codes, vec![200, 500, 200, 500, 429]);
+
+    let metrics = client
+        .get(&format!("http://{}/metrics", admin_address))
+        .send()
+        .await
+        .expect("Error sending request to the admin listener")
+        .text()
+        .await
+        .unwrap();
+    log::info!("Metrics:\n{}", metrics);
+    let metric_lines: Vec<&str> = metrics.lines().collect();
+    let expected_lines = [
+        format!(
+            "requests_total{{upstream=\"{}\",status_class=\"2xx\"}} 2",
+            echo_upstream.address
+        ),
+        format!(
+            "requests_total{{upstream=\"{}\",status_class=\"5xx\"}} 0",
+            echo_upstream.address
+        ),
+        format!(
+            "requests_total{{upstream=\"{}\",status_class=\"5xx\"}} 2",
+            error_upstream.address
+        ),
+        format!(
+            "upstream_failures_total{{upstream=\"{}\"}} 0",
+            error_upstream.address
+        ),
+        String::from("request_duration_seconds_count 4"),
+        String::from("request_duration_seconds_bucket{le=\"+Inf\"} 4"),
+        String::from("rate_limited_total 1"),
+        String::from("upstreams_healthy 2"),
+    ];
+    for expected in &expected_lines {
+        assert!(
+            metric_lines.contains(&expected.as_str()),
+            "missing {:?} in /metrics",
+            expected
+        );
+    }
+
+    log::info!("All done :)");
+}
+
+/// Upstreams added with POST /upstreams should start getting traffic, and ones removed with
+/// DELETE /upstreams/{address} should stop getting it
+#[tokio::test]
+async fn test_admin_add_and_remove_upstreams() {
+    let (mut upstreams, upstream_addresses) = start_upstreams(2).await;
+    let admin_address = free_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_addresses[0]],
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--strategy",
+            "round-robin",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let admin_url = format!("http://{}/upstreams", admin_address);
+
+    log::info!("Adding the second upstream at runtime");
+    let response = client
+        .post(&admin_url)
+        .body(upstream_addresses[1].clone())
+        .send()
+        .await
+        .expect("Error sending request to the admin listener");
+    assert_eq!(response.status().as_u16(), 201);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains(&upstream_addresses[1]));
+    let response = client
+        .post(&admin_url)
+        .body(upstream_addresses[1].clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 409);
+
+    for i in 0..10 {
+        balancebeam
+            .get(&format!("/both-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    log::info!("Removing the first upstream");
+    let response = client
+        .delete(&format!("{}/{}", admin_url, upstream_addresses[0]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(!response
+        .text()
+        .await
+        .unwrap()
+        .contains(&upstream_addresses[0]));
+    let response = client
+        .delete(&format!("{}/{}", admin_url, upstream_addresses[0]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+
+    for i in 0..10 {
+        balancebeam
+            .get(&format!("/second-only-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let second_count = upstreams.pop().unwrap().stop().await;
+    let first_count = upstreams.pop().unwrap().stop().await;
+    assert_eq!((first_count, second_count), (5, 15));
+
+    log::info!("All done :)");
+}
+
+/// Removing an upstream shouldn't cut off a request it is in the middle of answering
+#[tokio::test]
+async fn test_admin_remove_upstream_drains_in_flight_request() {
+    init_logging();
+    let slow_address = start_slow_upstream(Duration::from_secs(2)).await;
+    let admin_address = free_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&slow_address],
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    let address = balancebeam.address.clone();
+    let in_flight = tokio::spawn(async move {
+        reqwest::Client::new()
+            .get(&format!("http://{}/slow", address))
+            .send()
+            .await
+    });
+    delay_for(Duration::from_millis(500)).await;
+    log::info!("Removing the upstream while it is answering a request");
+    let response = reqwest::Client::new()
+        .delete(&format!(
+            "http://{}/upstreams/{}",
+            admin_address, slow_address
+        ))
+        .send()
+        .await
+        .expect("Error sending request to the admin listener");
+    assert_eq!(response.status().as_u16(), 200);
+
+    let response = in_flight
+        .await
+        .unwrap()
+        .expect("The in-flight request was cut off by the removal");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.text().await.unwrap(), "slow");
+
+    // With no upstreams left, new requests can't go anywhere
+    let response = reqwest::Client::new()
+        .get(&format!("http://{}/after-removal", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 503);
+    assert!(response.headers().contains_key("retry-after"));
+
+    log::info!("All done :)");
+}
+
+/// On SIGHUP, balancebeam should switch to the upstreams in its rewritten config file, and keep its
+/// old ones if the new file is invalid
+#[tokio::test]
+async fn test_sighup_reloads_upstreams() {
+    let (mut upstreams, upstream_addresses) = start_upstreams(2).await;
+    let config_path = std::env::temp_dir().join(format!(
+        "balancebeam-reload-test-{}.toml",
+        std::process::id()
+    ));
+    let write_config = |contents: String| {
+        std::fs::write(&config_path, contents).expect("Could not write config file");
+    };
+    let upstream_table = |address: &str| format!("[[upstreams]]\naddress = \"{}\"\n", address);
+    write_config(upstream_table(&upstream_addresses[0]));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        &[
+            "--config",
+            config_path.to_str().unwrap(),
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    for i in 0..5 {
+        balancebeam
+            .get(&format!("/before-reload-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    log::info!("Replacing the first upstream with the second one");
+    write_config(upstream_table(&upstream_addresses[1]));
+    balancebeam.reload();
+    delay_for(Duration::from_millis(500)).await;
+    for i in 0..5 {
+        balancebeam
+            .get(&format!("/after-reload-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    log::info!("Reloading a broken config file");
+    write_config(String::from("[[upstreams]]\naddress = 42\n"));
+    balancebeam.reload();
+    delay_for(Duration::from_millis(500)).await;
+    for i in 0..5 {
+        balancebeam
+            .get(&format!("/after-bad-reload-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    let _ = std::fs::remove_file(&config_path);
+
+    let second_count = upstreams.pop().unwrap().stop().await;
+    let first_count = upstreams.pop().unwrap().stop().await;
+    assert_eq!((first_count, second_count), (5, 10));
+
+    log::info!("All done :)");
+}
+
+/// Checks, going by the admin status, that a single upstream has answered all `count` requests so
+/// far, and returns its index.
+async fn only_upstream_used(admin_address: &str, count: u64) -> usize {
+    let url = format!("http://{}/status", admin_address);
+    let body = reqwest::get(&url).await.unwrap().text().await.unwrap();
+    let status: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let requests: Vec<u64> = status["upstreams"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|upstream| upstream["requests"].as_u64().unwrap())
+        .collect();
+    assert_eq!(requests.iter().sum::<u64>(), count, "{:?}", requests);
+    requests
+        .iter()
+        .position(|&n| n == count)
+        .unwrap_or_else(|| panic!("Requests were spread over upstreams: {:?}", requests))
+}
+
+/// Requests carrying a sticky session cookie should all go to the upstream it names, over any
+/// number of connections, until that upstream goes down
+#[tokio::test]
+async fn test_sticky_session_cookie() {
+    let (mut upstreams, upstream_addresses) = start_upstreams(3).await;
+    let admin_address = free_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &upstream_addresses
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>(),
+        &[
+            "--sticky-cookie",
+            "bb_session",
+            "--strategy",
+            "round-robin",
+            "--admin-bind",
+            &admin_address,
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+    // Each request gets a fresh client, and so a fresh connection
+    let get = |cookie: Option<String>| {
+        let url = format!("http://{}/", balancebeam.address);
+        async move {
+            let mut request = reqwest::Client::new().get(&url);
+            if let Some(cookie) = cookie {
+                request = request.header("Cookie", format!("theme=dark; bb_session={}", cookie));
+            }
+            let response = request
+                .send()
+                .await
+                .expect("Error sending request to balancebeam");
+            assert_eq!(response.status().as_u16(), 200);
+            response
+                .headers()
+                .get("set-cookie")
+                .map(|value| value.to_str().unwrap().to_string())
+        }
+    };
+    let session_from = |set_cookie: String| {
+        let (pair, attributes) = set_cookie.split_once(';').unwrap();
+        assert!(attributes.contains("Path=/"));
+        assert!(!set_cookie.contains("127.0.0.1"));
+        String::from(pair.strip_prefix("bb_session=").unwrap())
+    };
+    let session = session_from(get(None).await.expect("No sticky session cookie was set"));
+    for _ in 0..9 {
+        assert_eq!(get(Some(session.clone())).await, None);
+    }
+    let pinned = only_upstream_used(&admin_address, 10).await;
+
+    log::info!("Taking down the pinned upstream");
+    assert_eq!(upstreams.remove(pinned).stop().await, 10);
+    let new_session = session_from(
+        get(Some(session.clone()))
+            .await
+            .expect("The sticky session cookie was not re-issued"),
+    );
+    assert_ne!(new_session, session);
+    for _ in 0..5 {
+        assert_eq!(get(Some(new_session.clone())).await, None);
+    }
+    let counts: Vec<usize> = {
+        let mut counts = Vec::new();
+        for upstream in upstreams {
+            counts.push(upstream.stop().await);
+        }
+        counts
+    };
+    assert!(counts.contains(&6), "{:?}", counts);
+    assert_eq!(counts.iter().sum::<usize>(), 6);
+
+    log::info!("All done :)");
+}
+
+/// Under ip-hash, a client should keep reaching the same upstream, and move to another one only
+/// once that upstream is marked down
+#[tokio::test]
+async fn test_ip_hash_strategy() {
+    let (mut upstreams, upstream_addresses) = start_upstreams(3).await;
+    let admin_address = free_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &upstream_addresses
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>(),
+        &[
+            "--strategy",
+            "ip-hash",
+            "--admin-bind",
+            &admin_address,
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    for i in 0..10 {
+        balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    let chosen = only_upstream_used(&admin_address, 10).await;
+
+    log::info!("Taking down the client's upstream");
+    assert_eq!(upstreams.remove(chosen).stop().await, 10);
+    for i in 0..10 {
+        let response_text = balancebeam
+            .get(&format!("/after-failure-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("/after-failure-{}", i)));
+    }
+    let mut counts = Vec::new();
+    for upstream in upstreams {
+        counts.push(upstream.stop().await);
+    }
+    counts.sort_unstable();
+    assert_eq!(counts, vec![0, 10]);
+
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that answers every request with an empty 500 while `failing` is set, and an
+/// empty 200 otherwise, closing the connection each time. Returns its address and when it got each
+/// request so far.
+async fn start_flaky_upstream(failing: Arc<AtomicBool>) -> (String, Arc<Mutex<Vec<Instant>>>) {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let received = requests.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let failing = failing.clone();
+            let received = received.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buffer = [0_u8; 4096];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(bytes_read) => request.extend_from_slice(&buffer[..bytes_read]),
+                    }
+                }
+                received.lock().unwrap().push(Instant::now());
+                let status = if failing.load(Ordering::SeqCst) {
+                    "500 Internal Server Error"
+                } else {
+                    "200 OK"
+                };
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    (address, requests)
+}
+
+/// The state of each upstream's circuit, going by the admin status
+async fn circuit_states(admin_address: &str) -> Vec<String> {
+    let url = format!("http://{}/status", admin_address);
+    let body = reqwest::get(&url).await.unwrap().text().await.unwrap();
+    let status: serde_json::Value = serde_json::from_str(&body).unwrap();
+    status["upstreams"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|upstream| upstream["circuit"].as_str().unwrap().to_string())
+        .collect()
+}
+
+/// An upstream answering mostly with 500s should have its circuit opened, get no traffic while it
+/// cools down, and only be let back in once a trial request to it succeeds
+#[tokio::test]
+async fn test_circuit_breaker() {
+    let (mut upstreams, upstream_addresses) = start_upstreams(1).await;
+    let failing = Arc::new(AtomicBool::new(true));
+    let (flaky_address, flaky_requests) = start_flaky_upstream(failing.clone()).await;
+    let admin_address = free_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&flaky_address, &upstream_addresses[0]],
+        &[
+            "--strategy",
+            "round-robin",
+            "--circuit-breaker-window",
+            "4",
+            "--circuit-breaker-threshold",
+            "0.5",
+            "--circuit-breaker-cooldown",
+            "1",
+            "--admin-bind",
+            &admin_address,
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let send = |path: &'static str| {
+        let request = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .send();
+        async move {
+            request
+                .await
+                .expect("Error sending request to balancebeam")
+                .status()
+                .as_u16()
+        }
+    };
+
+    // Round-robin alternates, so the flaky upstream's four 500s fill its window
+    let mut statuses = Vec::new();
+    for _ in 0..8 {
+        statuses.push(send("/erroring").await);
+    }
+    assert_eq!(statuses.iter().filter(|&&status| status == 500).count(), 4);
+    assert_eq!(flaky_requests.lock().unwrap().len(), 4);
+    assert_eq!(circuit_states(&admin_address).await, vec!["open", "closed"]);
+
+    log::info!("Circuit is open; everything should go to the other upstream");
+    for _ in 0..6 {
+        assert_eq!(send("/open").await, 200);
+    }
+    assert_eq!(flaky_requests.lock().unwrap().len(), 4);
+
+    log::info!("After the cool-down, a failed trial should open the circuit again");
+    delay_for(Duration::from_millis(1100)).await;
+    let mut statuses = Vec::new();
+    for _ in 0..4 {
+        statuses.push(send("/trial").await);
+    }
+    assert_eq!(statuses.iter().filter(|&&status| status == 500).count(), 1);
+    assert_eq!(flaky_requests.lock().unwrap().len(), 5);
+    assert_eq!(circuit_states(&admin_address).await, vec!["open", "closed"]);
+
+    log::info!("Once the upstream recovers, a successful trial should close the circuit");
+    failing.store(false, Ordering::SeqCst);
+    delay_for(Duration::from_millis(1100)).await;
+    for _ in 0..8 {
+        assert_eq!(send("/recovered").await, 200);
+    }
+    assert_eq!(
+        circuit_states(&admin_address).await,
+        vec!["closed", "closed"]
+    );
+    // Back in the rotation: the trial, then every other request
+    let flaky_total = flaky_requests.lock().unwrap().len();
+    assert!(flaky_total >= 5 + 4, "{}", flaky_total);
+    assert_eq!(
+        upstreams.pop().unwrap().stop().await,
+        8 + 6 + 4 + 8 - flaky_total
+    );
+
+    log::info!("All done :)");
+}
+
+/// While an upstream is down, the active health checks should come further and further apart (up
+/// to --health-check-max-backoff), and back to every interval as soon as one passes
+#[tokio::test]
+async fn test_health_check_backoff() {
+    init_logging();
+    let failing = Arc::new(AtomicBool::new(true));
+    let (address, probes) = start_flaky_upstream(failing.clone()).await;
+    let _balancebeam = BalanceBeam::new_with_args(
+        &[&address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--health-check-failure-threshold",
+            "1",
+            "--health-check-max-backoff",
+            "4",
+        ],
+    )
+    .await;
+
+    let wait_for_probes = |count: usize| {
+        let probes = probes.clone();
+        async move {
+            let started = Instant::now();
+            while probes.lock().unwrap().len() < count {
+                assert!(
+                    started.elapsed() < Duration::from_secs(15),
+                    "Only {} health checks so far",
+                    probes.lock().unwrap().len()
+                );
+                delay_for(Duration::from_millis(50)).await;
+            }
+        }
+    };
+    log::info!("Waiting for the upstream to fail a few health checks");
+    wait_for_probes(3).await;
+    failing.store(false, Ordering::SeqCst);
+    log::info!("Upstream recovered; waiting for health checks to catch up");
+    wait_for_probes(6).await;
+
+    let probes = probes.lock().unwrap().clone();
+    let gaps: Vec<Duration> = probes.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    log::info!("Gaps between health checks: {:?}", gaps);
+    // Down after the first, so two seconds to the second and four to the third (give or take a
+    // fifth), then the four-second cap until one passes
+    assert!(gaps[0] > Duration::from_millis(1500), "{:?}", gaps);
+    assert!(gaps[1] > gaps[0], "{:?}", gaps);
+    assert!(gaps[2] < Duration::from_millis(5000), "{:?}", gaps);
+    assert!(gaps[3] < Duration::from_millis(1500), "{:?}", gaps);
+    assert!(gaps[4] < Duration::from_millis(1500), "{:?}", gaps);
+
+    log::info!("All done :)");
+}
+
+/// How many requests each upstream has answered, going by the admin status, and whether each is up
+async fn upstream_status(admin_address: &str) -> Vec<(u64, bool)> {
+    let url = format!("http://{}/status", admin_address);
+    let body = reqwest::get(&url).await.unwrap().text().await.unwrap();
+    let status: serde_json::Value = serde_json::from_str(&body).unwrap();
+    status["upstreams"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|upstream| {
+            (
+                upstream["requests"].as_u64().unwrap(),
+                upstream["alive"].as_bool().unwrap(),
+            )
+        })
+        .collect()
+}
+
+/// An upstream that was marked back up should get only a small share of traffic at first, working
+/// up to its full share over --slow-start
+#[tokio::test]
+async fn test_slow_start() {
+    let (mut upstreams, upstream_addresses) = start_upstreams(1).await;
+    let failing = Arc::new(AtomicBool::new(true));
+    let (flaky_address, _) = start_flaky_upstream(failing.clone()).await;
+    let admin_address = free_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_addresses[0], &flaky_address],
+        &[
+            "--slow-start",
+            "5",
+            "--active-health-check-interval",
+            "1",
+            "--health-check-failure-threshold",
+            "1",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+
+    let wait_until_alive = |alive: bool| {
+        let admin_address = admin_address.clone();
+        async move {
+            let started = Instant::now();
+            while upstream_status(&admin_address).await[1].1 != alive {
+                assert!(started.elapsed() < Duration::from_secs(10));
+                delay_for(Duration::from_millis(20)).await;
+            }
+        }
+    };
+    log::info!("Waiting for the flaky upstream to be marked down");
+    wait_until_alive(false).await;
+    failing.store(false, Ordering::SeqCst);
+    log::info!("Waiting for the flaky upstream to be marked back up");
+    wait_until_alive(true).await;
+    let recovered = Instant::now();
+
+    // Share of `n` requests sent now that go to the flaky upstream. They go out twenty at a time
+    // from one client, so that sending them takes well under a second.
+    let client = reqwest::Client::new();
+    let share = |n: u64| {
+        let admin_address = admin_address.clone();
+        let url = format!("http://{}/warming-up", balancebeam.address);
+        let client = client.clone();
+        async move {
+            let before = upstream_status(&admin_address).await[1].0;
+            for _ in 0..n / 20 {
+                let batch: Vec<_> = (0..20)
+                    .map(|_| tokio::spawn(client.get(&url).send()))
+                    .collect();
+                for request in batch {
+                    request
+                        .await
+                        .unwrap()
+                        .expect("Error sending request to balancebeam");
+                }
+            }
+            (upstream_status(&admin_address).await[1].0 - before) as f64 / n as f64
+        }
+    };
+    let warming_up = share(100).await;
+    log::info!(
+        "Share of traffic {:?} after recovery: {}",
+        recovered.elapsed(),
+        warming_up
+    );
+    assert!(recovered.elapsed() < Duration::from_secs(2));
+    delay_for(Duration::from_secs(5).saturating_sub(recovered.elapsed())).await;
+    let warmed_up = share(200).await;
+    log::info!("Share of traffic once warmed up: {}", warmed_up);
+    assert!(warming_up < 0.25, "{} then {}", warming_up, warmed_up);
+    assert!(warmed_up > 0.35, "{} then {}", warming_up, warmed_up);
+    upstreams.pop().unwrap().stop().await;
+
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that accepts connections and hangs up on them straight away, so that a TLS
+/// handshake with it fails. Returns its port and how many connections it has accepted.
+async fn start_slam_door_upstream() -> (u16, Arc<AtomicUsize>) {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            drop(stream);
+        }
+    });
+    (port, connections)
+}
+
+/// Upstreams that keep failing to connect without being marked down (here thanks to a huge failure
+/// threshold) should each be tried once per request before the client gets a 502, rather than
+/// over and over
+#[tokio::test]
+async fn test_connect_attempts_are_bounded() {
+    init_logging();
+    let mut addresses = Vec::new();
+    let mut connections = Vec::new();
+    for _ in 0..3 {
+        let (port, count) = start_slam_door_upstream().await;
+        addresses.push(format!("https://localhost:{}", port));
+        connections.push(count);
+    }
+    let addresses: Vec<&str> = addresses.iter().map(String::as_str).collect();
+
+    // Sends a request through a new balancebeam, returning how many times it connected to each
+    // upstream for it
+    let attempts_per_upstream = |extra_args: &'static [&'static str]| {
+        let addresses = addresses.clone();
+        let connections = connections.clone();
+        async move {
+            let mut args = vec![
+                "--insecure-upstream",
+                "--health-check-failure-threshold",
+                "1000000",
+                "--active-health-check-interval",
+                "3600",
+            ];
+            args.extend_from_slice(extra_args);
+            let balancebeam = BalanceBeam::new_with_args(&addresses, &args).await;
+            let before: Vec<usize> = connections
+                .iter()
+                .map(|count| count.load(Ordering::SeqCst))
+                .collect();
+            let response = timeout(
+                Duration::from_secs(5),
+                reqwest::get(&format!("http://{}/doomed", balancebeam.address)),
+            )
+            .await
+            .expect("balancebeam kept trying to connect")
+            .expect("Error sending request to balancebeam");
+            assert_eq!(response.status().as_u16(), 502);
+            let attempts: Vec<usize> = connections
+                .iter()
+                .zip(before)
+                .map(|(count, before)| count.load(Ordering::SeqCst) - before)
+                .collect();
+            log::info!("Connection attempts per upstream: {:?}", attempts);
+            attempts
+        }
+    };
+
+    assert_eq!(attempts_per_upstream(&[]).await, vec![1, 1, 1]);
+    let attempts = attempts_per_upstream(&["--max-connect-attempts", "2"]).await;
+    assert_eq!(attempts.iter().sum::<usize>(), 2, "{:?}", attempts);
+    assert!(attempts.iter().all(|&count| count <= 1), "{:?}", attempts);
+
+    log::info!("All done :)");
+}
+
+/// Sends a GET for `path` through balancebeam with the given Host header, returning the status
+async fn get_with_host(client: &reqwest::Client, balancebeam: &BalanceBeam, host: &str) -> u16 {
+    client
+        .get(&format!("http://{}/routed", balancebeam.address))
+        .header("Host", host)
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// Requests go to the upstreams of the route matching their Host, and the rest to the --upstream
+/// ones (or nowhere, with --reject-unknown-hosts)
+#[tokio::test]
+async fn test_host_routing() {
+    let (upstreams, addresses) = start_upstreams(3).await;
+    let api_route = format!("api.test={}", addresses[1]);
+    let www_route = format!("*.www.test={}", addresses[2]);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&addresses[0]],
+        &["--route", &api_route, "--route", &www_route],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    for _ in 0..3 {
+        assert_eq!(
+            get_with_host(&client, &balancebeam, "API.test:80").await,
+            200
+        );
+    }
+    for _ in 0..2 {
+        assert_eq!(
+            get_with_host(&client, &balancebeam, "a.www.test").await,
+            200
+        );
+    }
+    assert_eq!(
+        get_with_host(&client, &balancebeam, "other.test").await,
+        200
+    );
+
+    let strict = BalanceBeam::new_with_args(
+        &[&addresses[0]],
+        &["--route", &api_route, "--reject-unknown-hosts"],
+    )
+    .await;
+    assert_eq!(get_with_host(&client, &strict, "api.test").await, 200);
+    assert_eq!(get_with_host(&client, &strict, "other.test").await, 421);
+
+    let mut counts = Vec::new();
+    for upstream in upstreams {
+        counts.push(upstream.stop().await);
+    }
+    assert_eq!(counts, vec![1, 4, 2]);
+
+    log::info!("All done :)");
+}
+
+/// Requests an upstream has no room for under its request limit go to the others, and only once
+/// every upstream is full do clients get a 503
+#[tokio::test]
+async fn test_max_requests_per_upstream() {
+    init_logging();
+    let (upstreams, addresses) = start_upstreams(1).await;
+    let slow_address = start_slow_upstream(Duration::from_secs(2)).await;
+    // The slow upstream may have 2 requests at once, and the fast one (overriding the flag) as
+    // many as it likes
+    let config_path = std::env::temp_dir().join(format!(
+        "balancebeam-max-requests-test-{}.toml",
+        std::process::id()
+    ));
+    std::fs::write(
+        &config_path,
+        format!(
+            "[[upstreams]]\naddress = \"{}\"\nmax_requests = 2\n\n\
+             [[upstreams]]\naddress = \"{}\"\nmax_requests = 0\n",
+            slow_address, addresses[0]
+        ),
+    )
+    .expect("Could not write config file");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        &[
+            "--config",
+            config_path.to_str().unwrap(),
+            "--strategy",
+            "round-robin",
+            "--max-requests-per-upstream",
+            "1",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    // Round-robin alone would send half of these to the slow upstream
+    let requests: Vec<_> = (0..10)
+        .map(|n| {
+            let address = balancebeam.address.clone();
+            tokio::spawn(async move {
+                // Stagger them a little, so that the slow upstream's requests are in flight
+                // before the rest are picked for
+                delay_for(Duration::from_millis(50 * n)).await;
+                let response = reqwest::Client::new()
+                    .get(&format!("http://{}/request-{}", address, n))
+                    .send()
+                    .await
+                    .expect("Error sending request to balancebeam");
+                (response.status().as_u16(), response.text().await.unwrap())
+            })
+        })
+        .collect();
+    let mut slow = 0;
+    for request in requests {
+        let (status, body) = request.await.unwrap();
+        assert_eq!(status, 200);
+        if body == "slow" {
+            slow += 1;
+        }
+    }
+    assert_eq!(slow, 2);
+    assert_eq!(upstreams.into_iter().next().unwrap().stop().await, 8);
+    let _ = std::fs::remove_file(&config_path);
+
+    // With nowhere else to go, requests beyond the slow upstream's limit are turned away
+    let saturated = BalanceBeam::new_with_args(
+        &[&slow_address],
+        &[
+            "--max-requests-per-upstream",
+            "1",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+    let address = saturated.address.clone();
+    let in_flight = tokio::spawn(async move {
+        reqwest::Client::new()
+            .get(&format!("http://{}/slow", address))
+            .send()
+            .await
+            .unwrap()
+            .status()
+            .as_u16()
+    });
+    delay_for(Duration::from_millis(500)).await;
+    let response = reqwest::Client::new()
+        .get(&format!("http://{}/turned-away", saturated.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 503);
+    assert_eq!(response.headers()["retry-after"], "1");
+    assert_eq!(in_flight.await.unwrap(), 200);
+    // Its slot was given back
+    let response = reqwest::Client::new()
+        .get(&format!("http://{}/after", saturated.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that answers each request with a 200 and then hangs up, keeping every
+/// request it got, body and all. Returns its address and the requests.
+async fn start_recording_upstream() -> (String, Arc<Mutex<Vec<String>>>) {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let recorded = requests.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let recorded = recorded.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buffer = [0_u8; 1024];
+                let complete = |request: &[u8]| {
+                    let text = String::from_utf8_lossy(request).to_lowercase();
+                    let head_len = match text.find("\r\n\r\n") {
+                        Some(idx) => idx + 4,
+                        None => return false,
+                    };
+                    let body_len = text
+                        .lines()
+                        .find_map(|line| line.strip_prefix("content-length: "))
+                        .map_or(0, |len| len.trim().parse::<usize>().unwrap());
+                    request.len() >= head_len + body_len
+                };
+                while !complete(&request) {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(bytes_read) => request.extend_from_slice(&buffer[..bytes_read]),
+                    }
+                }
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push(String::from_utf8(request).unwrap());
+                let _ = stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+                    )
+                    .await;
+            });
+        }
+    });
+    (address, requests)
+}
+
+/// Active health checks should be sent with the method, Host and body they're configured with,
+/// and ask the upstream to close the connection after
+#[tokio::test]
+async fn test_configured_health_check_request() {
+    init_logging();
+    let (address, requests) = start_recording_upstream().await;
+    let _balancebeam = BalanceBeam::new_with_args(
+        &[&address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--active-health-check-path",
+            "/health/deep",
+            "--health-check-method",
+            "POST",
+            "--health-check-host",
+            "health.internal",
+            "--health-check-body",
+            "{\"deep\": true}",
+        ],
+    )
+    .await;
+    delay_for(Duration::from_millis(2500)).await;
+
+    let requests = requests.lock().unwrap().clone();
+    log::info!("Upstream got {:?}", requests);
+    assert!(!requests.is_empty(), "No health checks arrived");
+    for request in &requests {
+        let lowercase = request.to_lowercase();
+        assert!(
+            request.starts_with("POST /health/deep HTTP/1.1\r\n"),
+            "{}",
+            request
+        );
+        assert!(
+            lowercase.contains("\r\nhost: health.internal\r\n"),
+            "{}",
+            request
+        );
+        assert!(
+            lowercase.contains("\r\nconnection: close\r\n"),
+            "{}",
+            request
+        );
+        assert!(
+            lowercase.contains("\r\ncontent-length: 14\r\n"),
+            "{}",
+            request
+        );
+        assert!(request.ends_with("\r\n\r\n{\"deep\": true}"), "{}", request);
+    }
+
+    log::info!("All done :)");
+}
+
+/// With --startup-health-check, an upstream that is down at boot should start out marked down, so
+/// that no request is wasted finding out. If none is up, balancebeam should refuse to start, unless
+/// --start-degraded.
+#[tokio::test]
+async fn test_startup_health_check() {
+    let (mut upstreams, upstream_addresses) = start_upstreams(1).await;
+    let dead = free_local_address();
+    let admin_address = free_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_addresses[0], &dead],
+        &[
+            "--startup-health-check",
+            "--active-health-check-interval",
+            "3600",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+
+    for i in 0..10 {
+        let response_text = balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET /request-{} HTTP/1.1", i)));
+    }
+    let status = reqwest::get(&format!("http://{}/status", admin_address))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    log::info!("Status: {}", status);
+    // Never tried, so never failed
+    assert!(status.contains(&format!(
+        "{{\"address\":\"{}\",\"alive\":false,\"circuit\":\"closed\",\"requests\":0,\"failures\":0}}",
+        dead
+    )));
+    // Every request, plus the startup health check
+    assert_eq!(upstreams.pop().unwrap().stop().await, 11);
+
+    log::info!("Starting a balancebeam whose only upstream is down");
+    let mut refused =
+        BalanceBeam::new_with_args(&[&free_local_address()], &["--startup-health-check"]).await;
+    let status = timeout(Duration::from_secs(5), refused.wait())
+        .await
+        .expect("balancebeam started without a working upstream");
+    assert!(!status.success());
+
+    let degraded = BalanceBeam::new_with_args(
+        &[&free_local_address()],
+        &["--startup-health-check", "--start-degraded"],
+    )
+    .await;
+    let response = reqwest::get(&format!("http://{}/", degraded.address))
+        .await
+        .expect("balancebeam didn't start degraded");
+    assert!(response.status().is_server_error());
+
+    log::info!("All done :)");
+}
+
+/// With --mirror-upstream, the mirror should get a copy of each request, body and all, marked as
+/// mirrored, while the client gets the primary's response.
+#[tokio::test]
+async fn test_mirror_gets_copy() {
+    init_logging();
+    let primary = EchoServer::new().await;
+    let (mirror_address, mirrored) = start_recording_upstream().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&primary.address], &["--mirror-upstream", &mirror_address])
+            .await;
+
+    let response = balancebeam
+        .post("/orders", "two widgets, please")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(
+        response.starts_with("POST /orders HTTP/1.1"),
+        "{}",
+        response
+    );
+    assert!(response.ends_with("two widgets, please"), "{}", response);
+    assert!(
+        !response.to_lowercase().contains("x-mirrored"),
+        "The primary's copy was marked as mirrored:\n{}",
+        response
+    );
+
+    let started = Instant::now();
+    while mirrored.lock().unwrap().is_empty() && started.elapsed() < Duration::from_secs(2) {
+        delay_for(Duration::from_millis(20)).await;
+    }
+    let mirrored = mirrored.lock().unwrap().clone();
+    log::info!("Mirror got {:?}", mirrored);
+    assert_eq!(mirrored.len(), 1, "{:?}", mirrored);
+    let copy = &mirrored[0];
+    assert!(copy.starts_with("POST /orders HTTP/1.1\r\n"), "{}", copy);
+    assert!(
+        copy.to_lowercase().contains("\r\nx-mirrored: true\r\n"),
+        "{}",
+        copy
+    );
+    assert!(copy.ends_with("\r\n\r\ntwo widgets, please"), "{}", copy);
+
+    log::info!("All done :)");
+}
+
+/// A mirror that hangs, or isn't there at all, shouldn't hold up or fail the requests it gets
+/// copies of.
+#[tokio::test]
+async fn test_dead_mirror_does_not_slow_requests() {
+    init_logging();
+    let primary = EchoServer::new().await;
+    let hanging_mirror = start_slow_upstream(Duration::from_secs(30)).await;
+    let missing_mirror = free_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&primary.address],
+        &[
+            "--mirror-upstream",
+            &hanging_mirror,
+            "--mirror-upstream",
+            &missing_mirror,
+        ],
+    )
+    .await;
+
+    let started = Instant::now();
+    for i in 0..6 {
+        let path = format!("/request-{}", i);
+        let response = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response.starts_with(&format!("GET {} HTTP/1.1", path)));
+    }
+    let elapsed = started.elapsed();
+    assert!(
+        elapsed < Duration::from_secs(2),
+        "Requests took {:?} with dead mirrors",
+        elapsed
+    );
+
+    log::info!("All done :)");
+}
+
+/// A response that isn't HTTP should get the client of a request that can't be replayed a 502, and
+/// its connection shouldn't be used again, so the next request gets a fresh one and a proper answer
+#[tokio::test]
+async fn test_malformed_upstream_response() {
+    init_logging();
+    let upstream = MockUpstream::new(Reply::ok("proper").delayed(50)).await;
+    upstream.script_connection(vec![Reply::Malformed]);
+    let balancebeam = BalanceBeam::in_process(
+        &[&upstream.address],
+        &["--active-health-check-interval", "3600"],
+    )
+    .await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post(&format!("http://{}/garbled", balancebeam.address))
+        .body("once only")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+    let response = balancebeam
+        .get("/proper")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response, "proper");
+
+    let requests = upstream.requests_for("/");
+    let paths: Vec<&str> = requests
+        .iter()
+        .map(|request| request.path.as_str())
+        .collect();
+    assert_eq!(paths, vec!["/garbled", "/proper"]);
+    assert_ne!(requests[0].connection, requests[1].connection);
+    log::info!("All done :)");
+}
+
+/// An upstream with a host_override in the config file should get requests, and health checks,
+/// with that Host, and the client's in X-Forwarded-Host; one without should get the client's Host
+/// untouched
+#[tokio::test]
+async fn test_host_override() {
+    init_logging();
+    let overridden = MockUpstream::new(Reply::ok("overridden")).await;
+    let plain = MockUpstream::new(Reply::ok("plain")).await;
+    let config_path = std::env::temp_dir().join(format!(
+        "balancebeam-host-override-test-{}.toml",
+        std::process::id()
+    ));
+    std::fs::write(
+        &config_path,
+        format!(
+            "[[upstreams]]\naddress = \"{}\"\nhost_override = \"app.internal\"\n\n\
+             [[upstreams]]\naddress = \"{}\"\n",
+            overridden.address, plain.address
+        ),
+    )
+    .expect("Could not write config file");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        &[
+            "--config",
+            config_path.to_str().unwrap(),
+            "--strategy",
+            "round-robin",
+            "--startup-health-check",
+            "--active-health-check-path",
+            "/healthz",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+    let _ = std::fs::remove_file(&config_path);
+
+    let mut bodies = Vec::new();
+    for i in 0..4 {
+        bodies.push(
+            balancebeam
+                .get(&format!("/request-{}", i))
+                .await
+                .expect("Error sending request to balancebeam"),
+        );
+    }
+    bodies.sort();
+    assert_eq!(bodies, vec!["overridden", "overridden", "plain", "plain"]);
+
+    let client_host = balancebeam.address.clone();
+    for request in overridden.requests_for("/request-") {
+        assert_eq!(request.header("host"), Some("app.internal"));
+        assert_eq!(
+            request.header("x-forwarded-host"),
+            Some(client_host.as_str())
+        );
+    }
+    for request in plain.requests_for("/request-") {
+        assert_eq!(request.header("host"), Some(client_host.as_str()));
+        assert_eq!(request.header("x-forwarded-host"), None);
+    }
+    let probe = &overridden.requests_for("/healthz")[0];
+    assert_eq!(probe.header("host"), Some("app.internal"));
+    let probe = &plain.requests_for("/healthz")[0];
+    assert_eq!(probe.header("host"), Some(plain.address.as_str()));
+    log::info!("All done :)");
+}
+
+/// --self-health-path should answer 200 on both listeners while any upstream is up, without going
+/// to one or counting against the rate limit, and 503 once a health check has found them all down
+#[tokio::test]
+async fn test_self_health_endpoint() {
+    init_logging();
+    let upstreams = vec![
+        MockUpstream::new(Reply::ok("first")).await,
+        MockUpstream::new(Reply::ok("second")).await,
+    ];
+    let admin_address = free_local_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstreams[0].address, &upstreams[1].address],
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--self-health-on-main",
+            "--max-requests-per-minute",
+            "2",
+            "--active-health-check-interval",
+            "1",
+            "--health-check-failure-threshold",
+            "1",
+        ],
+    )
+    .await;
+    let get = |url: String| async move {
+        let response = reqwest::get(&url)
+            .await
+            .expect("Error sending health check to balancebeam");
+        let status = response.status().as_u16();
+        (status, response.text().await.unwrap())
+    };
+    let main_url = format!("http://{}/healthz", balancebeam.address);
+    let admin_url = format!("http://{}/healthz", admin_address);
+
+    // Well past the rate limit, and none of it to an upstream
+    for _ in 0..5 {
+        assert_eq!(
+            get(main_url.clone()).await,
+            (200, String::from("{\"healthy\":2,\"upstreams\":2}"))
+        );
+    }
+    assert_eq!(get(admin_url.clone()).await.0, 200);
+    for upstream in &upstreams {
+        assert!(upstream.requests_for("/healthz").is_empty());
+    }
+    // Other paths are still rate limited
+    balancebeam.get("/a").await.unwrap();
+    balancebeam.get("/b").await.unwrap();
+    assert!(balancebeam
+        .get("/c")
+        .await
+        .unwrap()
+        .contains("Too Many Requests"));
+
+    log::info!("Killing all the upstreams");
+    for upstream in upstreams {
+        Box::new(upstream).stop().await;
+    }
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while get(main_url.clone()).await.0 == 200 {
+        assert!(
+            Instant::now() < deadline,
+            "Still healthy with every upstream dead"
+        );
+        delay_for(Duration::from_millis(200)).await;
+    }
+    assert_eq!(
+        get(main_url).await,
+        (503, String::from("{\"healthy\":0,\"upstreams\":2}"))
+    );
+    assert_eq!(get(admin_url).await.0, 503);
+    log::info!("All done :)");
+}
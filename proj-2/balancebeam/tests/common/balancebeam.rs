@@ -1,13 +1,21 @@
-use rand::Rng;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::TcpStream;
 use tokio::process::{Child, Command};
+use tokio::sync::oneshot;
 use tokio::time::delay_for;
 
 pub struct BalanceBeam {
+    /// The balancebeam process, or None if it runs inside the test process (see in_process)
     #[allow(dead_code)]
-    child: Child, // process is killed when dropped (Command::kill_on_drop)
+    child: Option<Child>, // process is killed when dropped (Command::kill_on_drop)
+    /// Stops an in-process balancebeam when dropped
+    #[allow(dead_code)]
+    shutdown: Option<oneshot::Sender<()>>,
     pub address: String,
+    /// Every line balancebeam has written to stdout so far
+    stdout_lines: Arc<Mutex<Vec<String>>>,
 }
 
 impl BalanceBeam {
@@ -19,33 +27,66 @@ impl BalanceBeam {
         path
     }
 
+    /// The command line for balancebeam listening on `address`, without the program name.
+    fn args(address: &str, upstreams: &[&str], extra_args: &[&str]) -> Vec<String> {
+        let mut args = vec![String::from("--bind"), address.to_string()];
+        for upstream in upstreams {
+            args.push(String::from("--upstream"));
+            args.push(upstream.to_string());
+        }
+        // Tests count (and script) every request their upstreams see, so the startup health check
+        // that comes with setting a health check interval is left to those that ask for it
+        if !extra_args.contains(&"--startup-health-check") {
+            args.push(String::from("--no-startup-health-check"));
+        }
+        args.extend(extra_args.iter().map(|arg| arg.to_string()));
+        args
+    }
+
     pub async fn new(
         upstreams: &[&str],
         active_health_check_interval: Option<usize>,
         max_requests_per_minute: Option<usize>,
     ) -> BalanceBeam {
-        let mut rng = rand::thread_rng();
-        let address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
-        let mut cmd = Command::new(BalanceBeam::target_bin_path());
-        cmd.arg("--bind").arg(&address);
-        for upstream in upstreams {
-            cmd.arg("--upstream").arg(upstream);
-        }
+        let mut extra_args = Vec::new();
         if let Some(active_health_check_interval) = active_health_check_interval {
-            cmd.arg("--active-health-check-interval")
-                .arg(active_health_check_interval.to_string());
+            extra_args.push(String::from("--active-health-check-interval"));
+            extra_args.push(active_health_check_interval.to_string());
         }
         if let Some(max_requests_per_minute) = max_requests_per_minute {
-            cmd.arg("--max-requests-per-minute")
-                .arg(max_requests_per_minute.to_string());
+            extra_args.push(String::from("--max-requests-per-minute"));
+            extra_args.push(max_requests_per_minute.to_string());
         }
+        let extra_args: Vec<&str> = extra_args.iter().map(|arg| arg.as_str()).collect();
+        BalanceBeam::new_with_args(upstreams, &extra_args).await
+    }
+
+    /// Starts balancebeam with the given upstreams, passing `extra_args` through verbatim (e.g.
+    /// `&["--strategy", "round-robin"]`).
+    pub async fn new_with_args(upstreams: &[&str], extra_args: &[&str]) -> BalanceBeam {
+        BalanceBeam::new_with_bind(&crate::common::free_local_address(), upstreams, extra_args)
+            .await
+    }
+
+    /// Like new_with_args, but listening on `address`, for tests that need to know it beforehand.
+    pub async fn new_with_bind(
+        address: &str,
+        upstreams: &[&str],
+        extra_args: &[&str],
+    ) -> BalanceBeam {
+        let address = address.to_string();
+        let mut cmd = Command::new(BalanceBeam::target_bin_path());
+        cmd.args(BalanceBeam::args(&address, upstreams, extra_args));
         cmd.kill_on_drop(true);
         cmd.stdout(std::process::Stdio::piped());
         cmd.stderr(std::process::Stdio::piped());
-        let mut child = cmd.spawn().expect(&format!(
-            "Could not execute balancebeam binary {}",
-            BalanceBeam::target_bin_path().to_str().unwrap()
-        ));
+        let mut child = cmd.spawn().unwrap_or_else(|err| {
+            panic!(
+                "Could not execute balancebeam binary {}: {}",
+                BalanceBeam::target_bin_path().to_str().unwrap(),
+                err
+            )
+        });
 
         // Print output from the child. We want to intercept and log this output (instead of letting
         // the child inherit stderr and print directly to the terminal) so that the output can be
@@ -54,6 +95,8 @@ impl BalanceBeam {
             .stdout
             .take()
             .expect("Child process somehow missing stdout pipe!");
+        let stdout_lines = Arc::new(Mutex::new(Vec::new()));
+        let captured_lines = stdout_lines.clone();
         tokio::spawn(async move {
             let mut stdout_reader = BufReader::new(stdout).lines();
             while let Some(line) = stdout_reader
@@ -62,6 +105,7 @@ impl BalanceBeam {
                 .expect("I/O error reading from child stdout")
             {
                 println!("Balancebeam output: {}", line);
+                captured_lines.lock().unwrap().push(line);
             }
         });
         let stderr = child
@@ -81,7 +125,92 @@ impl BalanceBeam {
 
         // Hack: wait for executable to start running
         delay_for(Duration::from_secs(1)).await;
-        BalanceBeam { child, address }
+        BalanceBeam {
+            child: Some(child),
+            shutdown: None,
+            address,
+            stdout_lines,
+        }
+    }
+
+    /// Like new_with_args, but runs balancebeam on the test's own runtime, with CmdOptions parsed
+    /// from the same arguments, instead of starting the binary. It shuts down when dropped. Its
+    /// logs go to the test's logger, so stdout_lines stays empty, and there is no process to
+    /// signal or wait for.
+    #[allow(dead_code)]
+    pub async fn in_process(upstreams: &[&str], extra_args: &[&str]) -> BalanceBeam {
+        let address = crate::common::free_local_address();
+        let args = BalanceBeam::args(&address, upstreams, extra_args);
+        let (options, matches) = balancebeam::CmdOptions::parse_from_args(
+            std::iter::once(String::from("balancebeam")).chain(args),
+        )
+        .unwrap_or_else(|err| panic!("Invalid balancebeam arguments: {}", err));
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let shutdown = async {
+                let _ = shutdown_rx.await;
+            };
+            if let Err(err) = balancebeam::run(options, matches, shutdown).await {
+                log::error!("balancebeam stopped: {}", err);
+            }
+        });
+
+        // Wait for it to start listening
+        let started = Instant::now();
+        while TcpStream::connect(&address).await.is_err() {
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "balancebeam did not start listening on {}",
+                address
+            );
+            delay_for(Duration::from_millis(10)).await;
+        }
+        BalanceBeam {
+            child: None,
+            shutdown: Some(shutdown_tx),
+            address,
+            stdout_lines: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    fn child(&self) -> &Child {
+        self.child
+            .as_ref()
+            .expect("balancebeam runs in the test process, not as a child process")
+    }
+
+    fn send_signal(&self, signal: nix::sys::signal::Signal) {
+        let pid = nix::unistd::Pid::from_raw(self.child().id() as i32);
+        nix::sys::signal::kill(pid, signal)
+            .unwrap_or_else(|err| panic!("Could not send {:?} to balancebeam: {}", signal, err));
+    }
+
+    /// Sends balancebeam a SIGTERM, asking it to shut down gracefully.
+    #[allow(dead_code)]
+    pub fn terminate(&self) {
+        self.send_signal(nix::sys::signal::Signal::SIGTERM);
+    }
+
+    /// Sends balancebeam a SIGHUP, asking it to reload its config file.
+    #[allow(dead_code)]
+    pub fn reload(&self) {
+        self.send_signal(nix::sys::signal::Signal::SIGHUP);
+    }
+
+    /// Waits for the balancebeam process to exit.
+    #[allow(dead_code)]
+    pub async fn wait(&mut self) -> std::process::ExitStatus {
+        self.child
+            .as_mut()
+            .expect("balancebeam runs in the test process, not as a child process")
+            .await
+            .expect("Could not wait for balancebeam to exit")
+    }
+
+    /// The lines balancebeam has written to stdout so far (e.g. JSON access log entries).
+    #[allow(dead_code)]
+    pub fn stdout_lines(&self) -> Vec<String> {
+        self.stdout_lines.lock().unwrap().clone()
     }
 
     #[allow(dead_code)]
@@ -2,7 +2,6 @@ use crate::common::server::Server;
 use async_trait::async_trait;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response};
-use rand::Rng;
 use std::sync::{atomic, Arc};
 use tokio::sync::oneshot;
 
@@ -41,12 +40,20 @@ pub struct EchoServer {
 
 impl EchoServer {
     pub async fn new() -> EchoServer {
-        let mut rng = rand::thread_rng();
-        EchoServer::new_at_address(format!("127.0.0.1:{}", rng.gen_range(1024, 65535))).await
+        // Let the OS pick the port, and keep the listener so nothing can take it in the meantime
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        EchoServer::from_listener(listener)
     }
 
     pub async fn new_at_address(bind_addr_string: String) -> EchoServer {
-        let bind_addr = bind_addr_string.parse().unwrap();
+        let listener = std::net::TcpListener::bind(&bind_addr_string)
+            .unwrap_or_else(|err| panic!("error binding to {}: {}", bind_addr_string, err));
+        EchoServer::from_listener(listener)
+    }
+
+    fn from_listener(listener: std::net::TcpListener) -> EchoServer {
+        let bind_addr_string = listener.local_addr().unwrap().to_string();
+        listener.set_nonblocking(true).unwrap();
         // Create a one-shot channel that can be used to tell the server to shut down
         let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
 
@@ -65,7 +72,8 @@ impl EchoServer {
                     }))
                 }
             });
-            let server = hyper::Server::bind(&bind_addr)
+            let server = hyper::Server::from_tcp(listener)
+                .unwrap()
                 .serve(service)
                 .with_graceful_shutdown(async {
                     shutdown_rx.await.ok();
@@ -0,0 +1,317 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::time::delay_for;
+
+/// What a MockUpstream does with a request it has read
+#[derive(Clone, Debug)]
+pub enum Reply {
+    /// Answers with this status, extra headers and body, framed by a Content-Length
+    Respond {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: String,
+    },
+    /// Waits this long, then does the rest
+    Delay(Duration, Box<Reply>),
+    /// Hangs up without answering, like a server crashing mid-request
+    Close,
+    /// Answers with something that isn't HTTP at all
+    Malformed,
+}
+
+impl Reply {
+    /// A 200 with `body`
+    pub fn ok(body: &str) -> Reply {
+        Reply::status(200, body)
+    }
+
+    /// A response with `status` and `body`
+    pub fn status(status: u16, body: &str) -> Reply {
+        Reply::Respond {
+            status,
+            headers: Vec::new(),
+            body: body.to_string(),
+        }
+    }
+
+    /// The same response with an extra header
+    pub fn header(self, name: &str, value: &str) -> Reply {
+        match self {
+            Reply::Respond {
+                status,
+                mut headers,
+                body,
+            } => {
+                headers.push((name.to_string(), value.to_string()));
+                Reply::Respond {
+                    status,
+                    headers,
+                    body,
+                }
+            }
+            Reply::Delay(delay, reply) => Reply::Delay(delay, Box::new(reply.header(name, value))),
+            other => other,
+        }
+    }
+
+    /// The same reply, `millis` milliseconds later
+    pub fn delayed(self, millis: u64) -> Reply {
+        Reply::Delay(Duration::from_millis(millis), Box::new(self))
+    }
+}
+
+/// A request a MockUpstream got
+#[derive(Clone, Debug)]
+pub struct ReceivedRequest {
+    pub method: String,
+    pub path: String,
+    /// Names lowercased, in the order they came
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Which of the upstream's connections it came on, counting from 0
+    pub connection: usize,
+}
+
+impl ReceivedRequest {
+    /// The value of the first header named `name` (lowercase)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header_name, _)| header_name == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug)]
+struct MockState {
+    /// What requests get once their connection's script has run out
+    reply: Mutex<Reply>,
+    /// Replies for the requests on each of the next connections in turn
+    scripts: Mutex<VecDeque<Vec<Reply>>>,
+    requests: Mutex<Vec<ReceivedRequest>>,
+    connections: AtomicUsize,
+}
+
+/// An upstream whose behaviour each test scripts: what it answers (or whether it answers at all)
+/// can be set for all requests and for those on particular connections, and it records every
+/// request it gets, health checks included, headers and all.
+pub struct MockUpstream {
+    pub address: String,
+    state: Arc<MockState>,
+    stop_sender: watch::Sender<bool>,
+    accept_task: tokio::task::JoinHandle<()>,
+}
+
+impl MockUpstream {
+    /// Starts one answering every request with `reply`
+    pub async fn new(reply: Reply) -> MockUpstream {
+        // Let the OS pick the port, and keep the listener so nothing can take it in the meantime
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        MockUpstream::from_listener(listener, reply)
+    }
+
+    /// Like new, at `address`, e.g. to bring back one that was stopped
+    pub async fn new_at_address(address: String, reply: Reply) -> MockUpstream {
+        let listener = std::net::TcpListener::bind(&address)
+            .unwrap_or_else(|err| panic!("error binding to {}: {}", address, err));
+        MockUpstream::from_listener(listener, reply)
+    }
+
+    fn from_listener(listener: std::net::TcpListener, reply: Reply) -> MockUpstream {
+        let address = listener.local_addr().unwrap().to_string();
+        let mut listener = TcpListener::from_std(listener).unwrap();
+        let state = Arc::new(MockState {
+            reply: Mutex::new(reply),
+            scripts: Mutex::new(VecDeque::new()),
+            requests: Mutex::new(Vec::new()),
+            connections: AtomicUsize::new(0),
+        });
+        let (stop_sender, stop) = watch::channel(false);
+        let task_state = state.clone();
+        let accept_task = tokio::spawn(async move {
+            let mut accept_stop = stop.clone();
+            loop {
+                let conn = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((conn, _)) => conn,
+                        Err(_) => continue,
+                    },
+                    _ = stopped(&mut accept_stop) => return,
+                };
+                let connection = task_state.connections.fetch_add(1, Ordering::SeqCst);
+                let script = task_state
+                    .scripts
+                    .lock()
+                    .unwrap()
+                    .pop_front()
+                    .unwrap_or_default();
+                let state = task_state.clone();
+                let mut conn_stop = stop.clone();
+                // Stopping drops the connection along with the task serving it
+                tokio::spawn(async move {
+                    tokio::select! {
+                        _ = serve(conn, connection, script, state) => {}
+                        _ = stopped(&mut conn_stop) => {}
+                    }
+                });
+            }
+        });
+        MockUpstream {
+            address,
+            state,
+            stop_sender,
+            accept_task,
+        }
+    }
+
+    /// Makes every request from now on get `reply`, other than those the connections scripted with
+    /// script_connection have left to answer
+    pub fn set_reply(&self, reply: Reply) {
+        *self.state.reply.lock().unwrap() = reply;
+    }
+
+    /// Makes the first requests on the next connection accepted (after those already scripted) get
+    /// `replies` in turn; any more on it get what set_reply last set
+    pub fn script_connection(&self, replies: Vec<Reply>) {
+        self.state.scripts.lock().unwrap().push_back(replies);
+    }
+
+    /// Every request received so far, in the order they were read
+    pub fn requests(&self) -> Vec<ReceivedRequest> {
+        self.state.requests.lock().unwrap().clone()
+    }
+
+    /// The requests received so far for paths starting with `prefix`, e.g. to leave out health
+    /// checks
+    pub fn requests_for(&self, prefix: &str) -> Vec<ReceivedRequest> {
+        self.requests()
+            .into_iter()
+            .filter(|request| request.path.starts_with(prefix))
+            .collect()
+    }
+
+    pub fn request_count(&self) -> usize {
+        self.state.requests.lock().unwrap().len()
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.state.connections.load(Ordering::SeqCst)
+    }
+}
+
+/// Resolves once the upstream is told to stop
+async fn stopped(stop: &mut watch::Receiver<bool>) {
+    while let Some(stopping) = stop.recv().await {
+        if stopping {
+            return;
+        }
+    }
+}
+
+/// Reads requests off `conn`, recording each and replying as scripted, until either side hangs up
+async fn serve(conn: TcpStream, connection: usize, script: Vec<Reply>, state: Arc<MockState>) {
+    let mut conn = BufReader::new(conn);
+    let mut script = script.into_iter();
+    while let Some(request) = read_request(&mut conn, connection).await {
+        state.requests.lock().unwrap().push(request);
+        let mut reply = script
+            .next()
+            .unwrap_or_else(|| state.reply.lock().unwrap().clone());
+        while let Reply::Delay(delay, then) = reply {
+            delay_for(delay).await;
+            reply = *then;
+        }
+        let response = match reply {
+            Reply::Respond {
+                status,
+                headers,
+                body,
+            } => {
+                let mut response = format!(
+                    "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\n",
+                    status,
+                    body.len()
+                );
+                for (name, value) in headers {
+                    response += &format!("{}: {}\r\n", name, value);
+                }
+                response + "\r\n" + &body
+            }
+            Reply::Close => return,
+            Reply::Malformed => String::from("this is not HTTP\r\n\r\n"),
+            Reply::Delay(..) => unreachable!(),
+        };
+        if conn.get_mut().write_all(response.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Reads one request (with a Content-Length body, if any), or None once the connection closes
+async fn read_request(
+    conn: &mut BufReader<TcpStream>,
+    connection: usize,
+) -> Option<ReceivedRequest> {
+    let mut request_line = String::new();
+    if conn.read_line(&mut request_line).await.ok()? == 0 {
+        return None;
+    }
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if conn.read_line(&mut line).await.ok()? == 0 {
+            return None;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':')?;
+        headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+    }
+    let content_length = headers
+        .iter()
+        .find(|(name, _)| name == "content-length")
+        .map_or(0, |(_, value)| value.parse::<usize>().unwrap_or(0));
+    let mut body = vec![0_u8; content_length];
+    conn.read_exact(&mut body).await.ok()?;
+    Some(ReceivedRequest {
+        method,
+        path,
+        headers,
+        body,
+        connection,
+    })
+}
+
+#[async_trait]
+impl Server for MockUpstream {
+    async fn stop(self: Box<Self>) -> usize {
+        let MockUpstream {
+            state,
+            stop_sender,
+            accept_task,
+            ..
+        } = *self;
+        let _ = stop_sender.broadcast(true);
+        accept_task
+            .await
+            .expect("MockUpstream accept task panicked");
+        let count = state.requests.lock().unwrap().len();
+        count
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
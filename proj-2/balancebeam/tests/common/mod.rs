@@ -1,15 +1,69 @@
+// Each test binary only uses some of these helpers.
+#![allow(dead_code)]
+
 mod balancebeam;
 mod echo_server;
 mod error_server;
+mod mock_upstream;
 mod server;
 
 use std::sync;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time::delay_for;
 
 pub use balancebeam::BalanceBeam;
 pub use echo_server::EchoServer;
+#[allow(unused_imports)]
 pub use error_server::ErrorServer;
+#[allow(unused_imports)]
+pub use mock_upstream::{MockUpstream, ReceivedRequest, Reply};
 pub use server::Server;
 
+/// Returns a localhost address on a port that is free right now. Picking one at random tends to
+/// collide with the ephemeral ports of the tests' own client connections.
+pub fn free_local_address() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Could not find a free port");
+    listener.local_addr().unwrap().to_string()
+}
+
+/// Starts an upstream that waits `delay` before answering each request with a 200. Returns its
+/// address.
+pub async fn start_slow_upstream(delay: Duration) -> String {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buffer = [0_u8; 4096];
+                while let Ok(bytes_read) = stream.read(&mut buffer).await {
+                    if bytes_read == 0 {
+                        break;
+                    }
+                    // Requests have no body, so one is complete once its headers are
+                    request.extend_from_slice(&buffer[..bytes_read]);
+                    if !request.ends_with(b"\r\n\r\n") {
+                        continue;
+                    }
+                    request.clear();
+                    delay_for(delay).await;
+                    if stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nslow")
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
 static INIT_TESTS: sync::Once = sync::Once::new();
 
 pub fn init_logging() {
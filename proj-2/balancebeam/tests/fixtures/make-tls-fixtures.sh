@@ -0,0 +1,15 @@
+#!/bin/sh
+# Regenerates the TLS test fixtures: a throwaway CA (tls-ca.pem) and a certificate it signed for
+# localhost and 127.0.0.1 (tls-cert.pem, with its key in tls-key.pem). Both are valid for 100 years.
+set -e
+cd "$(dirname "$0")"
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+openssl req -x509 -newkey rsa:2048 -nodes -days 36500 -subj "/CN=balancebeam test CA" \
+    -keyout "$work/ca-key.pem" -out tls-ca.pem
+openssl req -newkey rsa:2048 -nodes -subj "/CN=localhost" \
+    -keyout tls-key.pem -out "$work/cert.csr"
+printf 'subjectAltName=DNS:localhost,IP:127.0.0.1\nbasicConstraints=CA:FALSE\nextendedKeyUsage=serverAuth\n' \
+    > "$work/ext.cnf"
+openssl x509 -req -days 36500 -in "$work/cert.csr" -CA tls-ca.pem -CAkey "$work/ca-key.pem" \
+    -CAcreateserial -CAserial "$work/ca.srl" -extfile "$work/ext.cnf" -out tls-cert.pem
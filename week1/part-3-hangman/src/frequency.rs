@@ -0,0 +1,129 @@
+use std::collections::{HashMap, HashSet};
+use {normalize, BLANK};
+
+/// How many suggested letters to show after a wrong guess.
+pub const NUM_SUGGESTIONS: usize = 5;
+
+/// Returns the words that could still be the secret word: same length as `pattern`, matching
+/// every revealed position, and with no guessed letter hiding behind a blank (a guessed letter
+/// would have been revealed everywhere it occurs).
+pub fn candidates<'a>(
+    words: &'a [String],
+    pattern: &[char],
+    guessed: &HashSet<char>,
+) -> Vec<&'a str> {
+    words
+        .iter()
+        .map(|word| word.as_str())
+        .filter(|word| word.chars().count() == pattern.len())
+        .filter(|word| {
+            word.chars().zip(pattern.iter()).all(|(c, &shown)| {
+                if shown == BLANK {
+                    c.is_alphabetic() && !guessed.contains(&normalize(c))
+                } else {
+                    normalize(c) == normalize(shown)
+                }
+            })
+        })
+        .collect()
+}
+
+/// Tallies how many of `words` contain each unguessed letter and returns the `n` most common,
+/// most frequent first (ties broken alphabetically so the output is stable).
+pub fn top_letters(words: &[&str], guessed: &HashSet<char>, n: usize) -> Vec<(char, usize)> {
+    let mut counts: HashMap<char, usize> = HashMap::new();
+    for word in words {
+        let letters: HashSet<char> = word
+            .chars()
+            .filter(|c| c.is_alphabetic())
+            .map(normalize)
+            .filter(|c| !guessed.contains(c))
+            .collect();
+        for c in letters {
+            *counts.entry(c).or_insert(0) += 1;
+        }
+    }
+    let mut counts: Vec<(char, usize)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    counts.truncate(n);
+    counts
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use Game;
+
+    fn word_list() -> Vec<String> {
+        [
+            "lobster", "lockets", "starfish", "crawfish", "pockets", "rockets", "hello",
+        ]
+        .iter()
+        .map(|word| String::from(*word))
+        .collect()
+    }
+
+    fn suggest(game: &Game, words: &[String]) -> (usize, Vec<(char, usize)>) {
+        let remaining = candidates(words, &game.so_far_word, &game.guessed_letters);
+        let letters = top_letters(&remaining, &game.guessed_letters, NUM_SUGGESTIONS);
+        (remaining.len(), letters)
+    }
+
+    #[test]
+    fn test_candidates_narrow_after_guesses() {
+        let words = word_list();
+        let mut game = Game::new("rockets", 5);
+        assert_eq!(
+            candidates(&words, &game.so_far_word, &game.guessed_letters).len(),
+            4
+        );
+
+        game.guess('z').unwrap();
+        let (count, letters) = suggest(&game, &words);
+        assert_eq!(count, 4);
+        assert_eq!(
+            letters,
+            vec![('e', 4), ('o', 4), ('s', 4), ('t', 4), ('c', 3)]
+        );
+
+        game.guess('k').unwrap();
+        game.guess('p').unwrap();
+        let remaining = candidates(&words, &game.so_far_word, &game.guessed_letters);
+        assert_eq!(remaining, vec!["lockets", "rockets"]);
+        assert_eq!(
+            top_letters(&remaining, &game.guessed_letters, NUM_SUGGESTIONS),
+            vec![('c', 2), ('e', 2), ('o', 2), ('s', 2), ('t', 2)]
+        );
+
+        game.guess('l').unwrap();
+        let (count, letters) = suggest(&game, &words);
+        assert_eq!(count, 1);
+        assert_eq!(letters[0], ('c', 1));
+        assert!(letters
+            .iter()
+            .all(|&(c, _)| c != 'k' && c != 'l' && c != 'p'));
+    }
+
+    #[test]
+    fn test_candidates_respect_punctuation_and_case() {
+        let words: Vec<String> = ["don't", "dent", "Won't"]
+            .iter()
+            .map(|word| String::from(*word))
+            .collect();
+        let mut game = Game::new("don't", 5);
+        assert_eq!(
+            candidates(&words, &game.so_far_word, &game.guessed_letters),
+            vec!["don't", "Won't"]
+        );
+        game.guess('w').unwrap();
+        assert_eq!(
+            candidates(&words, &game.so_far_word, &game.guessed_letters),
+            vec!["don't"]
+        );
+    }
+
+    #[test]
+    fn test_top_letters_empty() {
+        assert!(top_letters(&[], &HashSet::new(), NUM_SUGGESTIONS).is_empty());
+    }
+}
@@ -12,22 +12,87 @@
 // - user input
 // We've tried to limit/hide Rust's quirks since we'll discuss those details
 // more in depth in the coming lectures.
+extern crate clap;
+extern crate dirs;
 extern crate rand;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+#[cfg(test)]
+extern crate tempfile;
+
+mod frequency;
+mod save;
+mod words;
+
+use clap::Parser;
 use rand::Rng;
-use std::fs;
+use std::collections::HashSet;
 use std::io;
-use std::io::Write;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use words::{WordListError, WordSource};
 
 const NUM_INCORRECT_GUESSES: u32 = 5;
-const WORDS_PATH: &str = "words.txt";
+/// Shown in place of letters that haven't been guessed yet. Deliberately not '-', which appears
+/// literally in hyphenated words.
+const BLANK: char = '_';
 
-fn pick_a_random_word() -> String {
-    let file_string = fs::read_to_string(WORDS_PATH).expect("Unable to read file.");
-    let words: Vec<&str> = file_string.split('\n').collect();
-    String::from(words[rand::thread_rng().gen_range(0, words.len())].trim())
+/// Command-line options for tweaking the difficulty of a game.
+#[derive(Parser, Debug)]
+#[clap(about = "CS110L Hangman")]
+struct CmdOptions {
+    #[clap(
+        long,
+        help = "Number of incorrect guesses allowed",
+        default_value_t = NUM_INCORRECT_GUESSES
+    )]
+    guesses: u32,
+    #[clap(long, help = "Only pick words with at least this many letters")]
+    min_length: Option<usize>,
+    #[clap(long, help = "Only pick words with at most this many letters")]
+    max_length: Option<usize>,
+    #[clap(long, help = "Reveal one letter of the word before the first guess")]
+    easy: bool,
+    #[clap(
+        long,
+        help = "Newline-delimited word list to pick from",
+        conflicts_with = "category"
+    )]
+    words: Option<PathBuf>,
+    #[clap(long, help = "Pick from the word list in words/<CATEGORY>.txt")]
+    category: Option<String>,
+    #[clap(long, help = "List the available word categories and exit")]
+    list_categories: bool,
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "Resume a saved game (from the default save file if no path is given)"
+    )]
+    resume: Option<Option<PathBuf>>,
+    #[clap(
+        long,
+        help = "After each wrong guess, show how many words still fit and the most common letters"
+    )]
+    show_frequency: bool,
+}
+
+/// Loads the word list selected on the command line. Only the default list falls back to the
+/// built-in words if it's missing.
+fn load_word_list(options: &CmdOptions) -> Result<(Vec<String>, WordSource), WordListError> {
+    let path = match (&options.words, &options.category) {
+        (Some(path), _) => words::resolve_path(path),
+        (None, Some(category)) => words::category_path(category),
+        (None, None) => {
+            return words::load_words_or_builtin(&words::resolve_path(Path::new(words::WORDS_PATH)))
+        }
+    };
+    let word_list = words::load_words(&path)?;
+    Ok((word_list, WordSource::File(path)))
 }
 
-fn vec2str(v: &Vec<char>) -> String {
+fn vec2str(v: &[char]) -> String {
     let mut s = String::new();
     for c in v {
         s.push(*c);
@@ -35,50 +100,612 @@ fn vec2str(v: &Vec<char>) -> String {
     s
 }
 
-fn main() {
-    let secret_word = pick_a_random_word();
+/// Lowercases a single character so that guesses and the secret word compare case-insensitively.
+fn normalize(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+/// Reveals every position of `c` in `secret` that is still hidden in `so_far`, returning the
+/// number of newly revealed letters (0 means the guess was wrong). Matching ignores case, but
+/// the revealed letter keeps the case it has in `secret`.
+fn apply_guess(secret: &[char], so_far: &mut [char], c: char) -> usize {
+    let c = normalize(c);
+    let mut revealed = 0;
+    for i in 0..secret.len() {
+        if normalize(secret[i]) == c && so_far[i] == BLANK {
+            so_far[i] = secret[i];
+            revealed += 1;
+        }
+    }
+    revealed
+}
+
+/// Reasons a line of input is rejected as a guess. None of these cost the player a guess.
+#[derive(Debug, PartialEq)]
+enum GuessError {
+    NotOneCharacter,
+    NotALetter(char),
+    AlreadyGuessed(char),
+}
+
+/// The result of a guess that was accepted.
+#[derive(Debug, PartialEq)]
+enum GuessOutcome {
+    /// The letter is in the word; holds how many positions were revealed.
+    Correct(usize),
+    Incorrect,
+}
+
+/// Turns a raw input line into a guess: exactly one alphabetic character, lowercased.
+fn parse_guess(line: &str) -> Result<char, GuessError> {
+    let mut chars = line.trim().chars();
+    let c = match (chars.next(), chars.next()) {
+        (Some(c), None) => c,
+        _ => return Err(GuessError::NotOneCharacter),
+    };
+    if !c.is_alphabetic() {
+        return Err(GuessError::NotALetter(c));
+    }
+    Ok(normalize(c))
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Game {
+    secret_word: String,
     // Note: given what you know about Rust so far, it's easier to pull characters out of a
     // vector than it is to pull them out of a string. You can get the ith character of
     // secret_word by doing secret_word_chars[i].
-    let secret_word_chars: Vec<char> = secret_word.chars().collect();
+    secret_word_chars: Vec<char>,
+    so_far_word: Vec<char>,
+    guessed_letters: HashSet<char>,
+    max_incorrect: u32,
+    used_guessed: u32,
+    guessd_count: usize,
+}
+
+impl Game {
+    /// Punctuation such as apostrophes and hyphens isn't guessable, so it starts out revealed.
+    fn new(secret_word: &str, max_incorrect: u32) -> Game {
+        debug_assert!(!secret_word.is_empty(), "the secret word must not be empty");
+        let secret_word_chars: Vec<char> = secret_word.chars().collect();
+        Game {
+            secret_word: String::from(secret_word),
+            so_far_word: secret_word_chars
+                .iter()
+                .map(|&c| if c.is_alphabetic() { BLANK } else { c })
+                .collect(),
+            secret_word_chars,
+            guessed_letters: HashSet::new(),
+            max_incorrect,
+            used_guessed: 0,
+            guessd_count: 0,
+        }
+    }
+
+    fn guesses_left(&self) -> u32 {
+        self.max_incorrect.saturating_sub(self.used_guessed)
+    }
+
+    /// Number of positions in the secret word that the player has to guess.
+    fn num_letters(&self) -> usize {
+        self.secret_word_chars
+            .iter()
+            .filter(|c| c.is_alphabetic())
+            .count()
+    }
+
+    fn is_won(&self) -> bool {
+        self.guessd_count == self.num_letters()
+    }
+
+    fn is_over(&self) -> bool {
+        self.is_won() || self.guesses_left() == 0
+    }
+
+    /// Guessed letters in alphabetical order, for display.
+    fn guessed_letters_sorted(&self) -> String {
+        let mut letters: Vec<char> = self.guessed_letters.iter().cloned().collect();
+        letters.sort();
+        vec2str(&letters)
+    }
+
+    /// Applies a guess. Repeating an earlier guess is rejected and leaves the game untouched.
+    fn guess(&mut self, c: char) -> Result<GuessOutcome, GuessError> {
+        let c = normalize(c);
+        if !self.guessed_letters.insert(c) {
+            return Err(GuessError::AlreadyGuessed(c));
+        }
+        let revealed = apply_guess(&self.secret_word_chars, &mut self.so_far_word, c);
+        self.guessd_count += revealed;
+        if revealed > 0 {
+            Ok(GuessOutcome::Correct(revealed))
+        } else {
+            self.used_guessed += 1;
+            Ok(GuessOutcome::Incorrect)
+        }
+    }
+
+    /// Reveals every occurrence of one randomly chosen, still-hidden letter, as if the player
+    /// had guessed it.
+    fn reveal_random_letter<R: Rng>(&mut self, rng: &mut R) {
+        let hidden: Vec<char> = (0..self.secret_word_chars.len())
+            .filter(|&i| self.so_far_word[i] == BLANK)
+            .map(|i| self.secret_word_chars[i])
+            .collect();
+        if hidden.is_empty() {
+            return;
+        }
+        let c = hidden[rng.gen_range(0, hidden.len())];
+        let _ = self.guess(c);
+    }
+}
+
+/// What the player did with their turn.
+#[derive(Debug, PartialEq)]
+enum Turn {
+    Guessed(GuessOutcome),
+    Save,
+}
+
+/// How a call to `play` ended.
+#[derive(Debug, PartialEq)]
+enum PlayResult {
+    Finished,
+    /// The player asked to save; the game is untouched and can be resumed later.
+    Saved,
+}
+
+/// Prompts on `output` until `input` yields a guess the game accepts or the `save` command.
+/// Invalid or repeated guesses are explained and re-prompted without costing a guess.
+fn take_turn<R: BufRead, W: Write>(
+    game: &mut Game,
+    input: &mut R,
+    output: &mut W,
+) -> io::Result<Turn> {
+    loop {
+        write!(output, "Please guess a letter: ")?;
+        output.flush()?;
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "no more input",
+            ));
+        }
+        if line.trim().eq_ignore_ascii_case("save") {
+            return Ok(Turn::Save);
+        }
+        match parse_guess(&line).and_then(|c| game.guess(c)) {
+            Ok(outcome) => return Ok(Turn::Guessed(outcome)),
+            Err(GuessError::NotOneCharacter) => writeln!(output, "Please enter a single letter.")?,
+            Err(GuessError::NotALetter(c)) => writeln!(output, "'{}' is not a letter.", c)?,
+            Err(GuessError::AlreadyGuessed(c)) => {
+                writeln!(output, "You have already guessed '{}'.", c)?
+            }
+        }
+    }
+}
+
+/// Prints how many words in `word_list` still fit what the player knows, and the letters most
+/// likely to be in the secret word.
+fn show_frequency<W: Write>(game: &Game, word_list: &[String], output: &mut W) -> io::Result<()> {
+    let remaining = frequency::candidates(word_list, &game.so_far_word, &game.guessed_letters);
+    let letters = frequency::top_letters(
+        &remaining,
+        &game.guessed_letters,
+        frequency::NUM_SUGGESTIONS,
+    );
+    writeln!(output, "{} possible words remain", remaining.len())?;
+    if !letters.is_empty() {
+        let letters: Vec<String> = letters
+            .iter()
+            .map(|(c, count)| format!("{} ({})", c, count))
+            .collect();
+        writeln!(
+            output,
+            "Most common unguessed letters: {}",
+            letters.join(", ")
+        )?;
+    }
+    Ok(())
+}
+
+/// Runs a game until it's over or the player saves it, reading guesses from `input` and writing
+/// the transcript to `output`. If `frequency_words` is given, letter-frequency hints drawn from
+/// it are shown after each wrong guess.
+fn play<R: BufRead, W: Write>(
+    game: &mut Game,
+    input: &mut R,
+    output: &mut W,
+    frequency_words: Option<&[String]>,
+) -> io::Result<PlayResult> {
+    writeln!(output, "Welcome to CS110L Hangman!")?;
+    while !game.is_over() {
+        writeln!(output, "The word so far is {}", vec2str(&game.so_far_word))?;
+        writeln!(
+            output,
+            "You have guessed the following letters: {}",
+            game.guessed_letters_sorted()
+        )?;
+        writeln!(output, "You have {} guesses left", game.guesses_left())?;
+        match take_turn(game, input, output)? {
+            Turn::Save => return Ok(PlayResult::Saved),
+            Turn::Guessed(GuessOutcome::Incorrect) if !game.is_over() => {
+                if let Some(word_list) = frequency_words {
+                    show_frequency(game, word_list, output)?;
+                }
+            }
+            Turn::Guessed(_) => {}
+        }
+        writeln!(output)?;
+    }
+
+    if game.is_won() {
+        writeln!(
+            output,
+            "Congratulations you guessed the secret word: {}!",
+            game.secret_word
+        )?;
+    } else {
+        writeln!(output, "Sorry, you ran out of guesses!")?;
+    }
+    Ok(PlayResult::Finished)
+}
+
+/// Asks a yes/no question on stdout, treating anything but "y"/"yes" as no.
+fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    io::stdout().flush().expect("Error flushing stdout.");
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    let answer = answer.trim().to_lowercase();
+    answer == "y" || answer == "yes"
+}
+
+/// Loads the word list, exiting if it can't be used.
+fn load_word_list_or_exit(options: &CmdOptions) -> Vec<String> {
+    match load_word_list(options) {
+        Ok((word_list, source)) => {
+            if source == WordSource::Builtin {
+                println!("Note: {} not found, using {}.", words::WORDS_PATH, source);
+            } else {
+                println!("Using {}.", source);
+            }
+            word_list
+        }
+        Err(err) => {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Starts a new game according to the command-line options, exiting if no word can be picked.
+fn new_game(options: &CmdOptions, word_list: &[String]) -> Game {
+    let secret_word =
+        match words::pick_a_random_word(word_list, options.min_length, options.max_length) {
+            Ok(word) => word,
+            Err(err) => {
+                println!("{}", err);
+                std::process::exit(1);
+            }
+        };
     // Uncomment for debugging:
     println!("[debug] random word: {}", secret_word);
-    
-    let mut so_far_word = vec!['-'; secret_word_chars.len()];
-    let mut guessed_letters = String::new();
-    let mut used_guessed = 0;
-    let mut guessd_count = 0;
-    println!("Welcome to CS110L Hangman!");
-    while NUM_INCORRECT_GUESSES - used_guessed != 0 && guessd_count != secret_word_chars.len() {
-        println!("The word so far is {}", vec2str(&so_far_word));
-        println!("You have guessed the following letters: {}", guessed_letters);
-        println!("You have {} guesses left", NUM_INCORRECT_GUESSES - used_guessed);
-        print!("Please guess a letter: ");
-        io::stdout().flush().expect("Error flushing stdout.");
-        let mut guess = String::new();
-        io::stdin().read_line(&mut guess).expect("Error reading line.");
-
-        guessed_letters.push(guess.chars().nth(0).unwrap());
-        let mut right_letter = false;
-        let mut i = 0;
-        while i < secret_word_chars.len() {
-            if secret_word_chars[i] == guess.chars().nth(0).unwrap() && so_far_word[i] =='-' {
-                so_far_word[i] = secret_word_chars[i];
-                right_letter = true;
-                guessd_count += 1;
-                break;
+
+    let mut game = Game::new(&secret_word, options.guesses);
+    if options.easy {
+        game.reveal_random_letter(&mut rand::thread_rng());
+    }
+    game
+}
+
+fn main() {
+    let options = CmdOptions::parse();
+    if options.list_categories {
+        let dir = words::resolve_path(Path::new(words::CATEGORIES_DIR));
+        match words::list_categories(&dir) {
+            Ok(categories) => {
+                for category in categories {
+                    println!("{}", category);
+                }
+            }
+            Err(err) => {
+                println!("Unable to list categories in {}: {}", dir.display(), err);
+                std::process::exit(1);
             }
-            i += 1;
-        }
-        if !right_letter {
-            used_guessed += 1;
         }
-        println!();
+        return;
     }
 
-    if NUM_INCORRECT_GUESSES - used_guessed == 0 {
-        println!("Sorry, you ran out of guesses!")
+    // Only read the word list if it's needed, so a saved game can be resumed even if the list
+    // has since gone missing.
+    let mut word_list = None;
+    let (mut game, save_path, resumed) = match options.resume {
+        Some(ref path) => {
+            let path = path.clone().unwrap_or_else(save::default_save_path);
+            match save::load_game(&path) {
+                Ok(game) => (game, path, true),
+                Err(err) => {
+                    println!("{}", err);
+                    if !confirm("Start a new game instead?") {
+                        std::process::exit(1);
+                    }
+                    // Otherwise the unusable save would be offered again next time
+                    if let Err(err) = save::delete_save(&path) {
+                        println!("{}", err);
+                    }
+                    let word_list =
+                        word_list.get_or_insert_with(|| load_word_list_or_exit(&options));
+                    (new_game(&options, word_list), path, false)
+                }
+            }
+        }
+        None => {
+            let word_list = word_list.get_or_insert_with(|| load_word_list_or_exit(&options));
+            (
+                new_game(&options, word_list),
+                save::default_save_path(),
+                false,
+            )
+        }
+    };
+    let frequency_words = if options.show_frequency {
+        Some(
+            word_list
+                .get_or_insert_with(|| load_word_list_or_exit(&options))
+                .as_slice(),
+        )
     } else {
-        println!("Congratulations you guessed the secret word: {}!", secret_word);
+        None
+    };
+
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    match play(
+        &mut game,
+        &mut stdin.lock(),
+        &mut stdout.lock(),
+        frequency_words,
+    ) {
+        Ok(PlayResult::Finished) => {
+            if resumed {
+                if let Err(err) = save::delete_save(&save_path) {
+                    println!("{}", err);
+                }
+            }
+        }
+        Ok(PlayResult::Saved) => match save::save_game(&save_path, &game) {
+            Ok(()) => {
+                println!();
+                println!(
+                    "Game saved to {}. Continue it later with --resume.",
+                    save_path.display()
+                );
+            }
+            Err(err) => {
+                println!("{}", err);
+                std::process::exit(1);
+            }
+        },
+        Err(err) => {
+            println!();
+            println!("Game aborted: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chars(s: &str) -> Vec<char> {
+        s.chars().collect()
+    }
+
+    #[test]
+    fn test_apply_guess_reveals_all_occurrences() {
+        let secret = chars("hello");
+        let mut so_far = vec![BLANK; secret.len()];
+        assert_eq!(apply_guess(&secret, &mut so_far, 'l'), 2);
+        assert_eq!(vec2str(&so_far), "__ll_");
+    }
+
+    #[test]
+    fn test_apply_guess_wrong_letter() {
+        let secret = chars("hello");
+        let mut so_far = vec![BLANK; secret.len()];
+        assert_eq!(apply_guess(&secret, &mut so_far, 'z'), 0);
+        assert_eq!(vec2str(&so_far), "_____");
+    }
+
+    #[test]
+    fn test_apply_guess_repeated_guess_reveals_nothing() {
+        let secret = chars("starfish");
+        let mut so_far = vec![BLANK; secret.len()];
+        assert_eq!(apply_guess(&secret, &mut so_far, 's'), 2);
+        assert_eq!(apply_guess(&secret, &mut so_far, 's'), 0);
+        assert_eq!(vec2str(&so_far), "s_____s_");
+    }
+
+    #[test]
+    fn test_apply_guess_single_letter_word() {
+        let secret = chars("aaaa");
+        let mut so_far = vec![BLANK; secret.len()];
+        assert_eq!(apply_guess(&secret, &mut so_far, 'a'), secret.len());
+        assert_eq!(vec2str(&so_far), "aaaa");
+    }
+
+    fn run(secret: &str, script: &str) -> (Game, String) {
+        let mut game = Game::new(secret, NUM_INCORRECT_GUESSES);
+        let mut output = Vec::new();
+        let result = play(&mut game, &mut script.as_bytes(), &mut output, None);
+        assert_eq!(
+            result.expect("script ran out of input"),
+            PlayResult::Finished
+        );
+        (game, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_parse_guess() {
+        assert_eq!(parse_guess("a\n"), Ok('a'));
+        assert_eq!(parse_guess("  Q  \n"), Ok('q'));
+        assert_eq!(parse_guess("\n"), Err(GuessError::NotOneCharacter));
+        assert_eq!(parse_guess(""), Err(GuessError::NotOneCharacter));
+        assert_eq!(parse_guess("ab\n"), Err(GuessError::NotOneCharacter));
+        assert_eq!(parse_guess("7\n"), Err(GuessError::NotALetter('7')));
+    }
+
+    #[test]
+    fn test_repeated_guess_is_free() {
+        let mut game = Game::new("hello", NUM_INCORRECT_GUESSES);
+        assert_eq!(game.guess('z'), Ok(GuessOutcome::Incorrect));
+        assert_eq!(game.guess('z'), Err(GuessError::AlreadyGuessed('z')));
+        assert_eq!(game.guesses_left(), NUM_INCORRECT_GUESSES - 1);
+        assert_eq!(game.guess('l'), Ok(GuessOutcome::Correct(2)));
+        assert_eq!(game.guess('l'), Err(GuessError::AlreadyGuessed('l')));
+    }
+
+    #[test]
+    fn test_guessed_letters_sorted() {
+        let mut game = Game::new("hello", NUM_INCORRECT_GUESSES);
+        for c in "zelb".chars() {
+            game.guess(c).unwrap();
+        }
+        assert_eq!(game.guessed_letters_sorted(), "belz");
+    }
+
+    #[test]
+    fn test_invalid_input_costs_nothing() {
+        let (game, output) = run("hello", "\n7\nxy\nH\nh\ne\nL\no\n");
+        assert!(game.is_won());
+        assert_eq!(game.guesses_left(), NUM_INCORRECT_GUESSES);
+        assert!(output.contains("Please enter a single letter."));
+        assert!(output.contains("'7' is not a letter."));
+        assert!(output.contains("You have already guessed 'h'."));
+        assert!(output.contains("Congratulations you guessed the secret word: hello!"));
+    }
+
+    #[test]
+    fn test_running_out_of_guesses() {
+        let (game, output) = run("hello", "a\nb\nc\nd\nf\n");
+        assert!(!game.is_won());
+        assert_eq!(game.guesses_left(), 0);
+        assert!(output.contains("Sorry, you ran out of guesses!"));
+    }
+
+    #[test]
+    fn test_eof_aborts_game() {
+        let mut game = Game::new("hello", NUM_INCORRECT_GUESSES);
+        let mut output = Vec::new();
+        let err = play(&mut game, &mut "h\n".as_bytes(), &mut output, None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_guess_counter_at_limit() {
+        let mut game = Game::new("hello", 1);
+        assert_eq!(game.guesses_left(), 1);
+        assert_eq!(game.guess('z'), Ok(GuessOutcome::Incorrect));
+        assert_eq!(game.guesses_left(), 0);
+        assert!(game.is_over());
+        // Further wrong guesses must not wrap the counter around.
+        assert_eq!(game.guess('y'), Ok(GuessOutcome::Incorrect));
+        assert_eq!(game.guesses_left(), 0);
+    }
+
+    #[test]
+    fn test_zero_guesses_is_immediately_lost() {
+        let (game, output) = {
+            let mut game = Game::new("hello", 0);
+            let mut output = Vec::new();
+            play(&mut game, &mut "".as_bytes(), &mut output, None).unwrap();
+            (game, String::from_utf8(output).unwrap())
+        };
+        assert!(!game.is_won());
+        assert!(output.contains("Sorry, you ran out of guesses!"));
+    }
+
+    #[test]
+    fn test_reveal_random_letter() {
+        let mut game = Game::new("hello", NUM_INCORRECT_GUESSES);
+        game.reveal_random_letter(&mut rand::thread_rng());
+        assert_eq!(game.guessed_letters.len(), 1);
+        assert_eq!(game.guesses_left(), NUM_INCORRECT_GUESSES);
+        let revealed = game.so_far_word.iter().filter(|&&c| c != BLANK).count();
+        assert_eq!(revealed, game.guessd_count);
+        assert!(revealed >= 1);
+    }
+
+    #[test]
+    fn test_hyphenated_word() {
+        let mut game = Game::new("ice-cream", NUM_INCORRECT_GUESSES);
+        assert_eq!(vec2str(&game.so_far_word), "___-_____");
+        assert_eq!(game.num_letters(), 8);
+        for c in "icream".chars() {
+            game.guess(c).unwrap();
+        }
+        assert_eq!(vec2str(&game.so_far_word), "ice-cream");
+        assert!(game.is_won());
+    }
+
+    #[test]
+    fn test_apostrophe_word() {
+        let (game, output) = run("don't", "d\no\nn\nt\n");
+        assert!(game.is_won());
+        assert!(output.contains("The word so far is ___'_"));
+        assert_eq!(parse_guess("'\n"), Err(GuessError::NotALetter('\'')));
+    }
+
+    #[test]
+    fn test_mixed_case_word() {
+        let mut game = Game::new("McRust", NUM_INCORRECT_GUESSES);
+        assert_eq!(game.guess('m'), Ok(GuessOutcome::Correct(1)));
+        assert_eq!(game.guess('R'), Ok(GuessOutcome::Correct(1)));
+        assert_eq!(game.guess('r'), Err(GuessError::AlreadyGuessed('r')));
+        assert_eq!(vec2str(&game.so_far_word), "M_R___");
+        for c in "cust".chars() {
+            game.guess(c).unwrap();
+        }
+        assert!(game.is_won());
+        let (_, output) = run("McRust", "m\nc\nr\nu\ns\nt\n");
+        assert!(output.contains("Congratulations you guessed the secret word: McRust!"));
+    }
+
+    #[test]
+    fn test_show_frequency_after_wrong_guesses() {
+        let word_list: Vec<String> = ["lockets", "rockets", "pockets", "lobster"]
+            .iter()
+            .map(|word| String::from(*word))
+            .collect();
+        let mut game = Game::new("rockets", NUM_INCORRECT_GUESSES);
+        let mut output = Vec::new();
+        let script = "o\nz\np\nr\nc\nk\ne\nt\ns\n";
+        let result = play(
+            &mut game,
+            &mut script.as_bytes(),
+            &mut output,
+            Some(&word_list),
+        );
+        assert_eq!(result.unwrap(), PlayResult::Finished);
+        let output = String::from_utf8(output).unwrap();
+        // Only shown after the two wrong guesses.
+        assert_eq!(output.matches("possible words remain").count(), 2);
+        assert!(output.contains(
+            "4 possible words remain\nMost common unguessed letters: e (4), s (4), t (4), c (3), k (3)"
+        ));
+        assert!(output.contains(
+            "3 possible words remain\nMost common unguessed letters: e (3), s (3), t (3), c (2), k (2)"
+        ));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "the secret word must not be empty")]
+    fn test_empty_secret_word_is_rejected() {
+        Game::new("", NUM_INCORRECT_GUESSES);
     }
 }
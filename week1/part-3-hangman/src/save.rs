@@ -0,0 +1,211 @@
+use serde_json;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use Game;
+use BLANK;
+
+/// Bump whenever the serialized shape of Game changes, so old save files are rejected cleanly
+/// instead of being misread.
+pub const SAVE_VERSION: u64 = 1;
+const SAVE_FILE_NAME: &str = "save.json";
+
+/// Ways that saving or restoring a game can fail.
+#[derive(Debug)]
+pub enum SaveError {
+    Io(PathBuf, io::Error),
+    Corrupt(PathBuf, String),
+    VersionMismatch(PathBuf, Option<u64>),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveError::Io(path, err) => write!(f, "Unable to access {}: {}", path.display(), err),
+            SaveError::Corrupt(path, reason) => {
+                write!(f, "Save file {} is corrupt: {}", path.display(), reason)
+            }
+            SaveError::VersionMismatch(path, Some(found)) => write!(
+                f,
+                "Save file {} is from version {} of the game, but this is version {}",
+                path.display(),
+                found,
+                SAVE_VERSION
+            ),
+            SaveError::VersionMismatch(path, None) => {
+                write!(f, "Save file {} has no version number", path.display())
+            }
+        }
+    }
+}
+
+/// Where games are saved unless the player resumed from somewhere else: under the user's data
+/// directory, or the working directory if the platform doesn't have one.
+pub fn default_save_path() -> PathBuf {
+    match dirs::data_dir() {
+        Some(dir) => dir.join("cs110l-hangman").join(SAVE_FILE_NAME),
+        None => PathBuf::from(SAVE_FILE_NAME),
+    }
+}
+
+pub fn save_game(path: &Path, game: &Game) -> Result<(), SaveError> {
+    let io_err = |err| SaveError::Io(path.to_path_buf(), err);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(io_err)?;
+    }
+    let saved = json!({ "version": SAVE_VERSION, "game": game });
+    let contents = serde_json::to_string_pretty(&saved).expect("Game is always serializable");
+    fs::write(path, contents).map_err(io_err)
+}
+
+pub fn load_game(path: &Path) -> Result<Game, SaveError> {
+    let corrupt = |reason: String| SaveError::Corrupt(path.to_path_buf(), reason);
+    let contents =
+        fs::read_to_string(path).map_err(|err| SaveError::Io(path.to_path_buf(), err))?;
+    let mut saved: serde_json::Value =
+        serde_json::from_str(&contents).map_err(|err| corrupt(err.to_string()))?;
+    match saved.get("version").and_then(|version| version.as_u64()) {
+        Some(SAVE_VERSION) => {}
+        found => return Err(SaveError::VersionMismatch(path.to_path_buf(), found)),
+    }
+    let game: Game =
+        serde_json::from_value(saved["game"].take()).map_err(|err| corrupt(err.to_string()))?;
+    check_consistent(&game).map_err(|reason| corrupt(String::from(reason)))?;
+    Ok(game)
+}
+
+pub fn delete_save(path: &Path) -> Result<(), SaveError> {
+    match fs::remove_file(path) {
+        Err(ref err) if err.kind() != io::ErrorKind::NotFound => Err(SaveError::Io(
+            path.to_path_buf(),
+            io::Error::new(err.kind(), err.to_string()),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// A hand-edited or truncated save file can deserialize fine but describe an impossible game,
+/// which would otherwise cause out-of-bounds panics mid-game.
+fn check_consistent(game: &Game) -> Result<(), &'static str> {
+    if game.secret_word.is_empty() {
+        return Err("the secret word is empty");
+    }
+    if game
+        .secret_word_chars
+        .iter()
+        .cloned()
+        .ne(game.secret_word.chars())
+    {
+        return Err("the secret word's letters don't match the secret word");
+    }
+    if game.so_far_word.len() != game.secret_word_chars.len() {
+        return Err("the revealed word has the wrong length");
+    }
+    let mut revealed = 0;
+    for (&shown, &actual) in game.so_far_word.iter().zip(game.secret_word_chars.iter()) {
+        if shown == BLANK && actual.is_alphabetic() {
+            continue;
+        }
+        if shown != actual {
+            return Err("the revealed word doesn't match the secret word");
+        }
+        if actual.is_alphabetic() {
+            revealed += 1;
+        }
+    }
+    if revealed != game.guessd_count {
+        return Err("the number of revealed letters is wrong");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use play;
+    use tempfile::TempDir;
+    use PlayResult;
+    use NUM_INCORRECT_GUESSES;
+
+    fn run(game: &mut Game, script: &str) -> (PlayResult, String) {
+        let mut output = Vec::new();
+        let result =
+            play(game, &mut script.as_bytes(), &mut output, None).expect("script ran out of input");
+        (result, String::from_utf8(output).unwrap())
+    }
+
+    fn ending(transcript: &str) -> &str {
+        let start = transcript.rfind("The word so far is").unwrap();
+        &transcript[start..]
+    }
+
+    #[test]
+    fn test_resume_matches_straight_through() {
+        let script_before = "h\nz\nq\n";
+        let script_after = "e\nl\no\n";
+
+        let mut straight = Game::new("hello", NUM_INCORRECT_GUESSES);
+        let (result, straight_output) =
+            run(&mut straight, &format!("{}{}", script_before, script_after));
+        assert_eq!(result, PlayResult::Finished);
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("nested").join("save.json");
+        let mut interrupted = Game::new("hello", NUM_INCORRECT_GUESSES);
+        let (result, _) = run(&mut interrupted, &format!("{}save\n", script_before));
+        assert_eq!(result, PlayResult::Saved);
+        save_game(&path, &interrupted).unwrap();
+
+        let mut resumed = load_game(&path).unwrap();
+        assert_eq!(resumed, interrupted);
+        assert_eq!(resumed.guessed_letters_sorted(), "hqz");
+        let (result, resumed_output) = run(&mut resumed, script_after);
+        assert_eq!(result, PlayResult::Finished);
+        assert_eq!(resumed, straight);
+        assert_eq!(ending(&resumed_output), ending(&straight_output));
+
+        delete_save(&path).unwrap();
+        assert!(!path.exists());
+        delete_save(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_corrupt_save() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("save.json");
+        fs::write(&path, "{ not json").unwrap();
+        assert!(matches!(load_game(&path), Err(SaveError::Corrupt(_, _))));
+
+        let mut game = Game::new("hello", NUM_INCORRECT_GUESSES);
+        game.guess('l').unwrap();
+        game.guessd_count = 5;
+        save_game(&path, &game).unwrap();
+        assert!(matches!(load_game(&path), Err(SaveError::Corrupt(_, _))));
+    }
+
+    #[test]
+    fn test_load_version_mismatch() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("save.json");
+        fs::write(&path, r#"{ "version": 999, "game": {} }"#).unwrap();
+        assert!(matches!(
+            load_game(&path),
+            Err(SaveError::VersionMismatch(_, Some(999)))
+        ));
+        fs::write(&path, r#"{ "game": {} }"#).unwrap();
+        assert!(matches!(
+            load_game(&path),
+            Err(SaveError::VersionMismatch(_, None))
+        ));
+    }
+
+    #[test]
+    fn test_load_missing_save() {
+        let dir = TempDir::new().unwrap();
+        assert!(matches!(
+            load_game(&dir.path().join("save.json")),
+            Err(SaveError::Io(_, _))
+        ));
+    }
+}
@@ -0,0 +1,305 @@
+use rand::Rng;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Word list used when neither --words nor --category is given.
+pub const WORDS_PATH: &str = "words.txt";
+/// Directory holding one `<category>.txt` word list per category.
+pub const CATEGORIES_DIR: &str = "words";
+/// Copy of words.txt compiled into the binary, used when the file itself can't be found.
+pub const BUILTIN_WORDS: &str = include_str!("../words.txt");
+
+/// Where a word list was loaded from.
+#[derive(Debug, PartialEq)]
+pub enum WordSource {
+    File(PathBuf),
+    Builtin,
+}
+
+impl fmt::Display for WordSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WordSource::File(path) => write!(f, "words from {}", path.display()),
+            WordSource::Builtin => write!(f, "the built-in word list"),
+        }
+    }
+}
+
+/// Ways that loading a word list or picking a secret word can fail.
+#[derive(Debug)]
+pub enum WordListError {
+    Unreadable(PathBuf, io::Error),
+    Empty(PathBuf),
+    NoEligibleWords {
+        min_length: Option<usize>,
+        max_length: Option<usize>,
+    },
+}
+
+impl fmt::Display for WordListError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WordListError::Unreadable(path, err) => {
+                write!(f, "Unable to read {}: {}", path.display(), err)
+            }
+            WordListError::Empty(path) => {
+                write!(f, "{} does not contain any words", path.display())
+            }
+            WordListError::NoEligibleWords {
+                min_length,
+                max_length,
+            } => {
+                write!(f, "No words in the word list have")?;
+                match (min_length, max_length) {
+                    (Some(min), Some(max)) => write!(f, " between {} and {} letters", min, max),
+                    (Some(min), None) => write!(f, " at least {} letters", min),
+                    (None, Some(max)) => write!(f, " at most {} letters", max),
+                    (None, None) => write!(f, " any letters"),
+                }
+            }
+        }
+    }
+}
+
+/// Splits a newline-delimited word list into words, skipping blank lines and `#` comments.
+pub fn parse_word_list(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+/// Relative paths are looked up in the working directory first, then next to the executable, so
+/// the game can be run from anywhere. If neither exists the path is returned unchanged so that
+/// the eventual error message names what the user asked for.
+pub fn resolve_path(path: &Path) -> PathBuf {
+    if path.is_absolute() || path.exists() {
+        return path.to_path_buf();
+    }
+    if let Ok(exe) = std::env::current_exe() {
+        if let Some(exe_dir) = exe.parent() {
+            let candidate = exe_dir.join(path);
+            if candidate.exists() {
+                return candidate;
+            }
+        }
+    }
+    path.to_path_buf()
+}
+
+/// Path of the word list for a category, e.g. `words/animals.txt` for "animals".
+pub fn category_path(category: &str) -> PathBuf {
+    resolve_path(&Path::new(CATEGORIES_DIR).join(format!("{}.txt", category)))
+}
+
+/// Names of the categories available in `dir`, sorted alphabetically.
+pub fn list_categories(dir: &Path) -> io::Result<Vec<String>> {
+    let mut categories = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "txt") {
+            if let Some(stem) = path.file_stem() {
+                categories.push(stem.to_string_lossy().into_owned());
+            }
+        }
+    }
+    categories.sort();
+    Ok(categories)
+}
+
+/// Reads a word list, failing if the file can't be read or contains no words.
+pub fn load_words(path: &Path) -> Result<Vec<String>, WordListError> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| WordListError::Unreadable(path.to_path_buf(), err))?;
+    let words = parse_word_list(&contents);
+    if words.is_empty() {
+        return Err(WordListError::Empty(path.to_path_buf()));
+    }
+    Ok(words)
+}
+
+/// Like `load_words`, but falls back to the built-in list if the file doesn't exist. Only meant
+/// for the default list: a file the user named explicitly should fail loudly instead.
+pub fn load_words_or_builtin(path: &Path) -> Result<(Vec<String>, WordSource), WordListError> {
+    match load_words(path) {
+        Ok(words) => Ok((words, WordSource::File(path.to_path_buf()))),
+        Err(WordListError::Unreadable(_, ref err)) if err.kind() == io::ErrorKind::NotFound => {
+            Ok((parse_word_list(BUILTIN_WORDS), WordSource::Builtin))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Returns the words whose length (in characters) lies within the given bounds.
+pub fn eligible_words(
+    words: &[String],
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+) -> Vec<&str> {
+    words
+        .iter()
+        .map(|word| word.as_str())
+        .filter(|word| {
+            let len = word.chars().count();
+            min_length.is_none_or(|min| len >= min) && max_length.is_none_or(|max| len <= max)
+        })
+        .collect()
+}
+
+pub fn pick_a_random_word(
+    words: &[String],
+    min_length: Option<usize>,
+    max_length: Option<usize>,
+) -> Result<String, WordListError> {
+    let words = eligible_words(words, min_length, max_length);
+    if words.is_empty() {
+        return Err(WordListError::NoEligibleWords {
+            min_length,
+            max_length,
+        });
+    }
+    let word = words[rand::thread_rng().gen_range(0, words.len())];
+    debug_assert!(
+        !word.is_empty(),
+        "parse_word_list let an empty word through"
+    );
+    Ok(String::from(word))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+    use tempfile::{NamedTempFile, TempDir};
+
+    fn word_file(contents: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        file
+    }
+
+    fn strings(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| String::from(*word)).collect()
+    }
+
+    #[test]
+    fn test_eligible_words() {
+        let words = strings(&["a", "lobster", "oxidation", "shared", "crawfish"]);
+        assert_eq!(eligible_words(&words, None, None), words);
+        assert_eq!(
+            eligible_words(&words, Some(7), Some(8)),
+            vec!["lobster", "crawfish"]
+        );
+        assert_eq!(eligible_words(&words, Some(9), None), vec!["oxidation"]);
+        assert_eq!(eligible_words(&words, None, Some(1)), vec!["a"]);
+        assert!(eligible_words(&words, Some(10), None).is_empty());
+        assert!(eligible_words(&words, Some(8), Some(7)).is_empty());
+    }
+
+    #[test]
+    fn test_load_words_skips_blanks_and_comments() {
+        let file = word_file(
+            "# sea creatures\nlobster\n\n   \nstarfish\n  # indented comment\ncrawfish\n",
+        );
+        let words = load_words(file.path()).unwrap();
+        assert_eq!(words, strings(&["lobster", "starfish", "crawfish"]));
+        for _ in 0..100 {
+            let word = pick_a_random_word(&words, None, None).unwrap();
+            assert!(words.contains(&word));
+        }
+    }
+
+    #[test]
+    fn test_load_words_empty_file() {
+        let file = word_file("");
+        match load_words(file.path()) {
+            Err(WordListError::Empty(path)) => assert_eq!(path, file.path()),
+            other => panic!("expected Empty, got {:?}", other),
+        }
+        let file = word_file("# nothing but comments\n\n");
+        assert!(matches!(
+            load_words(file.path()),
+            Err(WordListError::Empty(_))
+        ));
+    }
+
+    #[test]
+    fn test_load_words_missing_file() {
+        let dir = TempDir::new().unwrap();
+        let missing = dir.path().join("missing.txt");
+        assert!(matches!(
+            load_words(&missing),
+            Err(WordListError::Unreadable(_, _))
+        ));
+    }
+
+    #[test]
+    fn test_list_categories() {
+        let dir = TempDir::new().unwrap();
+        for name in &["sports.txt", "animals.txt", "README.md"] {
+            fs::write(dir.path().join(name), "word\n").unwrap();
+        }
+        assert_eq!(
+            list_categories(dir.path()).unwrap(),
+            strings(&["animals", "sports"])
+        );
+    }
+
+    #[test]
+    fn test_load_words_trailing_newlines() {
+        let file = word_file("lobster\nstarfish\n\n\n");
+        assert_eq!(
+            load_words(file.path()).unwrap(),
+            strings(&["lobster", "starfish"])
+        );
+        let file = word_file("lobster\r\nstarfish\r\n");
+        assert_eq!(
+            load_words(file.path()).unwrap(),
+            strings(&["lobster", "starfish"])
+        );
+    }
+
+    #[test]
+    fn test_load_words_whitespace_only() {
+        let file = word_file("   \n\t\n \t \n");
+        assert!(matches!(
+            load_words(file.path()),
+            Err(WordListError::Empty(_))
+        ));
+        assert!(matches!(
+            load_words_or_builtin(file.path()),
+            Err(WordListError::Empty(_))
+        ));
+    }
+
+    #[test]
+    fn test_missing_file_uses_builtin() {
+        let dir = TempDir::new().unwrap();
+        let (words, source) = load_words_or_builtin(&dir.path().join("words.txt")).unwrap();
+        assert_eq!(source, WordSource::Builtin);
+        assert_eq!(words, parse_word_list(BUILTIN_WORDS));
+        assert!(!words.is_empty());
+
+        let file = word_file("lobster\n");
+        let (words, source) = load_words_or_builtin(file.path()).unwrap();
+        assert_eq!(source, WordSource::File(file.path().to_path_buf()));
+        assert_eq!(words, strings(&["lobster"]));
+    }
+
+    #[test]
+    fn test_never_picks_empty_word() {
+        let words = parse_word_list("\n  \nlobster\n\n\t\n");
+        assert!(words.iter().all(|word| !word.is_empty()));
+        for _ in 0..100 {
+            assert_eq!(pick_a_random_word(&words, None, None).unwrap(), "lobster");
+        }
+        assert!(matches!(
+            pick_a_random_word(&words, None, Some(0)),
+            Err(WordListError::NoEligibleWords { .. })
+        ));
+    }
+}
@@ -0,0 +1,22 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use parallel_map::{parallel_map, parallel_map_chunked};
+
+const INPUT_NUM: u64 = 1_000_000;
+const NUM_THREADS: usize = 4;
+
+/// Mapping a million integers through a trivial closure, where channel traffic is nearly all of
+/// the cost: one send per item versus letting `parallel_map` pick a chunk size.
+fn cheap_closure(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cheap_closure");
+    group.sample_size(10);
+    group.bench_function(BenchmarkId::new("per_item", INPUT_NUM), |b| {
+        b.iter(|| parallel_map_chunked(0..INPUT_NUM, NUM_THREADS, 1, |num| num * 3 + 1))
+    });
+    group.bench_function(BenchmarkId::new("chunked", INPUT_NUM), |b| {
+        b.iter(|| parallel_map(0..INPUT_NUM, NUM_THREADS, |num| num * 3 + 1))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, cheap_closure);
+criterion_main!(benches);
@@ -0,0 +1,36 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use parallel_map::{parallel_map, parallel_map_with_progress};
+use std::hint::black_box;
+
+const INPUT_NUM: u64 = 1_000_000;
+const NUM_THREADS: usize = 4;
+
+/// A cheap closure makes collection the bottleneck, so this is where a no-op progress callback
+/// would show up if it cost anything.
+fn noop_progress(c: &mut Criterion) {
+    let mut group = c.benchmark_group("noop_progress");
+    group.sample_size(20);
+    group.bench_function("without_progress", |b| {
+        b.iter(|| {
+            parallel_map((0..INPUT_NUM).collect::<Vec<_>>(), NUM_THREADS, |num| {
+                num * 3 + 1
+            })
+        })
+    });
+    group.bench_function("with_progress", |b| {
+        b.iter(|| {
+            parallel_map_with_progress(
+                (0..INPUT_NUM).collect(),
+                NUM_THREADS,
+                |completed, total| {
+                    black_box((completed, total));
+                },
+                |num| num * 3 + 1,
+            )
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, noop_progress);
+criterion_main!(benches);
@@ -0,0 +1,66 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use parallel_map::ParallelMapBuilder;
+use std::hint::black_box;
+use std::{thread, time};
+
+const THREAD_COUNTS: &[usize] = &[1, 2, 4, 8, 16, 32];
+
+/// Roughly 100µs of pure arithmetic per input, so the thread count that wins should track the
+/// number of CPUs.
+fn cpu_bound(num: u64) -> u64 {
+    let mut acc = num;
+    for _ in 0..50_000 {
+        acc = black_box(
+            acc.wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407),
+        );
+    }
+    acc
+}
+
+fn bench_cpu_bound(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cpu_bound");
+    group.sample_size(10);
+    for &num_threads in THREAD_COUNTS {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(num_threads),
+            &num_threads,
+            |b, &num_threads| {
+                let builder = ParallelMapBuilder::new().threads(num_threads);
+                b.iter(|| builder.map(0..256, cpu_bound))
+            },
+        );
+    }
+    group.bench_function("default", |b| {
+        b.iter(|| ParallelMapBuilder::new().map(0..256, cpu_bound))
+    });
+    group.finish();
+}
+
+/// Each input just waits for a millisecond, so more threads keep helping well past the number of
+/// CPUs.
+fn bench_sleep_bound(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sleep_bound");
+    group.sample_size(10);
+    let sleep = |num: u64| {
+        thread::sleep(time::Duration::from_millis(1));
+        num
+    };
+    for &num_threads in THREAD_COUNTS {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(num_threads),
+            &num_threads,
+            |b, &num_threads| {
+                let builder = ParallelMapBuilder::new().threads(num_threads).chunk_size(1);
+                b.iter(|| builder.map(0..128, sleep))
+            },
+        );
+    }
+    group.bench_function("default", |b| {
+        b.iter(|| ParallelMapBuilder::new().map(0..128, sleep))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_cpu_bound, bench_sleep_bound);
+criterion_main!(benches);
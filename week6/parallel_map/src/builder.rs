@@ -0,0 +1,139 @@
+use crate::{run_vec, unwrap_results, ThreadPool, CHANNEL_CAPACITY_PER_THREAD};
+use std::thread;
+
+/// Configures a map beyond what `parallel_map`'s arguments allow. Anything left unset gets a
+/// default that depends on the machine or the input:
+///
+/// - `threads`: the number of CPUs, as reported by `std::thread::available_parallelism` (1 if
+///   that can't be determined). Right for CPU-bound closures; closures that mostly wait (on I/O or
+///   sleeping) can benefit from more.
+/// - `chunk_size`: enough for about four chunks per thread, i.e. the input length (as far as its
+///   size hint knows) divided by four times the thread count, and at least 1.
+/// - `capacity`: two chunks per thread.
+///
+/// ```
+/// use parallel_map::ParallelMapBuilder;
+///
+/// let squares = ParallelMapBuilder::new()
+///     .threads(4)
+///     .map(1..=4, |num: u32| num * num);
+/// assert_eq!(squares, vec![1, 4, 9, 16]);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ParallelMapBuilder {
+    num_threads: Option<usize>,
+    chunk_size: Option<usize>,
+    capacity: Option<usize>,
+}
+
+impl ParallelMapBuilder {
+    pub fn new() -> ParallelMapBuilder {
+        ParallelMapBuilder::default()
+    }
+
+    /// Panics if `num_threads` is 0.
+    pub fn threads(mut self, num_threads: usize) -> ParallelMapBuilder {
+        assert!(num_threads > 0, "parallel_map needs at least one thread");
+        self.num_threads = Some(num_threads);
+        self
+    }
+
+    /// Panics if `chunk_size` is 0.
+    pub fn chunk_size(mut self, chunk_size: usize) -> ParallelMapBuilder {
+        assert!(
+            chunk_size > 0,
+            "parallel_map: chunk size must be at least 1"
+        );
+        self.chunk_size = Some(chunk_size);
+        self
+    }
+
+    /// How many chunks of inputs and outputs may be queued between the threads at once.
+    pub fn capacity(mut self, capacity: usize) -> ParallelMapBuilder {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// The number of threads a map will use with this configuration.
+    pub fn num_threads(&self) -> usize {
+        self.num_threads.unwrap_or_else(default_num_threads)
+    }
+
+    /// Maps `f` over `inputs` like `parallel_map` does, with this configuration.
+    pub fn map<T, U, F>(&self, inputs: impl IntoIterator<Item = T>, f: F) -> Vec<U>
+    where
+        F: Fn(T) -> U + Send + Sync + 'static,
+        T: Send + 'static,
+        U: Send + 'static,
+    {
+        let num_threads = self.num_threads();
+        let capacity = self
+            .capacity
+            .unwrap_or(num_threads * CHANNEL_CAPACITY_PER_THREAD);
+        unwrap_results(run_vec(
+            &ThreadPool::new(num_threads),
+            inputs,
+            capacity,
+            self.chunk_size,
+            || (),
+            move |_, input_val| f(input_val),
+        ))
+    }
+}
+
+/// Like `parallel_map`, but with one thread per CPU rather than a thread count of your choosing;
+/// shorthand for `ParallelMapBuilder::new().map(inputs, f)`.
+pub fn parallel_map_auto<T, U, F>(inputs: impl IntoIterator<Item = T>, f: F) -> Vec<U>
+where
+    F: Fn(T) -> U + Send + Sync + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    ParallelMapBuilder::new().map(inputs, f)
+}
+
+fn default_num_threads() -> usize {
+    thread::available_parallelism().map_or(1, |num| num.get())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_defaults_give_correct_results() {
+        let v: Vec<u64> = (0..10_000).collect();
+        let expected: Vec<u64> = v.iter().map(|num| num * num).collect();
+        assert_eq!(parallel_map_auto(v.clone(), |num| num * num), expected);
+        assert_eq!(
+            ParallelMapBuilder::new()
+                .chunk_size(13)
+                .capacity(0)
+                .map(v, |num| num * num),
+            expected
+        );
+        assert!(ParallelMapBuilder::new().num_threads() >= 1);
+    }
+
+    #[test]
+    fn test_one_thread_is_serial() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let recorder = calls.clone();
+        let builder = ParallelMapBuilder::new().threads(1);
+        assert_eq!(builder.num_threads(), 1);
+        let results = builder.map(0..100, move |num: u32| {
+            recorder.lock().unwrap().push(num);
+            num + 1
+        });
+        assert_eq!(results, (1..=100).collect::<Vec<_>>());
+        // A single worker takes the inputs one at a time, front to back.
+        assert_eq!(*calls.lock().unwrap(), (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    #[should_panic(expected = "parallel_map needs at least one thread")]
+    fn test_zero_threads_rejected() {
+        ParallelMapBuilder::new().threads(0);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::{run_vec, ThreadPool, CHANNEL_CAPACITY_PER_THREAD};
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+
+/// How much of an input's Debug rendering an `ItemPanic` keeps, in characters.
+const MAX_INPUT_SNIPPET: usize = 60;
+
+/// A panic in the closure passed to `parallel_map_checked`, together with which input caused it.
+/// The original payload is kept as is, so it can still be inspected or handed to
+/// `std::panic::resume_unwind`.
+pub struct ItemPanic {
+    index: usize,
+    input: Option<String>,
+    payload: Box<dyn Any + Send>,
+}
+
+impl ItemPanic {
+    /// The index of the input the closure panicked on.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// The Debug rendering of that input, cut short if it's long. `None` if the panic happened
+    /// outside the closure call itself (e.g. while building per-worker state).
+    pub fn input(&self) -> Option<&str> {
+        self.input.as_deref()
+    }
+
+    /// The panic message, if the payload is a string (as it is for `panic!` with a message).
+    pub fn message(&self) -> Option<&str> {
+        string_payload(&*self.payload)
+    }
+
+    pub fn payload(&self) -> &(dyn Any + Send) {
+        &*self.payload
+    }
+
+    pub fn into_payload(self) -> Box<dyn Any + Send> {
+        self.payload
+    }
+}
+
+impl fmt::Display for ItemPanic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "closure panicked on input {}", self.index)?;
+        if let Some(input) = &self.input {
+            write!(f, " ({})", input)?;
+        }
+        write!(f, ": {}", payload_message(&*self.payload))
+    }
+}
+
+impl fmt::Debug for ItemPanic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ItemPanic")
+            .field("index", &self.index)
+            .field("input", &self.input)
+            .field("message", &payload_message(&*self.payload))
+            .finish()
+    }
+}
+
+impl Error for ItemPanic {}
+
+fn string_payload(payload: &(dyn Any + Send)) -> Option<&str> {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+}
+
+/// What to show for a panic payload in an error message.
+pub(crate) fn payload_message(payload: &(dyn Any + Send)) -> &str {
+    string_payload(payload).unwrap_or("<non-string panic payload>")
+}
+
+fn debug_snippet<T: fmt::Debug>(input: &T) -> String {
+    let rendered = format!("{:?}", input);
+    if rendered.chars().count() <= MAX_INPUT_SNIPPET {
+        return rendered;
+    }
+    let mut snippet: String = rendered.chars().take(MAX_INPUT_SNIPPET).collect();
+    snippet.push_str("...");
+    snippet
+}
+
+/// Like `parallel_map`, but if `f` panics, returns an `ItemPanic` for the lowest-indexed input it
+/// panicked on instead of panicking, naming that input by index and by (the start of) its Debug
+/// rendering, and keeping the original panic payload. Every input is still processed first.
+///
+/// The input has to be rendered before `f` takes it, so this costs a `format!` per item on top of
+/// the map itself: it's meant for tracking down data-dependent crashes, not for hot paths.
+pub fn parallel_map_checked<T, U, F>(
+    inputs: impl IntoIterator<Item = T>,
+    num_threads: usize,
+    f: F,
+) -> Result<Vec<U>, ItemPanic>
+where
+    F: Fn(T) -> U + Send + Sync + 'static,
+    T: fmt::Debug + Send + 'static,
+    U: Send + 'static,
+{
+    let capacity = num_threads * CHANNEL_CAPACITY_PER_THREAD;
+    let results = run_vec(
+        &ThreadPool::new(num_threads),
+        inputs,
+        capacity,
+        None,
+        || (),
+        move |_, input_val: T| {
+            let input = debug_snippet(&input_val);
+            panic::catch_unwind(AssertUnwindSafe(|| f(input_val)))
+                .map_err(|payload| (input, payload))
+        },
+    );
+    let mut outputs = Vec::with_capacity(results.len());
+    for (index, result) in results.into_iter().enumerate() {
+        match result {
+            Ok(Ok(output_val)) => outputs.push(output_val),
+            Ok(Err((input, payload))) => {
+                return Err(ItemPanic {
+                    index,
+                    input: Some(input),
+                    payload,
+                })
+            }
+            Err(payload) => {
+                return Err(ItemPanic {
+                    index,
+                    input: None,
+                    payload,
+                })
+            }
+        }
+    }
+    Ok(outputs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_error_names_index_and_input() {
+        let words: Vec<String> = (0..50).map(|num| format!("word-{}", num)).collect();
+        let err = parallel_map_checked(words, 4, |word: String| {
+            if word.ends_with("-17") || word.ends_with("-40") {
+                panic!("cannot handle {}", word);
+            }
+            word.len()
+        })
+        .unwrap_err();
+        assert_eq!(err.index(), 17);
+        assert_eq!(err.input(), Some("\"word-17\""));
+        assert_eq!(err.message(), Some("cannot handle word-17"));
+        assert_eq!(
+            err.to_string(),
+            "closure panicked on input 17 (\"word-17\"): cannot handle word-17"
+        );
+        // The original payload survives intact.
+        let payload = err.into_payload();
+        assert_eq!(
+            payload.downcast_ref::<String>().map(String::as_str),
+            Some("cannot handle word-17")
+        );
+    }
+
+    #[test]
+    fn test_long_inputs_are_truncated() {
+        let err = parallel_map_checked(vec![vec![7u8; 100]], 1, |bytes: Vec<u8>| {
+            if bytes.len() > 10 {
+                panic!("too long");
+            }
+        })
+        .unwrap_err();
+        let input = err.input().unwrap();
+        assert_eq!(input.chars().count(), MAX_INPUT_SNIPPET + 3);
+        assert!(input.starts_with("[7, 7, 7"));
+        assert!(input.ends_with("..."));
+    }
+
+    #[test]
+    fn test_non_string_payload() {
+        let err = parallel_map_checked(vec![1, 2], 2, |num: i32| {
+            if num == 2 {
+                panic::panic_any(num);
+            }
+            num
+        })
+        .unwrap_err();
+        assert_eq!(err.message(), None);
+        assert_eq!(err.payload().downcast_ref::<i32>(), Some(&2));
+        assert!(err.to_string().ends_with("<non-string panic payload>"));
+    }
+
+    #[test]
+    fn test_no_panics() {
+        assert_eq!(
+            parallel_map_checked(0..100, 3, |num: u32| num * 2).unwrap(),
+            (0..100).map(|num| num * 2).collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,1409 @@
+mod builder;
+mod item_panic;
+mod priority;
+mod scoped;
+mod thread_pool;
+mod timeout;
+
+pub use builder::{parallel_map_auto, ParallelMapBuilder};
+pub use item_panic::{parallel_map_checked, ItemPanic};
+pub use priority::parallel_map_by_priority;
+pub use scoped::parallel_map_scoped;
+pub use thread_pool::ThreadPool;
+pub use timeout::{parallel_map_timeout, Timeout};
+
+use crossbeam_channel::{Receiver, Select, Sender};
+use std::any::Any;
+use std::collections::VecDeque;
+use std::ops::ControlFlow;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// How many items each channel buffers per worker thread unless a capacity is given explicitly.
+pub(crate) const CHANNEL_CAPACITY_PER_THREAD: usize = 2;
+
+/// Hands `(index, input)` pairs to the workers.
+type InputSender<T> = Sender<(usize, T)>;
+/// Carries `(index, result)` pairs back from the workers.
+type OutputReceiver<U> = Receiver<(usize, thread::Result<U>)>;
+
+/// Maps `f` over `inputs` using `num_threads` worker threads, returning the results in input
+/// order. If `f` panics on any input, every other input is still processed, and then this panics
+/// with the indices of all the inputs that failed and the message of the first one's panic (use
+/// `parallel_map_checked` to see the input itself and get the original payload back).
+///
+/// `inputs` can be any iterator (a range, lines from a reader, ...). Items are pulled from it
+/// only as the workers are ready for them, so the whole input never needs to be in memory at
+/// once. Its `size_hint` is used to pre-size the output and to pick a chunk size, but doesn't
+/// have to be accurate; an iterator that gives no lower bound just gets one input per chunk,
+/// which `parallel_map_chunked` can improve on.
+///
+/// This starts a fresh `ThreadPool` for the call; to run many maps, create one pool and use
+/// `ThreadPool::map` instead. To let the thread count default to the number of CPUs, use
+/// `parallel_map_auto` or `ParallelMapBuilder`.
+pub fn parallel_map<T, U, F>(
+    inputs: impl IntoIterator<Item = T>,
+    num_threads: usize,
+    f: F,
+) -> Vec<U>
+where
+    F: Fn(T) -> U + Send + Sync + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    ThreadPool::new(num_threads).map(inputs, f)
+}
+
+/// `parallel_map` for a Vec specifically, as it was before it took any iterator.
+pub fn parallel_map_vec<T, U, F>(input_vec: Vec<T>, num_threads: usize, f: F) -> Vec<U>
+where
+    F: Fn(T) -> U + Send + Sync + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    parallel_map(input_vec, num_threads, f)
+}
+
+/// Like `parallel_map`, but a panic in `f` becomes an `Err` holding the panic payload for that
+/// item instead of aborting the whole map.
+///
+/// `f` is run under `AssertUnwindSafe`. Each call gets its own input, so the only state a panic
+/// can leave half-updated is whatever `f` captures and mutates through shared references (e.g. an
+/// `Arc<Mutex<_>>`, which poisons itself anyway); keep that in mind if `f` mutates shared state.
+pub fn parallel_map_catch<T, U, F>(
+    inputs: impl IntoIterator<Item = T>,
+    num_threads: usize,
+    f: F,
+) -> Vec<thread::Result<U>>
+where
+    F: Fn(T) -> U + Send + Sync + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    let capacity = num_threads * CHANNEL_CAPACITY_PER_THREAD;
+    run_vec(
+        &ThreadPool::new(num_threads),
+        inputs,
+        capacity,
+        None,
+        || (),
+        move |_, input_val| f(input_val),
+    )
+}
+
+/// Like `parallel_map`, but with an explicit bound on how many chunks of inputs and outputs may be
+/// queued between the threads at once (see `dispatch` and `run_vec`).
+pub fn parallel_map_with_capacity<T, U, F>(
+    inputs: impl IntoIterator<Item = T>,
+    num_threads: usize,
+    capacity: usize,
+    f: F,
+) -> Vec<U>
+where
+    F: Fn(T) -> U + Send + Sync + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    unwrap_results(run_vec(
+        &ThreadPool::new(num_threads),
+        inputs,
+        capacity,
+        None,
+        || (),
+        move |_, input_val| f(input_val),
+    ))
+}
+
+/// Like `parallel_map`, but hands inputs to the workers `chunk_size` at a time instead of letting
+/// it pick a chunk size. Results, and how panics are reported, don't depend on the chunk size;
+/// only throughput does. Use 1 for expensive items of very uneven cost, or something large for
+/// cheap ones, where a channel send per item would cost more than the work itself.
+///
+/// Panics if `chunk_size` is 0.
+pub fn parallel_map_chunked<T, U, F>(
+    inputs: impl IntoIterator<Item = T>,
+    num_threads: usize,
+    chunk_size: usize,
+    f: F,
+) -> Vec<U>
+where
+    F: Fn(T) -> U + Send + Sync + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    let capacity = num_threads * CHANNEL_CAPACITY_PER_THREAD;
+    unwrap_results(run_vec(
+        &ThreadPool::new(num_threads),
+        inputs,
+        capacity,
+        Some(chunk_size),
+        || (),
+        move |_, input_val| f(input_val),
+    ))
+}
+
+/// Like `parallel_map`, but calls `progress(completed, total)` each time another result has been
+/// collected, e.g. to drive a progress bar. `completed` goes up by one with each call, ending at
+/// `total`, the number of inputs.
+///
+/// `progress` runs on the calling thread, which collects the results, so it's never called
+/// concurrently and doesn't need to be `Send` or `Sync`; it does hold up collection while it
+/// runs, so it should be quick. If it panics, it isn't called again, the map runs to completion
+/// (so no worker is left blocked), and then the panic is resumed.
+pub fn parallel_map_with_progress<T, U, F, P>(
+    input_vec: Vec<T>,
+    num_threads: usize,
+    mut progress: P,
+    f: F,
+) -> Vec<U>
+where
+    F: Fn(T) -> U + Send + Sync + 'static,
+    P: FnMut(usize, usize),
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    let total = input_vec.len();
+    let capacity = num_threads * CHANNEL_CAPACITY_PER_THREAD;
+    let mut progress_panic = None;
+    let outputs = run_vec_reporting(
+        &ThreadPool::new(num_threads),
+        input_vec,
+        capacity,
+        None,
+        || (),
+        move |_, input_val| f(input_val),
+        |completed| {
+            if progress_panic.is_none() {
+                let reported = panic::catch_unwind(AssertUnwindSafe(|| progress(completed, total)));
+                progress_panic = reported.err();
+            }
+        },
+    );
+    if let Some(payload) = progress_panic {
+        panic::resume_unwind(payload);
+    }
+    unwrap_results(outputs)
+}
+
+/// Like `parallel_map`, but each worker thread first builds its own state with `init` and passes
+/// it to every call of `f` it makes, so expensive setup (a buffer, an RNG, a connection) happens
+/// once per worker rather than once per item. If `f` panics, that worker's state is thrown away
+/// and rebuilt with `init`, since the panic may have left it half-updated.
+pub fn parallel_map_init<T, U, S, I, F>(
+    inputs: impl IntoIterator<Item = T>,
+    num_threads: usize,
+    init: I,
+    f: F,
+) -> Vec<U>
+where
+    I: Fn() -> S + Send + Sync + 'static,
+    F: Fn(&mut S, T) -> U + Send + Sync + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    let capacity = num_threads * CHANNEL_CAPACITY_PER_THREAD;
+    unwrap_results(run_vec(
+        &ThreadPool::new(num_threads),
+        inputs,
+        capacity,
+        None,
+        init,
+        f,
+    ))
+}
+
+/// Maps a fallible `f` over `inputs`, returning either every output in input order or an
+/// error from one of the inputs.
+///
+/// The error returned is the first one the collector *observes*, which isn't necessarily the one
+/// with the lowest input index: finding that would mean waiting for every earlier input. As soon
+/// as an error is seen, no more inputs are handed out and workers stop picking up queued ones, so
+/// at most about `num_threads` plus the channel capacity of extra items get computed, and their
+/// results are dropped. A panic in `f` is reported like in `parallel_map`.
+pub fn parallel_try_map<T, U, E, F>(
+    inputs: impl IntoIterator<Item = T>,
+    num_threads: usize,
+    f: F,
+) -> Result<Vec<U>, E>
+where
+    F: Fn(T) -> Result<U, E> + Send + Sync + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+    E: Send + 'static,
+{
+    let mut input_num = 0;
+    let mut output_slots = Vec::new();
+    let mut first_error = None;
+    dispatch(
+        &ThreadPool::new(num_threads),
+        inputs.into_iter().inspect(|_| input_num += 1).enumerate(),
+        num_threads * CHANNEL_CAPACITY_PER_THREAD,
+        || (),
+        move |_, input_val| f(input_val),
+        |output_index, output_val| match output_val {
+            Ok(Err(err)) => {
+                first_error = Some(err);
+                ControlFlow::Break(())
+            }
+            Ok(Ok(output_val)) => {
+                fill_slot(&mut output_slots, output_index, Ok(output_val));
+                ControlFlow::Continue(())
+            }
+            Err(payload) => {
+                fill_slot(&mut output_slots, output_index, Err(payload));
+                ControlFlow::Continue(())
+            }
+        },
+    );
+    match first_error {
+        Some(err) => Err(err),
+        None => {
+            output_slots.resize_with(input_num, || None);
+            Ok(unwrap_results(unwrap_slots(output_slots)))
+        }
+    }
+}
+
+/// Calls `f` on every input using `num_threads` worker threads, in no particular order. Since
+/// nothing is returned there's no ordering bookkeeping at all: the collector only hears about
+/// completions so it can report panics, which it does like `parallel_map` once every input has
+/// been processed.
+pub fn parallel_for_each<T, F>(inputs: impl IntoIterator<Item = T>, num_threads: usize, f: F)
+where
+    F: Fn(T) + Send + Sync + 'static,
+    T: Send + 'static,
+{
+    let mut failed = Vec::new();
+    // The payload of the panic with the lowest index seen so far, so the report is the same
+    // whichever order the panics arrive in.
+    let mut first_payload: Option<(usize, Box<dyn Any + Send>)> = None;
+    dispatch(
+        &ThreadPool::new(num_threads),
+        inputs.into_iter().enumerate(),
+        num_threads * CHANNEL_CAPACITY_PER_THREAD,
+        || (),
+        move |_, input_val| f(input_val),
+        |output_index, output_val| {
+            if let Err(payload) = output_val {
+                failed.push(output_index);
+                if first_payload
+                    .as_ref()
+                    .is_none_or(|(first_index, _)| output_index < *first_index)
+                {
+                    first_payload = Some((output_index, payload));
+                }
+            }
+            ControlFlow::Continue(())
+        },
+    );
+    failed.sort_unstable();
+    if let Some((_, payload)) = first_payload {
+        panic_with_failures(&failed, &*payload);
+    }
+}
+
+/// Maps `f` over `inputs` and keeps only the `Some` outputs, in the same relative order as
+/// their inputs regardless of which finished first. Panics are reported like in `parallel_map`.
+pub fn parallel_filter_map<T, U, F>(
+    inputs: impl IntoIterator<Item = T>,
+    num_threads: usize,
+    f: F,
+) -> Vec<U>
+where
+    F: Fn(T) -> Option<U> + Send + Sync + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    let capacity = num_threads * CHANNEL_CAPACITY_PER_THREAD;
+    let outputs = run_vec(
+        &ThreadPool::new(num_threads),
+        inputs,
+        capacity,
+        None,
+        || (),
+        move |_, input_val| f(input_val),
+    );
+    unwrap_results(outputs).into_iter().flatten().collect()
+}
+
+/// Like `parallel_map`, but returns an iterator that yields the outputs in input order as soon as
+/// they're available, instead of waiting for every input to be processed. Outputs that finish
+/// ahead of an earlier, slower input are held in a reorder buffer until it's their turn.
+///
+/// Inputs are only pulled while fewer than about `num_threads` plus twice the channel capacity of
+/// them are outstanding (fed but not yet yielded), so a slow input can't make the buffer grow
+/// without bound. Work only happens while the iterator is being advanced. Dropping it early stops
+/// the workers from picking up any more inputs and waits for the ones in progress to finish.
+///
+/// If `f` panicked on an input, advancing the iterator to that input panics with its index.
+pub fn parallel_map_iter<T, U, F>(
+    inputs: impl IntoIterator<Item = T>,
+    num_threads: usize,
+    f: F,
+) -> impl Iterator<Item = U>
+where
+    F: Fn(T) -> U + Send + Sync + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    let pool = ThreadPool::new(num_threads);
+    let capacity = num_threads * CHANNEL_CAPACITY_PER_THREAD;
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let (input_sender, output_receiver) =
+        spawn_workers(&pool, capacity, || (), move |_, x| f(x), &cancelled);
+    ParallelMapIter {
+        indexed_inputs: inputs.into_iter().enumerate(),
+        next_input: None,
+        input_sender: Some(input_sender),
+        output_receiver,
+        reorder_buffer: VecDeque::new(),
+        next_index: 0,
+        num_fed: 0,
+        max_outstanding: num_threads + 2 * capacity,
+        cancelled,
+        _pool: pool,
+    }
+}
+
+/// The iterator behind `parallel_map_iter`. `reorder_buffer[i]` holds the result for input
+/// `next_index + i` once it has arrived.
+struct ParallelMapIter<T, U, Iter> {
+    indexed_inputs: Iter,
+    /// An input pulled from `indexed_inputs` that the workers had no room for yet.
+    next_input: Option<(usize, T)>,
+    /// Dropped once `indexed_inputs` runs out, so the workers know to exit.
+    input_sender: Option<InputSender<T>>,
+    output_receiver: OutputReceiver<U>,
+    reorder_buffer: VecDeque<Option<thread::Result<U>>>,
+    next_index: usize,
+    num_fed: usize,
+    max_outstanding: usize,
+    cancelled: Arc<AtomicBool>,
+    /// Only held so the worker threads are joined when the iterator goes away. Fields are dropped
+    /// in order, so this has to stay after `output_receiver`.
+    _pool: ThreadPool,
+}
+
+impl<T, U, Iter> ParallelMapIter<T, U, Iter> {
+    fn store(&mut self, output_index: usize, output_val: thread::Result<U>) {
+        let offset = output_index - self.next_index;
+        if self.reorder_buffer.len() <= offset {
+            self.reorder_buffer.resize_with(offset + 1, || None);
+        }
+        self.reorder_buffer[offset] = Some(output_val);
+    }
+}
+
+impl<T, U, Iter> Iterator for ParallelMapIter<T, U, Iter>
+where
+    Iter: Iterator<Item = (usize, T)>,
+{
+    type Item = U;
+
+    fn next(&mut self) -> Option<U> {
+        loop {
+            if let Some(Some(_)) = self.reorder_buffer.front() {
+                let output_val = self.reorder_buffer.pop_front().unwrap().unwrap();
+                self.next_index += 1;
+                match output_val {
+                    Ok(output_val) => return Some(output_val),
+                    Err(payload) => panic_with_failures(&[self.next_index - 1], &*payload),
+                }
+            }
+            if self.next_input.is_none() && self.input_sender.is_some() {
+                self.next_input = self.indexed_inputs.next();
+                if self.next_input.is_none() {
+                    self.input_sender = None;
+                }
+            }
+            if self.next_input.is_none() && self.next_index == self.num_fed {
+                return None;
+            }
+
+            let outstanding = self.num_fed - self.next_index;
+            let (output_index, output_val) = match &self.input_sender {
+                Some(input_sender) if outstanding < self.max_outstanding => {
+                    // Same reasoning as in `dispatch`: never block on sending while the workers
+                    // might be blocked on sending their outputs to us.
+                    let mut select = Select::new();
+                    let send_index = select.send(input_sender);
+                    select.recv(&self.output_receiver);
+                    let oper = select.select();
+                    if oper.index() == send_index {
+                        match oper.send(input_sender, self.next_input.take().unwrap()) {
+                            Ok(()) => self.num_fed += 1,
+                            // Every worker is gone, so nothing more will be processed; the
+                            // missing results are reported below.
+                            Err(_) => self.input_sender = None,
+                        }
+                        continue;
+                    }
+                    oper.recv(&self.output_receiver)
+                }
+                _ => self.output_receiver.recv(),
+            }
+            .unwrap_or_else(|_| {
+                panic!(
+                    "parallel_map: no result for input index {}",
+                    self.next_index
+                )
+            });
+            self.store(output_index, output_val);
+        }
+    }
+}
+
+impl<T, U, Iter> Drop for ParallelMapIter<T, U, Iter> {
+    /// Tells the workers to stop. Any of them blocked on sending an output gives up once
+    /// `output_receiver` is dropped, which happens right after this, before the pool joins them.
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        drop(self.input_sender.take());
+    }
+}
+
+/// Picks a chunk size for `input_num` inputs when the caller doesn't: big enough that one channel
+/// send is cheap next to the work it carries, but small enough that each worker gets about four
+/// chunks, so one slow chunk near the end doesn't leave the other workers idle.
+pub(crate) fn auto_chunk_size(input_num: usize, num_threads: usize) -> usize {
+    (input_num / (num_threads * 4)).max(1)
+}
+
+/// Runs the workers over `inputs`, collecting every result into a Vec, in input order.
+///
+/// Inputs are pulled from the iterator only as the workers have room for them, so they never all
+/// have to be in memory at once. They travel to the workers `chunk_size` at a time (a channel
+/// message per chunk rather than per item; by default `auto_chunk_size` of the iterator's lower
+/// size bound), and `capacity` counts chunks. Each item is still run under its own
+/// `catch_unwind` and, after a panic, gets freshly built state, so this behaves exactly like
+/// sending them one by one.
+pub(crate) fn run_vec<T, U, S, I, F>(
+    pool: &ThreadPool,
+    inputs: impl IntoIterator<Item = T>,
+    capacity: usize,
+    chunk_size: Option<usize>,
+    init: I,
+    f: F,
+) -> Vec<thread::Result<U>>
+where
+    I: Fn() -> S + Send + Sync + 'static,
+    F: Fn(&mut S, T) -> U + Send + Sync + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    run_vec_reporting(pool, inputs, capacity, chunk_size, init, f, |_| ())
+}
+
+/// Like `run_vec`, but calls `on_collected` with the number of results collected so far each
+/// time one is, from the calling thread.
+fn run_vec_reporting<T, U, S, I, F, P>(
+    pool: &ThreadPool,
+    inputs: impl IntoIterator<Item = T>,
+    capacity: usize,
+    chunk_size: Option<usize>,
+    init: I,
+    f: F,
+    mut on_collected: P,
+) -> Vec<thread::Result<U>>
+where
+    I: Fn() -> S + Send + Sync + 'static,
+    F: Fn(&mut S, T) -> U + Send + Sync + 'static,
+    P: FnMut(usize),
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    let inputs = inputs.into_iter();
+    // Only a hint: the iterator may yield more or fewer items than this, so slots are added as
+    // results arrive and the real count is taken as the inputs go by.
+    let expected_num = inputs.size_hint().0;
+    let chunk_size =
+        chunk_size.unwrap_or_else(|| auto_chunk_size(expected_num, pool.num_threads()));
+    assert!(
+        chunk_size > 0,
+        "parallel_map: chunk size must be at least 1"
+    );
+    let init = Arc::new(init);
+    let chunk_init = init.clone();
+    let map_chunk = move |state: &mut S, chunk: Vec<T>| -> Vec<thread::Result<U>> {
+        chunk
+            .into_iter()
+            .map(|input_val| {
+                let output_val = panic::catch_unwind(AssertUnwindSafe(|| f(state, input_val)));
+                if output_val.is_err() {
+                    *state = chunk_init();
+                }
+                output_val
+            })
+            .collect()
+    };
+
+    let mut input_num = 0;
+    let mut output_slots = empty_slots(expected_num);
+    let mut num_collected = 0;
+    dispatch(
+        pool,
+        chunked(inputs.inspect(|_| input_num += 1), chunk_size),
+        capacity,
+        move || init(),
+        move |state, chunk| map_chunk(state, chunk),
+        |chunk_index, chunk_outputs| {
+            // Every item's panic was caught inside the chunk, so only `init` can get here.
+            let chunk_outputs =
+                chunk_outputs.unwrap_or_else(|payload| panic::resume_unwind(payload));
+            let start_index = chunk_index * chunk_size;
+            for (offset, output_val) in chunk_outputs.into_iter().enumerate() {
+                fill_slot(&mut output_slots, start_index + offset, output_val);
+                num_collected += 1;
+                on_collected(num_collected);
+            }
+            ControlFlow::Continue(())
+        },
+    );
+    // Slots past the end of a shorter-than-hinted input were never inputs at all, while a lost
+    // result at the very end has to show up as a missing slot.
+    output_slots.resize_with(input_num, || None);
+    unwrap_slots(output_slots)
+}
+
+/// Splits `inputs` into `(chunk index, chunk)` pairs of `chunk_size` inputs each (the last one
+/// may be shorter), front to back, pulling from `inputs` only as chunks are taken.
+fn chunked<T>(
+    mut inputs: impl Iterator<Item = T>,
+    chunk_size: usize,
+) -> impl Iterator<Item = (usize, Vec<T>)> {
+    (0..).map_while(move |chunk_index| {
+        let chunk: Vec<T> = inputs.by_ref().take(chunk_size).collect();
+        if chunk.is_empty() {
+            None
+        } else {
+            Some((chunk_index, chunk))
+        }
+    })
+}
+
+/// Turns per-item results into plain values, panicking with the failing indices if any item's
+/// closure panicked.
+pub(crate) fn unwrap_results<U>(results: Vec<thread::Result<U>>) -> Vec<U> {
+    let failed: Vec<usize> = results
+        .iter()
+        .enumerate()
+        .filter(|(_, result)| result.is_err())
+        .map(|(index, _)| index)
+        .collect();
+    if let Some(&first_index) = failed.first() {
+        if let Err(payload) = &results[first_index] {
+            panic_with_failures(&failed, &**payload);
+        }
+    }
+    results
+        .into_iter()
+        .map(|result| result.ok().unwrap())
+        .collect()
+}
+
+/// Re-raises, as a single panic on the calling thread, the panics `f` had on the inputs at the
+/// (ascending, non-empty) indices in `failed`, quoting the message of the first one so the report
+/// doesn't depend on which worker happened to fail first.
+fn panic_with_failures(failed: &[usize], first_payload: &(dyn Any + Send)) -> ! {
+    panic!(
+        "parallel_map: closure panicked on input indices {:?} (input {}: {})",
+        failed,
+        failed[0],
+        item_panic::payload_message(first_payload)
+    );
+}
+
+/// Starts workers on every thread in `pool` (see `spawn_workers`), then, from the calling thread,
+/// feeds the workers `(index, input)` pairs from `indexed_inputs`
+/// while handing each `(index, result)` to `sink` as it arrives, in completion order.
+///
+/// Both channels hold at most `capacity` items, so feeding can't get more than that far ahead of
+/// the workers and the workers can't get more than that far ahead of the sink: no matter how many
+/// inputs there are, only O(num_threads + capacity) of them are in flight at once.
+///
+/// If `sink` returns `ControlFlow::Break`, no more inputs are fed, workers stop picking up the
+/// ones already queued, and results still in flight are dropped without reaching `sink`.
+pub(crate) fn dispatch<T, U, S, Iter, I, F, K>(
+    pool: &ThreadPool,
+    indexed_inputs: Iter,
+    capacity: usize,
+    init: I,
+    f: F,
+    mut sink: K,
+) where
+    Iter: Iterator<Item = (usize, T)>,
+    I: Fn() -> S + Send + Sync + 'static,
+    F: Fn(&mut S, T) -> U + Send + Sync + 'static,
+    K: FnMut(usize, thread::Result<U>) -> ControlFlow<()>,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let (input_sender, output_receiver) = spawn_workers(pool, capacity, init, f, &cancelled);
+
+    // Feed and collect at the same time: whichever channel is ready first. Waiting for room to
+    // send while the workers wait for room to send their outputs would deadlock.
+    let mut stopped = false;
+    let mut indexed_inputs = indexed_inputs;
+    let mut next_input = indexed_inputs.next();
+    while let Some(indexed_input) = next_input.take() {
+        let mut select = Select::new();
+        let send_index = select.send(&input_sender);
+        select.recv(&output_receiver);
+        let oper = select.select();
+        if oper.index() == send_index {
+            if oper.send(&input_sender, indexed_input).is_err() {
+                // Every worker has exited. Whoever reads the results will find some missing.
+                break;
+            }
+            next_input = indexed_inputs.next();
+        } else {
+            let (output_index, output_val) = match oper.recv(&output_receiver) {
+                Ok(indexed_output) => indexed_output,
+                Err(_) => break,
+            };
+            if sink(output_index, output_val).is_break() {
+                stopped = true;
+                cancelled.store(true, Ordering::Relaxed);
+                break;
+            }
+            next_input = Some(indexed_input);
+        }
+    }
+    drop(input_sender);
+
+    // The workers exit, dropping their output senders, once the input channel is drained (or as
+    // soon as they notice the cancellation). Keep receiving until then so none of them block.
+    for (output_index, output_val) in output_receiver {
+        if !stopped && sink(output_index, output_val).is_break() {
+            stopped = true;
+            cancelled.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Occupies every thread in `pool` with a worker loop that takes `(index, input)` pairs from the
+/// returned sender and sends back `(index, result)` pairs on the returned receiver, each channel
+/// holding at most `capacity` items. The workers exit once the input sender is dropped and the
+/// queue is drained, as soon as they see `cancelled` set, or when the output receiver is dropped.
+fn spawn_workers<T, U, S, I, F>(
+    pool: &ThreadPool,
+    capacity: usize,
+    init: I,
+    f: F,
+    cancelled: &Arc<AtomicBool>,
+) -> (InputSender<T>, OutputReceiver<U>)
+where
+    I: Fn() -> S + Send + Sync + 'static,
+    F: Fn(&mut S, T) -> U + Send + Sync + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    let init = Arc::new(init);
+    let f = Arc::new(f);
+    // main thread --(input index, input data)--> work thread
+    let (input_sender, input_receiver) = crossbeam_channel::bounded(capacity);
+    // work thread --(input index, output data)--> main thread
+    let (output_sender, output_receiver) = crossbeam_channel::bounded(capacity);
+    for _ in 0..pool.num_threads() {
+        let input_receiver = input_receiver.clone();
+        let output_sender = output_sender.clone();
+        let init = init.clone();
+        let f = f.clone();
+        let cancelled = Arc::clone(cancelled);
+        pool.execute(move || {
+            let mut state = init();
+            while !cancelled.load(Ordering::Relaxed) {
+                let (input_index, input_val) = match input_receiver.recv() {
+                    Ok(indexed_input) => indexed_input,
+                    Err(_) => break,
+                };
+                let output_val = panic::catch_unwind(AssertUnwindSafe(|| f(&mut state, input_val)));
+                if output_val.is_err() {
+                    state = init();
+                }
+                if output_sender.send((input_index, output_val)).is_err() {
+                    // Nobody is collecting any more (e.g. the collector panicked): shut down
+                    // quietly rather than panicking every worker in turn.
+                    break;
+                }
+            }
+        });
+    }
+    (input_sender, output_receiver)
+}
+
+pub(crate) fn empty_slots<U>(input_num: usize) -> Vec<Option<U>> {
+    let mut output_slots = Vec::with_capacity(input_num);
+    output_slots.resize_with(input_num, || None);
+    output_slots
+}
+
+/// Stores `output_val` in the slot for `index`, first adding slots if there turned out to be more
+/// inputs than `output_slots` was sized for.
+pub(crate) fn fill_slot<U>(output_slots: &mut Vec<Option<U>>, index: usize, output_val: U) {
+    if output_slots.len() <= index {
+        output_slots.resize_with(index + 1, || None);
+    }
+    output_slots[index] = Some(output_val);
+}
+
+/// Every index in `0..input_num` must have been filled exactly once; a missing one means a worker
+/// lost an item, which is a bug we'd rather panic on than paper over.
+pub(crate) fn unwrap_slots<U>(output_slots: Vec<Option<U>>) -> Vec<U> {
+    output_slots
+        .into_iter()
+        .enumerate()
+        .map(|(index, slot)| {
+            slot.unwrap_or_else(|| panic!("parallel_map: no result for input index {}", index))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::time;
+
+    /// Deliberately has no Default impl.
+    #[derive(Debug, PartialEq)]
+    struct Labeled {
+        label: String,
+        value: u32,
+    }
+
+    #[test]
+    fn test_squares_in_order() {
+        let v: Vec<u64> = (0..100).collect();
+        let squares = parallel_map(v.clone(), 8, |num| num * num);
+        assert_eq!(squares, v.iter().map(|num| num * num).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_non_default_output() {
+        let labeled = parallel_map(vec![1, 2, 3], 2, |value| Labeled {
+            label: format!("#{}", value),
+            value,
+        });
+        assert_eq!(
+            labeled,
+            vec![
+                Labeled {
+                    label: String::from("#1"),
+                    value: 1
+                },
+                Labeled {
+                    label: String::from("#2"),
+                    value: 2
+                },
+                Labeled {
+                    label: String::from("#3"),
+                    value: 3
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_empty_input() {
+        let empty: Vec<u32> = parallel_map(Vec::new(), 4, |num: u32| num);
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_catch_keeps_other_results() {
+        let v: Vec<u32> = (0..100).collect();
+        let results = parallel_map_catch(v, 8, |num| {
+            if num == 42 {
+                panic!("unlucky number");
+            }
+            num + 1
+        });
+        assert_eq!(results.len(), 100);
+        for (index, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(value) => assert_eq!(value, index as u32 + 1),
+                Err(payload) => {
+                    assert_eq!(index, 42);
+                    assert_eq!(payload.downcast_ref::<&str>(), Some(&"unlucky number"));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_worker_survives_panics() {
+        // With a single worker, every input after a panic has to be handled by the same thread.
+        let results = parallel_map_catch(0..10, 1, |num: u32| {
+            if num.is_multiple_of(3) {
+                panic!("multiple of three");
+            }
+            num
+        });
+        let ok: Vec<u32> = results.into_iter().filter_map(Result::ok).collect();
+        assert_eq!(ok, vec![1, 2, 4, 5, 7, 8]);
+    }
+
+    #[test]
+    #[should_panic(expected = "parallel_map: closure panicked on input indices [3, 97]")]
+    fn test_panic_reports_failed_indices() {
+        parallel_map(0..100, 8, |num: u32| {
+            if num == 3 || num == 97 {
+                panic!("bad input");
+            }
+            num
+        });
+    }
+
+    #[test]
+    fn test_closure_captures_shared_table() {
+        let mut table = HashMap::new();
+        table.insert(1, "one");
+        table.insert(2, "two");
+        table.insert(3, "three");
+        let table = Arc::new(table);
+        let names = parallel_map(vec![3, 1, 2, 4], 3, move |num| {
+            table.get(&num).map(|name| name.to_string())
+        });
+        assert_eq!(
+            names,
+            vec![
+                Some(String::from("three")),
+                Some(String::from("one")),
+                Some(String::from("two")),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn test_init_once_per_worker() {
+        let init_calls = Arc::new(AtomicUsize::new(0));
+        let counter = init_calls.clone();
+        let v: Vec<usize> = (0..200).collect();
+        let results = parallel_map_init(
+            v.clone(),
+            4,
+            move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Vec::with_capacity(16)
+            },
+            |buffer: &mut Vec<usize>, num| {
+                buffer.clear();
+                buffer.extend(0..num % 5);
+                buffer.len() + num
+            },
+        );
+        assert_eq!(init_calls.load(Ordering::SeqCst), 4);
+        assert_eq!(
+            results,
+            v.iter().map(|num| num % 5 + num).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_init_rebuilds_state_after_panic() {
+        let results = run_vec(
+            &ThreadPool::new(1),
+            0..6,
+            1,
+            Some(1),
+            || 0,
+            |calls: &mut u32, num: u32| {
+                *calls += 1;
+                if num == 2 {
+                    panic!("bad input");
+                }
+                *calls
+            },
+        );
+        // Whatever order the items were processed in, the panic should reset the call count.
+        let mut calls: Vec<u32> = results.into_iter().filter_map(Result::ok).collect();
+        calls.sort();
+        assert_eq!(calls, vec![1, 1, 2, 2, 3]);
+    }
+
+    #[test]
+    fn test_bounded_in_flight() {
+        const INPUT_NUM: usize = 10_000;
+        const NUM_THREADS: usize = 4;
+        const CAPACITY: usize = 4;
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let started = Arc::new(AtomicUsize::new(0));
+        let max_queued = Arc::new(AtomicUsize::new(0));
+
+        let feeder_pulled = pulled.clone();
+        let indexed_inputs = (0..INPUT_NUM).map(move |input_index| {
+            feeder_pulled.fetch_add(1, Ordering::SeqCst);
+            (input_index, input_index)
+        });
+        let (worker_pulled, worker_started, worker_max) =
+            (pulled.clone(), started.clone(), max_queued.clone());
+        let mut output_slots = empty_slots(INPUT_NUM);
+        dispatch(
+            &ThreadPool::new(NUM_THREADS),
+            indexed_inputs,
+            CAPACITY,
+            || (),
+            move |_, num| {
+                let started = worker_started.fetch_add(1, Ordering::SeqCst) + 1;
+                let queued = worker_pulled.load(Ordering::SeqCst) - started;
+                worker_max.fetch_max(queued, Ordering::SeqCst);
+                thread::sleep(time::Duration::from_micros(20));
+                num * 2
+            },
+            |output_index, output_val| {
+                output_slots[output_index] = Some(output_val);
+                ControlFlow::Continue(())
+            },
+        );
+
+        let results = unwrap_results(unwrap_slots(output_slots));
+        assert_eq!(
+            results,
+            (0..INPUT_NUM).map(|num| num * 2).collect::<Vec<_>>()
+        );
+        assert_eq!(pulled.load(Ordering::SeqCst), INPUT_NUM);
+        // Pulled but not yet started: what sits in the channel, plus one item the feeder may be
+        // holding while blocked, plus items other workers have received but not yet counted.
+        let max_queued = max_queued.load(Ordering::SeqCst);
+        assert!(
+            max_queued <= CAPACITY + 1 + NUM_THREADS,
+            "{} items were queued at once",
+            max_queued
+        );
+    }
+
+    #[test]
+    fn test_capacity_does_not_change_results() {
+        let v: Vec<u64> = (0..1000).collect();
+        let expected: Vec<u64> = v.iter().map(|num| num * 3).collect();
+        for &capacity in &[0, 1, 7, 10_000] {
+            assert_eq!(
+                parallel_map_with_capacity(v.clone(), 3, capacity, |num| num * 3),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "parallel_map: no result for input index 1")]
+    fn test_missing_slot_detected() {
+        let output_slots = vec![
+            Some(Labeled {
+                label: String::new(),
+                value: 0,
+            }),
+            None,
+            Some(Labeled {
+                label: String::new(),
+                value: 2,
+            }),
+        ];
+        unwrap_slots(output_slots);
+    }
+
+    #[test]
+    fn test_try_map_all_ok_matches_serial() {
+        let v: Vec<i64> = (-50..50).collect();
+        let checked = |num: i64| -> Result<i64, String> { Ok(num * 2) };
+        let serial: Result<Vec<i64>, String> = v.iter().cloned().map(checked).collect();
+        assert_eq!(parallel_try_map(v, 4, checked), serial);
+    }
+
+    #[test]
+    fn test_try_map_stops_early() {
+        const INPUT_NUM: usize = 10_000;
+        const NUM_THREADS: usize = 4;
+        const FAIL_AFTER: usize = 100;
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let started = time::Instant::now();
+        let result = parallel_try_map(0..INPUT_NUM, NUM_THREADS, move |num| {
+            let call = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            thread::sleep(time::Duration::from_millis(1));
+            if call == FAIL_AFTER {
+                Err(format!("call {} failed", call))
+            } else {
+                Ok(num)
+            }
+        });
+        assert_eq!(result, Err(format!("call {} failed", FAIL_AFTER)));
+        // Running all 10,000 items would take 2.5s on 4 threads.
+        assert!(started.elapsed() < time::Duration::from_secs(1));
+        let calls = calls.load(Ordering::SeqCst);
+        // Besides the items in progress, up to a channel's worth of outputs can be queued ahead
+        // of the error, and workers keep going until the collector gets to it.
+        let capacity = NUM_THREADS * CHANNEL_CAPACITY_PER_THREAD;
+        assert!(
+            calls <= FAIL_AFTER + NUM_THREADS + 2 * capacity,
+            "{} calls after the failure",
+            calls - FAIL_AFTER
+        );
+    }
+
+    #[test]
+    fn test_try_map_empty() {
+        let result: Result<Vec<u32>, ()> = parallel_try_map(Vec::new(), 2, |num: u32| Ok(num));
+        assert_eq!(result, Ok(Vec::new()));
+    }
+
+    /// A tiny xorshift generator, so the tests get varied but reproducible inputs.
+    fn pseudo_random(seed: u64, len: usize) -> Vec<u64> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state % 1000
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_for_each_visits_every_input() {
+        let visited = Arc::new(AtomicUsize::new(0));
+        let sum = Arc::new(AtomicUsize::new(0));
+        let (visited_counter, sum_counter) = (visited.clone(), sum.clone());
+        parallel_for_each(1..=500, 6, move |num: usize| {
+            visited_counter.fetch_add(1, Ordering::SeqCst);
+            sum_counter.fetch_add(num, Ordering::SeqCst);
+        });
+        assert_eq!(visited.load(Ordering::SeqCst), 500);
+        assert_eq!(sum.load(Ordering::SeqCst), 500 * 501 / 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "parallel_map: closure panicked on input indices [4, 10]")]
+    fn test_for_each_reports_panics() {
+        parallel_for_each(0..20, 3, |num: u32| {
+            if num == 4 || num == 10 {
+                panic!("bad input");
+            }
+        });
+    }
+
+    #[test]
+    fn test_filter_map_matches_serial() {
+        for seed in 1..20 {
+            let v = pseudo_random(seed, 300);
+            let keep_odd_halves = |num: u64| if num % 2 == 1 { Some(num / 2) } else { None };
+            let serial: Vec<u64> = v.iter().cloned().filter_map(keep_odd_halves).collect();
+            let parallel = parallel_filter_map(v, (seed % 8 + 1) as usize, move |num| {
+                // Uneven work so items finish out of order.
+                thread::sleep(time::Duration::from_micros(num % 7 * 10));
+                keep_odd_halves(num)
+            });
+            assert_eq!(parallel, serial);
+        }
+    }
+
+    #[test]
+    fn test_iter_matches_vec_api() {
+        let v: Vec<u64> = pseudo_random(7, 2000);
+        let expected = parallel_map(v.clone(), 5, |num| num * 2 + 1);
+        let streamed: Vec<u64> = parallel_map_iter(v, 5, |num| {
+            thread::sleep(time::Duration::from_micros(num % 5 * 10));
+            num * 2 + 1
+        })
+        .collect();
+        assert_eq!(streamed, expected);
+        assert_eq!(parallel_map_iter(Vec::new(), 3, |num: u32| num).count(), 0);
+    }
+
+    #[test]
+    fn test_iter_early_drop_stops_workers() {
+        const INPUT_NUM: usize = 10_000;
+        const NUM_THREADS: usize = 4;
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let start = time::Instant::now();
+        let mut results = parallel_map_iter(0..INPUT_NUM, NUM_THREADS, move |num| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(time::Duration::from_millis(1));
+            num * num
+        });
+        let first: Vec<usize> = results.by_ref().take(10).collect();
+        drop(results);
+        assert_eq!(first, (0..10).map(|num| num * num).collect::<Vec<_>>());
+        // Processing everything would take about 2.5 seconds.
+        assert!(start.elapsed() < time::Duration::from_secs(1));
+        let capacity = NUM_THREADS * CHANNEL_CAPACITY_PER_THREAD;
+        let calls = calls.load(Ordering::SeqCst);
+        assert!(
+            calls <= 10 + 2 * NUM_THREADS + 2 * capacity,
+            "{} inputs were processed",
+            calls
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "parallel_map: closure panicked on input indices [5]")]
+    fn test_iter_panics_on_failed_input() {
+        for num in parallel_map_iter(0..10, 2, |num: u32| {
+            if num == 5 {
+                panic!("bad input");
+            }
+            num
+        }) {
+            assert!(num < 5);
+        }
+    }
+
+    #[test]
+    fn test_chunk_sizes_do_not_change_results() {
+        let v: Vec<u64> = pseudo_random(3, 1000);
+        let expected: Vec<u64> = v.iter().map(|num| num * 7 + 1).collect();
+        // 7 doesn't divide 1000, and 5000 is more than there are inputs.
+        for &chunk_size in &[1, 7, 5000] {
+            assert_eq!(
+                parallel_map_chunked(v.clone(), 4, chunk_size, |num| num * 7 + 1),
+                expected
+            );
+        }
+        assert!(parallel_map_chunked(Vec::new(), 4, 7, |num: u64| num).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "parallel_map: closure panicked on input indices [8, 13]")]
+    fn test_chunked_panic_reports_item_indices() {
+        parallel_map_chunked(0..20, 2, 7, |num: u32| {
+            if num == 8 || num == 13 {
+                panic!("bad input");
+            }
+            num
+        });
+    }
+
+    #[test]
+    fn test_auto_chunk_size() {
+        assert_eq!(auto_chunk_size(0, 4), 1);
+        assert_eq!(auto_chunk_size(15, 4), 1);
+        assert_eq!(auto_chunk_size(1_000_000, 4), 62_500);
+    }
+
+    #[test]
+    fn test_progress_counts_up_to_total() {
+        let mut reports = Vec::new();
+        let results = parallel_map_with_progress(
+            (0..1000).collect(),
+            4,
+            |completed, total| reports.push((completed, total)),
+            |num: u64| num + 1,
+        );
+        assert_eq!(results, (1..=1000).collect::<Vec<_>>());
+        assert_eq!(
+            reports,
+            (1..=1000)
+                .map(|completed| (completed, 1000))
+                .collect::<Vec<_>>()
+        );
+
+        let mut calls = 0;
+        parallel_map_with_progress(Vec::new(), 2, |_, _| calls += 1, |num: u32| num);
+        assert_eq!(calls, 0);
+    }
+
+    #[test]
+    fn test_progress_panic_is_resumed_after_map() {
+        let processed = Arc::new(AtomicUsize::new(0));
+        let counter = processed.clone();
+        let mut calls = 0;
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            parallel_map_with_progress(
+                (0..500).collect(),
+                4,
+                |completed, _| {
+                    calls += 1;
+                    if completed == 10 {
+                        panic!("progress bar broke");
+                    }
+                },
+                move |num: u32| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    num
+                },
+            )
+        }));
+        let payload = result.unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"progress bar broke"));
+        assert_eq!(calls, 10);
+        assert_eq!(processed.load(Ordering::SeqCst), 500);
+    }
+
+    /// Yields `0..len` while claiming, through `size_hint`, to yield `claimed` items.
+    struct LyingIter {
+        next: u32,
+        len: u32,
+        claimed: usize,
+    }
+
+    impl Iterator for LyingIter {
+        type Item = u32;
+
+        fn next(&mut self) -> Option<u32> {
+            if self.next == self.len {
+                return None;
+            }
+            self.next += 1;
+            Some(self.next - 1)
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            (self.claimed, Some(self.claimed))
+        }
+    }
+
+    #[test]
+    fn test_range_input() {
+        assert_eq!(
+            parallel_map(0..1000u64, 4, |num| num * num),
+            (0..1000u64).map(|num| num * num).collect::<Vec<_>>()
+        );
+        let doubled: Vec<u32> = parallel_map_iter(0..50, 3, |num: u32| num * 2).collect();
+        assert_eq!(doubled, (0..50).map(|num| num * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_lines_from_cursor() {
+        use std::io::{BufRead, Cursor};
+        let text: String = (0..500).map(|num| format!("line {}\n", num)).collect();
+        let lines = Cursor::new(text.clone()).lines().map(Result::unwrap);
+        let lengths = parallel_map(lines, 4, |line: String| line.len());
+        assert_eq!(
+            lengths,
+            text.lines().map(|line| line.len()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_lying_size_hint() {
+        for &(len, claimed) in &[(100, 0), (100, 3), (10, 1000), (0, 50)] {
+            let iter = LyingIter {
+                next: 0,
+                len,
+                claimed,
+            };
+            assert_eq!(
+                parallel_map(iter, 4, |num| num + 1),
+                (1..=len).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn test_vec_alias() {
+        assert_eq!(
+            parallel_map_vec(vec![1, 2, 3], 2, |num| num * 10),
+            vec![10, 20, 30]
+        );
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "parallel_map: closure panicked on input indices [12, 30] (input 12: no digit 12)"
+    )]
+    fn test_panic_quotes_first_message() {
+        parallel_map(0..40, 4, |num: u32| {
+            if num == 12 || num == 30 {
+                panic!("no digit {}", num);
+            }
+            num
+        });
+    }
+
+    #[test]
+    fn test_inputs_dispatched_front_to_back() {
+        const NUM_THREADS: usize = 4;
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let recorder = log.clone();
+        parallel_for_each(0..1000, NUM_THREADS, move |num: usize| {
+            recorder.lock().unwrap().push(num);
+        });
+        let log = log.lock().unwrap();
+        assert_eq!(log.len(), 1000);
+        // Workers interleave, but each one takes inputs off the channel in the order they were
+        // sent, so no input can be logged further from its place than the items in flight.
+        let in_flight = NUM_THREADS + NUM_THREADS * CHANNEL_CAPACITY_PER_THREAD;
+        for (position, &num) in log.iter().enumerate() {
+            assert!(
+                num.abs_diff(position) <= in_flight,
+                "input {} was logged at position {}",
+                num,
+                position
+            );
+        }
+
+        // With a single worker there's no interleaving at all.
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let recorder = log.clone();
+        let outputs: Result<Vec<usize>, ()> = parallel_try_map(0..100, 1, move |num: usize| {
+            recorder.lock().unwrap().push(num);
+            Ok(num)
+        });
+        assert_eq!(outputs.unwrap(), (0..100).collect::<Vec<_>>());
+        assert_eq!(*log.lock().unwrap(), (0..100).collect::<Vec<_>>());
+    }
+
+    /// Per-worker state that records, when its worker loop ends, whether it ended by panicking.
+    struct ExitGuard(Arc<AtomicUsize>);
+
+    impl Drop for ExitGuard {
+        fn drop(&mut self) {
+            if !thread::panicking() {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    #[test]
+    fn test_collector_panic_shuts_workers_down_quietly() {
+        const NUM_THREADS: usize = 4;
+        const CAPACITY: usize = 4;
+        const GIVE_UP_AFTER: usize = 10;
+        let clean_exits = Arc::new(AtomicUsize::new(0));
+        let processed = Arc::new(AtomicUsize::new(0));
+        let (guard_exits, counter) = (clean_exits.clone(), processed.clone());
+        let mut collected = 0;
+        let start = time::Instant::now();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let pool = ThreadPool::new(NUM_THREADS);
+            dispatch(
+                &pool,
+                (0..100_000).enumerate(),
+                CAPACITY,
+                move || ExitGuard(guard_exits.clone()),
+                move |_, num: usize| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    thread::sleep(time::Duration::from_micros(100));
+                    num
+                },
+                |_, _| {
+                    collected += 1;
+                    if collected == GIVE_UP_AFTER {
+                        panic!("collector gave up");
+                    }
+                    ControlFlow::Continue(())
+                },
+            );
+        }));
+        // The pool was dropped, joining every worker, as the panic unwound out of the closure.
+        let payload = result.unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"collector gave up"));
+        assert_eq!(clean_exits.load(Ordering::SeqCst), NUM_THREADS);
+        assert_eq!(collected, GIVE_UP_AFTER);
+        let processed = processed.load(Ordering::SeqCst);
+        assert!(
+            processed <= GIVE_UP_AFTER + NUM_THREADS + 2 * CAPACITY,
+            "{} inputs were processed",
+            processed
+        );
+        assert!(start.elapsed() < time::Duration::from_secs(1));
+    }
+}
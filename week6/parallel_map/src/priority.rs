@@ -0,0 +1,155 @@
+use crate::{
+    dispatch, empty_slots, fill_slot, unwrap_results, unwrap_slots, ThreadPool,
+    CHANNEL_CAPACITY_PER_THREAD,
+};
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::ops::ControlFlow;
+
+/// An input waiting in the feeder's heap. Higher priorities come out first, and among equal
+/// priorities, lower indices do, so that equal priorities mean plain input order.
+struct Prioritized<T> {
+    priority: u64,
+    index: usize,
+    input: T,
+}
+
+impl<T> Prioritized<T> {
+    fn key(&self) -> (u64, Reverse<usize>) {
+        (self.priority, Reverse(self.index))
+    }
+}
+
+impl<T> PartialEq for Prioritized<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl<T> Eq for Prioritized<T> {}
+
+impl<T> PartialOrd for Prioritized<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Prioritized<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// Yields `(index, input)` pairs highest priority first. `priority` is called once per input,
+/// all of them up front, since the first input out could be the last one in.
+fn by_priority<T>(
+    inputs: impl IntoIterator<Item = T>,
+    priority: impl Fn(&T) -> u64,
+) -> impl Iterator<Item = (usize, T)> {
+    let mut heap: BinaryHeap<Prioritized<T>> = inputs
+        .into_iter()
+        .enumerate()
+        .map(|(index, input)| Prioritized {
+            priority: priority(&input),
+            index,
+            input,
+        })
+        .collect();
+    std::iter::from_fn(move || heap.pop().map(|item| (item.index, item.input)))
+}
+
+/// Like `parallel_map`, but hands inputs to the workers in descending order of `priority`
+/// (ties go in input order), while still returning the outputs in input order. Giving the most
+/// expensive inputs the highest priority gets them started first, so a long one doesn't end up
+/// running alone at the end after everything else has finished.
+///
+/// Every input has to be read (and prioritized) before the first one can be handed out, so unlike
+/// `parallel_map` this holds the whole input in memory. Inputs are also sent one at a time rather
+/// than in chunks, since chunks would blur the order.
+pub fn parallel_map_by_priority<T, U, P, F>(
+    inputs: impl IntoIterator<Item = T>,
+    num_threads: usize,
+    priority: P,
+    f: F,
+) -> Vec<U>
+where
+    P: Fn(&T) -> u64,
+    F: Fn(T) -> U + Send + Sync + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    let inputs: Vec<T> = inputs.into_iter().collect();
+    let mut output_slots = empty_slots(inputs.len());
+    dispatch(
+        &ThreadPool::new(num_threads),
+        by_priority(inputs, priority),
+        num_threads * CHANNEL_CAPACITY_PER_THREAD,
+        || (),
+        move |_, input_val| f(input_val),
+        |output_index, output_val| {
+            fill_slot(&mut output_slots, output_index, output_val);
+            ControlFlow::Continue(())
+        },
+    );
+    unwrap_results(unwrap_slots(output_slots))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parallel_map;
+    use std::sync::{Arc, Mutex};
+    use std::{thread, time};
+
+    #[test]
+    fn test_dispatch_order() {
+        let costs = vec![1, 1, 1, 8, 1, 3, 1, 1];
+        let order: Vec<usize> = by_priority(costs, |&cost| cost)
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(order, vec![3, 5, 0, 1, 2, 4, 6, 7]);
+    }
+
+    #[test]
+    fn test_equal_priorities_match_parallel_map() {
+        let v: Vec<u32> = (0..500).collect();
+        let order: Vec<usize> = by_priority(v.clone(), |_| 7)
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(order, (0..500).collect::<Vec<_>>());
+        assert_eq!(
+            parallel_map_by_priority(v.clone(), 4, |_| 7, |num| num * 3),
+            parallel_map(v, 4, |num| num * 3)
+        );
+    }
+
+    #[test]
+    fn test_long_pole_goes_first() {
+        const UNIT: time::Duration = time::Duration::from_millis(30);
+        // In input order, two workers would split the seven short items (about four units) and
+        // only then start the long one, for about twelve units in total.
+        let costs: Vec<u32> = vec![1, 1, 1, 1, 1, 1, 1, 8];
+        let started = Arc::new(Mutex::new(Vec::new()));
+        let recorder = started.clone();
+        let start = time::Instant::now();
+        let outputs = parallel_map_by_priority(
+            costs.clone(),
+            2,
+            |&cost| cost as u64,
+            move |cost| {
+                recorder.lock().unwrap().push(cost);
+                thread::sleep(UNIT * cost);
+                cost * 10
+            },
+        );
+        let elapsed = start.elapsed();
+        assert_eq!(
+            outputs,
+            costs.iter().map(|cost| cost * 10).collect::<Vec<_>>()
+        );
+        // The two workers race for the first two items, so either may log first.
+        assert!(started.lock().unwrap()[..2].contains(&8));
+        // The long item starts right away while the other worker gets through the short ones.
+        assert!(elapsed < UNIT * 10, "took {:?}", elapsed);
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{auto_chunk_size, empty_slots, unwrap_results, unwrap_slots};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// Like `parallel_map`, but maps over a borrowed slice, and `f` may borrow from the caller too
+/// (a lookup table, say), since every worker thread is joined before this returns. The input
+/// isn't consumed, so the caller can keep using it afterwards.
+///
+/// There are no channels here: workers claim chunks of the slice by bumping a shared index, keep
+/// their outputs, and hand them all back when they're joined. Panics in `f` are reported like in
+/// `parallel_map`.
+pub fn parallel_map_scoped<'a, T, U, F>(input: &'a [T], num_threads: usize, f: F) -> Vec<U>
+where
+    F: Fn(&'a T) -> U + Sync,
+    T: Sync,
+    U: Send,
+{
+    assert!(num_threads > 0, "parallel_map needs at least one thread");
+    let chunk_size = auto_chunk_size(input.len(), num_threads);
+    let next_index = AtomicUsize::new(0);
+    let (f, next_index) = (&f, &next_index);
+    let mut output_slots = empty_slots(input.len());
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..num_threads)
+            .map(|_| {
+                scope.spawn(move || {
+                    let mut outputs = Vec::new();
+                    loop {
+                        let start_index = next_index.fetch_add(chunk_size, Ordering::Relaxed);
+                        if start_index >= input.len() {
+                            break;
+                        }
+                        let chunk = input[start_index..].iter().take(chunk_size);
+                        for (offset, input_val) in chunk.enumerate() {
+                            let output_val = panic::catch_unwind(AssertUnwindSafe(|| f(input_val)));
+                            outputs.push((start_index + offset, output_val));
+                        }
+                    }
+                    outputs
+                })
+            })
+            .collect();
+        for worker in workers {
+            let outputs = worker
+                .join()
+                .expect("every panic in f is caught, so workers can't panic");
+            for (output_index, output_val) in outputs {
+                output_slots[output_index] = Some(output_val);
+            }
+        }
+    });
+    unwrap_results(unwrap_slots(output_slots))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Deliberately neither Clone nor Copy.
+    #[derive(Debug)]
+    struct Record {
+        name: String,
+        scores: Vec<u32>,
+    }
+
+    #[test]
+    fn test_borrowed_slice_from_stack() {
+        let records: Vec<Record> = (0..300)
+            .map(|num| Record {
+                name: format!("record {}", num),
+                scores: (0..num % 10).collect(),
+            })
+            .collect();
+        let totals = parallel_map_scoped(&records, 4, |record| record.scores.iter().sum::<u32>());
+        let names = parallel_map_scoped(&records, 3, |record| record.name.as_str());
+        // Still ours to use.
+        assert_eq!(records.len(), 300);
+        for (index, record) in records.iter().enumerate() {
+            assert_eq!(totals[index], record.scores.iter().sum::<u32>());
+            assert_eq!(names[index], record.name);
+        }
+    }
+
+    #[test]
+    fn test_closure_borrows_local_table() {
+        let mut table = HashMap::new();
+        table.insert("lobster", 10);
+        table.insert("crab", 8);
+        let table = &table;
+        let keys = ["crab", "starfish", "lobster"];
+        let legs = parallel_map_scoped(&keys, 2, |key| table.get(key).copied());
+        assert_eq!(legs, vec![Some(8), None, Some(10)]);
+        assert!(parallel_map_scoped(&keys[..0], 2, |key| table.get(key)).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "parallel_map: closure panicked on input indices [1, 6]")]
+    fn test_scoped_panic_reports_indices() {
+        let v: Vec<u32> = (0..10).collect();
+        parallel_map_scoped(&v, 3, |&num| {
+            if num == 1 || num == 6 {
+                panic!("bad input");
+            }
+            num
+        });
+    }
+}
@@ -0,0 +1,155 @@
+use crossbeam_channel::Sender;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed set of worker threads that run jobs from a shared queue. Workers block on the queue
+/// between jobs instead of exiting, so one pool can serve any number of maps without paying for
+/// thread creation each time.
+pub struct ThreadPool {
+    job_sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    /// Starts `num_threads` worker threads. Panics if `num_threads` is 0, since a pool without
+    /// workers would never run anything.
+    pub fn new(num_threads: usize) -> ThreadPool {
+        assert!(num_threads > 0, "ThreadPool needs at least one thread");
+        let (job_sender, job_receiver) = crossbeam_channel::unbounded::<Job>();
+        let workers = (0..num_threads)
+            .map(|_| {
+                let job_receiver = job_receiver.clone();
+                thread::spawn(move || {
+                    while let Ok(job) = job_receiver.recv() {
+                        // A panicking job mustn't take the worker down with it. Whatever the job
+                        // wanted to report about the panic, it has to catch it itself.
+                        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                    }
+                })
+            })
+            .collect();
+        ThreadPool {
+            job_sender: Some(job_sender),
+            workers,
+        }
+    }
+
+    pub fn num_threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Queues `job` to run on the next free worker.
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.job_sender
+            .as_ref()
+            .expect("job sender is only taken on drop")
+            .send(Box::new(job))
+            .expect("Tried writing to channel, but there are no receivers!");
+    }
+
+    /// Maps `f` over `inputs` on this pool's threads; see `parallel_map`.
+    ///
+    /// This occupies every worker until the map finishes, so don't call it from inside a job
+    /// running on the same pool.
+    pub fn map<T, U, F>(&self, inputs: impl IntoIterator<Item = T>, f: F) -> Vec<U>
+    where
+        F: Fn(T) -> U + Send + Sync + 'static,
+        T: Send + 'static,
+        U: Send + 'static,
+    {
+        let capacity = self.num_threads() * crate::CHANNEL_CAPACITY_PER_THREAD;
+        crate::unwrap_results(crate::run_vec(
+            self,
+            inputs,
+            capacity,
+            None,
+            || (),
+            move |_, input_val| f(input_val),
+        ))
+    }
+}
+
+impl Drop for ThreadPool {
+    /// Lets the workers finish every job already queued, then joins them.
+    fn drop(&mut self) {
+        drop(self.job_sender.take());
+        for worker in self.workers.drain(..) {
+            worker.join().expect("Panic occurred in thread");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    static THREADS_SEEN: AtomicUsize = AtomicUsize::new(0);
+
+    thread_local! {
+        static SEEN: Cell<bool> = const { Cell::new(false) };
+    }
+
+    #[test]
+    fn test_many_maps_reuse_threads() {
+        let pool = ThreadPool::new(4);
+        for round in 0..1000 {
+            let v: Vec<usize> = (0..8).collect();
+            let output = pool.map(v, move |num| {
+                SEEN.with(|seen| {
+                    if !seen.replace(true) {
+                        THREADS_SEEN.fetch_add(1, Ordering::SeqCst);
+                    }
+                });
+                num + round
+            });
+            assert_eq!(output, (round..round + 8).collect::<Vec<_>>());
+        }
+        assert!(THREADS_SEEN.load(Ordering::SeqCst) <= pool.num_threads());
+    }
+
+    #[test]
+    fn test_drop_runs_queued_jobs_and_joins() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let pool = ThreadPool::new(3);
+        for _ in 0..100 {
+            let counter = counter.clone();
+            pool.execute(move || {
+                thread::sleep(std::time::Duration::from_micros(100));
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        drop(pool);
+        assert_eq!(counter.load(Ordering::SeqCst), 100);
+        // The pool's clones of the jobs (and so of the Arc) are gone too.
+        assert_eq!(Arc::strong_count(&counter), 1);
+    }
+
+    #[test]
+    fn test_panicking_job_keeps_worker_alive() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let pool = ThreadPool::new(1);
+        pool.execute(|| panic!("job failed"));
+        let job_counter = counter.clone();
+        pool.execute(move || {
+            job_counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let output = pool.map(vec![1, 2, 3], |num| num * 10);
+        drop(pool);
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+        assert_eq!(output, vec![10, 20, 30]);
+    }
+
+    #[test]
+    #[should_panic(expected = "ThreadPool needs at least one thread")]
+    fn test_zero_threads() {
+        ThreadPool::new(0);
+    }
+}
@@ -0,0 +1,250 @@
+use crate::{fill_slot, unwrap_results, unwrap_slots, CHANNEL_CAPACITY_PER_THREAD};
+use crossbeam_channel::{Receiver, Select, Sender};
+use std::collections::HashMap;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The result `parallel_map_timeout` gives an input whose closure didn't return in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout;
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "parallel_map: closure timed out")
+    }
+}
+
+/// What a worker reports back about each input it takes. The flag in `Started` is claimed by
+/// whichever of the worker (on finishing) and the collector (on timing the input out) gets to it
+/// first; the worker only sends `Finished` if it did.
+enum WorkerEvent<U> {
+    Started(usize, Instant, Arc<AtomicBool>),
+    Finished(usize, thread::Result<U>),
+}
+
+/// Like `parallel_map`, but an input whose closure runs longer than `per_item_timeout` (measured
+/// from when a worker picks it up) gets `Err(Timeout)` instead of holding up the whole map.
+///
+/// A running closure can't be killed, so the worker stuck in it is simply abandoned and a fresh
+/// one is started in its place to keep `num_threads` workers busy. The abandoned thread leaks
+/// until its closure returns, at which point its result is dropped and it exits without taking
+/// another input, so no more than `num_threads` workers are ever working through the inputs; if
+/// the closure never returns, neither does the thread. For the same reason this doesn't use a
+/// `ThreadPool`, whose workers are joined on drop.
+///
+/// Panics in `f` are reported like in `parallel_map`, once every input has a result or timed out.
+pub fn parallel_map_timeout<T, U, F>(
+    inputs: impl IntoIterator<Item = T>,
+    num_threads: usize,
+    per_item_timeout: Duration,
+    f: F,
+) -> Vec<Result<U, Timeout>>
+where
+    F: Fn(T) -> U + Send + Sync + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    assert!(num_threads > 0, "parallel_map needs at least one thread");
+    let f = Arc::new(f);
+    let capacity = num_threads * CHANNEL_CAPACITY_PER_THREAD;
+    let (input_sender, input_receiver) = crossbeam_channel::bounded(capacity);
+    let (event_sender, event_receiver) = crossbeam_channel::bounded(capacity);
+    let spawn_worker = || {
+        let input_receiver = input_receiver.clone();
+        let event_sender = event_sender.clone();
+        let f = f.clone();
+        thread::spawn(move || run_worker(input_receiver, event_sender, f));
+    };
+    for _ in 0..num_threads {
+        spawn_worker();
+    }
+
+    let mut output_slots = Vec::new();
+    let mut num_fed = 0;
+    let mut num_resolved = 0;
+    // Inputs a worker has started on but not finished, when they time out, and the flag deciding
+    // whether they finished or timed out.
+    let mut deadlines: HashMap<usize, (Instant, Arc<AtomicBool>)> = HashMap::new();
+    let mut indexed_inputs = inputs.into_iter().enumerate();
+    let mut next_input = indexed_inputs.next();
+    let mut input_sender = Some(input_sender);
+    while next_input.is_some() || num_resolved < num_fed {
+        if next_input.is_none() {
+            // Lets the workers that aren't stuck exit once the queue is drained.
+            input_sender = None;
+        }
+        let mut select = Select::new();
+        let send_index = match (&input_sender, &next_input) {
+            (Some(input_sender), Some(_)) => Some(select.send(input_sender)),
+            _ => None,
+        };
+        select.recv(&event_receiver);
+        let oper = match deadlines.values().map(|(deadline, _)| deadline).min() {
+            Some(&deadline) => {
+                select.select_timeout(deadline.saturating_duration_since(Instant::now()))
+            }
+            None => Ok(select.select()),
+        };
+
+        let oper = match oper {
+            Ok(oper) => oper,
+            Err(_) => {
+                let now = Instant::now();
+                let expired: Vec<usize> = deadlines
+                    .iter()
+                    .filter(|(_, (deadline, _))| *deadline <= now)
+                    .map(|(&input_index, _)| input_index)
+                    .collect();
+                for input_index in expired {
+                    let (_, resolved) = deadlines.remove(&input_index).unwrap();
+                    // Otherwise the worker finished just in time, and its result is on the way.
+                    if !resolved.swap(true, Ordering::SeqCst) {
+                        fill_slot(&mut output_slots, input_index, Ok(Err(Timeout)));
+                        num_resolved += 1;
+                        spawn_worker();
+                    }
+                }
+                continue;
+            }
+        };
+        if Some(oper.index()) == send_index {
+            oper.send(input_sender.as_ref().unwrap(), next_input.take().unwrap())
+                .expect("Tried writing to channel, but there are no receivers!");
+            num_fed += 1;
+            next_input = indexed_inputs.next();
+            continue;
+        }
+        // The collector holds a sender itself (to start replacements), so this can't disconnect.
+        match oper.recv(&event_receiver).unwrap() {
+            WorkerEvent::Started(input_index, started_at, resolved) => {
+                deadlines.insert(input_index, (started_at + per_item_timeout, resolved));
+            }
+            WorkerEvent::Finished(input_index, output_val) => {
+                deadlines.remove(&input_index);
+                fill_slot(&mut output_slots, input_index, output_val.map(Ok));
+                num_resolved += 1;
+            }
+        }
+    }
+    output_slots.resize_with(num_fed, || None);
+    unwrap_results(unwrap_slots(output_slots))
+}
+
+/// Runs `f` on inputs until the input channel is closed, the collector has gone away, or the input
+/// it was working on timed out (in which case a replacement has taken its place).
+fn run_worker<T, U, F>(
+    input_receiver: Receiver<(usize, T)>,
+    event_sender: Sender<WorkerEvent<U>>,
+    f: Arc<F>,
+) where
+    F: Fn(T) -> U,
+{
+    for (input_index, input_val) in input_receiver {
+        let resolved = Arc::new(AtomicBool::new(false));
+        let started = WorkerEvent::Started(input_index, Instant::now(), resolved.clone());
+        if event_sender.send(started).is_err() {
+            break;
+        }
+        let output_val = panic::catch_unwind(AssertUnwindSafe(|| f(input_val)));
+        if resolved.swap(true, Ordering::SeqCst) {
+            break;
+        }
+        if event_sender
+            .send(WorkerEvent::Finished(input_index, output_val))
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sleep_forever() -> ! {
+        loop {
+            thread::sleep(Duration::from_secs(3600));
+        }
+    }
+
+    #[test]
+    fn test_one_stuck_input_times_out() {
+        let start = Instant::now();
+        let results = parallel_map_timeout(0..50, 4, Duration::from_millis(200), |num: u64| {
+            if num == 13 {
+                sleep_forever();
+            }
+            thread::sleep(Duration::from_millis(5));
+            num * num
+        });
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(results.len(), 50);
+        for (index, result) in results.into_iter().enumerate() {
+            if index == 13 {
+                assert_eq!(result, Err(Timeout));
+            } else {
+                assert_eq!(result, Ok(index as u64 * index as u64));
+            }
+        }
+    }
+
+    #[test]
+    fn test_more_stuck_inputs_than_threads() {
+        // Without replacement workers, the two threads would be stuck after the first two.
+        let results = parallel_map_timeout(0..20, 2, Duration::from_millis(100), |num: u32| {
+            if num.is_multiple_of(5) {
+                sleep_forever();
+            }
+            num + 1
+        });
+        let timed_out: Vec<usize> = results
+            .iter()
+            .enumerate()
+            .filter(|(_, result)| result.is_err())
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(timed_out, vec![0, 5, 10, 15]);
+        assert_eq!(results[7], Ok(8));
+    }
+
+    #[test]
+    fn test_abandoned_worker_takes_no_more_inputs() {
+        use std::sync::Mutex;
+        let ran_on = Arc::new(Mutex::new(Vec::new()));
+        let recorder = ran_on.clone();
+        let results =
+            parallel_map_timeout(0..40, 2, Duration::from_millis(100), move |num: u32| {
+                recorder.lock().unwrap().push((num, thread::current().id()));
+                // Returns while plenty of inputs are still left for its thread to take
+                thread::sleep(Duration::from_millis(if num == 0 { 300 } else { 20 }));
+                num
+            });
+        assert_eq!(results[0], Err(Timeout));
+        assert_eq!(results[39], Ok(39));
+        let ran_on = ran_on.lock().unwrap();
+        let abandoned = ran_on.iter().find(|(num, _)| *num == 0).unwrap().1;
+        assert_eq!(
+            ran_on
+                .iter()
+                .filter(|(_, thread)| *thread == abandoned)
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "parallel_map: closure panicked on input indices [2]")]
+    fn test_timeout_panic_reports_indices() {
+        parallel_map_timeout(0..5, 2, Duration::from_secs(5), |num: u32| {
+            if num == 2 {
+                panic!("bad input");
+            }
+            num
+        });
+    }
+}
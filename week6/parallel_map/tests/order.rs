@@ -0,0 +1,88 @@
+//! Checks, for random inputs and thread counts, that every public map returns exactly what a
+//! serial map would: every output present, each one at its input's index.
+
+use parallel_map::{
+    parallel_map, parallel_map_chunked, parallel_map_iter, parallel_map_scoped, ThreadPool,
+};
+use proptest::prelude::*;
+use std::{thread, time};
+
+/// Depends on where in the input a value sits, so an output landing at the wrong index can't go
+/// unnoticed even when the input has repeated values.
+fn index_sensitive((index, value): (usize, u32)) -> u64 {
+    (value as u64).wrapping_mul(31) ^ (index as u64)
+}
+
+/// Sleeps a pseudo-random few microseconds first, so items finish out of order.
+fn jittered((index, value): (usize, u32)) -> u64 {
+    thread::sleep(time::Duration::from_micros(value as u64 % 50));
+    index_sensitive((index, value))
+}
+
+fn with_indices(values: &[u32]) -> Vec<(usize, u32)> {
+    values.iter().cloned().enumerate().collect()
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    // Thread counts go up to 16 while inputs may be empty or tiny, so more threads than items
+    // and a single thread are both covered.
+    #[test]
+    fn identity_matches_serial(
+        values in prop::collection::vec(any::<u32>(), 0..300),
+        num_threads in 1usize..16,
+    ) {
+        prop_assert_eq!(parallel_map(values.clone(), num_threads, |value| value), values);
+    }
+
+    #[test]
+    fn index_sensitive_matches_serial(
+        values in prop::collection::vec(any::<u32>(), 0..300),
+        num_threads in 1usize..16,
+    ) {
+        let inputs = with_indices(&values);
+        let expected: Vec<u64> = inputs.iter().cloned().map(index_sensitive).collect();
+        prop_assert_eq!(&parallel_map(inputs.clone(), num_threads, index_sensitive), &expected);
+        let streamed: Vec<u64> =
+            parallel_map_iter(inputs.clone(), num_threads, index_sensitive).collect();
+        prop_assert_eq!(&streamed, &expected);
+        let scoped = parallel_map_scoped(&inputs, num_threads, |&input| index_sensitive(input));
+        prop_assert_eq!(&scoped, &expected);
+    }
+
+    #[test]
+    fn random_sleeps_match_serial(
+        values in prop::collection::vec(any::<u32>(), 0..100),
+        num_threads in 1usize..16,
+        chunk_size in 1usize..20,
+    ) {
+        let inputs = with_indices(&values);
+        let expected: Vec<u64> = inputs.iter().cloned().map(index_sensitive).collect();
+        let chunked = parallel_map_chunked(inputs.clone(), num_threads, chunk_size, jittered);
+        prop_assert_eq!(&chunked, &expected);
+        prop_assert_eq!(&parallel_map(inputs, num_threads, jittered), &expected);
+    }
+}
+
+/// Thousands of items over many threads, reusing one pool. Meant to stay well under a few
+/// seconds, even in a debug build.
+#[test]
+fn stress_many_items_many_threads() {
+    let pool = ThreadPool::new(64);
+    for round in 0..20u32 {
+        let values: Vec<u32> = (0..5_000).map(|num| num ^ round).collect();
+        let inputs = with_indices(&values);
+        let expected: Vec<u64> = inputs.iter().cloned().map(index_sensitive).collect();
+        assert_eq!(pool.map(inputs, index_sensitive), expected);
+    }
+    let values: Vec<u32> = (0..2_000).collect();
+    let expected: Vec<u64> = with_indices(&values)
+        .into_iter()
+        .map(index_sensitive)
+        .collect();
+    assert_eq!(
+        parallel_map_chunked(with_indices(&values), 128, 1, jittered),
+        expected
+    );
+}